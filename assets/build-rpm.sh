@@ -93,6 +93,8 @@ install -Dm644 ./assets/kumo-tsa-daemon.service -t %{buildroot}/usr/lib/systemd/
 
 %files
 /opt/kumomta/sbin/kcli
+/opt/kumomta/sbin/kumo-logq
+/opt/kumomta/sbin/kumo-spool
 /opt/kumomta/sbin/kumod
 /opt/kumomta/sbin/proxy-server
 /opt/kumomta/sbin/resolve-site-name
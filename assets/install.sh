@@ -13,6 +13,8 @@ install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/tsa-daemon -t ${PREFIX}/sbi
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/proxy-server -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/kumod -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/kcli -t ${PREFIX}/sbin
+install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/kumo-spool -t ${PREFIX}/sbin
+install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/kumo-logq -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/traffic-gen -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/tailer -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/toml2jsonc -t ${PREFIX}/sbin
@@ -177,6 +177,49 @@ local function apply_sched_q_suspension(item)
   end
 end
 
+local function apply_sched_q_bounce(item)
+  local current_bounces = kumo.api.admin.bounce.list()
+  local reason =
+    string.format('%s (rule_hash=%s)', item.reason, item.rule_hash)
+
+  -- avoid conflating/overriding existing entries
+  for _, v in ipairs(current_bounces) do
+    if v.reason == reason then
+      return
+    end
+  end
+
+  kumo.api.admin.bounce.bounce {
+    campaign = item.campaign,
+    domain = item.domain,
+    tenant = item.tenant,
+    reason = reason,
+    expires = item.expires,
+  }
+end
+
+local function apply_sched_q_reroute(item)
+  local current_reroutes = kumo.api.admin.reroute.list()
+  local reason =
+    string.format('%s (rule_hash=%s)', item.reason, item.rule_hash)
+
+  -- avoid conflating/overriding existing entries
+  for _, v in ipairs(current_reroutes) do
+    if v.reason == reason and v.egress_pool == item.egress_pool then
+      return
+    end
+  end
+
+  kumo.api.admin.reroute.reroute {
+    campaign = item.campaign,
+    domain = item.domain,
+    tenant = item.tenant,
+    egress_pool = item.egress_pool,
+    reason = reason,
+    expires = item.expires,
+  }
+end
+
 local function process_suspension_subscriptions(url)
   -- Generate the websocket URL from the user-provided HTTP URL
   local endpoint =
@@ -193,19 +236,37 @@ local function process_suspension_subscriptions(url)
       apply_ready_q_suspension(data.ReadyQ)
     elseif data.SchedQ then
       apply_sched_q_suspension(data.SchedQ)
+    elseif data.Bounce then
+      apply_sched_q_bounce(data.Bounce)
+    elseif data.Reroute then
+      apply_sched_q_reroute(data.Reroute)
     end
   end
 end
 
 kumo.on('kumo.tsa.suspension.subscriber', function(args)
-  local url = args[1]
+  -- Either a single URL, or the URLs of the members of a
+  -- tsa-daemon cluster, which share the same state
+  local urls = args[1]
+  if type(urls) == 'string' then
+    urls = { urls }
+  end
 
   -- If we encounter an error (likely cause: tsa-daemon restarting),
-  -- then we'll try again after a short sleep
+  -- then we'll fail over to the next member of the cluster, and
+  -- once we've tried all of them, try again after a short sleep
+  local idx = 1
   while true do
+    local url = urls[idx]
     local status, err = pcall(process_suspension_subscriptions, url)
-    print('TSA Error, will retry in 30 seconds', status, err)
-    kumo.sleep(30)
+    idx = idx + 1
+    if idx > #urls then
+      idx = 1
+      print('TSA Error, will retry in 30 seconds', url, status, err)
+      kumo.sleep(30)
+    else
+      print('TSA Error, failing over to', urls[idx], url, status, err)
+    end
   end
 end)
 
@@ -254,11 +315,20 @@ function mod:setup_with_automation(options)
     end
   end
   if options.subscribe then
-    for _, url in ipairs(options.subscribe) do
-      table.insert(
-        file_names,
-        string.format('%s/get_config_v1/shaping.toml', url)
-      )
+    for _, entry in ipairs(options.subscribe) do
+      -- An entry may list the members of a tsa-daemon cluster;
+      -- we load the config from all of them so that it remains
+      -- available while any one of them is down
+      local urls = entry
+      if type(entry) == 'string' then
+        urls = { entry }
+      end
+      for _, url in ipairs(urls) do
+        table.insert(
+          file_names,
+          string.format('%s/get_config_v1/shaping.toml', url)
+        )
+      end
     end
   end
 
@@ -304,10 +374,10 @@ function mod:setup_with_automation(options)
     end
 
     if options.subscribe then
-      for _, url in ipairs(options.subscribe) do
+      for _, entry in ipairs(options.subscribe) do
         kumo.spawn_task {
           event_name = 'kumo.tsa.suspension.subscriber',
-          args = { url },
+          args = { entry },
         }
       end
     end
@@ -0,0 +1,150 @@
+//! Helpers for suggesting new classifier rules based on responses
+//! that were not matched by any existing rule.
+//!
+//! Responses are normalized into templates by masking out the parts
+//! that typically vary between otherwise identical responses, such
+//! as addresses, IPs, queue ids and numbers, so that they can be
+//! counted and turned into a candidate regex.
+use regex::{Captures, Regex};
+use std::sync::OnceLock;
+
+const ADDR: &str = "<ADDR>";
+const IP: &str = "<IP>";
+const ID: &str = "<ID>";
+const NUM: &str = "<NUM>";
+
+struct Patterns {
+    addr: Regex,
+    ip: Regex,
+    token: Regex,
+    space: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        addr: Regex::new(r#"[^\s<>"'()\[\]:;,]+@[A-Za-z0-9.\-\[\]:]+[A-Za-z0-9\]]"#).unwrap(),
+        ip: Regex::new(
+            r"(?:[0-9a-fA-F]{1,4}:){4,7}[0-9a-fA-F]{1,4}|(?:[0-9a-fA-F]{1,4}:)+(?::[0-9a-fA-F]{1,4})+|\b\d{1,3}(?:\.\d{1,3}){3}\b",
+        )
+        .unwrap(),
+        token: Regex::new(r"[A-Za-z0-9][A-Za-z0-9_\-./+=]*").unwrap(),
+        space: Regex::new(r"\s+").unwrap(),
+    })
+}
+
+/// Returns true if the token looks like an enhanced status code
+fn is_enhanced_code(token: &str) -> bool {
+    let mut parts = token.split('.');
+    let class = parts.next().unwrap_or("");
+    let subject = parts.next().unwrap_or("");
+    let detail = parts.next().unwrap_or("");
+    parts.next().is_none()
+        && matches!(class, "2" | "4" | "5")
+        && !subject.is_empty()
+        && subject.len() <= 3
+        && subject.chars().all(|c| c.is_ascii_digit())
+        && !detail.is_empty()
+        && detail.len() <= 3
+        && detail.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Normalize a single line response into a template by masking
+/// addresses, IP addresses, ids and numbers. The leading status code
+/// and enhanced status code are preserved.
+pub fn normalize_response(line: &str) -> String {
+    let patterns = patterns();
+
+    let line = patterns.space.replace_all(line.trim(), " ");
+    let line = patterns.addr.replace_all(&line, ADDR);
+    let line = patterns.ip.replace_all(&line, IP);
+
+    let mut is_first = true;
+    let line = patterns.token.replace_all(&line, |caps: &Captures| {
+        let token = &caps[0];
+        let first = std::mem::replace(&mut is_first, false);
+
+        if first && token.len() == 3 && token.chars().all(|c| c.is_ascii_digit()) {
+            // The status code
+            return token.to_string();
+        }
+        if is_enhanced_code(token) {
+            return token.to_string();
+        }
+        let trimmed = token.trim_end_matches('.');
+        if trimmed.chars().all(|c| c.is_ascii_digit()) {
+            return format!("{NUM}{}", &token[trimmed.len()..]);
+        }
+        let has_digit = token.chars().any(|c| c.is_ascii_digit());
+        if has_digit && trimmed.len() >= 8 && !trimmed.contains('.') {
+            return format!("{ID}{}", &token[trimmed.len()..]);
+        }
+        token.to_string()
+    });
+
+    line.into_owned()
+}
+
+/// Produce a candidate classifier rule regex from a template that
+/// was produced by `normalize_response`
+pub fn suggest_regex(template: &str) -> String {
+    let escaped = regex::escape(template)
+        .replace(ADDR, r"\S+@\S+")
+        .replace(IP, r"[0-9a-fA-F.:]+")
+        .replace(ID, r"\S+")
+        .replace(NUM, r"\d+");
+    format!("^{escaped}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let corpus = &[
+            (
+                "550 5.1.1 <user@example.com>: Recipient address rejected: User unknown",
+                "550 5.1.1 <<ADDR>>: Recipient address rejected: User unknown",
+            ),
+            (
+                "421 4.7.0 [10.0.0.1] Our system has detected an unusual rate of unsolicited mail. 1a2b3c4d5e6f7a8b - gsmtp",
+                "421 4.7.0 [<IP>] Our system has detected an unusual rate of unsolicited mail. <ID> - gsmtp",
+            ),
+            (
+                "554 5.7.1 Service unavailable; Client host [2001:db8::1] blocked using zen.spamhaus.org",
+                "554 5.7.1 Service unavailable; Client host [<IP>] blocked using zen.spamhaus.org",
+            ),
+            (
+                "452 4.2.2 The email account that you tried to reach is over quota. Try again in 3600 seconds.",
+                "452 4.2.2 The email account that you tried to reach is over quota. Try again in <NUM> seconds.",
+            ),
+            (
+                "550   too   many\r\n  spaces queue id ABCD1234EF",
+                "550 too many spaces queue id <ID>",
+            ),
+        ];
+
+        for (input, expected) in corpus {
+            assert_eq!(normalize_response(input), *expected, "input: {input}");
+        }
+    }
+
+    #[test]
+    fn regex() {
+        let line = "421 4.7.0 [10.0.0.1] rate limited. 1a2b3c4d5e6f7a8b - try again in 30 minutes";
+        let template = normalize_response(line);
+        let regex = suggest_regex(&template);
+        assert_eq!(
+            regex,
+            r"^421 4\.7\.0 \[[0-9a-fA-F.:]+\] rate limited\. \S+ \- try again in \d+ minutes"
+        );
+
+        let re = Regex::new(&regex).unwrap();
+        assert!(re.is_match(line));
+        assert!(re.is_match(
+            "421 4.7.0 [192.168.1.1] rate limited. ffff00001111 - try again in 5 minutes"
+        ));
+        assert!(!re.is_match("550 5.7.0 [10.0.0.1] rate limited. abc - try again in 5 minutes"));
+    }
+}
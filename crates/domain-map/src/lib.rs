@@ -154,6 +154,23 @@ impl<V: Clone> From<DomainMap<V>> for BTreeMap<String, V> {
     }
 }
 
+/// Returns true if `name` is equal to `suffix`, or is a subdomain of it.
+/// The comparison is case insensitive, and ignores a leading `.` on the
+/// suffix as well as trailing `.`s on either of them, so that
+/// `mx.Example.com.` has the suffix `.example.com`.
+pub fn domain_has_suffix(name: &str, suffix: &str) -> bool {
+    let name = name.trim_end_matches('.').as_bytes();
+    let suffix = suffix
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .as_bytes();
+    if name.len() < suffix.len() {
+        return false;
+    }
+    let split = name.len() - suffix.len();
+    name[split..].eq_ignore_ascii_case(suffix) && (split == 0 || name[split - 1] == b'.')
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let dmap_mod = get_or_create_sub_module(lua, "domain_map")?;
 
@@ -180,6 +197,16 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn suffix() {
+        assert!(domain_has_suffix("example.com", "example.com"));
+        assert!(domain_has_suffix("mx.Example.COM.", ".example.com"));
+        assert!(domain_has_suffix("a.b.example.com", "example.com."));
+        assert!(!domain_has_suffix("badexample.com", "example.com"));
+        assert!(!domain_has_suffix("com", "example.com"));
+        assert!(!domain_has_suffix("example.com", "mx.example.com"));
+    }
+
     #[test]
     fn basic() {
         let mut map: DomainMap<u32> = DomainMap::new();
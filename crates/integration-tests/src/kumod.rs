@@ -1,3 +1,4 @@
+use crate::otlp::OtlpCollector;
 use crate::tsa::{TsaArgs, TsaDaemon};
 use crate::webhook::WebHookServer;
 use anyhow::Context;
@@ -150,6 +151,7 @@ impl DaemonWithMaildir {
         let source = KumoDaemon::spawn(KumoArgs {
             policy_file: "source.lua".to_string(),
             env,
+            args: vec![],
         })
         .await
         .context("KumoDaemon::spawn")?;
@@ -266,6 +268,8 @@ pub struct KumoDaemon {
 pub struct KumoArgs {
     pub policy_file: String,
     pub env: Vec<(String, String)>,
+    /// Additional command line arguments for kumod
+    pub args: Vec<String>,
 }
 
 impl KumoDaemon {
@@ -273,6 +277,7 @@ impl KumoDaemon {
         KumoDaemon::spawn(KumoArgs {
             policy_file: "maildir-sink.lua".to_string(),
             env: vec![],
+            args: vec![],
         })
         .await
     }
@@ -282,6 +287,7 @@ impl KumoDaemon {
         KumoDaemon::spawn(KumoArgs {
             policy_file: "sink.lua".to_string(),
             env: vec![],
+            args: vec![],
         })
         .await
     }
@@ -297,6 +303,7 @@ impl KumoDaemon {
 
         let mut cmd = Command::new(&path);
         cmd.args(["--policy", &args.policy_file, "--user", &user.name])
+            .args(&args.args)
             .env(
                 "KUMOD_LOG",
                 "kumod=trace,kumo_server_common=info,kumo_server_runtime=info",
@@ -557,6 +564,7 @@ impl DaemonWithMaildirAndWebHook {
                     webhook.addr.port().to_string(),
                 ),
             ],
+            args: vec![],
         })
         .await?;
 
@@ -577,6 +585,36 @@ impl DaemonWithMaildirAndWebHook {
     }
 }
 
+pub struct DaemonWithMaildirAndOtlp {
+    pub with_maildir: DaemonWithMaildir,
+    pub collector: OtlpCollector,
+}
+
+impl DaemonWithMaildirAndOtlp {
+    pub async fn start() -> anyhow::Result<Self> {
+        let collector = OtlpCollector::start().await?;
+        let sink = KumoDaemon::spawn_maildir().await?;
+        let smtp = sink.listener("smtp");
+        let source = KumoDaemon::spawn(KumoArgs {
+            policy_file: "source.lua".to_string(),
+            env: vec![("KUMOD_SMTP_SINK_PORT".to_string(), smtp.port().to_string())],
+            args: vec!["--otlp-endpoint".to_string(), collector.endpoint()],
+        })
+        .await?;
+
+        Ok(Self {
+            with_maildir: DaemonWithMaildir { source, sink },
+            collector,
+        })
+    }
+
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        self.with_maildir.stop_both().await?;
+        self.collector.shutdown();
+        Ok(())
+    }
+}
+
 pub struct DaemonWithTsa {
     pub with_maildir: DaemonWithMaildir,
     pub tsa: TsaDaemon,
@@ -602,6 +640,7 @@ impl DaemonWithTsa {
                     tsa_listener.port().to_string(),
                 ),
             ],
+            args: vec![],
         })
         .await?;
 
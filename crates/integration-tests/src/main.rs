@@ -1,6 +1,8 @@
 #[cfg(test)]
 mod kumod;
 #[cfg(test)]
+mod otlp;
+#[cfg(test)]
 mod tsa;
 #[cfg(test)]
 mod webhook;
@@ -880,6 +882,69 @@ DeliverySummary {
         Ok(())
     }
 
+    /// Verify that a trace is exported for each message, and that the
+    /// spans for its reception and delivery belong to the same trace
+    #[tokio::test]
+    async fn end_to_end_with_otlp() -> anyhow::Result<()> {
+        let mut daemon = DaemonWithMaildirAndOtlp::start().await?;
+
+        let mut client = daemon.with_maildir.smtp_client().await?;
+        let response = MailGenParams::default().send(&mut client).await?;
+        eprintln!("{response:?}");
+        anyhow::ensure!(response.code == 250);
+
+        daemon
+            .with_maildir
+            .wait_for_maildir_count(1, Duration::from_secs(10))
+            .await;
+
+        // Stopping kumod flushes the exporter
+        daemon.stop().await?;
+
+        let traces = daemon.collector.traces();
+        let message_trace = traces
+            .values()
+            .find(|spans| spans.iter().any(|span| span.name == "reception"))
+            .ok_or_else(|| anyhow::anyhow!("no reception span in {traces:#?}"))?;
+
+        let mut names: Vec<&str> = message_trace
+            .iter()
+            .map(|span| span.name.as_str())
+            .collect();
+        names.sort();
+        names.dedup();
+        k9::snapshot!(
+            names,
+            r#"
+[
+    "delivery_attempt",
+    "lua_callback",
+    "reception",
+    "schedule",
+    "smtp_data",
+    "smtp_envelope",
+    "spool_store",
+]
+"#
+        );
+
+        // Every span other than the reception span has a parent
+        // within the trace
+        for span in message_trace {
+            if span.name == "reception" {
+                continue;
+            }
+            assert!(
+                message_trace
+                    .iter()
+                    .any(|parent| parent.span_id == span.parent_span_id),
+                "{span:?} has no parent in {message_trace:#?}"
+            );
+        }
+
+        Ok(())
+    }
+
     /// Verify that what we send in transits through and is delivered
     /// into the maildir at the other end with the same content,
     /// and that the webhook logging is also used and captures
@@ -0,0 +1,106 @@
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::routing::post;
+use axum::Router;
+use axum_server::Handle;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+/// A span received by the collector
+#[derive(Debug, Clone)]
+pub struct CollectedSpan {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: String,
+    pub name: String,
+}
+
+/// A stand-in for an OpenTelemetry collector, which accepts
+/// spans exported via OTLP/HTTP in protobuf encoding
+pub struct OtlpCollector {
+    pub addr: SocketAddr,
+    pub spans: Arc<Mutex<Vec<CollectedSpan>>>,
+    handle: Handle,
+}
+
+impl OtlpCollector {
+    pub async fn start() -> anyhow::Result<Self> {
+        let spans = Arc::new(Mutex::new(vec![]));
+
+        let app = Router::new()
+            .route("/v1/traces", post(export_traces))
+            .layer(Extension(Arc::clone(&spans)));
+
+        let handle = Handle::new();
+
+        let socket = TcpListener::bind("127.0.0.1:0")?;
+        let addr = socket.local_addr()?;
+
+        let server = axum_server::from_tcp(socket);
+        let handle_copy = handle.clone();
+        tokio::spawn(async move {
+            server
+                .handle(handle_copy)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        Ok(Self {
+            addr,
+            spans,
+            handle,
+        })
+    }
+
+    /// The URL to pass to kumod --otlp-endpoint
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/v1/traces", self.addr)
+    }
+
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+
+    /// Returns the received spans, grouped by trace id
+    pub fn traces(&self) -> BTreeMap<String, Vec<CollectedSpan>> {
+        let mut traces: BTreeMap<String, Vec<CollectedSpan>> = BTreeMap::new();
+        for span in self.spans.lock().unwrap().iter() {
+            traces
+                .entry(span.trace_id.clone())
+                .or_default()
+                .push(span.clone());
+        }
+        traces
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+async fn export_traces(Extension(spans): Extension<Arc<Mutex<Vec<CollectedSpan>>>>, body: Bytes) {
+    let request = match ExportTraceServiceRequest::decode(body) {
+        Ok(request) => request,
+        Err(err) => {
+            eprintln!("OtlpCollector: failed to decode request: {err:#}");
+            return;
+        }
+    };
+    let mut spans = spans.lock().unwrap();
+    for resource in request.resource_spans {
+        for scope in resource.scope_spans {
+            for span in scope.spans {
+                spans.push(CollectedSpan {
+                    trace_id: hex(&span.trace_id),
+                    span_id: hex(&span.span_id),
+                    parent_span_id: hex(&span.parent_span_id),
+                    name: span.name,
+                });
+            }
+        }
+    }
+}
@@ -88,6 +88,7 @@ impl BounceCommand {
                 reason: self.reason.clone(),
                 duration: self.duration.clone(),
                 suppress_logging: self.suppress_logging,
+                expires: None,
             },
         )
         .await?;
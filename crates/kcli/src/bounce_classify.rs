@@ -0,0 +1,88 @@
+use clap::Parser;
+use kumo_api_types::bounce_classify::{
+    BounceClassifySuggestV1Request, BounceClassifySuggestV1Response,
+};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Work with the bounce classifier
+pub struct BounceClassifyCommand {
+    #[command(subcommand)]
+    cmd: BounceClassifySubCommand,
+}
+
+#[derive(Debug, Parser)]
+enum BounceClassifySubCommand {
+    Suggest(SuggestCommand),
+}
+
+#[derive(Debug, Parser)]
+/// Show the most frequently observed responses that were not matched
+/// by any bounce classifier rule, along with a candidate rule for each.
+///
+/// Addresses, IP addresses, ids and numbers in the responses are
+/// masked out so that similar responses are grouped together.
+///
+/// The candidate rules are a starting point; you should review them,
+/// and decide which classification is appropriate, before adding them
+/// to your rules files.
+struct SuggestCommand {
+    /// The maximum number of suggestions to show
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Only show responses that have been seen at least this many times
+    #[arg(long)]
+    min_count: Option<u64>,
+
+    /// Instead of json, output the candidate rules in the
+    /// toml rules file format
+    #[arg(long)]
+    toml: bool,
+}
+
+impl BounceClassifyCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        match &self.cmd {
+            BounceClassifySubCommand::Suggest(cmd) => cmd.run(endpoint).await,
+        }
+    }
+}
+
+/// Quote a string as a toml basic string
+fn toml_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl SuggestCommand {
+    async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/bounce-classify/suggest/v1")?;
+        let request = BounceClassifySuggestV1Request {
+            limit: self.limit,
+            min_count: self.min_count,
+        };
+        request.apply_to_url(&mut url);
+
+        let result: BounceClassifySuggestV1Response =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        if self.toml {
+            println!("# Move each rule to the appropriate classification");
+            println!("[rules]");
+            println!("Uncategorized = [");
+            for suggestion in &result.suggestions {
+                println!(
+                    "  # seen {} times, e.g.: {}",
+                    suggestion.count,
+                    suggestion.example.replace(['\r', '\n'], " ")
+                );
+                println!("  {},", toml_quote(&suggestion.regex));
+            }
+            println!("]");
+        } else {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+
+        Ok(())
+    }
+}
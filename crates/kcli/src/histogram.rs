@@ -0,0 +1,152 @@
+//! Helpers for working with the histograms reported by metrics.json
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize)]
+pub struct HistogramGroup {
+    pub help: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Either a single histogram, a map of label name -> label value
+    /// -> histogram, or an array of label sets with the histogram
+    /// in their `@` field, depending on the number of labels.
+    pub value: Value,
+}
+
+impl HistogramGroup {
+    /// Combine all of the labelled histograms into one
+    pub fn merged(&self) -> Histogram {
+        let mut result = Histogram::default();
+        collect(&self.value, &mut result);
+        result
+    }
+}
+
+fn collect(value: &Value, result: &mut Histogram) {
+    match value {
+        Value::Object(map) if map.contains_key("buckets") => {
+            if let Ok(histogram) = serde_json::from_value::<Histogram>(value.clone()) {
+                result.add(&histogram);
+            }
+        }
+        Value::Object(map) => {
+            for v in map.values() {
+                collect(v, result);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                if let Some(v) = item.get("@") {
+                    collect(v, result);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub count: f64,
+    pub sum: f64,
+    /// (upper bound, cumulative count) pairs, in ascending order
+    /// of upper bound
+    pub buckets: Vec<(f64, f64)>,
+}
+
+impl Histogram {
+    fn add(&mut self, other: &Histogram) {
+        self.count += other.count;
+        self.sum += other.sum;
+        if self.buckets.is_empty() {
+            self.buckets = other.buckets.clone();
+        } else {
+            for ((_, count), (_, other_count)) in self.buckets.iter_mut().zip(other.buckets.iter())
+            {
+                *count += other_count;
+            }
+        }
+    }
+
+    /// Returns the observations made since `prior`
+    pub fn since(&self, prior: &Histogram) -> Histogram {
+        if prior.buckets.len() != self.buckets.len() || prior.count > self.count {
+            // Reset or reconfigured; treat everything as new
+            return self.clone();
+        }
+        Histogram {
+            count: self.count - prior.count,
+            sum: self.sum - prior.sum,
+            buckets: self
+                .buckets
+                .iter()
+                .zip(prior.buckets.iter())
+                .map(|((bound, count), (_, prior_count))| (*bound, count - prior_count))
+                .collect(),
+        }
+    }
+
+    /// Estimate the value at quantile `q` (0.0 - 1.0) by linear
+    /// interpolation within the bucket that holds it, in the same
+    /// way as the prometheus `histogram_quantile` function.
+    /// Returns None if there are no observations.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count <= 0. {
+            return None;
+        }
+        let rank = q * self.count;
+        let mut lower_bound = 0.;
+        let mut lower_count = 0.;
+        for (bound, count) in &self.buckets {
+            if *count >= rank {
+                let in_bucket = count - lower_count;
+                if in_bucket <= 0. {
+                    return Some(*bound);
+                }
+                return Some(
+                    lower_bound + (bound - lower_bound) * (rank - lower_count) / in_bucket,
+                );
+            }
+            lower_bound = *bound;
+            lower_count = *count;
+        }
+        // In the implicit +Inf bucket; the best we can say is that
+        // it is larger than the largest bucket
+        Some(lower_bound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_and_quantile() {
+        let group: HistogramGroup = serde_json::from_value(serde_json::json!({
+            "help": "test",
+            "type": "histogram",
+            "value": [
+                {"tenant": "a", "@": {"count": 10, "sum": 5, "buckets": [[1, 10], [10, 10]]}},
+                {"tenant": "b", "@": {"count": 10, "sum": 50, "buckets": [[1, 0], [10, 10]]}},
+            ]
+        }))
+        .unwrap();
+
+        let merged = group.merged();
+        assert_eq!(merged.count, 20.);
+        assert_eq!(merged.buckets, vec![(1., 10.), (10., 20.)]);
+        assert_eq!(merged.quantile(0.5), Some(1.));
+        assert_eq!(merged.quantile(1.0), Some(10.));
+        assert_eq!(merged.quantile(0.75), Some(5.5));
+
+        let prior = Histogram {
+            count: 10.,
+            sum: 5.,
+            buckets: vec![(1., 10.), (10., 10.)],
+        };
+        let recent = merged.since(&prior);
+        assert_eq!(recent.buckets, vec![(1., 0.), (10., 10.)]);
+        assert_eq!(recent.quantile(0.99), Some(9.91));
+        assert_eq!(Histogram::default().quantile(0.99), None);
+    }
+}
@@ -1,13 +1,23 @@
 use clap::Parser;
 use kumo_api_types::{InspectMessageV1Request, InspectMessageV1Response};
 use reqwest::Url;
+use tabout::{Alignment, Column};
 
 #[derive(Debug, Parser)]
 /// Returns information about a message in the spool
+///
+/// By default, the information is printed as JSON.
+/// Use --timeline to instead print a human readable summary of
+/// the queue status of the message and its recent delivery attempts.
 pub struct InspectMessageCommand {
     #[arg(long)]
     pub want_body: bool,
 
+    /// Print a summary of the queue status and the delivery
+    /// attempts, rather than the JSON response
+    #[arg(long)]
+    pub timeline: bool,
+
     pub id: String,
 }
 
@@ -23,8 +33,102 @@ impl InspectMessageCommand {
         let result: InspectMessageV1Response =
             crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
 
-        println!("{}", serde_json::to_string_pretty(&result)?);
+        if self.timeline {
+            print_timeline(&result)?;
+        } else {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
 
         Ok(())
     }
 }
+
+fn print_timeline(result: &InspectMessageV1Response) -> anyhow::Result<()> {
+    println!("id:         {}", result.id);
+    println!("sender:     {}", result.message.sender);
+    println!("recipient:  {}", result.message.recipient);
+
+    if let Some(status) = &result.queue {
+        println!("queue:      {}", status.queue);
+        if let Some(ready_queue) = &status.ready_queue {
+            println!("ready:      {ready_queue}");
+        }
+        println!("attempts:   {}", status.num_attempts);
+        match &status.due {
+            Some(due) => println!("due:        {}", due.to_rfc3339()),
+            None => println!("due:        now"),
+        }
+        if let Some(suspension) = &status.suspension {
+            println!(
+                "suspended:  {} (for another {})",
+                suspension.reason,
+                humantime::format_duration(suspension.duration)
+            );
+        }
+        if let Some(suspension) = &status.ready_queue_suspension {
+            println!(
+                "suspended:  ready queue: {} (for another {})",
+                suspension.reason,
+                humantime::format_duration(suspension.duration)
+            );
+        }
+        if let Some(throttle) = &status.throttle {
+            println!(
+                "throttled:  {} at {}",
+                throttle.reason,
+                throttle.timestamp.to_rfc3339()
+            );
+        }
+    }
+
+    if result.attempts.is_empty() {
+        println!("\nNo delivery attempts have been recorded");
+        return Ok(());
+    }
+
+    println!();
+    let columns = [
+        Column {
+            name: "TIMESTAMP".to_string(),
+            alignment: Alignment::Left,
+        },
+        Column {
+            name: "TYPE".to_string(),
+            alignment: Alignment::Left,
+        },
+        Column {
+            name: "SOURCE".to_string(),
+            alignment: Alignment::Left,
+        },
+        Column {
+            name: "MX".to_string(),
+            alignment: Alignment::Left,
+        },
+        Column {
+            name: "IP".to_string(),
+            alignment: Alignment::Left,
+        },
+        Column {
+            name: "RESPONSE".to_string(),
+            alignment: Alignment::Left,
+        },
+    ];
+
+    let rows: Vec<Vec<String>> = result
+        .attempts
+        .iter()
+        .map(|attempt| {
+            vec![
+                attempt.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                attempt.kind.clone(),
+                attempt.egress_source.clone().unwrap_or_default(),
+                attempt.mx.clone().unwrap_or_default(),
+                attempt.ip.clone().unwrap_or_default(),
+                attempt.response.clone(),
+            ]
+        })
+        .collect();
+
+    tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+    Ok(())
+}
@@ -5,17 +5,23 @@ use std::time::Duration;
 
 mod bounce;
 mod bounce_cancel;
+mod bounce_classify;
 mod bounce_list;
+mod histogram;
 mod inspect_message;
 mod logfilter;
 mod queue_summary;
 mod rebind;
+mod suppression_add;
+mod suppression_list;
+mod suppression_remove;
 mod suspend;
 mod suspend_cancel;
 mod suspend_list;
 mod suspend_ready_q;
 mod suspend_ready_q_cancel;
 mod suspend_ready_q_list;
+mod throttles;
 mod top;
 mod trace_smtp_client;
 mod trace_smtp_server;
@@ -54,6 +60,7 @@ enum SubCommand {
     Bounce(bounce::BounceCommand),
     BounceList(bounce_list::BounceListCommand),
     BounceCancel(bounce_cancel::BounceCancelCommand),
+    BounceClassify(bounce_classify::BounceClassifyCommand),
     Rebind(rebind::RebindCommand),
     Suspend(suspend::SuspendCommand),
     SuspendList(suspend_list::SuspendListCommand),
@@ -61,6 +68,10 @@ enum SubCommand {
     SuspendReadyQ(suspend_ready_q::SuspendReadyQCommand),
     SuspendReadyQList(suspend_ready_q_list::SuspendReadyQListCommand),
     SuspendReadyQCancel(suspend_ready_q_cancel::SuspendReadyQCancelCommand),
+    SuppressionAdd(suppression_add::SuppressionAddCommand),
+    SuppressionList(suppression_list::SuppressionListCommand),
+    SuppressionRemove(suppression_remove::SuppressionRemoveCommand),
+    Throttles(throttles::ThrottlesCommand),
     SetLogFilter(logfilter::SetLogFilterCommand),
     InspectMessage(inspect_message::InspectMessageCommand),
     QueueSummary(queue_summary::QueueSummaryCommand),
@@ -113,6 +124,7 @@ impl SubCommand {
             Self::Bounce(cmd) => cmd.run(endpoint).await,
             Self::BounceCancel(cmd) => cmd.run(endpoint).await,
             Self::BounceList(cmd) => cmd.run(endpoint).await,
+            Self::BounceClassify(cmd) => cmd.run(endpoint).await,
             Self::Rebind(cmd) => cmd.run(endpoint).await,
             Self::Suspend(cmd) => cmd.run(endpoint).await,
             Self::SuspendCancel(cmd) => cmd.run(endpoint).await,
@@ -120,6 +132,10 @@ impl SubCommand {
             Self::SuspendReadyQ(cmd) => cmd.run(endpoint).await,
             Self::SuspendReadyQCancel(cmd) => cmd.run(endpoint).await,
             Self::SuspendReadyQList(cmd) => cmd.run(endpoint).await,
+            Self::SuppressionAdd(cmd) => cmd.run(endpoint).await,
+            Self::SuppressionList(cmd) => cmd.run(endpoint).await,
+            Self::SuppressionRemove(cmd) => cmd.run(endpoint).await,
+            Self::Throttles(cmd) => cmd.run(endpoint).await,
             Self::SetLogFilter(cmd) => cmd.run(endpoint).await,
             Self::InspectMessage(cmd) => cmd.run(endpoint).await,
             Self::QueueSummary(cmd) => cmd.run(endpoint).await,
@@ -1,3 +1,4 @@
+use crate::histogram::HistogramGroup;
 use clap::Parser;
 use dns_resolver::MailExchanger;
 use kumo_api_types::{BounceV1ListEntry, SuspendReadyQueueV1ListEntry, SuspendV1ListEntry};
@@ -121,8 +122,11 @@ pub struct Metrics {
     pub message_meta_resident_count: Option<IndividualCounter>,
     pub memory_usage: Option<IndividualCounter>,
     pub memory_limit: Option<IndividualCounter>,
+    pub system_pressure: Option<IndividualCounter>,
     pub thread_pool_size: Option<ThreadPoolGroup>,
     pub thread_pool_parked: Option<ThreadPoolGroup>,
+    pub delivery_latency_reception_to_delivery: Option<HistogramGroup>,
+    pub delivery_attempt_duration: Option<HistogramGroup>,
 }
 
 pub struct ThreadPoolMetrics {
@@ -0,0 +1,53 @@
+use clap::Parser;
+use kumo_api_types::suppression::{SuppressionV1AddRequest, SuppressionV1Entry};
+use reqwest::Url;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Adds a recipient to the suppression list.
+///
+/// Messages for suppressed recipients will be rejected, or logged
+/// and dropped, at reception, according to the action that was
+/// configured via kumo.configure_suppression.
+///
+/// If an entry already exists for the tenant and recipient,
+/// it will be replaced.
+pub struct SuppressionAddCommand {
+    /// The recipient address to suppress
+    #[arg(long)]
+    recipient: String,
+
+    /// The tenant to which the entry applies.
+    /// If omitted, the entry applies to all tenants!
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// The reason for the suppression
+    #[arg(long)]
+    reason: String,
+
+    /// How long the entry remains in effect.
+    /// If omitted, the entry never expires.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    duration: Option<Duration>,
+}
+
+impl SuppressionAddCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: SuppressionV1Entry = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/suppression/v1")?,
+            &SuppressionV1AddRequest {
+                tenant: self.tenant.clone(),
+                recipient: self.recipient.clone(),
+                reason: self.reason.clone(),
+                duration: self.duration,
+            },
+        )
+        .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use clap::Parser;
+use kumo_api_types::suppression::{SuppressionV1Entry, SuppressionV1ListRequest};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the list of active suppression list entries.
+///
+/// Expired entries are not included.
+pub struct SuppressionListCommand {
+    /// Only list entries for this tenant
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// Only list entries for this recipient
+    #[arg(long)]
+    recipient: Option<String>,
+
+    /// The maximum number of entries to return
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+impl SuppressionListCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/suppression/v1")?;
+        let request = SuppressionV1ListRequest {
+            tenant: self.tenant.clone(),
+            recipient: self.recipient.clone(),
+            limit: self.limit,
+        };
+        request.apply_to_url(&mut url);
+
+        let result: Vec<SuppressionV1Entry> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
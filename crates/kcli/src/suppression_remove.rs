@@ -0,0 +1,38 @@
+use clap::Parser;
+use kumo_api_types::suppression::SuppressionV1DeleteRequest;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Removes a recipient from the suppression list.
+pub struct SuppressionRemoveCommand {
+    /// The recipient address to remove
+    #[arg(long)]
+    recipient: String,
+
+    /// The tenant of the entry to remove.
+    /// If omitted, the entry that applies to all tenants is removed.
+    #[arg(long)]
+    tenant: Option<String>,
+}
+
+impl SuppressionRemoveCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = crate::request_with_text_response(
+            reqwest::Method::DELETE,
+            endpoint.join("/api/admin/suppression/v1")?,
+            &SuppressionV1DeleteRequest {
+                tenant: self.tenant.clone(),
+                recipient: self.recipient.clone(),
+            },
+        )
+        .await?;
+
+        if !response.is_empty() {
+            println!("{response}");
+        } else {
+            println!("OK");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use clap::Parser;
+use kumo_api_types::throttles::{
+    ThrottleV1KeyRequest, ThrottleV1OverrideRequest, ThrottlesV1ListRequest, ThrottlesV1Response,
+};
+use reqwest::Url;
+use std::time::Duration;
+use tabout::{Alignment, Column};
+
+#[derive(Debug, Parser)]
+/// Lists the throttle and limit keys that are in use, or manipulates
+/// the state of a specific key.
+///
+/// With no options, prints the current state of the keys that have
+/// been used by the node, including the remaining capacity of the
+/// throttles and the leases held on the limits.
+///
+/// Overrides apply only to the node to which the request is sent,
+/// even when the throttles are shared via redis.
+/// Resetting a key that is shared via redis applies to all of the
+/// nodes that share it.
+pub struct ThrottlesCommand {
+    /// Only list the keys that contain this string
+    #[arg(long, conflicts_with_all=["reset", "override_key", "cancel_override"])]
+    key: Option<String>,
+
+    /// Print the state of the keys as JSON, rather than as a table
+    #[arg(long, conflicts_with_all=["reset", "override_key", "cancel_override"])]
+    json: bool,
+
+    /// Discard the accumulated state of this throttle key, or the
+    /// leases held on this limit key
+    #[arg(long, value_name = "KEY", conflicts_with_all=["override_key", "cancel_override"])]
+    reset: Option<String>,
+
+    /// Temporarily override the parameters of this key.
+    /// Use --spec to override a throttle key, or --limit to
+    /// override a limit key.
+    #[arg(
+        long = "override",
+        value_name = "KEY",
+        conflicts_with = "cancel_override"
+    )]
+    override_key: Option<String>,
+
+    /// The rate to use for the overridden throttle key,
+    /// such as `10/m`
+    #[arg(long, requires = "override_key", conflicts_with = "limit")]
+    spec: Option<String>,
+
+    /// The maximum number of leases to use for the
+    /// overridden limit key
+    #[arg(long, requires = "override_key")]
+    limit: Option<usize>,
+
+    /// How long the override remains active.
+    /// The default is '1h'.
+    #[arg(long, requires = "override_key", value_parser=humantime::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Remove the override of this key
+    #[arg(long, value_name = "KEY")]
+    cancel_override: Option<String>,
+}
+
+impl ThrottlesCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = if let Some(key) = &self.reset {
+            crate::request_with_text_response(
+                reqwest::Method::POST,
+                endpoint.join("/api/admin/throttles/v1/reset")?,
+                &ThrottleV1KeyRequest { key: key.clone() },
+            )
+            .await?
+        } else if let Some(key) = &self.override_key {
+            if self.spec.is_none() && self.limit.is_none() {
+                anyhow::bail!("--override requires either --spec or --limit");
+            }
+            crate::request_with_text_response(
+                reqwest::Method::POST,
+                endpoint.join("/api/admin/throttles/v1/override")?,
+                &ThrottleV1OverrideRequest {
+                    key: key.clone(),
+                    spec: self.spec.clone(),
+                    limit: self.limit,
+                    duration: self.duration.unwrap_or(Duration::from_secs(3600)),
+                },
+            )
+            .await?
+        } else if let Some(key) = &self.cancel_override {
+            crate::request_with_text_response(
+                reqwest::Method::DELETE,
+                endpoint.join("/api/admin/throttles/v1/override")?,
+                &ThrottleV1KeyRequest { key: key.clone() },
+            )
+            .await?
+        } else {
+            let mut url = endpoint.join("/api/admin/throttles/v1")?;
+            let request = ThrottlesV1ListRequest {
+                key: self.key.clone(),
+            };
+            request.apply_to_url(&mut url);
+
+            let result: ThrottlesV1Response =
+                crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+            if self.json {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+            } else {
+                print_tables(&result)?;
+            }
+            return Ok(());
+        };
+
+        if !response.is_empty() {
+            println!("{response}");
+        } else {
+            println!("OK");
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats a duration to the nearest second, as the sub-second
+/// portion is just noise in the tables
+fn format_duration(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+}
+
+fn format_override(expires: Option<Duration>) -> String {
+    match expires {
+        Some(expires) => format!("expires in {}", format_duration(expires)),
+        None => String::new(),
+    }
+}
+
+fn column(name: &str, alignment: Alignment) -> Column {
+    Column {
+        name: name.to_string(),
+        alignment,
+    }
+}
+
+fn print_tables(result: &ThrottlesV1Response) -> anyhow::Result<()> {
+    let throttle_columns = [
+        column("THROTTLE", Alignment::Left),
+        column("RATE", Alignment::Right),
+        column("BURST", Alignment::Right),
+        column("REMAINING", Alignment::Right),
+        column("RESET", Alignment::Right),
+        column("IDLE", Alignment::Right),
+        column("OVERRIDE", Alignment::Left),
+    ];
+    let mut throttle_rows = vec![];
+    for entry in &result.throttles {
+        throttle_rows.push(vec![
+            entry.key.to_string(),
+            entry.spec.to_string(),
+            entry.max_burst.to_string(),
+            entry.remaining.to_string(),
+            format_duration(entry.reset_after),
+            format_duration(entry.idle),
+            format_override(entry.override_expires),
+        ]);
+    }
+    tabout::tabulate_output(&throttle_columns, &throttle_rows, &mut std::io::stdout())?;
+
+    let limit_columns = [
+        column("LIMIT", Alignment::Left),
+        column("MAX", Alignment::Right),
+        column("LEASES", Alignment::Right),
+        column("IDLE", Alignment::Right),
+        column("OVERRIDE", Alignment::Left),
+    ];
+    let mut limit_rows = vec![];
+    for entry in &result.limits {
+        limit_rows.push(vec![
+            entry.key.to_string(),
+            entry.limit.to_string(),
+            entry.leases.len().to_string(),
+            format_duration(entry.idle),
+            format_override(entry.override_expires),
+        ]);
+    }
+
+    println!();
+
+    tabout::tabulate_output(&limit_columns, &limit_rows, &mut std::io::stdout())?;
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use crate::histogram::Histogram;
 use clap::Parser;
 use crossterm::event::{Event, KeyCode, KeyEventKind};
 use futures::StreamExt;
@@ -113,9 +114,15 @@ struct State {
     scheduled: Vec<u64>,
     ready: Vec<u64>,
     memory: Vec<u64>,
+    pressure: Vec<u64>,
     error: String,
 
     thread_pools: BTreeMap<String, Vec<u64>>,
+
+    /// p99 reception to delivery latency, in seconds
+    latency_p99: Vec<u64>,
+    /// p99 SMTP transaction duration, in milliseconds
+    attempt_p99: Vec<u64>,
 }
 
 struct DiffState {
@@ -124,6 +131,8 @@ struct DiffState {
     received: f64,
     transfail: f64,
     fail: f64,
+    latency: Histogram,
+    attempt_duration: Histogram,
 }
 
 impl State {
@@ -178,6 +187,18 @@ impl State {
                         .and_then(|m| m.value.service.get("esmtp_listener"))
                         .copied()
                         .unwrap_or(0.),
+                    latency: metrics
+                        .raw
+                        .delivery_latency_reception_to_delivery
+                        .as_ref()
+                        .map(|h| h.merged())
+                        .unwrap_or_default(),
+                    attempt_duration: metrics
+                        .raw
+                        .delivery_attempt_duration
+                        .as_ref()
+                        .map(|h| h.merged())
+                        .unwrap_or_default(),
                 };
                 let scheduled = metrics
                     .raw
@@ -204,7 +225,16 @@ impl State {
                     .and_then(|g| g.value.service.get("smtp_client").copied())
                     .unwrap_or(0.);
 
+                // Expressed as a percentage of the configured limit
+                let pressure = metrics
+                    .raw
+                    .system_pressure
+                    .as_ref()
+                    .map(|m| m.value * 100.)
+                    .unwrap_or(0.);
+
                 for (target, value) in [
+                    (&mut self.pressure, pressure),
                     (&mut self.scheduled, scheduled),
                     (&mut self.ready, ready),
                     (&mut self.listener_conns, listener_conns),
@@ -259,6 +289,21 @@ impl State {
                     push_value(&mut self.transfail, transfail as u64);
                     push_value(&mut self.fail, fail as u64);
                     push_value(&mut self.received, received as u64);
+
+                    // Compute the p99 of the observations made during
+                    // this interval
+                    let latency_p99 = new_state
+                        .latency
+                        .since(&prior.latency)
+                        .quantile(0.99)
+                        .unwrap_or(0.);
+                    let attempt_p99 = new_state
+                        .attempt_duration
+                        .since(&prior.attempt_duration)
+                        .quantile(0.99)
+                        .unwrap_or(0.);
+                    push_value(&mut self.latency_p99, latency_p99 as u64);
+                    push_value(&mut self.attempt_p99, (attempt_p99 * 1000.) as u64);
                 }
                 self.diff_state.replace(new_state);
             }
@@ -269,12 +314,15 @@ impl State {
                 push_value(&mut self.message_data_resident, 0);
                 push_value(&mut self.scheduled, 0);
                 push_value(&mut self.ready, 0);
+                push_value(&mut self.pressure, 0);
                 push_value(&mut self.listener_conns, 0);
                 push_value(&mut self.smtp_conns, 0);
                 push_value(&mut self.delivered, 0);
                 push_value(&mut self.transfail, 0);
                 push_value(&mut self.fail, 0);
                 push_value(&mut self.received, 0);
+                push_value(&mut self.latency_p99, 0);
+                push_value(&mut self.attempt_p99, 0);
                 for target in self.thread_pools.values_mut() {
                     push_value(target, 0);
                 }
@@ -386,6 +434,7 @@ impl State {
                 1,
             ),
             Entry::new("Memory", &self.memory, Color::Green, false, "b", 2),
+            Entry::new("Pressure", &self.pressure, Color::LightRed, false, "%", 1),
             Entry::new(
                 "Conn Out",
                 &self.smtp_conns,
@@ -395,6 +444,15 @@ impl State {
                 2,
             ),
             Entry::new("Conn In", &self.listener_conns, Color::Green, true, "", 2),
+            Entry::new(
+                "Latency p99",
+                &self.latency_p99,
+                Color::LightRed,
+                false,
+                "s",
+                1,
+            ),
+            Entry::new("Attempt p99", &self.attempt_p99, Color::Red, false, "ms", 1),
         ];
 
         let pool_colors = [Color::LightGreen, Color::Green];
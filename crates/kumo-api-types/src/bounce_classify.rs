@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::{IntoParams, ToResponse, ToSchema};
+
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct BounceClassifySuggestV1Request {
+    /// The maximum number of suggestions to return
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    /// Only return suggestions that have been seen at least this
+    /// many times
+    #[serde(default)]
+    pub min_count: Option<u64>,
+}
+
+impl BounceClassifySuggestV1Request {
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(limit) = self.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+        if let Some(min_count) = self.min_count {
+            query.append_pair("min_count", &min_count.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct BounceClassifySuggestion {
+    /// The normalized response, with addresses, IPs, ids and numbers
+    /// replaced by placeholders
+    #[schema(example = "550 5.1.1 <<ADDR>>: Recipient address rejected: mailbox <NUM> disabled")]
+    pub template: String,
+
+    /// A candidate classifier rule regex that matches the template
+    #[schema(
+        example = r"^550 5\.1\.1 <\S+@\S+>: Recipient address rejected: mailbox \d+ disabled"
+    )]
+    pub regex: String,
+
+    /// The number of times that a response matching this template
+    /// was observed. Once the suggestion store is full, this is an
+    /// upper bound rather than an exact count.
+    pub count: u64,
+
+    /// The most recently observed response matching this template
+    pub example: String,
+
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct BounceClassifySuggestV1Response {
+    /// The suggestions, ordered from most to least frequently observed
+    pub suggestions: Vec<BounceClassifySuggestion>,
+}
@@ -1,11 +1,13 @@
 use cidr_map::CidrSet;
 use data_loader::KeySource;
+use message::scheduling::SendingWindow;
 #[cfg(feature = "lua")]
 use mlua::prelude::*;
 use openssl::ssl::SslOptions;
 use rfc5321::SmtpClientTimeouts;
 use rustls::SupportedCipherSuite;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::time::Duration;
 use throttle::ThrottleSpec;
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Copy)]
@@ -130,6 +132,16 @@ pub struct EgressPathConfig {
     #[serde(default = "EgressPathConfig::default_connection_limit")]
     pub connection_limit: usize,
 
+    /// Caps the number of connections to the site across all of
+    /// the egress sources that send to it
+    #[serde(default)]
+    pub site_connection_limit: Option<usize>,
+
+    /// When true, site_connection_limit is enforced separately by
+    /// each node, even when redis is used to share the throttles
+    #[serde(default)]
+    pub site_connection_limit_per_node: bool,
+
     #[serde(default)]
     pub enable_tls: Tls,
 
@@ -182,6 +194,25 @@ pub struct EgressPathConfig {
     #[serde(default = "EgressPathConfig::default_max_deliveries_per_connection")]
     pub max_deliveries_per_connection: usize,
 
+    /// Restricts the times at which messages may be delivered via
+    /// this path. Messages that become ready outside of the window
+    /// are held in their scheduled queue until the window opens.
+    #[serde(default)]
+    pub sending_window: Option<SendingWindow>,
+
+    /// How long to keep an idle connection open beyond the idle_timeout,
+    /// in anticipation of more messages arriving for the same site.
+    #[serde(default, with = "duration_serde")]
+    pub idle_connection_linger: Option<Duration>,
+
+    /// While lingering, how often to send NOOP to keep the
+    /// connection alive
+    #[serde(
+        default = "EgressPathConfig::default_idle_connection_keepalive_interval",
+        with = "duration_serde"
+    )]
+    pub idle_connection_keepalive_interval: Duration,
+
     #[serde(default = "CidrSet::default_prohibited_hosts")]
     pub prohibited_hosts: CidrSet,
 
@@ -209,6 +240,8 @@ impl Default for EgressPathConfig {
     fn default() -> Self {
         Self {
             connection_limit: Self::default_connection_limit(),
+            site_connection_limit: None,
+            site_connection_limit_per_node: false,
             tls_prefer_openssl: false,
             enable_tls: Tls::default(),
             enable_mta_sts: Self::default_enable_mta_sts(),
@@ -220,6 +253,9 @@ impl Default for EgressPathConfig {
             max_message_rate: None,
             max_connection_rate: None,
             max_deliveries_per_connection: Self::default_max_deliveries_per_connection(),
+            sending_window: None,
+            idle_connection_linger: None,
+            idle_connection_keepalive_interval: Self::default_idle_connection_keepalive_interval(),
             client_timeouts: SmtpClientTimeouts::default(),
             prohibited_hosts: CidrSet::default_prohibited_hosts(),
             skip_hosts: CidrSet::default(),
@@ -265,4 +301,8 @@ impl EgressPathConfig {
     fn default_max_deliveries_per_connection() -> usize {
         1024
     }
+
+    fn default_idle_connection_keepalive_interval() -> Duration {
+        Duration::from_secs(30)
+    }
 }
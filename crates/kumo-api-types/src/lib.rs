@@ -8,9 +8,12 @@ use url::Url;
 use utoipa::{IntoParams, ToResponse, ToSchema};
 use uuid::Uuid;
 
+pub mod bounce_classify;
 pub mod egress_path;
 pub mod rebind;
 pub mod shaping;
+pub mod suppression;
+pub mod throttles;
 pub mod tsa;
 
 /// Describes which messages should be bounced.
@@ -57,11 +60,21 @@ pub struct BounceV1Request {
     /// messages.
     #[serde(default)]
     pub suppress_logging: bool,
+
+    /// The time at which this bounce directive expires. If specified,
+    /// takes precedence over duration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
 }
 
 impl BounceV1Request {
     pub fn duration(&self) -> Duration {
-        self.duration.unwrap_or_else(default_duration)
+        if let Some(expires) = &self.expires {
+            let duration = expires.signed_duration_since(Utc::now());
+            duration.to_std().unwrap_or(Duration::ZERO)
+        } else {
+            self.duration.unwrap_or_else(default_duration)
+        }
     }
 }
 
@@ -214,6 +227,90 @@ pub struct SuspendV1ListEntry {
     pub duration: Duration,
 }
 
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct RerouteV1Request {
+    /// The campaign name to match. If omitted, any campaign will match.
+    #[serde(default)]
+    pub campaign: Option<String>,
+    /// The tenant name to match. If omitted, any tenant will match.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// The domain name to match. If omitted, any domain will match.
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// The name of the egress pool to use instead of the one
+    /// from the queue configuration
+    #[schema(example = "backup-pool")]
+    pub egress_pool: String,
+
+    /// The reason for the reroute
+    #[schema(example = "shifting traffic away from a blocked pool")]
+    pub reason: String,
+
+    /// Specifies how long this reroute remains active.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<Duration>,
+
+    /// The time at which this reroute expires. If specified,
+    /// takes precedence over duration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl RerouteV1Request {
+    pub fn duration(&self) -> Duration {
+        if let Some(expires) = &self.expires {
+            let duration = expires.signed_duration_since(Utc::now());
+            duration.to_std().unwrap_or(Duration::ZERO)
+        } else {
+            self.duration.unwrap_or_else(default_duration)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct RerouteV1Response {
+    /// The id of the reroute. This can be used later to cancel
+    /// the reroute.
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct RerouteV1CancelRequest {
+    /// The id of the reroute to cancel
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct RerouteV1ListEntry {
+    /// The id of the reroute. Can be used to cancel the reroute.
+    pub id: Uuid,
+    /// The campaign name to match. If omitted, any campaign will match.
+    #[serde(default)]
+    pub campaign: Option<String>,
+    /// The tenant name to match. If omitted, any tenant will match.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// The domain name to match. If omitted, any domain will match.
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// The egress pool that matching queues will use
+    pub egress_pool: String,
+
+    /// The reason for the reroute
+    pub reason: String,
+
+    /// how long until this reroute expires and is automatically removed
+    #[serde(with = "duration_serde")]
+    pub duration: Duration,
+}
+
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct SuspendReadyQueueV1Request {
     /// The name of the ready queue that should be suspended
@@ -289,6 +386,84 @@ pub struct InspectMessageV1Response {
     pub id: SpoolId,
     /// The message information
     pub message: MessageInformation,
+    /// The most recent delivery attempts made for this message,
+    /// oldest first. kumod keeps a bounded history in memory, so
+    /// this may be empty for messages that have not been attempted
+    /// since kumod was last started.
+    #[serde(default)]
+    pub attempts: Vec<MessageDeliveryAttempt>,
+    /// Where the message is currently queued, and what, if anything,
+    /// is holding it there
+    #[serde(default)]
+    pub queue: Option<MessageQueueStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct MessageDeliveryAttempt {
+    /// When the attempt was made
+    pub timestamp: DateTime<Utc>,
+    /// The type of the log record produced by the attempt
+    #[schema(example = "TransientFailure")]
+    pub kind: String,
+    /// The site name of the destination
+    #[schema(example = "(alt1|alt2|alt3|alt4)?.gmail-smtp-in.l.google.com")]
+    pub site: String,
+    /// The MX host that was being talked to
+    #[serde(default)]
+    #[schema(example = "gmail-smtp-in.l.google.com.")]
+    pub mx: Option<String>,
+    /// The IP address of the MX host
+    #[serde(default)]
+    #[schema(example = "142.250.101.26")]
+    pub ip: Option<String>,
+    /// The egress pool that was used
+    #[serde(default)]
+    pub egress_pool: Option<String>,
+    /// The egress source that was used
+    #[serde(default)]
+    pub egress_source: Option<String>,
+    /// The local address that the connection was made from
+    #[serde(default)]
+    pub source_address: Option<String>,
+    /// The response to the attempt
+    #[schema(example = "451 4.7.28 Our system has detected an unusual rate of mail")]
+    pub response: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct MessageQueueStatus {
+    /// The name of the scheduled queue
+    #[schema(example = "campaign:tenant@example.com")]
+    pub queue: String,
+    /// The ready queue that the message will use, based
+    /// on the egress source used by its most recent attempt
+    #[serde(default)]
+    pub ready_queue: Option<String>,
+    /// The number of delivery attempts made so far
+    pub num_attempts: u16,
+    /// When the message is next due to be attempted.
+    /// If absent, the message is due now.
+    #[serde(default)]
+    pub due: Option<DateTime<Utc>>,
+    /// The suspension that applies to the scheduled queue, if any
+    #[serde(default)]
+    pub suspension: Option<SuspendV1ListEntry>,
+    /// The suspension that applies to the ready queue, if any
+    #[serde(default)]
+    pub ready_queue_suspension: Option<SuspendReadyQueueV1ListEntry>,
+    /// The throttle that most recently delayed the message,
+    /// if it has not been attempted since then
+    #[serde(default)]
+    pub throttle: Option<MessageThrottle>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct MessageThrottle {
+    /// Describes which throttle delayed the message
+    #[schema(example = "max_message_rate for scheduled queue example.com")]
+    pub reason: String,
+    /// When the throttle was applied
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
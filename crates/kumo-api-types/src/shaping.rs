@@ -1,14 +1,18 @@
 use crate::egress_path::EgressPathConfig;
 #[cfg(feature = "lua")]
 use anyhow::Context;
+use cidr_map::CidrSet;
 #[cfg(feature = "lua")]
 use config::any_err;
 #[cfg(feature = "lua")]
 use config::serialize_options;
 #[cfg(feature = "lua")]
-use dns_resolver::{fully_qualify, MailExchanger};
+use dns_resolver::{fully_qualify, MailExchanger, ResolvedMxAddresses};
 #[cfg(feature = "lua")]
-use kumo_log_types::JsonLogRecord;
+use domain_map::domain_has_suffix;
+use kumo_log_types::{JsonLogRecord, RecordType};
+#[cfg(feature = "lua")]
+use lruttl::LruCacheWithTtl;
 #[cfg(feature = "lua")]
 use mlua::prelude::LuaUserData;
 #[cfg(feature = "lua")]
@@ -25,8 +29,12 @@ use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 #[cfg(feature = "lua")]
+use std::net::IpAddr;
+#[cfg(feature = "lua")]
 use std::sync::Arc;
 use std::time::Duration;
+#[cfg(feature = "lua")]
+use std::time::Instant;
 use throttle::ThrottleSpec;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -197,6 +205,56 @@ pub enum Action {
     SetConfig(EgressPathConfigValue),
     SuspendTenant,
     SuspendCampaign,
+    /// Halve the max_message_rate each time the rule triggers,
+    /// and restore it one step at a time once the rule has
+    /// stopped triggering.
+    Backoff(BackoffParams),
+    /// Bounce the mail that is queued for the tenant, campaign and
+    /// domain of the matching record.
+    Bounce,
+    /// Send the mail for the tenant, campaign and domain of the
+    /// matching record via the named egress pool instead.
+    Reroute(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BackoffParams {
+    /// The rate from which to back off. If not specified,
+    /// the max_message_rate from the shaping configuration
+    /// for the path is used.
+    #[serde(default)]
+    pub max_message_rate: Option<ThrottleSpec>,
+
+    /// The maximum number of times that the rate will be halved
+    #[serde(default = "BackoffParams::default_max_steps")]
+    pub max_steps: u8,
+
+    /// After halving the rate, further matches are ignored
+    /// for this long, to give the reduced rate time to have
+    /// an effect.
+    #[serde(default = "BackoffParams::default_hold", with = "duration_serde")]
+    pub hold: Duration,
+}
+
+impl BackoffParams {
+    fn default_max_steps() -> u8 {
+        4
+    }
+
+    fn default_hold() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+impl Default for BackoffParams {
+    fn default() -> Self {
+        Self {
+            max_message_rate: None,
+            max_steps: Self::default_max_steps(),
+            hold: Self::default_hold(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Hash, Default)]
@@ -207,12 +265,203 @@ pub enum Trigger {
     /// Trigger when a certain number of matches occur
     /// over a certain time period.
     Threshold(ThrottleSpec),
+    /// Trigger when the proportion of the delivery attempts for
+    /// the path that match exceeds a ratio over a sliding window.
+    Ratio(RatioTrigger),
+    /// Trigger when the proportion of the delivery attempts for
+    /// the path that match has increased by a factor, compared to
+    /// the window that precedes the current one.
+    RatioIncrease(RatioIncreaseTrigger),
+}
+
+impl Trigger {
+    pub fn is_ratio(&self) -> bool {
+        matches!(self, Self::Ratio(_) | Self::RatioIncrease(_))
+    }
+}
+
+/// A proportion, which can be written either as a percentage
+/// string such as `"20%"`, or as a number such as `0.2`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(try_from = "RatioUnchecked", into = "f64")]
+pub struct Ratio(pub f64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RatioUnchecked {
+    Number(f64),
+    String(String),
+}
+
+impl TryFrom<RatioUnchecked> for Ratio {
+    type Error = String;
+    fn try_from(value: RatioUnchecked) -> Result<Ratio, String> {
+        let ratio = match value {
+            RatioUnchecked::Number(n) => n,
+            RatioUnchecked::String(s) => match s.trim().strip_suffix('%') {
+                Some(percent) => {
+                    percent
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|err| format!("invalid ratio '{s}': {err}"))?
+                        / 100.0
+                }
+                None => s
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|err| format!("invalid ratio '{s}': {err}"))?,
+            },
+        };
+        if !ratio.is_finite() || ratio < 0.0 {
+            return Err(format!("invalid ratio {ratio}: must be a positive number"));
+        }
+        Ok(Ratio(ratio))
+    }
+}
+
+impl From<Ratio> for f64 {
+    fn from(ratio: Ratio) -> f64 {
+        ratio.0
+    }
+}
+
+impl Hash for Ratio {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.0.to_bits().hash(h)
+    }
+}
+
+/// Classifies the outcome of a delivery attempt
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    /// The message was delivered
+    Delivered,
+    /// The attempt failed with a 4xx response and will be retried
+    TransientFailure,
+    /// The attempt failed with a 5xx response
+    PermanentFailure,
+    /// Either a TransientFailure or a PermanentFailure
+    Failure,
+}
+
+impl AttemptOutcome {
+    pub fn matches(&self, kind: RecordType) -> bool {
+        match self {
+            Self::Delivered => kind == RecordType::Delivery,
+            Self::TransientFailure => kind == RecordType::TransientFailure,
+            Self::PermanentFailure => kind == RecordType::Bounce,
+            Self::Failure => matches!(kind, RecordType::TransientFailure | RecordType::Bounce),
+        }
+    }
+}
+
+/// Returns true if the record represents an attempt to deliver
+/// to the destination, as opposed to a disposition that was
+/// determined locally, such as expiration or an admin bounce
+fn is_delivery_attempt(record: &JsonLogRecord) -> bool {
+    !record.site.is_empty()
+        && matches!(
+            record.kind,
+            RecordType::Delivery | RecordType::TransientFailure | RecordType::Bounce
+        )
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct RatioTrigger {
+    /// Which attempts count towards the ratio. If omitted,
+    /// the attempts whose response matches the regex of the
+    /// rule are counted.
+    #[serde(default)]
+    pub outcome: Option<AttemptOutcome>,
+
+    /// The rule triggers when the ratio exceeds this value
+    pub above: Ratio,
+
+    /// The duration of the sliding window
+    #[serde(with = "duration_serde")]
+    pub window: Duration,
+
+    /// The ratio is not considered until there have been at least
+    /// this many attempts in the window
+    #[serde(default = "default_min_attempts")]
+    pub min_attempts: u64,
+}
+
+impl RatioTrigger {
+    pub fn is_triggered(&self, matched: u64, total: u64) -> bool {
+        total >= self.min_attempts.max(1) && matched as f64 / total as f64 > self.above.0
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct RatioIncreaseTrigger {
+    /// Which attempts count towards the ratio. If omitted,
+    /// the attempts whose response matches the regex of the
+    /// rule are counted.
+    #[serde(default)]
+    pub outcome: Option<AttemptOutcome>,
+
+    /// The rule triggers when the ratio in the current window
+    /// is at least this multiple of the ratio in the baseline window
+    pub factor: Ratio,
+
+    /// The duration of the current window
+    #[serde(with = "duration_serde")]
+    pub window: Duration,
+
+    /// The duration of the baseline window, which immediately
+    /// precedes the current window. Defaults to the same
+    /// duration as the current window.
+    #[serde(default, with = "duration_serde")]
+    pub baseline: Option<Duration>,
+
+    /// The ratio in the current window must also exceed this
+    /// value, so that a very small increase from a baseline of
+    /// zero does not trigger the rule. Defaults to 5%; set it
+    /// to 0 to disable this guard.
+    #[serde(default = "RatioIncreaseTrigger::default_min_ratio")]
+    pub min_ratio: Ratio,
+
+    /// Neither window is considered until it has at least this
+    /// many attempts
+    #[serde(default = "default_min_attempts")]
+    pub min_attempts: u64,
+}
+
+impl RatioIncreaseTrigger {
+    fn default_min_ratio() -> Ratio {
+        Ratio(0.05)
+    }
+
+    pub fn baseline(&self) -> Duration {
+        self.baseline.unwrap_or(self.window)
+    }
+
+    pub fn is_triggered(&self, current: (u64, u64), baseline: (u64, u64)) -> bool {
+        let min_attempts = self.min_attempts.max(1);
+        let (matched, total) = current;
+        let (baseline_matched, baseline_total) = baseline;
+        if total < min_attempts || baseline_total < min_attempts {
+            return false;
+        }
+        let ratio = matched as f64 / total as f64;
+        let baseline_ratio = baseline_matched as f64 / baseline_total as f64;
+        ratio > self.min_ratio.0 && ratio >= baseline_ratio * self.factor.0
+    }
+}
+
+fn default_min_attempts() -> u64 {
+    10
 }
 
 #[serde_as]
-#[derive(Deserialize, Serialize, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Rule {
-    #[serde(deserialize_with = "string_or_array")]
+    /// May be omitted for rules with a ratio trigger that
+    /// specifies an outcome
+    #[serde(default, deserialize_with = "string_or_array")]
     pub regex: Vec<Regex>,
 
     #[serde_as(deserialize_as = "OneOrMany<_, PreferOne>")]
@@ -226,6 +475,91 @@ pub struct Rule {
 
     #[serde(skip)]
     pub was_rollup: bool,
+
+    /// The name of the provider entry that defined this rule, if any
+    #[serde(skip)]
+    pub provider: Option<String>,
+}
+
+/// The history of the matches of a rule that is used to evaluate
+/// its trigger, for a single path. Times are in seconds since the epoch.
+pub trait TriggerHistory {
+    /// Record a match of a rule with a Threshold trigger
+    fn record_event(&mut self, ts: i64) -> anyhow::Result<()>;
+    /// Discard the matches that occurred before `ts`
+    fn prune_events(&mut self, ts: i64) -> anyhow::Result<()>;
+    /// Returns the number of matches that occurred at or after `ts`
+    fn count_events(&mut self, ts: i64) -> anyhow::Result<u64>;
+
+    /// Record a delivery attempt sampled by a rule with a ratio
+    /// trigger, and whether it matched
+    fn record_attempt(&mut self, ts: i64, matched: bool) -> anyhow::Result<()>;
+    /// Discard the attempts that occurred before `ts`
+    fn prune_attempts(&mut self, ts: i64) -> anyhow::Result<()>;
+    /// Returns the (matched, total) number of attempts that
+    /// occurred after `start`, up to and including `end`
+    fn count_attempts(&mut self, start: i64, end: i64) -> anyhow::Result<(u64, u64)>;
+}
+
+/// A TriggerHistory that is held in memory
+#[derive(Default, Debug)]
+pub struct MemoryTriggerHistory {
+    events: Vec<i64>,
+    attempts: Vec<(i64, bool)>,
+}
+
+impl TriggerHistory for MemoryTriggerHistory {
+    fn record_event(&mut self, ts: i64) -> anyhow::Result<()> {
+        self.events.push(ts);
+        Ok(())
+    }
+
+    fn prune_events(&mut self, ts: i64) -> anyhow::Result<()> {
+        self.events.retain(|&event| event >= ts);
+        Ok(())
+    }
+
+    fn count_events(&mut self, ts: i64) -> anyhow::Result<u64> {
+        Ok(self.events.iter().filter(|&&event| event >= ts).count() as u64)
+    }
+
+    fn record_attempt(&mut self, ts: i64, matched: bool) -> anyhow::Result<()> {
+        self.attempts.push((ts, matched));
+        Ok(())
+    }
+
+    fn prune_attempts(&mut self, ts: i64) -> anyhow::Result<()> {
+        self.attempts.retain(|&(attempt, _)| attempt >= ts);
+        Ok(())
+    }
+
+    fn count_attempts(&mut self, start: i64, end: i64) -> anyhow::Result<(u64, u64)> {
+        let mut matched = 0;
+        let mut total = 0;
+        for &(ts, is_match) in &self.attempts {
+            if ts > start && ts <= end {
+                total += 1;
+                if is_match {
+                    matched += 1;
+                }
+            }
+        }
+        Ok((matched, total))
+    }
+}
+
+/// The provider is intentionally excluded from the hash, so that the
+/// hash of the other rules is unchanged by its presence. tsa-daemon
+/// includes the provider in the key under which it stores the matches
+/// for provider rules.
+impl Hash for Rule {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.regex.hash(h);
+        self.action.hash(h);
+        self.trigger.hash(h);
+        self.duration.hash(h);
+        self.was_rollup.hash(h);
+    }
 }
 
 impl Rule {
@@ -235,11 +569,207 @@ impl Rule {
             .any(|r| r.is_match(response).unwrap_or(false))
     }
 
+    /// For a rule with a ratio trigger, classifies the record.
+    /// Returns None if the record is not a delivery attempt, otherwise
+    /// returns whether it counts towards the ratio.
+    pub fn ratio_sample(&self, record: &JsonLogRecord) -> Option<bool> {
+        let outcome = match &self.trigger {
+            Trigger::Ratio(ratio) => ratio.outcome,
+            Trigger::RatioIncrease(ratio) => ratio.outcome,
+            Trigger::Immediate | Trigger::Threshold(_) => return None,
+        };
+        if !is_delivery_attempt(record) {
+            return None;
+        }
+        Some(match outcome {
+            Some(outcome) => {
+                outcome.matches(record.kind)
+                    && (self.regex.is_empty() || self.matches(&record.response.to_single_line()))
+            }
+            None => self.matches(&record.response.to_single_line()),
+        })
+    }
+
+    /// Records a match of the rule, as returned by `match_rules`, for a
+    /// record with timestamp `ts`, and returns true if its trigger fires
+    /// when evaluated at `now`. Times are in seconds since the epoch.
+    /// Ratio rules are evaluated by `record_attempt` instead.
+    pub fn record_match<H: TriggerHistory>(
+        &self,
+        history: &mut H,
+        ts: i64,
+        now: i64,
+    ) -> anyhow::Result<bool> {
+        match &self.trigger {
+            Trigger::Immediate => Ok(true),
+            Trigger::Threshold(spec) => {
+                let period = spec.period as i64;
+                history.record_event(ts)?;
+                // Keep up to 2x the period
+                history.prune_events(now - 2 * period)?;
+                Ok(history.count_events(now - period)? >= spec.limit)
+            }
+            Trigger::Ratio(_) | Trigger::RatioIncrease(_) => Ok(false),
+        }
+    }
+
+    /// Records a delivery attempt that was classified by `ratio_sample`
+    /// for a rule with a ratio trigger, and returns true if the trigger
+    /// fires when evaluated at `now`.
+    /// Only a matching attempt can trigger the rule, so that the
+    /// effects of the actions are not extended by attempts that
+    /// succeeded while the ratio remains high.
+    pub fn record_attempt<H: TriggerHistory>(
+        &self,
+        history: &mut H,
+        matched: bool,
+        ts: i64,
+        now: i64,
+    ) -> anyhow::Result<bool> {
+        let retention = match &self.trigger {
+            Trigger::Ratio(ratio) => ratio.window,
+            Trigger::RatioIncrease(increase) => increase.window + increase.baseline(),
+            Trigger::Immediate | Trigger::Threshold(_) => return Ok(false),
+        };
+        history.record_attempt(ts, matched)?;
+        history.prune_attempts(now - retention.as_secs() as i64)?;
+        if !matched {
+            return Ok(false);
+        }
+
+        let mut count = |start: Duration, end: Duration| {
+            history.count_attempts(now - start.as_secs() as i64, now - end.as_secs() as i64)
+        };
+        Ok(match &self.trigger {
+            Trigger::Ratio(ratio) => {
+                let (matched, total) = count(ratio.window, Duration::ZERO)?;
+                ratio.is_triggered(matched, total)
+            }
+            Trigger::RatioIncrease(increase) => {
+                let current = count(increase.window, Duration::ZERO)?;
+                let baseline = count(increase.window + increase.baseline(), increase.window)?;
+                increase.is_triggered(current, baseline)
+            }
+            Trigger::Immediate | Trigger::Threshold(_) => false,
+        })
+    }
+
     pub fn clone_and_set_rollup(&self) -> Self {
         let mut result = self.clone();
         result.was_rollup = true;
         result
     }
+
+    /// Rules defined by a provider entry are applied to the site,
+    /// in the same way as the rules of a domain with mx_rollup
+    pub fn clone_for_provider(&self, provider: &str) -> Self {
+        let mut result = self.clone_and_set_rollup();
+        result.provider.replace(provider.to_string());
+        result
+    }
+}
+
+/// Criteria for matching the MX hosts of a destination to a provider
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ProviderMatch {
+    /// The MX host name is, or ends with, this DNS suffix
+    MXSuffix(String),
+    /// All of the addresses of the MX host are contained
+    /// in these CIDR blocks
+    MXCidr(CidrSet),
+    /// All of the addresses of the MX host belong to this
+    /// AS number, according to the `asn` table of the shaping files
+    MXAsn(u32),
+}
+
+#[cfg(feature = "lua")]
+impl ProviderMatch {
+    fn needs_addresses(&self) -> bool {
+        !matches!(self, Self::MXSuffix(_))
+    }
+
+    fn matches_host(
+        &self,
+        host: &str,
+        addresses: &[IpAddr],
+        asn_table: &HashMap<String, CidrSet>,
+    ) -> bool {
+        match self {
+            Self::MXSuffix(suffix) => domain_has_suffix(host, suffix),
+            Self::MXCidr(cidrs) => {
+                !addresses.is_empty() && addresses.iter().all(|addr| cidrs.contains(*addr))
+            }
+            Self::MXAsn(asn) => match asn_table.get(&asn.to_string()) {
+                Some(cidrs) => {
+                    !addresses.is_empty() && addresses.iter().all(|addr| cidrs.contains(*addr))
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ProviderEntry {
+    /// The provider applies to a destination when each of its
+    /// MX hosts satisfies at least one of these criteria.
+    /// May be omitted when overriding the parameters of a provider
+    /// that was defined in an earlier file.
+    #[serde(default, rename = "match")]
+    pub provider_match: Vec<ProviderMatch>,
+
+    #[serde(flatten)]
+    pub entry: PartialEntry,
+}
+
+#[cfg(feature = "lua")]
+impl ProviderEntry {
+    fn merge_from(&mut self, other: Self) {
+        if !other.provider_match.is_empty() {
+            self.provider_match = other.provider_match;
+        }
+        self.entry.merge_from(other.entry);
+    }
+
+    fn needs_addresses(&self) -> bool {
+        self.provider_match.iter().any(|m| m.needs_addresses())
+    }
+
+    /// `hosts` are the MX hosts of the destination, and `addresses`
+    /// holds the resolved addresses of each of those hosts
+    fn matches(
+        &self,
+        hosts: &[String],
+        addresses: &HashMap<String, Vec<IpAddr>>,
+        asn_table: &HashMap<String, CidrSet>,
+    ) -> bool {
+        !self.provider_match.is_empty()
+            && !hosts.is_empty()
+            && hosts.iter().all(|host| {
+                let host_addresses = addresses.get(host).map(|a| a.as_slice()).unwrap_or(&[]);
+                self.provider_match
+                    .iter()
+                    .any(|m| m.matches_host(host, host_addresses, asn_table))
+            })
+    }
+}
+
+/// The contents of a shaping file
+#[cfg(feature = "lua")]
+#[derive(Deserialize, Debug, Default)]
+struct ShapingFile {
+    /// Provider entries, keyed by provider name
+    #[serde(default)]
+    provider: HashMap<String, ProviderEntry>,
+
+    /// Maps AS numbers to the CIDR blocks that they announce
+    #[serde(default)]
+    asn: HashMap<String, CidrSet>,
+
+    /// Domain entries, keyed by domain name
+    #[serde(flatten)]
+    domains: HashMap<String, PartialEntry>,
 }
 
 #[cfg(feature = "lua")]
@@ -247,7 +777,35 @@ impl Rule {
 struct ShapingInner {
     by_site: HashMap<String, PartialEntry>,
     by_domain: HashMap<String, PartialEntry>,
+    /// Ordered by name, which is the order in which they are matched
+    by_provider: BTreeMap<String, ProviderEntry>,
+    asn: HashMap<String, CidrSet>,
     warnings: Vec<String>,
+    providers: ProviderCache,
+}
+
+/// How long the provider that was resolved for a domain is remembered
+#[cfg(feature = "lua")]
+const PROVIDER_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// The providers that were resolved for each destination domain.
+/// This is held by the Shaping instance, so that it is discarded
+/// when the shaping files are reloaded.
+#[cfg(feature = "lua")]
+struct ProviderCache(LruCacheWithTtl<String, Option<String>>);
+
+#[cfg(feature = "lua")]
+impl ProviderCache {
+    fn new() -> Self {
+        Self(LruCacheWithTtl::new(64 * 1024))
+    }
+}
+
+#[cfg(feature = "lua")]
+impl std::fmt::Debug for ProviderCache {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("ProviderCache").finish()
+    }
 }
 
 #[cfg(feature = "lua")]
@@ -257,14 +815,21 @@ impl ShapingInner {
         domain: &str,
         egress_source: &str,
         site_name: &str,
+        provider: Option<&str>,
     ) -> PartialEntry {
         let mut params = PartialEntry::default();
+        let provider = provider.and_then(|name| self.by_provider.get(name));
 
         // Apply basic/default configuration
         if let Some(default) = self.by_domain.get("default") {
             params.merge_from(default.clone());
         }
 
+        // Then provider config
+        if let Some(provider) = provider {
+            params.merge_from(provider.entry.clone());
+        }
+
         // Then site config
         if let Some(by_site) = self.by_site.get(site_name) {
             params.merge_from(by_site.clone());
@@ -275,6 +840,13 @@ impl ShapingInner {
             params.merge_from(by_domain.clone());
         }
 
+        // Then source config for the provider
+        if let Some(provider) = provider {
+            if let Some(source) = provider.entry.sources.get(egress_source) {
+                toml_table_merge_from(&mut params.params, &source);
+            }
+        }
+
         // Then source config for the site
         if let Some(by_site) = self.by_site.get(site_name) {
             if let Some(source) = by_site.sources.get(egress_source) {
@@ -292,48 +864,110 @@ impl ShapingInner {
         params
     }
 
-    pub fn match_rules(&self, record: &JsonLogRecord, domain: &str, site_name: &str) -> Vec<Rule> {
+    /// Returns the rules that apply to the path, along with the name
+    /// of the entry that defined them
+    fn rules_for_path(
+        &self,
+        domain: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> Vec<(String, Rule)> {
         let mut result = vec![];
-        let response = record.response.to_single_line();
-        tracing::trace!("Consider rules for {response}");
 
         if let Some(default) = self.by_domain.get("default") {
             for rule in &default.automation {
-                tracing::trace!("Consider \"default\" rule {rule:?} for {response}");
-                if rule.matches(&response) {
-                    // For automation under `default`, we always
-                    // assume that mx_rollup should be true.
-                    // If you somehow have a domain where that isn't
-                    // true, you should avoid using `default` for
-                    // automation.  Honestly, it's best to avoid
-                    // using `default` for automation.
-                    result.push(rule.clone_and_set_rollup());
-                }
+                // For automation under `default`, we always
+                // assume that mx_rollup should be true.
+                // If you somehow have a domain where that isn't
+                // true, you should avoid using `default` for
+                // automation.  Honestly, it's best to avoid
+                // using `default` for automation.
+                result.push(("default".to_string(), rule.clone_and_set_rollup()));
+            }
+        }
+
+        // Then provider config
+        if let Some((name, provider)) =
+            provider.and_then(|name| self.by_provider.get_key_value(name))
+        {
+            for rule in &provider.entry.automation {
+                result.push((format!("provider:{name}"), rule.clone_for_provider(name)));
             }
         }
 
         // Then site config
         if let Some(by_site) = self.by_site.get(site_name) {
             for rule in &by_site.automation {
-                tracing::trace!("Consider \"{site_name}\" rule {rule:?} for {response}");
-                if rule.matches(&response) {
-                    result.push(rule.clone_and_set_rollup());
-                }
+                result.push((site_name.to_string(), rule.clone_and_set_rollup()));
             }
         }
 
         // Then domain config
         if let Some(by_domain) = self.by_domain.get(domain) {
             for rule in &by_domain.automation {
-                tracing::trace!("Consider \"{domain}\" rule {rule:?} for {response}");
-                if rule.matches(&response) {
-                    result.push(rule.clone());
-                }
+                result.push((domain.to_string(), rule.clone()));
             }
         }
 
         result
     }
+
+    pub fn match_rules(
+        &self,
+        record: &JsonLogRecord,
+        domain: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> Vec<Rule> {
+        let response = record.response.to_single_line();
+        tracing::trace!("Consider rules for {response}");
+
+        self.rules_for_path(domain, site_name, provider)
+            .into_iter()
+            .filter_map(|(entry, rule)| {
+                tracing::trace!("Consider \"{entry}\" rule {rule:?} for {response}");
+                // Ratio rules are evaluated against every attempt;
+                // see ratio_rules
+                if !rule.trigger.is_ratio() && rule.matches(&response) {
+                    Some(rule)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn ratio_rules(&self, domain: &str, site_name: &str, provider: Option<&str>) -> Vec<Rule> {
+        self.rules_for_path(domain, site_name, provider)
+            .into_iter()
+            .filter_map(|(_entry, rule)| {
+                if rule.trigger.is_ratio() {
+                    Some(rule)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Warns about the automation rules of an entry that can never match
+#[cfg(feature = "lua")]
+fn check_automation(entry: &str, automation: &[Rule], warnings: &mut Vec<String>) {
+    for rule in automation {
+        let has_outcome = match &rule.trigger {
+            Trigger::Ratio(ratio) => ratio.outcome.is_some(),
+            Trigger::RatioIncrease(ratio) => ratio.outcome.is_some(),
+            Trigger::Immediate | Trigger::Threshold(_) => false,
+        };
+        if rule.regex.is_empty() && !has_outcome {
+            warnings.push(format!(
+                "automation rule {:?} for '{entry}' has no regex \
+                 and no outcome, and will never match.",
+                rule.action
+            ));
+        }
+    }
 }
 
 #[cfg(feature = "lua")]
@@ -344,7 +978,7 @@ pub struct Shaping {
 
 #[cfg(feature = "lua")]
 impl Shaping {
-    async fn load_from_file(path: &str) -> anyhow::Result<HashMap<String, PartialEntry>> {
+    async fn load_from_file(path: &str) -> anyhow::Result<ShapingFile> {
         let data: String = if path.starts_with("http://") || path.starts_with("https://") {
             // To facilitate startup ordering races, and listing multiple subscription
             // host replicas and allowing one or more of them to be temporarily down,
@@ -364,7 +998,7 @@ impl Shaping {
                 Ok(s) => s,
                 Err(err) => {
                     tracing::error!("{err:#}. Ignoring this shaping source for now");
-                    return Ok(HashMap::new());
+                    return Ok(ShapingFile::default());
                 }
             }
         } else {
@@ -388,12 +1022,39 @@ impl Shaping {
         let mut site_to_domains: HashMap<String, HashSet<String>> = HashMap::new();
         let mut by_site: HashMap<String, PartialEntry> = HashMap::new();
         let mut by_domain: HashMap<String, PartialEntry> = HashMap::new();
+        let mut by_provider: BTreeMap<String, ProviderEntry> = BTreeMap::new();
+        let mut asn: HashMap<String, CidrSet> = HashMap::new();
         let mut warnings = vec![];
 
         for item in loaded {
-            for (domain, mut partial) in item {
+            for (name, mut provider) in item.provider {
+                provider
+                    .entry
+                    .domain_name
+                    .replace(format!("provider:{name}"));
+                check_automation(
+                    &format!("provider:{name}"),
+                    &provider.entry.automation,
+                    &mut warnings,
+                );
+                match by_provider.get_mut(&name) {
+                    Some(existing) => {
+                        existing.merge_from(provider);
+                    }
+                    None => {
+                        by_provider.insert(name, provider);
+                    }
+                }
+            }
+
+            // Later files replace the blocks for an AS number
+            asn.extend(item.asn);
+
+            for (domain, mut partial) in item.domains {
                 partial.domain_name.replace(domain.clone());
 
+                check_automation(&domain, &partial.automation, &mut warnings);
+
                 let mx_rollup = if domain == "default" {
                     false
                 } else {
@@ -479,11 +1140,37 @@ impl Shaping {
                 .with_context(|| format!("domain: {domain}"))?;
         }
 
+        for (name, provider) in &by_provider {
+            if provider.provider_match.is_empty() {
+                warnings.push(format!(
+                    "provider '{name}' has no match criteria and will never be used."
+                ));
+            }
+            for m in &provider.provider_match {
+                if let ProviderMatch::MXAsn(number) = m {
+                    if !asn.contains_key(&number.to_string()) {
+                        warnings.push(format!(
+                            "provider '{name}' matches AS{number}, but there \
+                             is no entry for {number} in the asn table."
+                        ));
+                    }
+                }
+            }
+            provider
+                .entry
+                .clone()
+                .finish()
+                .with_context(|| format!("provider: {name}"))?;
+        }
+
         Ok(Self {
             inner: Arc::new(ShapingInner {
                 by_site,
                 by_domain,
+                by_provider,
+                asn,
                 warnings,
+                providers: ProviderCache::new(),
             }),
         })
     }
@@ -493,17 +1180,141 @@ impl Shaping {
         domain: &str,
         egress_source: &str,
         site_name: &str,
+        provider: Option<&str>,
     ) -> PartialEntry {
         self.inner
-            .get_egress_path_config(domain, egress_source, site_name)
+            .get_egress_path_config(domain, egress_source, site_name, provider)
+    }
+
+    /// Returns the name of the provider whose match criteria are
+    /// satisfied by the MX hosts of `domain`. If more than one provider
+    /// matches, the first one in name order is returned.
+    /// The result is cached for a few minutes, as this is called for
+    /// every message that is queued, and for every log record that
+    /// is processed by tsa-daemon.
+    pub async fn resolve_provider(&self, domain: &str) -> anyhow::Result<Option<String>> {
+        if self.inner.by_provider.is_empty() {
+            return Ok(None);
+        }
+
+        let key = domain.to_ascii_lowercase();
+        if let Some(provider) = self.inner.providers.0.get(&key) {
+            return Ok(provider);
+        }
+        let provider = self.resolve_provider_uncached(domain).await?;
+        self.inner
+            .providers
+            .0
+            .insert(key, provider.clone(), Instant::now() + PROVIDER_CACHE_TTL);
+        Ok(provider)
+    }
+
+    async fn resolve_provider_uncached(&self, domain: &str) -> anyhow::Result<Option<String>> {
+        let mx = MailExchanger::resolve(domain).await?;
+
+        let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        if self
+            .inner
+            .by_provider
+            .values()
+            .any(|provider| provider.needs_addresses())
+        {
+            if let ResolvedMxAddresses::Addresses(resolved) = mx.resolve_addresses().await {
+                for addr in resolved {
+                    addresses.entry(addr.name).or_default().push(addr.addr);
+                }
+            }
+        }
+
+        for (name, provider) in &self.inner.by_provider {
+            if provider.matches(&mx.hosts, &addresses, &self.inner.asn) {
+                return Ok(Some(name.to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the merged parameters for the path, as they were
+    /// written in the shaping files. Parameters that were not set
+    /// by any of the matching entries are not present.
+    pub fn get_egress_path_params(
+        &self,
+        domain: &str,
+        egress_source: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> toml::Table {
+        self.get_egress_path_config(domain, egress_source, site_name, provider)
+            .params
+    }
+
+    /// Returns the effective configuration for the path, including
+    /// the default values of parameters that were not set
+    pub fn get_effective_config(
+        &self,
+        domain: &str,
+        egress_source: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> anyhow::Result<EgressPathConfig> {
+        Ok(self
+            .get_egress_path_config(domain, egress_source, site_name, provider)
+            .finish()?
+            .params)
+    }
+
+    /// Returns the effective max_message_rate for the path, if any
+    pub fn get_max_message_rate(
+        &self,
+        domain: &str,
+        egress_source: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> anyhow::Result<Option<ThrottleSpec>> {
+        Ok(self
+            .get_effective_config(domain, egress_source, site_name, provider)?
+            .max_message_rate)
+    }
+
+    /// Returns the names of the site entries, sorted by name
+    pub fn get_site_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.inner.by_site.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the names of the domain entries, including `default`,
+    /// sorted by name
+    pub fn get_domain_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.inner.by_domain.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the names of the provider entries, sorted by name
+    pub fn get_provider_names(&self) -> Vec<String> {
+        self.inner.by_provider.keys().cloned().collect()
     }
 
     pub fn get_warnings(&self) -> &[String] {
         &self.inner.warnings
     }
 
-    pub fn match_rules(&self, record: &JsonLogRecord, domain: &str, site_name: &str) -> Vec<Rule> {
-        self.inner.match_rules(record, domain, site_name)
+    pub fn match_rules(
+        &self,
+        record: &JsonLogRecord,
+        domain: &str,
+        site_name: &str,
+        provider: Option<&str>,
+    ) -> Vec<Rule> {
+        self.inner.match_rules(record, domain, site_name, provider)
+    }
+
+    /// Returns the rules with ratio triggers that apply to the path.
+    /// Unlike match_rules, these are not filtered by the response,
+    /// as every delivery attempt contributes to the ratio.
+    pub fn ratio_rules(&self, domain: &str, site_name: &str, provider: Option<&str>) -> Vec<Rule> {
+        self.inner.ratio_rules(domain, site_name, provider)
     }
 
     pub fn get_referenced_sources(&self) -> BTreeMap<String, Vec<String>> {
@@ -525,6 +1336,14 @@ impl Shaping {
                     .push(format!("domain:{domain_name}"));
             }
         }
+        for (provider_name, provider) in &self.inner.by_provider {
+            for source_name in provider.entry.sources.keys() {
+                result
+                    .entry(source_name.to_string())
+                    .or_insert(vec![])
+                    .push(format!("provider:{provider_name}"));
+            }
+        }
 
         result
     }
@@ -534,13 +1353,33 @@ impl Shaping {
 impl LuaUserData for Shaping {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         mod_memoize::Memoized::impl_memoize(methods);
-        methods.add_method(
+        methods.add_async_method(
             "get_egress_path_config",
-            move |lua, this, (domain, egress_source, site_name): (String, String, String)| {
-                let params = this.get_egress_path_config(&domain, &egress_source, &site_name);
+            |lua, this, (domain, egress_source, site_name): (String, String, String)| async move {
+                let provider = match this.resolve_provider(&domain).await {
+                    Ok(provider) => provider,
+                    Err(err) => {
+                        // The domain may not be resolvable, such as when
+                        // it is routed via an explicit mx_list
+                        tracing::debug!("resolving provider for {domain}: {err:#}");
+                        None
+                    }
+                };
+                let params = this.get_egress_path_config(
+                    &domain,
+                    &egress_source,
+                    &site_name,
+                    provider.as_deref(),
+                );
                 lua.to_value_with(&params.params, serialize_options())
             },
         );
+        methods.add_async_method(
+            "resolve_provider",
+            |_lua, this, domain: String| async move {
+                this.resolve_provider(&domain).await.map_err(any_err)
+            },
+        );
         methods.add_method("get_warnings", move |_lua, this, ()| {
             let warnings: Vec<String> = this.get_warnings().iter().map(|s| s.to_string()).collect();
             Ok(warnings)
@@ -709,6 +1548,357 @@ pub fn register(lua: &mlua::Lua) -> anyhow::Result<()> {
 mod test {
     use super::*;
 
+    #[test]
+    fn parse_automation_actions() {
+        let rule: Rule = toml::from_str(
+            r#"
+regex = "rate limited"
+action = [
+    {Backoff={max_message_rate="100/h"}},
+    {Reroute="backup"},
+    "Bounce",
+]
+duration = "10 minutes"
+"#,
+        )
+        .unwrap();
+        k9::snapshot!(
+            rule.action,
+            r#"
+[
+    Backoff(
+        BackoffParams {
+            max_message_rate: Some(
+                ThrottleSpec {
+                    limit: 100,
+                    period: 3600,
+                    max_burst: None,
+                    pacing: false,
+                },
+            ),
+            max_steps: 4,
+            hold: 300s,
+        },
+    ),
+    Reroute(
+        "backup",
+    ),
+    Bounce,
+]
+"#
+        );
+
+        let rule: Result<Rule, _> = toml::from_str(
+            r#"
+regex = "rate limited"
+action = {Backoff={steps=2}}
+duration = "10 minutes"
+"#,
+        );
+        assert!(rule.is_err());
+    }
+
+    #[test]
+    fn ratio_triggers() {
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {Ratio={outcome="TransientFailure", above="20%", window="10 minutes"}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        assert!(rule.regex.is_empty());
+        let ratio = match &rule.trigger {
+            Trigger::Ratio(ratio) => ratio,
+            trigger => panic!("unexpected {trigger:?}"),
+        };
+        assert_eq!(ratio.above, Ratio(0.2));
+        assert_eq!(ratio.window, Duration::from_secs(600));
+        assert_eq!(ratio.min_attempts, 10);
+        // Not enough attempts to consider
+        assert!(!ratio.is_triggered(5, 5));
+        assert!(!ratio.is_triggered(2, 10));
+        assert!(ratio.is_triggered(3, 10));
+
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {RatioIncrease={outcome="TransientFailure", factor=2, window="10 minutes", baseline="1 hour", min_ratio=0.05}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let increase = match &rule.trigger {
+            Trigger::RatioIncrease(increase) => increase,
+            trigger => panic!("unexpected {trigger:?}"),
+        };
+        assert_eq!(increase.factor, Ratio(2.0));
+        assert_eq!(increase.baseline(), Duration::from_secs(3600));
+        assert!(increase.is_triggered((20, 100), (60, 600)));
+        assert!(!increase.is_triggered((15, 100), (60, 600)));
+        // Below min_ratio, even though the baseline was zero
+        assert!(!increase.is_triggered((4, 100), (0, 600)));
+        assert!(increase.is_triggered((6, 100), (0, 600)));
+        // Baseline window doesn't have enough attempts
+        assert!(!increase.is_triggered((20, 100), (0, 5)));
+
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {RatioIncrease={outcome="TransientFailure", factor=2, window="10 minutes"}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let increase = match &rule.trigger {
+            Trigger::RatioIncrease(increase) => increase,
+            trigger => panic!("unexpected {trigger:?}"),
+        };
+        assert_eq!(increase.min_ratio, Ratio(0.05));
+        // A single failure after a period without any is not enough
+        assert!(!increase.is_triggered((1, 100), (0, 100)));
+        assert!(increase.is_triggered((6, 100), (0, 100)));
+
+        let rule: Result<Rule, _> = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {Ratio={above="lots", window="10 minutes"}}
+duration = "1 hour"
+"#,
+        );
+        assert!(rule.is_err());
+    }
+
+    #[test]
+    fn threshold_trigger_history() {
+        let rule: Rule = toml::from_str(
+            r#"
+regex = "try later"
+action = "Suspend"
+trigger = {Threshold="2/minute"}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let mut history = MemoryTriggerHistory::default();
+        assert!(!rule.record_match(&mut history, 1000, 1000).unwrap());
+        assert!(rule.record_match(&mut history, 1010, 1010).unwrap());
+        // The earlier matches have fallen out of the period
+        assert!(!rule.record_match(&mut history, 1100, 1100).unwrap());
+        assert_eq!(history.events, vec![1000, 1010, 1100]);
+        // and are pruned once they are older than twice the period
+        assert!(!rule.record_match(&mut history, 1300, 1300).unwrap());
+        assert_eq!(history.events, vec![1300]);
+
+        let rule: Rule = toml::from_str(
+            r#"
+regex = "try later"
+action = "Suspend"
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let mut history = MemoryTriggerHistory::default();
+        assert!(rule.record_match(&mut history, 1000, 1000).unwrap());
+        assert!(history.events.is_empty());
+    }
+
+    #[test]
+    fn ratio_trigger_history() {
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {Ratio={outcome="TransientFailure", above="50%", window="1 minute", min_attempts=2}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let mut history = MemoryTriggerHistory::default();
+        assert!(!rule.record_attempt(&mut history, true, 1000, 1000).unwrap());
+        // The ratio is high enough, but only a match can trigger
+        assert!(!rule
+            .record_attempt(&mut history, false, 1001, 1001)
+            .unwrap());
+        assert!(rule.record_attempt(&mut history, true, 1002, 1002).unwrap());
+        // The earlier attempts are pruned once outside of the window
+        assert!(!rule.record_attempt(&mut history, true, 1100, 1100).unwrap());
+        assert_eq!(history.attempts, vec![(1100, true)]);
+
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+trigger = {RatioIncrease={outcome="TransientFailure", factor=2, window="1 minute", min_attempts=2}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let mut history = MemoryTriggerHistory::default();
+        // Baseline of 1 in 4
+        for (ts, matched) in [(990, true), (991, false), (992, false), (993, false)] {
+            assert!(!rule.record_attempt(&mut history, matched, ts, ts).unwrap());
+        }
+        // The current window has 1 in 2, which is at least double
+        assert!(!rule
+            .record_attempt(&mut history, false, 1061, 1061)
+            .unwrap());
+        assert!(rule.record_attempt(&mut history, true, 1062, 1062).unwrap());
+    }
+
+    #[test]
+    fn automation_warnings() {
+        let file: ShapingFile = toml::from_str(
+            r#"
+[provider."example"]
+match = [{MXSuffix=".example.com"}]
+
+[[provider."example".automation]]
+action = "Suspend"
+duration = "1 hour"
+
+[[provider."example".automation]]
+action = "Suspend"
+trigger = {Ratio={outcome="TransientFailure", above="20%", window="10 minutes"}}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let mut warnings = vec![];
+        check_automation(
+            "provider:example",
+            &file.provider["example"].entry.automation,
+            &mut warnings,
+        );
+        k9::snapshot!(
+            warnings,
+            r#"
+[
+    "automation rule [Suspend] for 'provider:example' has no regex and no outcome, and will never match.",
+]
+"#
+        );
+    }
+
+    #[test]
+    fn provider_entries() {
+        let file: ShapingFile = toml::from_str(
+            r#"
+[asn]
+8075 = ["40.92.0.0/15"]
+
+[provider."google"]
+match = [{MXSuffix = ".google.com"}]
+connection_limit = 5
+
+[provider."microsoft"]
+match = [{MXSuffix = "protection.outlook.com"}, {MXAsn = 8075}]
+connection_limit = 3
+
+[provider."microsoft".sources."ip-1"]
+connection_limit = 1
+
+[[provider."microsoft".automation]]
+regex = "rate limited"
+action = "Suspend"
+duration = "1 hour"
+
+["example.com"]
+mx_rollup = false
+connection_limit = 7
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(file.domains.keys().collect::<Vec<_>>(), vec!["example.com"]);
+
+        let google = &file.provider["google"];
+        let microsoft = &file.provider["microsoft"];
+        let no_addresses = HashMap::new();
+
+        assert!(google.matches(
+            &[
+                "gmail-smtp-in.l.google.com".to_string(),
+                "alt1.gmail-smtp-in.l.google.com.".to_string()
+            ],
+            &no_addresses,
+            &file.asn
+        ));
+        // Every MX host must match
+        assert!(!google.matches(
+            &[
+                "gmail-smtp-in.l.google.com".to_string(),
+                "mx.example.com".to_string()
+            ],
+            &no_addresses,
+            &file.asn
+        ));
+        assert!(!google.matches(&[], &no_addresses, &file.asn));
+
+        assert!(microsoft.matches(
+            &["example-com.mail.protection.outlook.com".to_string()],
+            &no_addresses,
+            &file.asn
+        ));
+
+        let host = "mx.example.net".to_string();
+        let in_asn: HashMap<String, Vec<IpAddr>> =
+            [(host.clone(), vec!["40.93.1.1".parse().unwrap()])]
+                .into_iter()
+                .collect();
+        let partly_in_asn: HashMap<String, Vec<IpAddr>> = [(
+            host.clone(),
+            vec!["40.93.1.1".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+        )]
+        .into_iter()
+        .collect();
+        assert!(microsoft.matches(&[host.clone()], &in_asn, &file.asn));
+        assert!(!microsoft.matches(&[host.clone()], &partly_in_asn, &file.asn));
+        assert!(!microsoft.matches(&[host.clone()], &no_addresses, &file.asn));
+
+        let inner = ShapingInner {
+            by_site: HashMap::new(),
+            by_domain: file.domains,
+            by_provider: file.provider.into_iter().collect(),
+            asn: file.asn,
+            warnings: vec![],
+            providers: ProviderCache::new(),
+        };
+
+        let connection_limit = |domain, source, provider| {
+            inner
+                .get_egress_path_config(domain, source, "site", provider)
+                .params
+                .get("connection_limit")
+                .cloned()
+        };
+
+        // The provider applies to domains without their own entries
+        assert_eq!(
+            connection_limit("example.net", "ip-2", Some("microsoft")),
+            Some(toml::Value::Integer(3))
+        );
+        // Its source config overrides it
+        assert_eq!(
+            connection_limit("example.net", "ip-1", Some("microsoft")),
+            Some(toml::Value::Integer(1))
+        );
+        // A domain entry overrides the provider
+        assert_eq!(
+            connection_limit("example.com", "ip-2", Some("microsoft")),
+            Some(toml::Value::Integer(7))
+        );
+        assert_eq!(connection_limit("example.net", "ip-2", None), None);
+
+        let rules = inner.rules_for_path("example.net", "site", Some("microsoft"));
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].0, "provider:microsoft");
+        assert_eq!(rules[0].1.provider.as_deref(), Some("microsoft"));
+        assert!(rules[0].1.was_rollup);
+        assert!(inner
+            .rules_for_path("example.net", "site", Some("google"))
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn test_defaults() {
         let shaping = Shaping::merge_files(&["../../assets/policy-extras/shaping.toml".into()])
@@ -716,7 +1906,7 @@ mod test {
             .unwrap();
 
         let default = shaping
-            .get_egress_path_config("invalid.domain", "invalid.source", "invalid.site")
+            .get_egress_path_config("invalid.domain", "invalid.source", "invalid.site", None)
             .finish()
             .unwrap();
         k9::snapshot!(
@@ -725,6 +1915,8 @@ mod test {
 MergedEntry {
     params: EgressPathConfig {
         connection_limit: 10,
+        site_connection_limit: None,
+        site_connection_limit_per_node: false,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
@@ -757,6 +1949,7 @@ MergedEntry {
                 limit: 100,
                 period: 1,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_connection_rate: Some(
@@ -764,9 +1957,13 @@ MergedEntry {
                 limit: 100,
                 period: 60,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_deliveries_per_connection: 100,
+        sending_window: None,
+        idle_connection_linger: None,
+        idle_connection_keepalive_interval: 30s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
@@ -850,6 +2047,7 @@ MergedEntry {
             trigger: Immediate,
             duration: 5400s,
             was_rollup: false,
+            provider: None,
         },
     ],
 }
@@ -857,7 +2055,7 @@ MergedEntry {
         );
 
         let example_com = shaping
-            .get_egress_path_config("example.com", "invalid.source", "invalid.site")
+            .get_egress_path_config("example.com", "invalid.source", "invalid.site", None)
             .finish()
             .unwrap();
         k9::snapshot!(
@@ -866,6 +2064,8 @@ MergedEntry {
 MergedEntry {
     params: EgressPathConfig {
         connection_limit: 3,
+        site_connection_limit: None,
+        site_connection_limit_per_node: false,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
@@ -898,6 +2098,7 @@ MergedEntry {
                 limit: 100,
                 period: 1,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_connection_rate: Some(
@@ -905,9 +2106,13 @@ MergedEntry {
                 limit: 100,
                 period: 60,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_deliveries_per_connection: 100,
+        sending_window: None,
+        idle_connection_linger: None,
+        idle_connection_keepalive_interval: 30s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
@@ -949,6 +2154,8 @@ MergedEntry {
     sources: {
         "my source name": EgressPathConfig {
             connection_limit: 5,
+            site_connection_limit: None,
+            site_connection_limit_per_node: false,
             enable_tls: Opportunistic,
             enable_mta_sts: true,
             enable_dane: false,
@@ -979,6 +2186,9 @@ MergedEntry {
             max_message_rate: None,
             max_connection_rate: None,
             max_deliveries_per_connection: 1024,
+            sending_window: None,
+            idle_connection_linger: None,
+            idle_connection_keepalive_interval: 30s,
             prohibited_hosts: CidrSet(
                 CidrMap {
                     root: Some(
@@ -1062,6 +2272,7 @@ MergedEntry {
             trigger: Immediate,
             duration: 5400s,
             was_rollup: false,
+            provider: None,
         },
     ],
 }
@@ -1075,6 +2286,7 @@ MergedEntry {
                 "yahoo.com",
                 "invalid.source",
                 "(mta5|mta6|mta7).am0.yahoodns.net",
+                None,
             )
             .finish()
             .unwrap();
@@ -1084,6 +2296,8 @@ MergedEntry {
 MergedEntry {
     params: EgressPathConfig {
         connection_limit: 10,
+        site_connection_limit: None,
+        site_connection_limit_per_node: false,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
@@ -1116,6 +2330,7 @@ MergedEntry {
                 limit: 100,
                 period: 1,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_connection_rate: Some(
@@ -1123,9 +2338,13 @@ MergedEntry {
                 limit: 100,
                 period: 60,
                 max_burst: None,
+                pacing: false,
             },
         ),
         max_deliveries_per_connection: 20,
+        sending_window: None,
+        idle_connection_linger: None,
+        idle_connection_keepalive_interval: 30s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
@@ -1209,6 +2428,7 @@ MergedEntry {
             trigger: Immediate,
             duration: 5400s,
             was_rollup: false,
+            provider: None,
         },
         Rule {
             regex: [
@@ -1222,6 +2442,7 @@ MergedEntry {
             trigger: Immediate,
             duration: 7200s,
             was_rollup: false,
+            provider: None,
         },
     ],
 }
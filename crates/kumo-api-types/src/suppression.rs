@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+/// Describes why a recipient was added to the suppression list
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum SuppressionSource {
+    /// A delivery attempt resulted in a bounce with a configured classification
+    Bounce,
+    /// A feedback (ARF) report was received for the recipient
+    Complaint,
+    /// The entry was added via the admin API
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SuppressionV1Entry {
+    /// The tenant to which this entry applies. If omitted, the
+    /// entry applies to all tenants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// The suppressed recipient address
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+
+    pub source: SuppressionSource,
+
+    /// The reason that the recipient was suppressed
+    #[schema(example = "550 5.1.1 no such user")]
+    pub reason: String,
+
+    /// The bounce classification that caused the recipient to be
+    /// suppressed, if applicable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounce_classification: Option<String>,
+
+    /// When the entry was created or most recently updated
+    pub created: DateTime<Utc>,
+
+    /// When the entry expires. If omitted, the entry never expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SuppressionV1AddRequest {
+    /// The tenant to which this entry applies. If omitted, the
+    /// entry applies to all tenants.
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    /// The recipient address to suppress
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+
+    /// The reason for the suppression
+    #[schema(example = "requested removal via support ticket")]
+    pub reason: String,
+
+    /// Specifies how long this entry remains active.
+    /// If omitted, the entry never expires.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct SuppressionV1ListRequest {
+    /// Only list entries for this tenant
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    /// Only list entries for this recipient
+    #[serde(default)]
+    pub recipient: Option<String>,
+
+    /// The maximum number of entries to return
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl SuppressionV1ListRequest {
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(tenant) = &self.tenant {
+            query.append_pair("tenant", tenant);
+        }
+        if let Some(recipient) = &self.recipient {
+            query.append_pair("recipient", recipient);
+        }
+        if let Some(limit) = self.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SuppressionV1DeleteRequest {
+    /// The tenant of the entry to remove. If omitted, the entry
+    /// that applies to all tenants is removed.
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    /// The recipient address to remove from the suppression list
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// The state of a throttle key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ThrottleV1Entry {
+    /// The throttle key
+    #[schema(example = "example.com-message-rate:100:100:3600")]
+    pub key: String,
+
+    /// The effective rate of the throttle, which is the overridden
+    /// rate when an override is active
+    #[schema(example = "100/h")]
+    pub spec: String,
+
+    /// The maximum burst permitted by the throttle
+    pub max_burst: u64,
+
+    /// The number of actions that can currently be performed
+    /// without being throttled
+    pub remaining: u64,
+
+    /// How long until the throttle returns to its full capacity
+    #[serde(with = "duration_serde")]
+    pub reset_after: Duration,
+
+    /// How long ago the key was last used by this node
+    #[serde(with = "duration_serde")]
+    pub idle: Duration,
+
+    /// How long until the active override expires.
+    /// Omitted if there is no override.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub override_expires: Option<Duration>,
+}
+
+/// A lease held on a limit key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LimitLeaseV1Entry {
+    /// Identifies the lease
+    pub uuid: Uuid,
+
+    /// How long until the lease expires, unless it is extended
+    /// by its holder
+    #[serde(with = "duration_serde")]
+    pub expires: Duration,
+}
+
+/// The state of a limit key, such as a connection limit
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LimitV1Entry {
+    /// The limit key
+    #[schema(example = "source->mx.example.com@smtp_client")]
+    pub key: String,
+
+    /// The effective maximum number of leases, which is the
+    /// overridden limit when an override is active
+    pub limit: usize,
+
+    /// The initial duration of a lease
+    #[serde(with = "duration_serde")]
+    pub lease_duration: Duration,
+
+    /// The currently held leases. When redis is in use, this
+    /// includes the leases held by other nodes.
+    pub leases: Vec<LimitLeaseV1Entry>,
+
+    /// How long ago a lease was last requested by this node
+    #[serde(with = "duration_serde")]
+    pub idle: Duration,
+
+    /// How long until the active override expires.
+    /// Omitted if there is no override.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub override_expires: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ThrottlesV1Response {
+    pub throttles: Vec<ThrottleV1Entry>,
+    pub limits: Vec<LimitV1Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct ThrottlesV1ListRequest {
+    /// Only list the keys that contain this string
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl ThrottlesV1ListRequest {
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(key) = &self.key {
+            query.append_pair("key", key);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ThrottleV1OverrideRequest {
+    /// The throttle or limit key to override
+    #[schema(example = "example.com-message-rate:100:100:3600")]
+    pub key: String,
+
+    /// The rate to use in place of the rate of a throttle key.
+    /// Exactly one of `spec` or `limit` must be specified.
+    #[serde(default)]
+    #[schema(example = "10/h")]
+    pub spec: Option<String>,
+
+    /// The maximum number of leases to use in place of the
+    /// limit of a limit key.
+    /// Exactly one of `spec` or `limit` must be specified.
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    /// How long the override remains active
+    #[serde(with = "duration_serde")]
+    pub duration: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ThrottleV1KeyRequest {
+    /// The throttle or limit key
+    #[schema(example = "example.com-message-rate:100:100:3600")]
+    pub key: String,
+}
@@ -1,13 +1,15 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Default)]
 pub struct Suspensions {
     pub ready_q: Vec<ReadyQSuspension>,
     pub sched_q: Vec<SchedQSuspension>,
+    pub bounce: Vec<SchedQBounce>,
+    pub reroute: Vec<SchedQReroute>,
 }
 
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct ReadyQSuspension {
     pub rule_hash: String,
     pub site_name: String,
@@ -16,7 +18,7 @@ pub struct ReadyQSuspension {
     pub expires: DateTime<Utc>,
 }
 
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct SchedQSuspension {
     pub rule_hash: String,
     pub tenant: String,
@@ -26,8 +28,31 @@ pub struct SchedQSuspension {
     pub expires: DateTime<Utc>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SchedQBounce {
+    pub rule_hash: String,
+    pub tenant: Option<String>,
+    pub domain: String,
+    pub campaign: Option<String>,
+    pub reason: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SchedQReroute {
+    pub rule_hash: String,
+    pub tenant: Option<String>,
+    pub domain: String,
+    pub campaign: Option<String>,
+    pub egress_pool: String,
+    pub reason: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum SuspensionEntry {
     ReadyQ(ReadyQSuspension),
     SchedQ(SchedQSuspension),
+    Bounce(SchedQBounce),
+    Reroute(SchedQReroute),
 }
@@ -110,6 +110,27 @@ pub struct JsonLogRecord {
     /// The Subject Name from the peer TLS certificate, if applicable
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls_peer_subject_name: Option<Vec<String>>,
+
+    /// For OOB and Feedback records, information about the original
+    /// message that the report was correlated with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlated_message: Option<CorrelatedMessage>,
+}
+
+/// Information about a previously delivered message that was
+/// matched with an incoming OOB or Feedback report
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CorrelatedMessage {
+    /// The spool id of the original message
+    pub id: String,
+    /// The Message-ID header of the original message
+    pub message_id: String,
+    /// The queue from which the original message was delivered
+    pub queue: String,
+    pub sender: String,
+    pub recipient: String,
+    pub egress_pool: Option<String>,
+    pub egress_source: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
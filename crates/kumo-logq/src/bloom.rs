@@ -0,0 +1,100 @@
+//! A simple bloom filter, used to record which ids, recipients and
+//! domains appear in a segment without having to store all of them.
+//!
+//! The hash function is part of the on-disk format, so we use our own
+//! FNV-1a implementation rather than the std hasher, which is not
+//! guaranteed to be stable across releases.
+use data_encoding::BASE64;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    num_bits: u64,
+    num_hashes: u32,
+    #[serde(serialize_with = "encode_bits", deserialize_with = "decode_bits")]
+    bits: Vec<u8>,
+}
+
+fn encode_bits<S: Serializer>(bits: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(bits))
+}
+
+fn decode_bits<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    BASE64
+        .decode(s.as_bytes())
+        .map_err(serde::de::Error::custom)
+}
+
+fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+    let mut hash = seed;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl BloomFilter {
+    /// Create a filter sized to hold `num_items` with the
+    /// specified false positive rate
+    pub fn new(num_items: usize, false_positive_rate: f64) -> Self {
+        let num_items = num_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(num_items * false_positive_rate.ln()) / (ln2 * ln2))
+            .ceil()
+            .max(8.0) as u64;
+        let num_hashes = ((num_bits as f64 / num_items) * ln2).round().max(1.0) as u32;
+        Self {
+            num_bits,
+            num_hashes,
+            bits: vec![0u8; ((num_bits + 7) / 8) as usize],
+        }
+    }
+
+    fn bit_indices<'a>(&'a self, item: &str) -> impl Iterator<Item = u64> + 'a {
+        // Double hashing: derive each of the k hashes from two base hashes
+        let h1 = fnv1a(0xcbf29ce484222325, item.as_bytes());
+        let h2 = fnv1a(0x84222325cbf29ce4, item.as_bytes()) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        let indices: Vec<u64> = self.bit_indices(item).collect();
+        for idx in indices {
+            self.bits[(idx / 8) as usize] |= 1 << (idx % 8);
+        }
+    }
+
+    /// Returns false if the item is definitely not present,
+    /// true if it may be present
+    pub fn may_contain(&self, item: &str) -> bool {
+        self.bit_indices(item)
+            .all(|idx| self.bits[(idx / 8) as usize] & (1 << (idx % 8)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bloom() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("user{i}@example.com"));
+        }
+        for i in 0..1000 {
+            assert!(filter.may_contain(&format!("user{i}@example.com")));
+        }
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(&format!("other{i}@example.com")))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let json = serde_json::to_string(&filter).unwrap();
+        let decoded: BloomFilter = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, filter);
+    }
+}
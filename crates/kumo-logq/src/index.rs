@@ -0,0 +1,328 @@
+//! The sidecar index.
+//!
+//! For each complete segment, we store the range of timestamps that it
+//! covers, together with bloom filters for the ids, recipients and
+//! recipient domains that it holds. This allows a query to skip
+//! reading most segments, while keeping the index small.
+//!
+//! The index is stored in a `.logq-index` directory inside the log
+//! directory, with one file per segment. As with `.tailer-checkpoint`,
+//! the leading `.` means that it will not be confused with a segment.
+use crate::bloom::BloomFilter;
+use crate::segment::{LogLine, Segment};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Bump this when the format of the index changes,
+/// to cause existing index files to be rebuilt
+const INDEX_VERSION: u32 = 1;
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+pub const INDEX_DIR_NAME: &str = ".logq-index";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentIndex {
+    pub version: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub num_records: u64,
+    pub min_timestamp: Option<i64>,
+    pub max_timestamp: Option<i64>,
+    pub ids: BloomFilter,
+    pub recipients: BloomFilter,
+    pub domains: BloomFilter,
+}
+
+/// The criteria used to select records
+#[derive(Debug, Default, Clone)]
+pub struct Criteria {
+    pub id: Option<String>,
+    pub recipient: Option<String>,
+    pub domain: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub kinds: Vec<String>,
+}
+
+impl Criteria {
+    pub fn matches(&self, record: &LogLine) -> bool {
+        if let Some(id) = &self.id {
+            if &record.id != id {
+                return false;
+            }
+        }
+        if let Some(recipient) = &self.recipient {
+            if record.recipient_key() != *recipient {
+                return false;
+            }
+        }
+        if let Some(domain) = &self.domain {
+            if record.domain_key().as_ref() != Some(domain) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if record.timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if record.timestamp > until {
+                return false;
+            }
+        }
+        if !self.kinds.is_empty()
+            && !self
+                .kinds
+                .iter()
+                .any(|k| k.eq_ignore_ascii_case(&record.kind))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+impl SegmentIndex {
+    pub fn build(segment: &Segment) -> anyhow::Result<Self> {
+        let mut ids = HashSet::new();
+        let mut recipients = HashSet::new();
+        let mut domains = HashSet::new();
+        let mut min_timestamp: Option<i64> = None;
+        let mut max_timestamp: Option<i64> = None;
+        let mut num_records = 0;
+
+        segment.for_each_line(|line| {
+            if let Some(record) = LogLine::parse(line) {
+                num_records += 1;
+                min_timestamp =
+                    Some(min_timestamp.map_or(record.timestamp, |t| t.min(record.timestamp)));
+                max_timestamp =
+                    Some(max_timestamp.map_or(record.timestamp, |t| t.max(record.timestamp)));
+                if let Some(domain) = record.domain_key() {
+                    domains.insert(domain);
+                }
+                recipients.insert(record.recipient_key());
+                ids.insert(record.id);
+            }
+            Ok(())
+        })?;
+
+        fn make_filter(items: HashSet<String>) -> BloomFilter {
+            let mut filter = BloomFilter::new(items.len(), FALSE_POSITIVE_RATE);
+            for item in items {
+                filter.insert(&item);
+            }
+            filter
+        }
+
+        Ok(Self {
+            version: INDEX_VERSION,
+            size: segment.size,
+            mtime: segment.mtime,
+            num_records,
+            min_timestamp,
+            max_timestamp,
+            ids: make_filter(ids),
+            recipients: make_filter(recipients),
+            domains: make_filter(domains),
+        })
+    }
+
+    /// Returns true if the index is for the current state of the segment
+    pub fn is_current(&self, segment: &Segment) -> bool {
+        self.version == INDEX_VERSION && self.size == segment.size && self.mtime == segment.mtime
+    }
+
+    /// Returns false if the segment definitely does not contain any
+    /// records that match the criteria
+    pub fn may_match(&self, criteria: &Criteria) -> bool {
+        let (min, max) = match (self.min_timestamp, self.max_timestamp) {
+            (Some(min), Some(max)) => (min, max),
+            // No records at all
+            _ => return false,
+        };
+        if let Some(since) = criteria.since {
+            if max < since {
+                return false;
+            }
+        }
+        if let Some(until) = criteria.until {
+            if min > until {
+                return false;
+            }
+        }
+        if let Some(id) = &criteria.id {
+            if !self.ids.may_contain(id) {
+                return false;
+            }
+        }
+        if let Some(recipient) = &criteria.recipient {
+            if !self.recipients.may_contain(recipient) {
+                return false;
+            }
+        }
+        if let Some(domain) = &criteria.domain {
+            if !self.domains.may_contain(domain) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct IndexDir {
+    dir: PathBuf,
+}
+
+impl IndexDir {
+    pub fn new(log_dir: &Path) -> Self {
+        Self {
+            dir: log_dir.join(INDEX_DIR_NAME),
+        }
+    }
+
+    fn path_for(&self, segment_name: &str) -> PathBuf {
+        self.dir.join(format!("{segment_name}.json"))
+    }
+
+    /// Load the index for a segment, returning None if there is no
+    /// index, or it is out of date
+    pub fn load(&self, segment: &Segment) -> Option<SegmentIndex> {
+        let data = std::fs::read(self.path_for(&segment.name)).ok()?;
+        let index: SegmentIndex = serde_json::from_slice(&data).ok()?;
+        if index.is_current(segment) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    pub fn store(&self, segment: &Segment, index: &SegmentIndex) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let path = self.path_for(&segment.name);
+        let temp_path = self.dir.join(format!(".{}.json", segment.name));
+        std::fs::write(&temp_path, serde_json::to_vec(index)?)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("renaming {} -> {}", temp_path.display(), path.display()))?;
+        Ok(())
+    }
+
+    /// Remove index files for segments that no longer exist,
+    /// returning the number that were removed
+    pub fn prune(&self, segments: &[Segment]) -> anyhow::Result<usize> {
+        let names: HashSet<String> = segments
+            .iter()
+            .map(|s| format!("{}.json", s.name))
+            .collect();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !names.contains(&name) {
+                std::fs::remove_file(entry.path())
+                    .with_context(|| format!("removing {}", entry.path().display()))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn record(id: &str, recipient: &str, timestamp: i64) -> String {
+        serde_json::json!({
+            "type": "Delivery",
+            "id": id,
+            "sender": "sender@example.com",
+            "recipient": recipient,
+            "queue": "example.com",
+            "site": "",
+            "timestamp": timestamp,
+            "response": {"code": 250, "content": "ok"},
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn index_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("20240101-000000");
+        let mut encoder =
+            zstd::stream::write::Encoder::new(std::fs::File::create(&path).unwrap(), 0).unwrap();
+        writeln!(encoder, "{}", record("aaa", "User@Example.com", 100)).unwrap();
+        writeln!(encoder, "{}", record("bbb", "other@example.org", 200)).unwrap();
+        writeln!(encoder, "not json, from a template").unwrap();
+        encoder.finish().unwrap();
+
+        let segments = Segment::list(dir.path()).unwrap();
+        assert_eq!(segments.len(), 1);
+        let index = SegmentIndex::build(&segments[0]).unwrap();
+        assert_eq!(index.num_records, 2);
+        assert_eq!(index.min_timestamp, Some(100));
+        assert_eq!(index.max_timestamp, Some(200));
+
+        let by_id = Criteria {
+            id: Some("aaa".to_string()),
+            ..Default::default()
+        };
+        assert!(index.may_match(&by_id));
+        let by_recipient = Criteria {
+            recipient: Some("user@example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(index.may_match(&by_recipient));
+        let by_domain = Criteria {
+            domain: Some("example.net".to_string()),
+            ..Default::default()
+        };
+        assert!(!index.may_match(&by_domain));
+        let too_late = Criteria {
+            since: Some(300),
+            ..Default::default()
+        };
+        assert!(!index.may_match(&too_late));
+
+        let index_dir = IndexDir::new(dir.path());
+        assert!(index_dir.load(&segments[0]).is_none());
+        index_dir.store(&segments[0], &index).unwrap();
+        assert!(index_dir.load(&segments[0]).is_some());
+
+        // The index directory is not listed as a segment
+        assert_eq!(Segment::list(dir.path()).unwrap().len(), 1);
+
+        assert_eq!(index_dir.prune(&segments).unwrap(), 0);
+        assert_eq!(index_dir.prune(&[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn criteria() {
+        let line = LogLine::parse(&record("aaa", "User@Example.com", 100)).unwrap();
+        let criteria = Criteria {
+            recipient: Some("user@example.com".to_string()),
+            domain: Some("example.com".to_string()),
+            since: Some(50),
+            until: Some(100),
+            kinds: vec!["delivery".to_string()],
+            ..Default::default()
+        };
+        assert!(criteria.matches(&line));
+        let criteria = Criteria {
+            kinds: vec!["Bounce".to_string()],
+            ..Default::default()
+        };
+        assert!(!criteria.matches(&line));
+    }
+}
@@ -0,0 +1,6 @@
+//! The log segment reader and index used by the `kumo-logq` utility.
+//! Other tools that need to read log segments use the reader from here,
+//! so that they follow the same conventions.
+pub mod bloom;
+pub mod index;
+pub mod segment;
@@ -0,0 +1,291 @@
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use clap::{Parser, ValueEnum};
+use kumo_logq::index::{Criteria, IndexDir, SegmentIndex};
+use kumo_logq::segment::{LogLine, Segment, SegmentFormat};
+use std::path::PathBuf;
+use tabout::{Alignment, Column};
+
+/// KumoMTA log query utility.
+///
+/// Answers questions such as "what happened to message X" or "what
+/// happened to recipient Y yesterday" by searching the log segments
+/// in a log directory, without having to decompress all of them.
+///
+/// Completed segments are indexed into a small sidecar index that is
+/// stored in a `.logq-index` directory inside the log directory.
+/// The index records the time range covered by each segment, together
+/// with bloom filters of the ids, recipients and domains that it holds,
+/// so that most segments can be skipped when querying.
+///
+/// Full docs available at: <https://docs.kumomta.com>
+#[derive(Debug, Parser)]
+#[command(about, version=version_info::kumo_version())]
+struct Opt {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(Debug, Parser)]
+enum SubCommand {
+    Index(IndexCommand),
+    Query(QueryCommand),
+}
+
+/// Build or refresh the index for the completed segments in a
+/// log directory, and remove index entries for segments that no
+/// longer exist.
+#[derive(Debug, Parser)]
+struct IndexCommand {
+    /// The directory which contains the logs
+    directory: PathBuf,
+}
+
+impl IndexCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let segments = Segment::list(&self.directory)?;
+        let index_dir = IndexDir::new(&self.directory);
+
+        let mut indexed = 0;
+        let mut num_records = 0;
+        for segment in &segments {
+            if !segment.done {
+                continue;
+            }
+            if index_dir.load(segment).is_some() {
+                continue;
+            }
+            match SegmentFormat::detect(&segment.path)? {
+                SegmentFormat::Parquet => continue,
+                SegmentFormat::Zstd | SegmentFormat::Plain => {}
+            }
+            let index = SegmentIndex::build(segment)
+                .with_context(|| format!("indexing {}", segment.name))?;
+            index_dir.store(segment, &index)?;
+            indexed += 1;
+            num_records += index.num_records;
+        }
+        let pruned = index_dir.prune(&segments)?;
+        eprintln!(
+            "indexed {num_records} records in {indexed} segments, \
+             removed {pruned} stale entries, {} segments total",
+            segments.len()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum OutputFormat {
+    /// The log records, one per line, as they appear in the segments
+    #[default]
+    Json,
+    /// A summary table
+    Table,
+}
+
+/// Search the logs for records that match all of the specified criteria.
+///
+/// Completed segments that have not yet been indexed are indexed as
+/// part of the query, unless --no-update is used. Segments that are
+/// still being written are always searched in full.
+#[derive(Debug, Parser)]
+struct QueryCommand {
+    /// Only show records for this message id
+    #[arg(long)]
+    id: Option<String>,
+
+    /// Only show records for this recipient
+    #[arg(long)]
+    recipient: Option<String>,
+
+    /// Only show records for recipients in this domain
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Only show records with a timestamp at or after this time.
+    /// Can be an RFC 3339 timestamp, a date and optional time in UTC
+    /// such as `2024-05-01` or `2024-05-01 12:00:00`, or a duration
+    /// such as `1day` which is interpreted as that long ago.
+    #[arg(long, value_parser=parse_time)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only show records with a timestamp at or before this time.
+    /// Accepts the same formats as --since.
+    #[arg(long, value_parser=parse_time)]
+    until: Option<DateTime<Utc>>,
+
+    /// Only show records of this type, such as `Delivery` or
+    /// `TransientFailure`. Can be specified multiple times.
+    #[arg(long = "type")]
+    kinds: Vec<String>,
+
+    /// Stop after showing this many records
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// How to display the matching records
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Don't write newly built index entries to the index
+    #[arg(long)]
+    no_update: bool,
+
+    /// The directory which contains the logs
+    directory: PathBuf,
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(Utc.from_utc_datetime(&t));
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).expect("midnight is valid")));
+    }
+    match humantime::parse_duration(s) {
+        Ok(duration) => {
+            let duration = chrono::Duration::from_std(duration).map_err(|err| err.to_string())?;
+            Ok(Utc::now() - duration)
+        }
+        Err(_) => Err(format!("{s} is not a valid timestamp, date or duration")),
+    }
+}
+
+impl QueryCommand {
+    fn criteria(&self) -> Criteria {
+        Criteria {
+            id: self.id.clone(),
+            recipient: self.recipient.as_ref().map(|r| r.to_ascii_lowercase()),
+            domain: self.domain.as_ref().map(|d| d.to_ascii_lowercase()),
+            since: self.since.map(|t| t.timestamp()),
+            until: self.until.map(|t| t.timestamp()),
+            kinds: self.kinds.clone(),
+        }
+    }
+
+    fn run(&self) -> anyhow::Result<()> {
+        let criteria = self.criteria();
+        let segments = Segment::list(&self.directory)?;
+        let index_dir = IndexDir::new(&self.directory);
+
+        let mut rows = vec![];
+        let mut num_shown = 0;
+
+        'segments: for segment in &segments {
+            if SegmentFormat::detect(&segment.path)? == SegmentFormat::Parquet {
+                continue;
+            }
+
+            if segment.done {
+                let index = match index_dir.load(segment) {
+                    Some(index) => index,
+                    None => {
+                        let index = SegmentIndex::build(segment)
+                            .with_context(|| format!("indexing {}", segment.name))?;
+                        if !self.no_update {
+                            if let Err(err) = index_dir.store(segment, &index) {
+                                eprintln!("failed to update index: {err:#}");
+                            }
+                        }
+                        index
+                    }
+                };
+                if !index.may_match(&criteria) {
+                    continue;
+                }
+            }
+
+            let mut limit_reached = false;
+            segment.for_each_line(|line| {
+                if limit_reached {
+                    return Ok(());
+                }
+                let record = match LogLine::parse(line) {
+                    Some(record) => record,
+                    None => return Ok(()),
+                };
+                if !criteria.matches(&record) {
+                    return Ok(());
+                }
+                match self.format {
+                    OutputFormat::Json => println!("{line}"),
+                    OutputFormat::Table => rows.push(table_row(&record)),
+                }
+                num_shown += 1;
+                if self.limit.map(|limit| num_shown >= limit).unwrap_or(false) {
+                    limit_reached = true;
+                }
+                Ok(())
+            })?;
+            if limit_reached {
+                break 'segments;
+            }
+        }
+
+        if let OutputFormat::Table = self.format {
+            let columns = [
+                Column {
+                    name: "TIMESTAMP".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "TYPE".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "ID".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "RECIPIENT".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "SITE".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "RESPONSE".to_string(),
+                    alignment: Alignment::Left,
+                },
+            ];
+            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn table_row(record: &LogLine) -> Vec<String> {
+    let timestamp = Utc
+        .timestamp_opt(record.timestamp, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| record.timestamp.to_string());
+    let response = match &record.response {
+        Some(response) => format!("{} {}", response.code, response.content),
+        None => String::new(),
+    };
+    vec![
+        timestamp,
+        record.kind.clone(),
+        record.id.clone(),
+        record.recipient.clone(),
+        record.site.clone(),
+        response,
+    ]
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opt::parse();
+    match &opts.cmd {
+        SubCommand::Index(cmd) => cmd.run(),
+        SubCommand::Query(cmd) => cmd.run(),
+    }
+}
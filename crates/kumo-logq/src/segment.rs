@@ -0,0 +1,179 @@
+//! Reading log segments, following the same conventions as `tailer`:
+//!
+//! * Files whose names start with `.` are not segments; they are
+//!   sidecar files such as `.tailer-checkpoint` or our own index.
+//! * kumod removes the `w` bits from the permissions of a segment when
+//!   it has finished writing it. Until then, the segment may have a
+//!   partially written zstd frame at its end.
+use anyhow::Context;
+use serde::Deserialize;
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const PARQUET_MAGIC: [u8; 4] = *b"PAR1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// zstd compressed, newline delimited records
+    Zstd,
+    /// Uncompressed, newline delimited records
+    Plain,
+    /// Parquet segments cannot be read by this tool
+    Parquet,
+}
+
+impl SegmentFormat {
+    pub fn detect(path: &Path) -> anyhow::Result<Self> {
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let len = file.read(&mut magic)?;
+        Ok(if len == 4 && magic == ZSTD_MAGIC {
+            Self::Zstd
+        } else if len == 4 && magic == PARQUET_MAGIC {
+            Self::Parquet
+        } else {
+            Self::Plain
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    /// Modification time, in seconds since the epoch
+    pub mtime: i64,
+    pub done: bool,
+}
+
+impl Segment {
+    /// Return the segments in `dir`, sorted by name, which is
+    /// also chronological order
+    pub fn list(dir: &Path) -> anyhow::Result<Vec<Self>> {
+        let mut result = vec![];
+        for entry in std::fs::read_dir(dir).with_context(|| format!("reading dir {dir:?}"))? {
+            let entry = entry?;
+            let name = match entry.file_name().to_str() {
+                Some(name) if name.starts_with('.') => continue,
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let meta = entry.metadata()?;
+            if !meta.is_file() {
+                continue;
+            }
+            result.push(Self::with_metadata(entry.path(), name, &meta)?);
+        }
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(result)
+    }
+
+    /// Return the segment at `path`
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let meta =
+            std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Self::with_metadata(path.to_path_buf(), name, &meta)
+    }
+
+    fn with_metadata(path: PathBuf, name: String, meta: &Metadata) -> anyhow::Result<Self> {
+        let mtime = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Ok(Self {
+            path,
+            name,
+            size: meta.len(),
+            mtime,
+            done: meta.permissions().readonly(),
+        })
+    }
+
+    /// Return a reader for the decompressed contents of the segment
+    pub fn reader(&self) -> anyhow::Result<Box<dyn BufRead>> {
+        let file =
+            File::open(&self.path).with_context(|| format!("opening {}", self.path.display()))?;
+        Ok(match SegmentFormat::detect(&self.path)? {
+            SegmentFormat::Zstd => {
+                Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?))
+            }
+            SegmentFormat::Plain => Box::new(BufReader::new(file)),
+            SegmentFormat::Parquet => {
+                anyhow::bail!("{} is a Parquet segment, which is not supported", self.name)
+            }
+        })
+    }
+
+    /// Call `func` for each line in the segment.
+    /// Errors caused by an incomplete trailing zstd frame are ignored
+    /// for segments that are still being written.
+    pub fn for_each_line<F>(&self, mut func: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&str) -> anyhow::Result<()>,
+    {
+        for line in self.reader()?.split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) if !self.done => {
+                    // Most likely a partially written frame
+                    eprintln!("{}: stopping at incomplete data: {err:#}", self.name);
+                    break;
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("reading {}", self.path.display()))
+                }
+            };
+            func(&String::from_utf8_lossy(&line))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordResponse {
+    pub code: u16,
+    #[serde(default)]
+    pub content: String,
+}
+
+/// The subset of the log record fields that we index and display.
+/// The records may hold many more fields than these.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogLine {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+    pub recipient: String,
+    #[serde(default)]
+    pub site: String,
+    /// Seconds since the epoch
+    pub timestamp: i64,
+    #[serde(default)]
+    pub response: Option<RecordResponse>,
+}
+
+impl LogLine {
+    /// Parse a line, returning None if it is not a JSON log record,
+    /// as can be the case when templates are used
+    pub fn parse(line: &str) -> Option<Self> {
+        serde_json::from_str(line).ok()
+    }
+
+    pub fn recipient_key(&self) -> String {
+        self.recipient.to_ascii_lowercase()
+    }
+
+    pub fn domain_key(&self) -> Option<String> {
+        self.recipient
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_ascii_lowercase())
+    }
+}
@@ -2,11 +2,24 @@ use anyhow::Context;
 use clap::ValueEnum;
 use metrics_prometheus::recorder::Layer as _;
 use once_cell::sync::OnceCell;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::Resource;
 use std::path::PathBuf;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
 use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter, Layer};
 
+/// The tracing target used by spans that describe the lifecycle
+/// of a message. Only spans with this target are exported via OTLP.
+pub const MESSAGE_TRACE_TARGET: &str = "message_trace";
+
+static OTLP_PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
 // Why in the heck is this a function and not simply the reload handle itself?
 // The reason is because the tracing_subscriber crate makes heavy use of composed
 // generic types and with the configuration we have chosen, some of the layers have
@@ -47,6 +60,58 @@ pub struct LoggingConfig<'a> {
     pub filter_env_var: &'a str,
     pub default_filter: &'a str,
     pub diag_format: DiagnosticFormat,
+    /// If set, the OTLP/HTTP endpoint to which message traces are exported
+    pub otlp_endpoint: Option<String>,
+    /// The service.name reported with exported traces
+    pub service_name: &'a str,
+}
+
+/// Returns true if message traces are being exported via OTLP
+pub fn otlp_enabled() -> bool {
+    OTLP_PROVIDER.get().is_some()
+}
+
+/// Flush any buffered spans and stop exporting traces.
+/// Called as part of shutdown.
+pub async fn shutdown_otlp() {
+    if let Some(provider) = OTLP_PROVIDER.get() {
+        let provider = provider.clone();
+        // Flushing blocks on the batch exporter, which runs on
+        // the tokio runtime, so do it from a blocking thread
+        let result = tokio::task::spawn_blocking(move || {
+            for result in provider.force_flush() {
+                if let Err(err) = result {
+                    tracing::error!("failed to flush traces: {err:#}");
+                }
+            }
+        })
+        .await;
+        if let Err(err) = result {
+            tracing::error!("failed to flush traces: {err:#}");
+        }
+    }
+}
+
+fn make_otlp_tracer(endpoint: &str, service_name: &str) -> anyhow::Result<Tracer> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint)
+        .build_span_exporter()
+        .with_context(|| format!("creating OTLP exporter for {endpoint}"))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_config(
+            opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name.to_string(),
+            )])),
+        )
+        .build();
+    let tracer = provider.tracer(service_name.to_string());
+    OTLP_PROVIDER
+        .set(provider)
+        .map_err(|_| anyhow::anyhow!("OTLP exporter was already configured"))?;
+    Ok(tracer)
 }
 
 impl<'a> LoggingConfig<'a> {
@@ -74,9 +139,25 @@ impl<'a> LoggingConfig<'a> {
                 .unwrap_or(self.default_filter),
         )?;
         let (env_filter, reload_handle) = tracing_subscriber::reload::Layer::new(env_filter);
+
+        let otlp_layer = match &self.otlp_endpoint {
+            Some(endpoint) => {
+                let tracer = make_otlp_tracer(endpoint, self.service_name)?;
+                Some(
+                    tracing_opentelemetry::layer()
+                        .with_tracer(tracer)
+                        .with_filter(
+                            Targets::new().with_target(MESSAGE_TRACE_TARGET, Level::TRACE),
+                        ),
+                )
+            }
+            None => None,
+        };
+
         tracing_subscriber::registry()
             .with(layer.with_filter(env_filter))
             .with(metrics_tracing_context::MetricsLayer::new())
+            .with(otlp_layer)
             .init();
 
         TRACING_FILTER_RELOAD_HANDLE
@@ -318,6 +318,21 @@ async fn report_metrics_json(_: TrustedIpRequired) -> Result<Json<serde_json::Va
 
                     apply_to_value(&mut value, this_value, label);
                 }
+                MetricType::HISTOGRAM => {
+                    let histogram = mc.get_histogram();
+                    let buckets: Vec<Value> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|b| json!([b.get_upper_bound(), b.get_cumulative_count()]))
+                        .collect();
+                    let this_value = json!({
+                        "count": histogram.get_sample_count(),
+                        "sum": histogram.get_sample_sum(),
+                        "buckets": buckets,
+                    });
+
+                    apply_to_value(&mut value, this_value, label);
+                }
                 _ => {
                     // Other types are currently not implemented
                     // as we don't currently export any other type
@@ -66,6 +66,7 @@ impl<'a> StartConfig<'a> {
         // after waiting for those to idle out, shut down logging
         let shutdown_future = (broadcast_shutdown)();
         shutdown_future.await;
+        crate::diagnostic_logging::shutdown_otlp().await;
 
         tracing::info!("Shutdown completed OK!");
 
@@ -0,0 +1,159 @@
+//! The archive format used by `kumo-spool export` and `kumo-spool import`.
+//!
+//! An archive is a zstd compressed stream of newline delimited JSON.
+//! The first line is an `ArchiveHeader`, and each subsequent line
+//! is an `ArchiveEntry` holding both the metadata and the message
+//! data for a single message.
+use anyhow::Context;
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use spool::SpoolId;
+use std::io::{BufRead, BufReader, Read, Write};
+
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ArchiveHeader {
+    kumo_spool_archive: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchiveEntry {
+    pub id: SpoolId,
+    /// The message metadata, as stored in the meta spool
+    pub meta: serde_json::Value,
+    /// The base64 encoded message data, as stored in the data spool
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+mod base64_bytes {
+    use super::BASE64;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64
+            .decode(encoded.as_bytes())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub struct ArchiveWriter<W: Write> {
+    encoder: zstd::stream::write::Encoder<'static, W>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(output: W) -> anyhow::Result<Self> {
+        let mut encoder = zstd::stream::write::Encoder::new(output, 3)?;
+        serde_json::to_writer(
+            &mut encoder,
+            &ArchiveHeader {
+                kumo_spool_archive: ARCHIVE_VERSION,
+            },
+        )?;
+        encoder.write_all(b"\n")?;
+        Ok(Self { encoder })
+    }
+
+    pub fn write_entry(&mut self, entry: &ArchiveEntry) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.encoder, entry)?;
+        self.encoder.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<W> {
+        let mut output = self.encoder.finish()?;
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+pub struct ArchiveReader<R: Read> {
+    lines: std::io::Lines<BufReader<zstd::stream::read::Decoder<'static, BufReader<R>>>>,
+    line_number: usize,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(input: R) -> anyhow::Result<Self> {
+        let decoder = zstd::stream::read::Decoder::new(input)?;
+        let mut lines = BufReader::new(decoder).lines();
+
+        let header = lines
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("archive is empty"))?
+            .context("reading archive header")?;
+        let header: ArchiveHeader =
+            serde_json::from_str(&header).context("parsing archive header")?;
+        anyhow::ensure!(
+            header.kumo_spool_archive == ARCHIVE_VERSION,
+            "unsupported archive version {}",
+            header.kumo_spool_archive
+        );
+
+        Ok(Self {
+            lines,
+            line_number: 1,
+        })
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = anyhow::Result<ArchiveEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        self.line_number += 1;
+        let line_number = self.line_number;
+        Some(
+            line.map_err(anyhow::Error::from)
+                .and_then(|line| Ok(serde_json::from_str(&line)?))
+                .with_context(|| format!("reading archive entry on line {line_number}")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let entries = vec![
+            ArchiveEntry {
+                id: SpoolId::new(),
+                meta: serde_json::json!({
+                    "sender": "sender@example.com",
+                    "recipient": "recip@example.com",
+                    "meta": {"queue": "example.com"},
+                }),
+                data: b"Subject: hello\r\n\r\nhello\r\n".to_vec(),
+            },
+            ArchiveEntry {
+                id: SpoolId::new(),
+                meta: serde_json::json!({
+                    "sender": "sender@example.com",
+                    "recipient": "other@example.com",
+                    "meta": {},
+                }),
+                data: vec![0, 1, 2, 255],
+            },
+        ];
+
+        let mut writer = ArchiveWriter::new(vec![])?;
+        for entry in &entries {
+            writer.write_entry(entry)?;
+        }
+        let archive = writer.finish()?;
+
+        let decoded =
+            ArchiveReader::new(archive.as_slice())?.collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(decoded, entries);
+
+        Ok(())
+    }
+}
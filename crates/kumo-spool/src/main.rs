@@ -0,0 +1,520 @@
+use crate::archive::{ArchiveEntry, ArchiveReader, ArchiveWriter};
+use anyhow::Context;
+use clap::builder::ValueParser;
+use clap::{Args, Parser, ValueEnum};
+use serde::Deserialize;
+use spool::compressed::{CompressedSpool, CompressionParams};
+use spool::local_disk::LocalDiskSpool;
+use spool::rocks::RocksSpool;
+use spool::{Spool, SpoolEntry, SpoolId};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod archive;
+
+/// KumoMTA spool maintenance utility.
+///
+/// Operates directly on the spool storage, and is intended to be used
+/// while kumod is not running. It can be used to examine the contents
+/// of a spool, verify its integrity, or to move messages to a different
+/// host, or a different kind of spool, by exporting them to a portable
+/// archive and then importing that archive into a different spool.
+///
+/// Full docs available at: <https://docs.kumomta.com>
+#[derive(Debug, Parser)]
+#[command(about, version=version_info::kumo_version())]
+struct Opt {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(Debug, Parser)]
+enum SubCommand {
+    List(ListCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+    Verify(VerifyCommand),
+}
+
+impl SubCommand {
+    async fn run(&self) -> anyhow::Result<()> {
+        match self {
+            Self::List(cmd) => cmd.run().await,
+            Self::Export(cmd) => cmd.run().await,
+            Self::Import(cmd) => cmd.run().await,
+            Self::Verify(cmd) => cmd.run().await,
+        }
+    }
+}
+
+/// Corresponds to the `kind` parameter of `kumo.define_spool`
+#[derive(ValueEnum, Default, Debug, Clone, Copy)]
+enum SpoolKind {
+    #[default]
+    #[value(name = "LocalDisk")]
+    LocalDisk,
+    #[value(name = "RocksDB")]
+    RocksDB,
+}
+
+#[derive(Debug, Args)]
+struct SpoolLocation {
+    /// The path to the meta spool, which holds the envelope and
+    /// per-message metadata
+    #[arg(long)]
+    meta_path: PathBuf,
+
+    /// The path to the data spool, which holds the message bodies
+    #[arg(long)]
+    data_path: PathBuf,
+
+    /// The kind of storage used by the meta spool
+    #[arg(long, value_enum, default_value_t)]
+    meta_kind: SpoolKind,
+
+    /// The kind of storage used by the data spool
+    #[arg(long, value_enum, default_value_t)]
+    data_kind: SpoolKind,
+}
+
+struct OpenedSpool {
+    meta: Arc<dyn Spool + Send + Sync>,
+    data: Arc<dyn Spool + Send + Sync>,
+}
+
+impl SpoolLocation {
+    fn open_one(
+        name: &str,
+        path: &Path,
+        kind: SpoolKind,
+        compression: Option<CompressionParams>,
+    ) -> anyhow::Result<Arc<dyn Spool + Send + Sync>> {
+        let spool: Arc<dyn Spool + Send + Sync> = match kind {
+            SpoolKind::LocalDisk => Arc::new(
+                LocalDiskSpool::new(path, false)
+                    .with_context(|| format!("opening {name} spool {}", path.display()))?,
+            ),
+            SpoolKind::RocksDB => Arc::new(
+                RocksSpool::new(path, false, None)
+                    .with_context(|| format!("opening {name} spool {}", path.display()))?,
+            ),
+        };
+        // Entries are transparently decompressed on load regardless
+        // of the params; the params only influence whether we compress
+        // when storing new entries. A threshold of usize::MAX disables
+        // compression on store.
+        let compression = compression.unwrap_or(CompressionParams {
+            threshold: usize::MAX,
+            ..CompressionParams::default()
+        });
+        Ok(Arc::new(CompressedSpool::new(name, spool, compression)))
+    }
+
+    fn open(&self, compression: Option<CompressionParams>) -> anyhow::Result<OpenedSpool> {
+        Ok(OpenedSpool {
+            meta: Self::open_one("meta", &self.meta_path, self.meta_kind, None)?,
+            data: Self::open_one("data", &self.data_path, self.data_kind, compression)?,
+        })
+    }
+}
+
+/// The portion of the metadata that we need in order to
+/// match and display messages.
+#[derive(Deserialize, Debug)]
+struct MetaSummary {
+    sender: String,
+    recipient: String,
+    #[serde(default)]
+    meta: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Args)]
+struct MetaFilter {
+    /// Only match messages whose recipient is in this domain
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Only match messages with a meta value matching KEY=VALUE,
+    /// for example `--filter tenant=mytenant`. Can be used multiple
+    /// times, in which case all of the filters must match.
+    #[arg(long, name="KEY=VALUE", value_parser=ValueParser::new(name_equals_value))]
+    filter: Vec<(String, String)>,
+}
+
+impl MetaFilter {
+    fn matches(&self, summary: &MetaSummary) -> bool {
+        if let Some(domain) = &self.domain {
+            match summary.recipient.rsplit_once('@') {
+                Some((_, recip_domain)) if recip_domain.eq_ignore_ascii_case(domain) => {}
+                _ => return false,
+            }
+        }
+        self.filter
+            .iter()
+            .all(|(key, value)| match summary.meta.get(key) {
+                Some(serde_json::Value::String(s)) => s == value,
+                Some(other) => other.to_string() == *value,
+                None => false,
+            })
+    }
+}
+
+/// Helper for parsing meta filters
+fn name_equals_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((left, right)) => {
+            let left = left.trim();
+            let right = right.trim();
+            if left.is_empty() {
+                return Err(format!("Got empty name `{arg}`; expected name=value"));
+            }
+            Ok((left.to_string(), right.to_string()))
+        }
+        None => Err(format!("Expected name=value, but got {arg}")),
+    }
+}
+
+/// Enumerate the meta spool, calling `func` for each entry.
+/// Corrupt entries are reported to stderr and counted.
+/// Returns the number of corrupt entries.
+async fn enumerate_meta<F>(spool: &OpenedSpool, mut func: F) -> anyhow::Result<usize>
+where
+    F: FnMut(SpoolId, Vec<u8>, MetaSummary) -> anyhow::Result<()>,
+{
+    let (tx, rx) = flume::bounded(1024);
+    spool.meta.enumerate(tx)?;
+    let mut corrupt = 0;
+    while let Ok(entry) = rx.recv_async().await {
+        match entry {
+            SpoolEntry::Item { id, data } => match serde_json::from_slice::<MetaSummary>(&data) {
+                Ok(summary) => func(id, data, summary)?,
+                Err(err) => {
+                    eprintln!("{id}: corrupt meta: {err:#}");
+                    corrupt += 1;
+                }
+            },
+            SpoolEntry::Corrupt { id, error } => {
+                eprintln!("{id}: corrupt meta: {error}");
+                corrupt += 1;
+            }
+        }
+    }
+    Ok(corrupt)
+}
+
+#[derive(Debug, Parser)]
+/// List the messages in the spool.
+///
+/// Prints the spool id, sender and recipient of each matching
+/// message, or its full metadata when `--json` is used.
+struct ListCommand {
+    #[command(flatten)]
+    spool: SpoolLocation,
+
+    #[command(flatten)]
+    filter: MetaFilter,
+
+    /// Print the full metadata for each message as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+impl ListCommand {
+    async fn run(&self) -> anyhow::Result<()> {
+        let spool = self.spool.open(None)?;
+        enumerate_meta(&spool, |id, data, summary| {
+            if !self.filter.matches(&summary) {
+                return Ok(());
+            }
+            if self.json {
+                let meta: serde_json::Value = serde_json::from_slice(&data)?;
+                println!("{}", serde_json::json!({"id": id, "meta": meta}));
+            } else {
+                println!("{id} {} {}", summary.sender, summary.recipient);
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+/// Export messages from the spool into a portable archive.
+///
+/// The archive holds both the metadata and the message data for
+/// each matching message, and can be loaded into a spool of any
+/// kind on any host via `kumo-spool import`.
+///
+/// Messages are not removed from the spool unless `--remove`
+/// is used.
+struct ExportCommand {
+    #[command(flatten)]
+    spool: SpoolLocation,
+
+    #[command(flatten)]
+    filter: MetaFilter,
+
+    /// Remove each message from the spool after it has
+    /// been written to the archive
+    #[arg(long)]
+    remove: bool,
+
+    /// The archive file to create
+    #[arg(long)]
+    output: PathBuf,
+}
+
+impl ExportCommand {
+    async fn run(&self) -> anyhow::Result<()> {
+        let spool = self.spool.open(None)?;
+
+        let mut matched = vec![];
+        let corrupt = enumerate_meta(&spool, |id, data, summary| {
+            if self.filter.matches(&summary) {
+                matched.push((id, data));
+            }
+            Ok(())
+        })
+        .await?;
+
+        let file = std::fs::File::create(&self.output)
+            .with_context(|| format!("creating {}", self.output.display()))?;
+        let mut writer = ArchiveWriter::new(std::io::BufWriter::new(file))?;
+        let mut exported = vec![];
+        let mut missing_data = 0;
+
+        for (id, meta) in matched {
+            let data = match spool.data.load(id).await {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("{id}: failed to load data: {err:#}");
+                    missing_data += 1;
+                    continue;
+                }
+            };
+            writer.write_entry(&ArchiveEntry {
+                id,
+                meta: serde_json::from_slice(&meta)?,
+                data,
+            })?;
+            exported.push(id);
+        }
+        writer.finish()?;
+
+        if self.remove {
+            for &id in &exported {
+                spool.data.remove(id).await?;
+                spool.meta.remove(id).await?;
+            }
+        }
+
+        eprintln!(
+            "Exported {} messages to {}. \
+             Skipped {corrupt} corrupt and {missing_data} incomplete entries.",
+            exported.len(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+/// Import messages from an archive produced by `kumo-spool export`.
+///
+/// Messages retain their original spool ids. If a message with the
+/// same id already exists in the destination spool, it will be
+/// replaced.
+struct ImportCommand {
+    #[command(flatten)]
+    spool: SpoolLocation,
+
+    /// If specified, message data at least this many bytes in
+    /// size will be zstd compressed when stored in the data spool.
+    /// This corresponds to the `compression.threshold` parameter
+    /// of `kumo.define_spool`.
+    #[arg(long)]
+    compress_threshold: Option<usize>,
+
+    /// The zstd compression level to use with `--compress-threshold`
+    #[arg(long, default_value = "3")]
+    compress_level: i32,
+
+    /// The archive file to import
+    #[arg(long)]
+    input: PathBuf,
+}
+
+impl ImportCommand {
+    async fn run(&self) -> anyhow::Result<()> {
+        let compression = self.compress_threshold.map(|threshold| CompressionParams {
+            threshold,
+            level: self.compress_level,
+        });
+        let spool = self.spool.open(compression)?;
+
+        let file = std::fs::File::open(&self.input)
+            .with_context(|| format!("opening {}", self.input.display()))?;
+        let reader = ArchiveReader::new(std::io::BufReader::new(file))?;
+
+        let mut imported = 0;
+        for entry in reader {
+            let entry = entry?;
+            let id = entry.id;
+            let meta = serde_json::to_vec(&entry.meta)?;
+            // Store the data first, so that we never have meta without
+            // its corresponding data in the event of a failure part way
+            spool
+                .data
+                .store(id, Arc::new(entry.data.into_boxed_slice()), false)
+                .await
+                .with_context(|| format!("storing data for {id}"))?;
+            spool
+                .meta
+                .store(id, Arc::new(meta.into_boxed_slice()), false)
+                .await
+                .with_context(|| format!("storing meta for {id}"))?;
+            imported += 1;
+        }
+
+        eprintln!("Imported {imported} messages from {}", self.input.display());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+/// Verify the integrity of the spool.
+///
+/// Reports meta entries that cannot be read or parsed, meta
+/// entries that have no corresponding data, and data entries
+/// that have no corresponding meta.
+///
+/// Exits with a non-zero status if any problems were found.
+struct VerifyCommand {
+    #[command(flatten)]
+    spool: SpoolLocation,
+}
+
+impl VerifyCommand {
+    async fn run(&self) -> anyhow::Result<()> {
+        let spool = self.spool.open(None)?;
+
+        let mut meta_ids = HashSet::new();
+        let corrupt_meta = enumerate_meta(&spool, |id, _data, _summary| {
+            meta_ids.insert(id);
+            Ok(())
+        })
+        .await?;
+
+        let mut data_ids = HashSet::new();
+        let mut corrupt_data = 0;
+        let (tx, rx) = flume::bounded(1024);
+        spool.data.enumerate(tx)?;
+        while let Ok(entry) = rx.recv_async().await {
+            match entry {
+                SpoolEntry::Item { id, .. } => {
+                    data_ids.insert(id);
+                }
+                SpoolEntry::Corrupt { id, error } => {
+                    eprintln!("{id}: corrupt data: {error}");
+                    data_ids.insert(id);
+                    corrupt_data += 1;
+                }
+            }
+        }
+
+        let mut missing_data = 0;
+        for id in meta_ids.difference(&data_ids) {
+            eprintln!("{id}: has meta but no data");
+            missing_data += 1;
+        }
+        let mut missing_meta = 0;
+        for id in data_ids.difference(&meta_ids) {
+            eprintln!("{id}: has data but no meta");
+            missing_meta += 1;
+        }
+
+        println!(
+            "{} messages. {corrupt_meta} corrupt meta, {corrupt_data} corrupt data, \
+             {missing_data} missing data, {missing_meta} missing meta.",
+            meta_ids.len()
+        );
+
+        if corrupt_meta + corrupt_data + missing_data + missing_meta > 0 {
+            std::process::exit(1);
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts = Opt::parse();
+    opts.cmd.run().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summary(recipient: &str, meta: serde_json::Value) -> MetaSummary {
+        MetaSummary {
+            sender: "sender@example.com".to_string(),
+            recipient: recipient.to_string(),
+            meta: meta.as_object().cloned().unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn spool_kinds() {
+        let opts = Opt::try_parse_from([
+            "kumo-spool",
+            "verify",
+            "--meta-path",
+            "/var/spool/kumomta/meta",
+            "--data-path",
+            "/var/spool/kumomta/data",
+            "--meta-kind",
+            "RocksDB",
+        ])
+        .unwrap();
+        let spool = match &opts.cmd {
+            SubCommand::Verify(cmd) => &cmd.spool,
+            cmd => panic!("unexpected {cmd:?}"),
+        };
+        assert!(matches!(spool.meta_kind, SpoolKind::RocksDB));
+        assert!(matches!(spool.data_kind, SpoolKind::LocalDisk));
+    }
+
+    #[test]
+    fn meta_filter() {
+        let filter = MetaFilter {
+            domain: Some("example.com".to_string()),
+            filter: vec![("tenant".to_string(), "mytenant".to_string())],
+        };
+
+        assert!(filter.matches(&summary(
+            "user@Example.com",
+            serde_json::json!({"tenant": "mytenant"})
+        )));
+        assert!(!filter.matches(&summary(
+            "user@example.org",
+            serde_json::json!({"tenant": "mytenant"})
+        )));
+        assert!(!filter.matches(&summary(
+            "user@example.com",
+            serde_json::json!({"tenant": "other"})
+        )));
+        assert!(!filter.matches(&summary("user@example.com", serde_json::json!({}))));
+
+        let filter = MetaFilter {
+            domain: None,
+            filter: vec![("priority".to_string(), "5".to_string())],
+        };
+        assert!(filter.matches(&summary(
+            "user@example.com",
+            serde_json::json!({"priority": 5})
+        )));
+    }
+}
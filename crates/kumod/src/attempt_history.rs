@@ -0,0 +1,245 @@
+//! This module maintains a bounded, in-memory history of the delivery
+//! attempts made for each message, so that the inspect-message API
+//! can explain why a message is still queued.
+//!
+//! Entries are keyed by spool id and are aged out in LRU order, or
+//! when their ttl expires, so the history is best effort: it does not
+//! survive a restart, and may not cover messages that have been
+//! queued for a very long time.
+use chrono::Utc;
+use kumo_api_types::{MessageDeliveryAttempt, MessageThrottle};
+use kumo_log_types::{MaybeProxiedSourceAddress, RecordType, ResolvedAddress};
+use lruttl::LruCacheWithTtl;
+use message::Message;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::Deserialize;
+use spool::SpoolId;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+static HISTORY: Lazy<Mutex<Arc<AttemptHistory>>> = Lazy::new(|| {
+    Mutex::new(Arc::new(AttemptHistory::new(
+        AttemptHistoryParams::default(),
+    )))
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AttemptHistoryParams {
+    /// The maximum number of messages to remember.
+    /// Set to 0 to disable the history.
+    #[serde(default = "AttemptHistoryParams::default_capacity")]
+    pub capacity: usize,
+
+    /// The maximum number of attempts to remember per message.
+    /// Older attempts are discarded.
+    #[serde(default = "AttemptHistoryParams::default_max_attempts")]
+    pub max_attempts: usize,
+
+    /// How long to remember a message, starting from the first time
+    /// that it was attempted or throttled
+    #[serde(default = "AttemptHistoryParams::default_ttl", with = "duration_serde")]
+    pub ttl: Duration,
+}
+
+impl AttemptHistoryParams {
+    fn default_capacity() -> usize {
+        100_000
+    }
+
+    fn default_max_attempts() -> usize {
+        16
+    }
+
+    fn default_ttl() -> Duration {
+        Duration::from_secs(7 * 86400)
+    }
+}
+
+impl Default for AttemptHistoryParams {
+    fn default() -> Self {
+        Self {
+            capacity: Self::default_capacity(),
+            max_attempts: Self::default_max_attempts(),
+            ttl: Self::default_ttl(),
+        }
+    }
+}
+
+/// What we remember about a message
+#[derive(Default, Debug)]
+struct MessageHistory {
+    attempts: VecDeque<MessageDeliveryAttempt>,
+    /// The throttle that most recently delayed the message.
+    /// Cleared by the next attempt.
+    throttle: Option<MessageThrottle>,
+}
+
+struct AttemptHistory {
+    params: AttemptHistoryParams,
+    index: LruCacheWithTtl<SpoolId, Arc<Mutex<MessageHistory>>>,
+}
+
+impl AttemptHistory {
+    fn new(params: AttemptHistoryParams) -> Self {
+        Self {
+            index: LruCacheWithTtl::new(params.capacity),
+            params,
+        }
+    }
+
+    fn update<F: FnOnce(&mut MessageHistory)>(&self, id: SpoolId, func: F) {
+        if self.params.capacity == 0 {
+            return;
+        }
+        let entry = self
+            .index
+            .get_or_insert(id, self.params.ttl, Default::default);
+        func(&mut entry.lock());
+    }
+
+    fn record_attempt(&self, id: SpoolId, attempt: MessageDeliveryAttempt) {
+        let max_attempts = self.params.max_attempts;
+        self.update(id, |history| {
+            history.throttle.take();
+            if max_attempts == 0 {
+                return;
+            }
+            while history.attempts.len() >= max_attempts {
+                history.attempts.pop_front();
+            }
+            history.attempts.push_back(attempt);
+        });
+    }
+}
+
+/// Replace the history with one using the provided parameters.
+/// Any existing history is discarded.
+pub fn configure(params: AttemptHistoryParams) {
+    *HISTORY.lock() = Arc::new(AttemptHistory::new(params));
+}
+
+fn get() -> Arc<AttemptHistory> {
+    HISTORY.lock().clone()
+}
+
+/// Returns true if the record type represents the outcome of
+/// an attempt to deliver the message
+fn is_attempt(kind: RecordType) -> bool {
+    matches!(
+        kind,
+        RecordType::Delivery | RecordType::TransientFailure | RecordType::Bounce
+    )
+}
+
+/// Called by log_disposition to record the outcome of an attempt
+#[allow(clippy::too_many_arguments)]
+pub fn record_attempt(
+    kind: RecordType,
+    msg: &Message,
+    site: &str,
+    peer_address: Option<&ResolvedAddress>,
+    response: &Response,
+    egress_pool: Option<&str>,
+    egress_source: Option<&str>,
+    source_address: Option<&MaybeProxiedSourceAddress>,
+) {
+    if !is_attempt(kind) {
+        return;
+    }
+    get().record_attempt(
+        *msg.id(),
+        MessageDeliveryAttempt {
+            timestamp: Utc::now(),
+            kind: format!("{kind:?}"),
+            site: site.to_string(),
+            mx: peer_address.map(|p| p.name.clone()),
+            ip: peer_address.map(|p| p.addr.to_string()),
+            egress_pool: egress_pool.map(|s| s.to_string()),
+            egress_source: egress_source.map(|s| s.to_string()),
+            source_address: source_address.map(|s| s.address.to_string()),
+            response: response.to_single_line(),
+        },
+    );
+}
+
+/// Called when a throttle delays the message without
+/// making a delivery attempt
+pub fn record_throttle(msg: &Message, reason: String) {
+    get().update(*msg.id(), |history| {
+        history.throttle.replace(MessageThrottle {
+            reason,
+            timestamp: Utc::now(),
+        });
+    });
+}
+
+/// Returns the attempts, oldest first, and the current throttle for
+/// the specified message
+pub fn lookup(id: &SpoolId) -> (Vec<MessageDeliveryAttempt>, Option<MessageThrottle>) {
+    match get().index.get(id) {
+        Some(entry) => {
+            let history = entry.lock();
+            (
+                history.attempts.iter().cloned().collect(),
+                history.throttle.clone(),
+            )
+        }
+        None => (vec![], None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn attempt(n: usize) -> MessageDeliveryAttempt {
+        MessageDeliveryAttempt {
+            timestamp: Utc::now(),
+            kind: "TransientFailure".to_string(),
+            site: "example.com".to_string(),
+            mx: None,
+            ip: None,
+            egress_pool: None,
+            egress_source: None,
+            source_address: None,
+            response: format!("451 attempt {n}"),
+        }
+    }
+
+    #[test]
+    fn bounded_attempts() {
+        let history = AttemptHistory::new(AttemptHistoryParams {
+            max_attempts: 3,
+            ..Default::default()
+        });
+        let id = SpoolId::new();
+        for n in 0..5 {
+            history.update(id, |h| {
+                h.throttle.replace(MessageThrottle {
+                    reason: "test".to_string(),
+                    timestamp: Utc::now(),
+                });
+            });
+            history.record_attempt(id, attempt(n));
+        }
+        let entry = history.index.get(&id).unwrap();
+        let entry = entry.lock();
+        let responses: Vec<&str> = entry.attempts.iter().map(|a| a.response.as_str()).collect();
+        assert_eq!(
+            responses,
+            vec!["451 attempt 2", "451 attempt 3", "451 attempt 4"]
+        );
+        assert!(entry.throttle.is_none());
+
+        let disabled = AttemptHistory::new(AttemptHistoryParams {
+            capacity: 0,
+            ..Default::default()
+        });
+        disabled.record_attempt(id, attempt(0));
+        assert!(disabled.index.get(&id).is_none());
+    }
+}
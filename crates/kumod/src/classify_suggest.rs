@@ -0,0 +1,184 @@
+//! Tracks the most frequently observed responses that were not matched
+//! by any bounce classifier rule, so that they can be used to propose
+//! new rules.
+//!
+//! Responses are normalized into templates and counted in a bounded
+//! store. When the store is full, the least frequently observed template
+//! is evicted to make room for the new one, which inherits its count.
+//! This keeps the frequently occurring templates in the store, at the
+//! cost of over-estimating the counts of newer entries.
+use bounce_classify::suggest::{normalize_response, suggest_regex};
+use chrono::{DateTime, Utc};
+use kumo_api_types::bounce_classify::BounceClassifySuggestion;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use std::collections::HashMap;
+
+/// The default maximum number of distinct templates to track
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+static STORE: Lazy<Mutex<SuggestionStore>> =
+    Lazy::new(|| Mutex::new(SuggestionStore::new(DEFAULT_CAPACITY)));
+
+struct Entry {
+    count: u64,
+    example: String,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+struct SuggestionStore {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+}
+
+impl SuggestionStore {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.evict_least_frequent();
+        }
+    }
+
+    /// Remove the entry with the lowest count, returning that count
+    fn evict_least_frequent(&mut self) -> u64 {
+        let victim = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| (entry.count, entry.last_seen))
+            .map(|(template, entry)| (template.clone(), entry.count));
+        match victim {
+            Some((template, count)) => {
+                self.entries.remove(&template);
+                count
+            }
+            None => 0,
+        }
+    }
+
+    /// Record an occurrence of `line`, whose normalized form is `template`
+    fn record(&mut self, template: String, line: &str, now: DateTime<Utc>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(entry) = self.entries.get_mut(&template) {
+            entry.count += 1;
+            entry.last_seen = now;
+            entry.example = line.to_string();
+            return;
+        }
+
+        let base_count = if self.entries.len() >= self.capacity {
+            self.evict_least_frequent()
+        } else {
+            0
+        };
+
+        self.entries.insert(
+            template,
+            Entry {
+                count: base_count + 1,
+                example: line.to_string(),
+                first_seen: now,
+                last_seen: now,
+            },
+        );
+    }
+
+    fn suggestions(&self, limit: Option<usize>, min_count: u64) -> Vec<BounceClassifySuggestion> {
+        let mut result: Vec<BounceClassifySuggestion> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.count >= min_count)
+            .map(|(template, entry)| BounceClassifySuggestion {
+                template: template.clone(),
+                regex: suggest_regex(template),
+                count: entry.count,
+                example: entry.example.clone(),
+                first_seen: entry.first_seen,
+                last_seen: entry.last_seen,
+            })
+            .collect();
+        result.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.template.cmp(&b.template))
+        });
+        if let Some(limit) = limit {
+            result.truncate(limit);
+        }
+        result
+    }
+}
+
+/// Set the maximum number of distinct templates to track.
+/// 0 disables tracking.
+pub fn set_capacity(capacity: usize) {
+    STORE.lock().set_capacity(capacity);
+}
+
+/// Record a response that was classified as Uncategorized
+pub fn record_unclassified(line: &str) {
+    // Normalization is relatively expensive, and this is called for
+    // every uncategorized failure, so avoid holding the lock for it
+    let template = normalize_response(line);
+    STORE.lock().record(template, line, Utc::now());
+}
+
+/// Return the most frequently observed unclassified templates
+pub fn get_suggestions(limit: Option<usize>, min_count: u64) -> Vec<BounceClassifySuggestion> {
+    STORE.lock().suggestions(limit, min_count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(store: &mut SuggestionStore, line: &str, now: DateTime<Utc>) {
+        store.record(normalize_response(line), line, now);
+    }
+
+    #[test]
+    fn store() {
+        let mut store = SuggestionStore::new(2);
+        let now = Utc::now();
+
+        record(&mut store, "550 5.1.1 <a@example.com> no such user", now);
+        record(&mut store, "550 5.1.1 <b@example.com> no such user", now);
+        record(&mut store, "550 5.1.1 <c@example.com> no such user", now);
+        record(&mut store, "421 4.7.0 try again in 30 seconds", now);
+
+        let suggestions = store.suggestions(None, 0);
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0].template, "550 5.1.1 <<ADDR>> no such user");
+        assert_eq!(suggestions[0].count, 3);
+        assert_eq!(
+            suggestions[0].example,
+            "550 5.1.1 <c@example.com> no such user"
+        );
+        assert_eq!(suggestions[1].count, 1);
+
+        // The store is full; the least frequent entry is replaced
+        // and the new entry inherits its count
+        record(&mut store, "554 5.7.1 blocked", now);
+        let suggestions = store.suggestions(None, 0);
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[1].template, "554 5.7.1 blocked");
+        assert_eq!(suggestions[1].count, 2);
+
+        assert_eq!(store.suggestions(None, 3).len(), 1);
+        assert_eq!(store.suggestions(Some(1), 0).len(), 1);
+
+        store.set_capacity(0);
+        record(&mut store, "554 5.7.1 blocked", now);
+        assert!(store.suggestions(None, 0).is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! This module correlates incoming OOB bounce reports and ARF feedback
+//! reports with the message that they are reporting on.
+//!
+//! When enabled, a bounded index of recently delivered messages is
+//! maintained, keyed by their Message-ID header and recipient, since
+//! the same Message-ID is delivered to each recipient of a message.
+//! Reports typically include the headers of the original message, so
+//! the Message-ID found there, together with the recipient named by
+//! the report, is used to look up the original, whose meta data can
+//! then be restored onto the OOB/Feedback log record.
+use kumo_log_types::CorrelatedMessage;
+use lruttl::LruCacheWithTtl;
+use mailparsing::{Header, HeaderParseResult};
+use message::Message;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref MATCHED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "report_correlation_matched",
+        "number of OOB/Feedback reports that were correlated with the original message",
+        &["type"]).unwrap();
+    static ref MISSED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "report_correlation_missed",
+        "number of OOB/Feedback reports that could not be correlated with the original message",
+        &["type"]).unwrap();
+}
+
+static CORRELATION: Lazy<Mutex<Option<Arc<Correlation>>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CorrelationParams {
+    /// The maximum number of delivered messages to remember
+    #[serde(default = "CorrelationParams::default_capacity")]
+    pub capacity: usize,
+
+    /// How long to remember a delivered message
+    #[serde(default = "CorrelationParams::default_ttl", with = "duration_serde")]
+    pub ttl: Duration,
+}
+
+impl CorrelationParams {
+    fn default_capacity() -> usize {
+        100_000
+    }
+
+    fn default_ttl() -> Duration {
+        Duration::from_secs(3 * 86400)
+    }
+}
+
+/// What we remember about a delivered message
+#[derive(Clone)]
+pub struct DeliveredMessage {
+    pub info: CorrelatedMessage,
+    pub meta: serde_json::Value,
+}
+
+struct Correlation {
+    ttl: Duration,
+    /// Keyed by (Message-ID, recipient)
+    by_recipient: LruCacheWithTtl<(String, String), Arc<DeliveredMessage>>,
+    /// Keyed by Message-ID, for reports that don't name the recipient.
+    /// The value is None when the Message-ID was delivered to more
+    /// than one recipient, as the report is then ambiguous.
+    by_message_id: LruCacheWithTtl<String, Option<Arc<DeliveredMessage>>>,
+}
+
+/// Enable report correlation. Until this is called, correlation
+/// is disabled.
+pub fn configure(params: CorrelationParams) {
+    CORRELATION.lock().replace(Arc::new(Correlation {
+        ttl: params.ttl,
+        by_recipient: LruCacheWithTtl::new(params.capacity),
+        by_message_id: LruCacheWithTtl::new(params.capacity),
+    }));
+}
+
+fn get() -> Option<Arc<Correlation>> {
+    CORRELATION.lock().clone()
+}
+
+/// Normalize a Message-ID header value for use as a key
+fn normalize_message_id(id: &str) -> Option<String> {
+    let id = id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+/// Normalize a recipient address for use as a key
+fn normalize_recipient(recipient: &str) -> String {
+    recipient
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Extract the Message-ID from the original message headers
+/// included in a report
+fn message_id_from_report(original_message: &str) -> Option<String> {
+    let HeaderParseResult { headers, .. } = Header::parse_headers(original_message).ok()?;
+    let value = headers.get_first("Message-ID")?.as_unstructured().ok()?;
+    normalize_message_id(&value)
+}
+
+/// Called when a message has been delivered, to record it in the index
+pub fn record_delivery(msg: &Message, egress_pool: Option<&str>, egress_source: Option<&str>) {
+    let correlation = match get() {
+        Some(c) => c,
+        None => return,
+    };
+
+    let message_id = match msg
+        .get_first_named_header_value("Message-ID")
+        .ok()
+        .flatten()
+        .and_then(|id| normalize_message_id(&id))
+    {
+        Some(id) => id,
+        None => return,
+    };
+
+    let info = CorrelatedMessage {
+        id: msg.id().to_string(),
+        message_id: message_id.clone(),
+        queue: msg.get_queue_name().unwrap_or_default(),
+        sender: msg
+            .sender()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        recipient: msg
+            .recipient()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        egress_pool: egress_pool.map(|s| s.to_string()),
+        egress_source: egress_source.map(|s| s.to_string()),
+    };
+    let meta = msg.get_meta_obj().unwrap_or(serde_json::Value::Null);
+    correlation.record(DeliveredMessage { info, meta });
+}
+
+impl Correlation {
+    fn record(&self, delivered: DeliveredMessage) {
+        let expiration = Instant::now() + self.ttl;
+        let message_id = delivered.info.message_id.clone();
+        let recipient = normalize_recipient(&delivered.info.recipient);
+        let delivered = Arc::new(delivered);
+
+        let unambiguous = match self.by_message_id.get(&message_id) {
+            Some(Some(prior)) => normalize_recipient(&prior.info.recipient) == recipient,
+            Some(None) => false,
+            None => true,
+        };
+        self.by_message_id.insert(
+            message_id.clone(),
+            if unambiguous {
+                Some(Arc::clone(&delivered))
+            } else {
+                None
+            },
+            expiration,
+        );
+        self.by_recipient
+            .insert((message_id, recipient), delivered, expiration);
+    }
+
+    fn lookup(&self, message_id: &str, recipients: &[&str]) -> Option<Arc<DeliveredMessage>> {
+        if recipients.is_empty() {
+            return self.by_message_id.get(message_id).flatten();
+        }
+        recipients.iter().find_map(|recipient| {
+            self.by_recipient
+                .get(&(message_id.to_string(), normalize_recipient(recipient)))
+        })
+    }
+}
+
+/// Find the original message for a report, given the original message
+/// (or its headers) that was included in the report, and the recipient
+/// addresses that the report names for it.
+/// When the report doesn't name a recipient, a match is made only if
+/// the original message was delivered to a single recipient.
+/// `kind` is used to label the matched/missed metrics.
+pub fn correlate_report(
+    kind: &str,
+    original_message: Option<&str>,
+    recipients: &[&str],
+) -> Option<Arc<DeliveredMessage>> {
+    let correlation = get()?;
+
+    let found = original_message
+        .and_then(message_id_from_report)
+        .and_then(|id| correlation.lookup(&id, recipients));
+
+    if found.is_some() {
+        MATCHED.with_label_values(&[kind]).inc();
+    } else {
+        MISSED.with_label_values(&[kind]).inc();
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_message_id() {
+        assert_eq!(
+            message_id_from_report(
+                "Subject: hello\nMessage-ID: <foo@example.com>\nFrom: someone@example.com\n\nbody\n"
+            ),
+            Some("foo@example.com".to_string())
+        );
+        assert_eq!(
+            message_id_from_report("Subject: hello\nMessage-Id:\n <bar@example.com>\n"),
+            Some("bar@example.com".to_string())
+        );
+        assert_eq!(message_id_from_report("Subject: hello\n\nbody\n"), None);
+        assert_eq!(normalize_message_id("  <>  "), None);
+    }
+
+    fn delivered(id: &str, recipient: &str, tenant: &str) -> DeliveredMessage {
+        DeliveredMessage {
+            info: CorrelatedMessage {
+                id: id.to_string(),
+                message_id: "foo@example.com".to_string(),
+                queue: "example.com".to_string(),
+                sender: "sender@example.net".to_string(),
+                recipient: recipient.to_string(),
+                egress_pool: None,
+                egress_source: None,
+            },
+            meta: serde_json::json!({"tenant": tenant}),
+        }
+    }
+
+    #[test]
+    fn multiple_recipients() {
+        let correlation = Correlation {
+            ttl: Duration::from_secs(60),
+            by_recipient: LruCacheWithTtl::new(16),
+            by_message_id: LruCacheWithTtl::new(16),
+        };
+        correlation.record(delivered("one", "a@example.com", "a"));
+        correlation.record(delivered("two", "b@example.com", "b"));
+
+        let found = correlation
+            .lookup("foo@example.com", &["<A@Example.com>"])
+            .unwrap();
+        assert_eq!(found.info.id, "one");
+        assert_eq!(found.meta["tenant"], "a");
+
+        let found = correlation
+            .lookup("foo@example.com", &["nobody@example.com", "b@example.com"])
+            .unwrap();
+        assert_eq!(found.info.id, "two");
+
+        assert!(correlation
+            .lookup("foo@example.com", &["nobody@example.com"])
+            .is_none());
+
+        // Without a recipient, the report is ambiguous
+        assert!(correlation.lookup("foo@example.com", &[]).is_none());
+
+        let single = Correlation {
+            ttl: Duration::from_secs(60),
+            by_recipient: LruCacheWithTtl::new(16),
+            by_message_id: LruCacheWithTtl::new(16),
+        };
+        single.record(delivered("one", "a@example.com", "a"));
+        assert_eq!(
+            single.lookup("foo@example.com", &[]).unwrap().info.id,
+            "one"
+        );
+    }
+}
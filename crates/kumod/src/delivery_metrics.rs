@@ -6,6 +6,10 @@ pub struct DeliveryMetrics {
     global_connection_gauge: IntGauge,
     connection_total: IntCounter,
     global_connection_total: IntCounter,
+    connection_reuse: IntCounter,
+    global_connection_reuse: IntCounter,
+    connection_linger_reuse: IntCounter,
+    global_connection_linger_reuse: IntCounter,
 
     pub ready_count: IntGauge,
     pub ready_full: IntCounter,
@@ -43,6 +47,15 @@ impl DeliveryMetrics {
             global_connection_total: crate::metrics_helper::connection_total_for_service(
                 service_type,
             ),
+            connection_reuse: crate::metrics_helper::connection_reuse_for_service(&service),
+            global_connection_reuse: crate::metrics_helper::connection_reuse_for_service(
+                service_type,
+            ),
+            connection_linger_reuse: crate::metrics_helper::connection_linger_reuse_for_service(
+                &service,
+            ),
+            global_connection_linger_reuse:
+                crate::metrics_helper::connection_linger_reuse_for_service(service_type),
             ready_full: crate::metrics_helper::ready_full_counter_for_service(&service),
             ready_count: crate::metrics_helper::ready_count_gauge_for_service(&service),
             msgs_delivered: crate::metrics_helper::total_msgs_delivered_for_service(&service),
@@ -58,6 +71,16 @@ impl DeliveryMetrics {
         }
     }
 
+    pub fn inc_connection_reuse(&self) {
+        self.connection_reuse.inc();
+        self.global_connection_reuse.inc();
+    }
+
+    pub fn inc_connection_linger_reuse(&self) {
+        self.connection_linger_reuse.inc();
+        self.global_connection_linger_reuse.inc();
+    }
+
     pub fn inc_transfail(&self) {
         self.msgs_transfail.inc();
         self.global_msgs_transfail.inc();
@@ -1,6 +1,7 @@
 use crate::http_server::admin_suspend_ready_q_v1::AdminSuspendReadyQEntry;
 use crate::queue::QueueConfig;
 use crate::ready_queue::{ReadyQueueManager, ReadyQueueName};
+use crate::warmup::{WarmupDecision, WarmupPlan};
 use anyhow::Context;
 use config::{CallbackSignature, LuaConfig};
 use data_loader::KeySource;
@@ -8,12 +9,14 @@ use gcd::Gcd;
 use kumo_log_types::MaybeProxiedSourceAddress;
 use kumo_server_common::config_handle::ConfigHandle;
 use lruttl::LruCacheWithTtl;
+use message::message::QueueNameComponents;
 use mlua::prelude::LuaUserData;
 use parking_lot::FairMutex as Mutex;
 use serde::{Deserialize, Serialize};
 use socksv5::v5::{
     SocksV5AuthMethod, SocksV5Command, SocksV5Host, SocksV5RequestStatus, SocksV5Response,
 };
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -62,6 +65,10 @@ pub struct EgressSource {
 
     #[serde(default = "default_ttl", with = "duration_serde")]
     pub ttl: Duration,
+
+    /// A plan to gradually increase the volume sent from this source
+    #[serde(default)]
+    pub warmup: Option<WarmupPlan>,
 }
 
 impl LuaUserData for EgressSource {}
@@ -85,6 +92,7 @@ impl EgressSource {
                 socks5_proxy_username: None,
                 socks5_proxy_password: None,
                 source_address: None,
+                warmup: None,
             }
         } else {
             let sig = CallbackSignature::<String, EgressSource>::new("get_egress_source");
@@ -295,6 +303,8 @@ impl EgressPool {
 pub struct EgressPoolRoundRobin {
     pub name: String,
     entries: Vec<EgressPoolEntry>,
+    /// The warm-up plans of the sources that have them
+    warmup: HashMap<String, WarmupPlan>,
 
     index_and_weight: Mutex<IndexAndWeight>,
 }
@@ -309,8 +319,8 @@ struct IndexAndWeight {
 pub enum RoundRobinResult {
     /// Use the source with this name
     Source(String),
-    /// All pathways are suspended. The smallest time until one
-    /// of them is enabled is this delay
+    /// All pathways are suspended or have exhausted their warm-up
+    /// plan. The smallest time until one of them is enabled is this delay
     Delay(chrono::Duration),
     /// No sources are configured, or all sources have zero weight
     NoSources,
@@ -330,6 +340,7 @@ impl EgressPoolRoundRobin {
         Self {
             name: pool.name.to_string(),
             entries,
+            warmup: HashMap::new(),
             index_and_weight: Mutex::new(IndexAndWeight {
                 current_index: 0,
                 current_weight: 0,
@@ -337,6 +348,19 @@ impl EgressPoolRoundRobin {
         }
     }
 
+    /// Create the round robin for the pool, taking the warm-up
+    /// plans from the current definitions of its sources
+    pub async fn resolve(pool: &EgressPool, config: &mut LuaConfig) -> anyhow::Result<Self> {
+        let mut rr = Self::new(pool);
+        for entry in &pool.entries {
+            let source = EgressSource::resolve(&entry.name, config).await?;
+            if let Some(plan) = source.warmup {
+                rr.warmup.insert(entry.name.to_string(), plan);
+            }
+        }
+        Ok(rr)
+    }
+
     #[cfg(test)]
     fn next_ignoring_suspend(&self) -> Option<String> {
         let entries = self.entries.clone();
@@ -392,12 +416,15 @@ impl EgressPoolRoundRobin {
         }
 
         let mut entries = vec![];
+        let mut site_names = HashMap::new();
         let mut min_delay = None;
 
         // filter to non-suspended pathways
         for entry in &self.entries {
             if let Ok(ReadyQueueName {
-                name: path_name, ..
+                name: path_name,
+                site_name,
+                ..
             }) =
                 ReadyQueueManager::compute_queue_name(queue_name, queue_config, &entry.name).await
             {
@@ -407,6 +434,7 @@ impl EgressPoolRoundRobin {
                         min_delay.replace(min_delay.unwrap_or(duration).min(duration));
                     }
                     None => {
+                        site_names.insert(entry.name.as_str(), site_name);
                         entries.push(entry.clone());
                     }
                 }
@@ -424,12 +452,41 @@ impl EgressPoolRoundRobin {
             }
         }
 
-        match self.next_impl(&entries) {
-            Some(name) => RoundRobinResult::Source(name),
-            None => match min_delay {
-                Some(duration) => RoundRobinResult::Delay(duration),
-                None => RoundRobinResult::NoSources,
-            },
+        let domain = QueueNameComponents::parse(queue_name).domain;
+
+        loop {
+            let name = match self.next_impl(&entries) {
+                Some(name) => name,
+                None => {
+                    return match min_delay {
+                        Some(duration) => RoundRobinResult::Delay(duration),
+                        None => RoundRobinResult::NoSources,
+                    }
+                }
+            };
+
+            let plan = match self.warmup.get(&name) {
+                Some(plan) => plan,
+                None => return RoundRobinResult::Source(name),
+            };
+
+            let site_name = site_names
+                .get(name.as_str())
+                .map(|s| s.as_str())
+                .unwrap_or("");
+            let delay = match plan.admit(&name, domain, site_name).await {
+                Ok(WarmupDecision::Proceed) => return RoundRobinResult::Source(name),
+                Ok(WarmupDecision::Exhausted(delay)) => delay,
+                Err(err) => {
+                    tracing::error!("error checking warm-up plan for source {name}: {err:#}");
+                    Duration::from_secs(60)
+                }
+            };
+
+            // Spill over to the other sources in the pool
+            let delay = chrono::Duration::from_std(delay).unwrap_or(kumo_chrono_helper::MINUTE);
+            min_delay.replace(min_delay.unwrap_or(delay).min(delay));
+            entries.retain(|entry| entry.name != name);
         }
     }
 }
@@ -474,6 +531,77 @@ mod test {
         assert_eq!(counts["two"], 20, "two");
         assert_eq!(counts["three"], 30, "three");
     }
+
+    fn warmup_plan(per_day: u64) -> WarmupPlan {
+        WarmupPlan {
+            start_date: chrono::Utc::now().date_naive(),
+            schedule: vec![crate::warmup::WarmupStep {
+                day: 1,
+                per_day: Some(per_day),
+                per_hour: None,
+            }],
+            providers: Default::default(),
+        }
+    }
+
+    fn maildir_queue_config() -> ConfigHandle<QueueConfig> {
+        ConfigHandle::new(QueueConfig {
+            protocol: crate::queue::DeliveryProto::Maildir {
+                maildir_path: "/tmp".into(),
+            },
+            ..Default::default()
+        })
+    }
+
+    fn pool_of(names: &[&str]) -> EgressPool {
+        EgressPool {
+            name: "pool".to_string(),
+            entries: names
+                .iter()
+                .map(|name| EgressPoolEntry {
+                    name: name.to_string(),
+                    weight: 1,
+                })
+                .collect(),
+            ttl: default_ttl(),
+        }
+    }
+
+    #[tokio::test]
+    async fn warmup_spillover() {
+        let queue_config = maildir_queue_config();
+
+        // Only one message is permitted from the warming source;
+        // the rest spill over to the other member of the pool
+        let mut rr = EgressPoolRoundRobin::new(&pool_of(&["spill-warm", "spill-other"]));
+        rr.warmup.insert("spill-warm".to_string(), warmup_plan(1));
+
+        let mut counts = HashMap::new();
+        for _ in 0..10 {
+            match rr.next("example.com", &queue_config).await {
+                RoundRobinResult::Source(name) => *counts.entry(name).or_insert(0) += 1,
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(counts["spill-warm"], 1);
+        assert_eq!(counts["spill-other"], 9);
+
+        // When every member is exhausted, the message is delayed
+        let mut rr = EgressPoolRoundRobin::new(&pool_of(&["spill-a", "spill-b"]));
+        rr.warmup.insert("spill-a".to_string(), warmup_plan(1));
+        rr.warmup.insert("spill-b".to_string(), warmup_plan(1));
+
+        for _ in 0..2 {
+            assert!(matches!(
+                rr.next("example.com", &queue_config).await,
+                RoundRobinResult::Source(_)
+            ));
+        }
+        match rr.next("example.com", &queue_config).await {
+            RoundRobinResult::Delay(delay) => assert!(delay > chrono::Duration::zero()),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
 }
 
 #[derive(Debug)]
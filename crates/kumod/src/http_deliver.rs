@@ -0,0 +1,507 @@
+//! This module implements the `http` delivery protocol, which delivers
+//! messages to an HTTP(S) endpoint using POST requests, optionally
+//! batching several messages into a single request.
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::logging::{log_disposition, LogDisposition};
+use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use crate::spool::SpoolManager;
+use anyhow::Context;
+use async_trait::async_trait;
+use data_encoding::{BASE64, HEXLOWER};
+use data_loader::KeySource;
+use kumo_log_types::{RecordType, ResolvedAddress};
+use kumo_server_runtime::spawn_local;
+use message::message::QueueNameComponents;
+use message::Message;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The maximum number of bytes of the HTTP response body that
+/// will be included in the logged response
+const MAX_RESPONSE_CONTENT: usize = 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum HttpBodyFormat {
+    /// The message data is sent as-is, with a `message/rfc822`
+    /// content type
+    Rfc5322,
+    /// A JSON object holding the envelope, metadata and message data
+    Json,
+    /// The message data is a JSON log record, such as those produced
+    /// by log hooks, and is sent as-is
+    LogRecord,
+}
+
+impl Default for HttpBodyFormat {
+    fn default() -> Self {
+        Self::Rfc5322
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum HttpAuth {
+    Basic {
+        username: String,
+        #[serde(default)]
+        password: Option<KeySource>,
+    },
+    Bearer {
+        token: KeySource,
+    },
+    /// Sign the request body using HMAC-SHA256 and pass the
+    /// signature in the specified header
+    HmacSignature {
+        key: KeySource,
+        #[serde(default = "HttpAuth::default_signature_header")]
+        header: String,
+    },
+}
+
+impl HttpAuth {
+    fn default_signature_header() -> String {
+        "X-Signature".to_string()
+    }
+
+    pub(crate) async fn resolve(&self) -> anyhow::Result<ResolvedAuth> {
+        Ok(match self {
+            Self::Basic { username, password } => ResolvedAuth::Basic {
+                username: username.to_string(),
+                password: match password {
+                    Some(password) => Some(String::from_utf8(
+                        password.get().await.context("fetching password")?,
+                    )?),
+                    None => None,
+                },
+            },
+            Self::Bearer { token } => ResolvedAuth::Bearer(String::from_utf8(
+                token.get().await.context("fetching token")?,
+            )?),
+            Self::HmacSignature { key, header } => ResolvedAuth::HmacSignature {
+                key: key.get().await.context("fetching hmac key")?,
+                header: header.to_string(),
+            },
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpDeliveryProtocol {
+    /// The URL to which requests will be posted. It is a template
+    /// that can reference the domain, routing_domain, tenant and
+    /// campaign of the queue
+    pub url: String,
+
+    #[serde(default)]
+    pub auth: Option<HttpAuth>,
+
+    #[serde(default)]
+    pub body_format: HttpBodyFormat,
+
+    /// The maximum number of messages to include in a single request
+    #[serde(default = "HttpDeliveryProtocol::default_batch_size")]
+    pub batch_size: usize,
+
+    /// Additional headers to include in each request
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    #[serde(
+        default = "HttpDeliveryProtocol::default_timeout",
+        with = "duration_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl HttpDeliveryProtocol {
+    fn default_batch_size() -> usize {
+        1
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.batch_size > 0, "batch_size must be larger than zero");
+        anyhow::ensure!(
+            self.batch_size == 1 || self.body_format != HttpBodyFormat::Rfc5322,
+            "batch_size must be 1 when using body_format Rfc5322"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ResolvedAuth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+    HmacSignature {
+        key: Vec<u8>,
+        header: String,
+    },
+}
+
+#[derive(Debug)]
+struct HttpConnection {
+    client: reqwest::Client,
+    url: String,
+    auth: Option<ResolvedAuth>,
+}
+
+#[derive(Debug)]
+pub struct HttpQueueDispatcher {
+    proto_config: HttpDeliveryProtocol,
+    connection: Option<MetricsWrappedConnection<HttpConnection>>,
+    peer_address: ResolvedAddress,
+}
+
+impl HttpQueueDispatcher {
+    pub fn new(proto_config: HttpDeliveryProtocol) -> anyhow::Result<Self> {
+        proto_config.validate()?;
+        let peer_address = ResolvedAddress {
+            name: format!("HTTP via {}", proto_config.url),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Ok(Self {
+            proto_config,
+            connection: None,
+            peer_address,
+        })
+    }
+
+    fn build_body(&self, batch: &[Message]) -> anyhow::Result<(Vec<u8>, &'static str)> {
+        match self.proto_config.body_format {
+            HttpBodyFormat::Rfc5322 => Ok((batch[0].get_data().to_vec(), "message/rfc822")),
+            HttpBodyFormat::Json => {
+                let mut items = vec![];
+                for msg in batch {
+                    items.push(serde_json::json!({
+                        "id": msg.id().to_string(),
+                        "sender": msg.sender()?.to_string(),
+                        "recipient": msg.recipient()?.to_string(),
+                        "meta": msg.get_meta_obj()?,
+                        // The message may be 8-bit or binary, so it is
+                        // base64 encoded rather than risk corrupting it
+                        "data": BASE64.encode(&msg.get_data()),
+                    }));
+                }
+                Ok((self.encode_json(items)?, "application/json"))
+            }
+            HttpBodyFormat::LogRecord => {
+                let mut items = vec![];
+                for msg in batch {
+                    let record: serde_json::Value = serde_json::from_slice(&msg.get_data())
+                        .with_context(|| format!("parsing log record from {}", msg.id()))?;
+                    items.push(record);
+                }
+                Ok((self.encode_json(items)?, "application/json"))
+            }
+        }
+    }
+
+    /// When batching is not enabled, the body is a single JSON value,
+    /// otherwise it is an array of values
+    fn encode_json(&self, mut items: Vec<serde_json::Value>) -> anyhow::Result<Vec<u8>> {
+        if self.proto_config.batch_size == 1 {
+            Ok(serde_json::to_vec(&items.remove(0))?)
+        } else {
+            Ok(serde_json::to_vec(&items)?)
+        }
+    }
+
+    async fn send_batch(&self, batch: &[Message]) -> anyhow::Result<(RecordType, Response)> {
+        let connection = self.connection.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("connection is not set in HttpQueueDispatcher::send_batch!?")
+        })?;
+
+        for msg in batch {
+            msg.load_meta_if_needed().await.context("loading meta")?;
+            msg.load_data_if_needed().await.context("loading data")?;
+        }
+        let (body, content_type) = self.build_body(batch)?;
+
+        let mut request = connection
+            .client
+            .post(&connection.url)
+            .header("Content-Type", content_type);
+        for (name, value) in &self.proto_config.headers {
+            request = request.header(name, value);
+        }
+        if let Some(auth) = &connection.auth {
+            request = auth.apply(request, &body)?;
+        }
+
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let kind = classify_status(status.as_u16());
+
+        Ok((kind, make_response(kind, status, &text)))
+    }
+}
+
+impl ResolvedAuth {
+    /// Add the authentication to a request that will send `body`
+    pub(crate) fn apply(
+        &self,
+        request: reqwest::RequestBuilder,
+        body: &[u8],
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        Ok(match self {
+            Self::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+            Self::Bearer(token) => request.bearer_auth(token),
+            Self::HmacSignature { key, header } => request.header(header, sign_body(key, body)?),
+        })
+    }
+}
+
+/// Compute the HMAC-SHA256 signature of the body, in the form `sha256=HEX`
+fn sign_body(key: &[u8], body: &[u8]) -> anyhow::Result<String> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    signer.update(body)?;
+    Ok(format!(
+        "sha256={}",
+        HEXLOWER.encode(&signer.sign_to_vec()?)
+    ))
+}
+
+/// Map an HTTP status code to the disposition of the message(s)
+/// that were part of the request
+fn classify_status(status: u16) -> RecordType {
+    match status {
+        200..=299 => RecordType::Delivery,
+        408 | 429 | 500..=599 => RecordType::TransientFailure,
+        _ => RecordType::Bounce,
+    }
+}
+
+/// Produce an SMTP style response for the log record. The code
+/// is chosen to reflect the disposition so that the normal transient
+/// and permanent failure handling applies, and the HTTP status is
+/// preserved in the content.
+fn make_response(kind: RecordType, status: reqwest::StatusCode, text: &str) -> Response {
+    let code = match kind {
+        RecordType::Delivery => 250,
+        RecordType::TransientFailure => 451,
+        _ => 554,
+    };
+    let mut text = text.trim();
+    if text.len() > MAX_RESPONSE_CONTENT {
+        let mut end = MAX_RESPONSE_CONTENT;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text = &text[..end];
+    }
+    Response {
+        code,
+        enhanced_code: None,
+        content: format!("HTTP {status} {text}").trim().to_string(),
+        command: None,
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for HttpQueueDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        Ok(self.connection.take().is_some())
+    }
+
+    async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        let connection_wrapper = dispatcher.metrics.wrap_connection(());
+
+        // As with the LuaQueueDispatcher, the url is computed based on
+        // the queue that caused this ready queue to be created.
+        let components =
+            QueueNameComponents::parse(&dispatcher.queue_name_for_config_change_purposes_only);
+        let url = minijinja::Environment::new()
+            .render_str(
+                &self.proto_config.url,
+                minijinja::context! {
+                    domain => components.domain,
+                    routing_domain => components.routing_domain.unwrap_or(components.domain),
+                    tenant => components.tenant,
+                    campaign => components.campaign,
+                },
+            )
+            .context("rendering url template")?;
+
+        let auth = match &self.proto_config.auth {
+            Some(auth) => Some(auth.resolve().await?),
+            None => None,
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(self.proto_config.timeout)
+            .build()?;
+
+        self.connection
+            .replace(connection_wrapper.map_connection(HttpConnection { client, url, auth }));
+        dispatcher.delivered_this_connection = 0;
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        let mut batch = vec![msg];
+        while batch.len() < self.proto_config.batch_size {
+            match dispatcher.pop_ready_message_for_batch().await {
+                Ok(Some(msg)) => batch.push(msg),
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::debug!("while filling batch for {}: {err:#}", dispatcher.name);
+                    break;
+                }
+            }
+        }
+        dispatcher.delivered_this_connection += batch.len() - 1;
+
+        let (kind, response) = match self.send_batch(&batch).await {
+            Ok(result) => result,
+            Err(err) => {
+                // We cannot tell whether the request made it to the
+                // endpoint, so treat this as a transient failure
+                tracing::debug!("failed to send to {}: {err:#}", dispatcher.name);
+                (
+                    RecordType::TransientFailure,
+                    Response {
+                        code: 451,
+                        enhanced_code: None,
+                        content: format!("KumoMTA internal: failed to send HTTP request: {err:#}"),
+                        command: None,
+                    },
+                )
+            }
+        };
+        tracing::debug!(
+            "{} message(s) to {}: {kind:?} {response:?}",
+            batch.len(),
+            dispatcher.name
+        );
+
+        // The first message in the batch is the one held by the dispatcher;
+        // we're taking responsibility for it here
+        dispatcher.msg.take();
+
+        for msg in batch {
+            log_disposition(LogDisposition {
+                kind,
+                msg: msg.clone(),
+                site: &dispatcher.name,
+                peer_address: Some(&self.peer_address),
+                response: response.clone(),
+                egress_pool: Some(&dispatcher.egress_pool),
+                egress_source: Some(&dispatcher.egress_source.name),
+                relay_disposition: None,
+                delivery_protocol: Some("HTTP"),
+                tls_info: None,
+                source_address: None,
+            })
+            .await;
+
+            // An error with one message must not prevent the disposition
+            // of the rest of the batch, so errors are logged rather than
+            // propagated
+            let id = *msg.id();
+            let result = match kind {
+                RecordType::Delivery => {
+                    dispatcher.metrics.inc_delivered();
+                    SpoolManager::remove_from_spool(id).await
+                }
+                RecordType::TransientFailure => {
+                    dispatcher.metrics.inc_transfail();
+                    spawn_local(
+                        "requeue message".to_string(),
+                        Dispatcher::requeue_message(msg, true, None),
+                    )
+                    .map(|_| ())
+                    .map_err(anyhow::Error::from)
+                }
+                _ => {
+                    dispatcher.metrics.inc_fail();
+                    SpoolManager::remove_from_spool(id).await
+                }
+            };
+            if let Err(err) = result {
+                tracing::error!(
+                    "{id}: {kind:?} disposition via {}: {err:#}",
+                    dispatcher.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_classification() {
+        assert_eq!(classify_status(200), RecordType::Delivery);
+        assert_eq!(classify_status(204), RecordType::Delivery);
+        assert_eq!(classify_status(408), RecordType::TransientFailure);
+        assert_eq!(classify_status(429), RecordType::TransientFailure);
+        assert_eq!(classify_status(503), RecordType::TransientFailure);
+        assert_eq!(classify_status(400), RecordType::Bounce);
+        assert_eq!(classify_status(404), RecordType::Bounce);
+
+        let response = make_response(
+            RecordType::TransientFailure,
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            "try later\n",
+        );
+        assert!(response.is_transient());
+        assert_eq!(response.content, "HTTP 503 Service Unavailable try later");
+    }
+
+    #[test]
+    fn hmac_signature() {
+        // Test vector from RFC 4231, test case 2
+        k9::assert_equal!(
+            sign_body(b"Jefe", b"what do ya want for nothing?").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let mut proto = HttpDeliveryProtocol {
+            url: "https://example.com/{{ domain }}".to_string(),
+            auth: None,
+            body_format: HttpBodyFormat::Rfc5322,
+            batch_size: 1,
+            headers: BTreeMap::new(),
+            timeout: HttpDeliveryProtocol::default_timeout(),
+        };
+        assert!(proto.validate().is_ok());
+        proto.batch_size = 10;
+        assert!(proto.validate().is_err());
+        proto.body_format = HttpBodyFormat::LogRecord;
+        assert!(proto.validate().is_ok());
+    }
+}
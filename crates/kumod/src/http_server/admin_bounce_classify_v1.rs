@@ -0,0 +1,30 @@
+use axum::extract::{Json, Query};
+use kumo_api_types::bounce_classify::{
+    BounceClassifySuggestV1Request, BounceClassifySuggestV1Response,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Returns the most frequently observed responses that were not matched
+/// by any bounce classifier rule, along with candidate rules that
+/// could be used to classify them.
+#[utoipa::path(
+    get,
+    tag="bounce",
+    path="/api/admin/bounce-classify/suggest/v1",
+    params(BounceClassifySuggestV1Request),
+    responses(
+        (status = 200, description = "Returned the suggestions", body=BounceClassifySuggestV1Response)
+    ),
+)]
+pub async fn suggest(
+    _: TrustedIpRequired,
+    Query(request): Query<BounceClassifySuggestV1Request>,
+) -> Result<Json<BounceClassifySuggestV1Response>, AppError> {
+    Ok(Json(BounceClassifySuggestV1Response {
+        suggestions: crate::classify_suggest::get_suggestions(
+            request.limit,
+            request.min_count.unwrap_or(0),
+        ),
+    }))
+}
@@ -3,12 +3,14 @@ use crate::queue::QueueManager;
 use axum::extract::Json;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use config::get_or_create_sub_module;
 use kumo_api_types::{BounceV1CancelRequest, BounceV1ListEntry, BounceV1Request, BounceV1Response};
 use kumo_server_common::http_server::auth::TrustedIpRequired;
 use kumo_server_common::http_server::AppError;
 use kumo_server_runtime::rt_spawn_non_blocking;
 use message::message::QueueNameComponents;
 use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
 use parking_lot::FairMutex as Mutex;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -47,6 +49,21 @@ fn match_criteria(current_thing: Option<&str>, wanted_thing: Option<&str>) -> bo
 }
 
 impl AdminBounceEntry {
+    fn from_request(request: BounceV1Request) -> Self {
+        let duration = request.duration();
+        Self {
+            id: Uuid::new_v4(),
+            campaign: request.campaign,
+            tenant: request.tenant,
+            domain: request.domain,
+            routing_domain: request.routing_domain,
+            reason: request.reason,
+            suppress_logging: request.suppress_logging,
+            expires: Instant::now() + duration,
+            bounced: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
     pub fn get_all() -> Vec<Self> {
         let mut entries = ENTRIES.lock();
         let now = Instant::now();
@@ -54,6 +71,31 @@ impl AdminBounceEntry {
         entries.clone()
     }
 
+    pub fn get_all_v1() -> Vec<BounceV1ListEntry> {
+        let now = Instant::now();
+        Self::get_all()
+            .into_iter()
+            .filter_map(|entry| {
+                let bounced = entry.bounced.lock().clone();
+                let total_bounced = bounced.values().sum();
+                entry
+                    .expires
+                    .checked_duration_since(now)
+                    .map(|duration| BounceV1ListEntry {
+                        id: entry.id,
+                        campaign: entry.campaign,
+                        tenant: entry.tenant,
+                        domain: entry.domain,
+                        routing_domain: entry.routing_domain,
+                        reason: entry.reason,
+                        bounced,
+                        total_bounced,
+                        duration,
+                    })
+            })
+            .collect()
+    }
+
     pub fn remove_by_id(id: &Uuid) -> bool {
         let mut entries = ENTRIES.lock();
         let len_before = entries.len();
@@ -196,18 +238,7 @@ pub async fn bounce_v1(
     // Note: Json<> must be last in the param list
     Json(request): Json<BounceV1Request>,
 ) -> Result<Json<BounceV1Response>, AppError> {
-    let duration = request.duration();
-    let entry = AdminBounceEntry {
-        id: Uuid::new_v4(),
-        campaign: request.campaign,
-        tenant: request.tenant,
-        domain: request.domain,
-        routing_domain: request.routing_domain,
-        reason: request.reason,
-        suppress_logging: request.suppress_logging,
-        expires: Instant::now() + duration,
-        bounced: Arc::new(Mutex::new(HashMap::new())),
-    };
+    let entry = AdminBounceEntry::from_request(request);
 
     AdminBounceEntry::add(entry.clone());
 
@@ -252,30 +283,7 @@ pub async fn bounce_v1(
 pub async fn bounce_v1_list(
     _: TrustedIpRequired,
 ) -> Result<Json<Vec<BounceV1ListEntry>>, AppError> {
-    let now = Instant::now();
-    Ok(Json(
-        AdminBounceEntry::get_all()
-            .into_iter()
-            .filter_map(|entry| {
-                let bounced = entry.bounced.lock().clone();
-                let total_bounced = bounced.values().sum();
-                entry
-                    .expires
-                    .checked_duration_since(now)
-                    .map(|duration| BounceV1ListEntry {
-                        id: entry.id,
-                        campaign: entry.campaign,
-                        tenant: entry.tenant,
-                        domain: entry.domain,
-                        routing_domain: entry.routing_domain,
-                        reason: entry.reason,
-                        bounced,
-                        total_bounced,
-                        duration,
-                    })
-            })
-            .collect(),
-    ))
+    Ok(Json(AdminBounceEntry::get_all_v1()))
 }
 
 /// Allows the system operator to delete an administrative bounce entry by its id.
@@ -303,3 +311,44 @@ pub async fn bounce_v1_delete(
     }
     .into_response()
 }
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "api.admin.bounce")?;
+
+    module.set(
+        "list",
+        lua.create_function(move |lua, ()| {
+            let result = AdminBounceEntry::get_all_v1();
+            lua.to_value(&result)
+        })?,
+    )?;
+
+    module.set(
+        "bounce",
+        lua.create_async_function(|lua, request: Value| async move {
+            let request: BounceV1Request = lua.from_value(request)?;
+            let entry = AdminBounceEntry::from_request(request);
+
+            AdminBounceEntry::add(entry.clone());
+
+            for name in entry.list_matching_queues().await {
+                if let Some(q) = QueueManager::get_opt(&name) {
+                    q.bounce_all(&entry).await;
+                }
+            }
+
+            lua.to_value(&entry.id)
+        })?,
+    )?;
+
+    module.set(
+        "delete",
+        lua.create_function(move |lua, id: Value| {
+            let id: Uuid = lua.from_value(id)?;
+            let removed = AdminBounceEntry::remove_by_id(&id);
+            Ok(removed)
+        })?,
+    )?;
+
+    Ok(())
+}
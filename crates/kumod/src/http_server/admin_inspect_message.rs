@@ -1,5 +1,11 @@
+use crate::http_server::admin_suspend_ready_q_v1::AdminSuspendReadyQEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
+use crate::queue::QueueManager;
 use axum::extract::{Json, Query};
-use kumo_api_types::{InspectMessageV1Request, InspectMessageV1Response, MessageInformation};
+use kumo_api_types::{
+    InspectMessageV1Request, InspectMessageV1Response, MessageDeliveryAttempt, MessageInformation,
+    MessageQueueStatus, MessageThrottle, SuspendReadyQueueV1ListEntry, SuspendV1ListEntry,
+};
 use kumo_server_common::http_server::auth::TrustedIpRequired;
 use kumo_server_common::http_server::AppError;
 use message::Message;
@@ -31,6 +37,9 @@ pub async fn inspect_v1(
         None
     };
 
+    let (attempts, throttle) = crate::attempt_history::lookup(msg.id());
+    let queue = queue_status(&msg, &attempts, throttle)?;
+
     Ok(Json(InspectMessageV1Response {
         id: request.id,
         message: MessageInformation {
@@ -39,5 +48,60 @@ pub async fn inspect_v1(
             meta,
             data,
         },
+        attempts,
+        queue: Some(queue),
     }))
 }
+
+fn queue_status(
+    msg: &Message,
+    attempts: &[MessageDeliveryAttempt],
+    throttle: Option<MessageThrottle>,
+) -> anyhow::Result<MessageQueueStatus> {
+    let queue = msg.get_queue_name()?;
+
+    let suspension = AdminSuspendEntry::get_for_queue_name(&queue).map(|entry| {
+        let duration = entry.get_duration().to_std().unwrap_or_default();
+        SuspendV1ListEntry {
+            id: entry.id,
+            campaign: entry.campaign,
+            tenant: entry.tenant,
+            domain: entry.domain,
+            reason: entry.reason,
+            duration,
+        }
+    });
+
+    // The ready queue depends on the egress source that is selected
+    // for the next attempt, which we can't know ahead of time, so
+    // we report the one that was used by the most recent attempt
+    let ready_queue = attempts
+        .iter()
+        .rev()
+        .find_map(|attempt| attempt.egress_source.as_deref())
+        .and_then(|source| QueueManager::get_opt(&queue)?.ready_queue_name_for_source(source));
+
+    let ready_queue_suspension = ready_queue
+        .as_deref()
+        .and_then(AdminSuspendReadyQEntry::get_for_queue_name)
+        .map(|entry| {
+            let duration = entry.get_duration();
+            SuspendReadyQueueV1ListEntry {
+                id: entry.id,
+                name: entry.name.clone(),
+                reason: entry.reason.clone(),
+                duration,
+                expires: chrono::Utc::now() + entry.get_duration_chrono(),
+            }
+        });
+
+    Ok(MessageQueueStatus {
+        queue,
+        ready_queue,
+        num_attempts: msg.get_num_attempts(),
+        due: msg.get_due(),
+        suspension,
+        ready_queue_suspension,
+        throttle,
+    })
+}
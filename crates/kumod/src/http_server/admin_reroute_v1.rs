@@ -0,0 +1,244 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use config::get_or_create_sub_module;
+use kumo_api_types::{
+    RerouteV1CancelRequest, RerouteV1ListEntry, RerouteV1Request, RerouteV1Response,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+use message::message::QueueNameComponents;
+use mlua::{Lua, LuaSerdeExt, Value};
+use parking_lot::FairMutex as Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+lazy_static::lazy_static! {
+    static ref ENTRIES: Mutex<Vec<AdminRerouteEntry>> = Mutex::new(vec![]);
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminRerouteEntry {
+    pub id: Uuid,
+    pub campaign: Option<String>,
+    pub tenant: Option<String>,
+    pub domain: Option<String>,
+    pub egress_pool: String,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+fn match_criteria(current_thing: Option<&str>, wanted_thing: Option<&str>) -> bool {
+    match (current_thing, wanted_thing) {
+        (Some(a), Some(b)) => a == b,
+        (None, Some(_)) => {
+            // Needs to match a specific thing and there is none
+            false
+        }
+        (_, None) => {
+            // No specific campaign required
+            true
+        }
+    }
+}
+
+impl AdminRerouteEntry {
+    fn from_request(request: RerouteV1Request) -> Self {
+        let duration = request.duration();
+        Self {
+            id: Uuid::new_v4(),
+            campaign: request.campaign,
+            tenant: request.tenant,
+            domain: request.domain,
+            egress_pool: request.egress_pool,
+            reason: request.reason,
+            expires: Instant::now() + duration,
+        }
+    }
+
+    pub fn get_all() -> Vec<Self> {
+        let mut entries = ENTRIES.lock();
+        let now = Instant::now();
+        entries.retain(|ent| ent.expires > now);
+        entries.clone()
+    }
+
+    pub fn get_all_v1() -> Vec<RerouteV1ListEntry> {
+        let now = Instant::now();
+        Self::get_all()
+            .into_iter()
+            .filter_map(|entry| {
+                entry
+                    .expires
+                    .checked_duration_since(now)
+                    .map(|duration| RerouteV1ListEntry {
+                        id: entry.id,
+                        campaign: entry.campaign,
+                        tenant: entry.tenant,
+                        domain: entry.domain,
+                        egress_pool: entry.egress_pool,
+                        reason: entry.reason,
+                        duration,
+                    })
+            })
+            .collect()
+    }
+
+    pub fn remove_by_id(id: &Uuid) -> bool {
+        let mut entries = ENTRIES.lock();
+        let len_before = entries.len();
+        entries.retain(|e| e.id != *id);
+        len_before != entries.len()
+    }
+
+    pub fn add(entry: Self) {
+        let mut entries = ENTRIES.lock();
+        let now = Instant::now();
+        // Age out expired entries, and replace any entries with the
+        // same criteria; this allows updating the pool and reason
+        // with a newer version of the reroute info.
+        entries.retain(|ent| {
+            ent.expires > now
+                && !(ent.campaign == entry.campaign
+                    && ent.tenant == entry.tenant
+                    && ent.domain == entry.domain)
+        });
+
+        entries.push(entry);
+    }
+
+    pub fn matches(
+        &self,
+        campaign: Option<&str>,
+        tenant: Option<&str>,
+        domain: Option<&str>,
+    ) -> bool {
+        if !match_criteria(campaign, self.campaign.as_deref()) {
+            return false;
+        }
+        if !match_criteria(tenant, self.tenant.as_deref()) {
+            return false;
+        }
+        if !match_criteria(domain, self.domain.as_deref()) {
+            return false;
+        }
+        true
+    }
+
+    /// Returns the most recently added reroute that applies to the
+    /// scheduled queue. This is called each time a message is promoted
+    /// to a ready queue, so it avoids copying the other entries.
+    pub fn get_for_queue_name(queue_name: &str) -> Option<Self> {
+        let entries = ENTRIES.lock();
+        if entries.is_empty() {
+            return None;
+        }
+        let components = QueueNameComponents::parse(queue_name);
+        let now = Instant::now();
+        entries
+            .iter()
+            .rev()
+            .find(|ent| {
+                ent.expires > now
+                    && ent.matches(
+                        components.campaign,
+                        components.tenant,
+                        Some(components.domain),
+                    )
+            })
+            .cloned()
+    }
+}
+
+/// Send the messages in matching scheduled queues via a different egress pool
+#[utoipa::path(
+    post,
+    tag="reroute",
+    path="/api/admin/reroute/v1",
+    responses(
+        (status = 200, description = "Rerouted", body=RerouteV1Response),
+    ),
+)]
+pub async fn reroute(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<RerouteV1Request>,
+) -> Result<Json<RerouteV1Response>, AppError> {
+    let entry = AdminRerouteEntry::from_request(request);
+    let id = entry.id;
+
+    AdminRerouteEntry::add(entry);
+
+    Ok(Json(RerouteV1Response { id }))
+}
+
+/// List the active scheduled-queue reroutes
+#[utoipa::path(
+    get,
+    tag="reroute",
+    path="/api/admin/reroute/v1",
+    responses(
+        (status = 200, description = "Rerouted", body=RerouteV1ListEntry),
+    ),
+)]
+pub async fn list(_: TrustedIpRequired) -> Result<Json<Vec<RerouteV1ListEntry>>, AppError> {
+    Ok(Json(AdminRerouteEntry::get_all_v1()))
+}
+
+/// Remove a scheduled-queue reroute
+#[utoipa::path(
+    delete,
+    tag="reroute",
+    path="/api/admin/reroute/v1",
+    responses(
+        (status = 200, description = "Removed the reroute"),
+        (status = 404, description = "Reroute either expired or was never valid"),
+    ),
+)]
+pub async fn delete(_: TrustedIpRequired, Json(request): Json<RerouteV1CancelRequest>) -> Response {
+    let removed = AdminRerouteEntry::remove_by_id(&request.id);
+    if removed {
+        (StatusCode::OK, format!("removed {}", request.id))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("reroute entry {} not found", request.id),
+        )
+    }
+    .into_response()
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "api.admin.reroute")?;
+
+    module.set(
+        "list",
+        lua.create_function(move |lua, ()| {
+            let result = AdminRerouteEntry::get_all_v1();
+            lua.to_value(&result)
+        })?,
+    )?;
+
+    module.set(
+        "reroute",
+        lua.create_function(move |lua, request: Value| {
+            let request: RerouteV1Request = lua.from_value(request)?;
+            let entry = AdminRerouteEntry::from_request(request);
+            let id = entry.id;
+
+            AdminRerouteEntry::add(entry);
+            lua.to_value(&id)
+        })?,
+    )?;
+
+    module.set(
+        "delete",
+        lua.create_function(move |lua, id: Value| {
+            let id: Uuid = lua.from_value(id)?;
+            let removed = AdminRerouteEntry::remove_by_id(&id);
+            Ok(removed)
+        })?,
+    )?;
+
+    Ok(())
+}
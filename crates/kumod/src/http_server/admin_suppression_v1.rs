@@ -0,0 +1,108 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use kumo_api_types::suppression::{
+    SuppressionSource, SuppressionV1AddRequest, SuppressionV1DeleteRequest, SuppressionV1Entry,
+    SuppressionV1ListRequest,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+fn get_suppression() -> anyhow::Result<std::sync::Arc<crate::suppression::Suppression>> {
+    crate::suppression::get().ok_or_else(|| {
+        anyhow::anyhow!("the suppression list has not been enabled via kumo.configure_suppression")
+    })
+}
+
+/// Allows the system operator to add a recipient to the suppression list.
+/// If an entry already exists for the tenant and recipient, it is replaced.
+#[utoipa::path(
+    post,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Suppression entry added successfully", body=SuppressionV1Entry)
+    ),
+)]
+pub async fn add(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SuppressionV1AddRequest>,
+) -> Result<Json<SuppressionV1Entry>, AppError> {
+    let suppression = get_suppression()?;
+    let now = Utc::now();
+    let expires = match request.duration {
+        Some(duration) => Some(now + chrono::Duration::from_std(duration)?),
+        None => None,
+    };
+    let entry = SuppressionV1Entry {
+        tenant: request.tenant,
+        recipient: request.recipient,
+        source: SuppressionSource::Admin,
+        reason: request.reason,
+        bounce_classification: None,
+        created: now,
+        expires,
+    };
+    suppression.add(entry.clone()).await?;
+    Ok(Json(entry))
+}
+
+/// Allows the system operator to list the active entries in the suppression list.
+#[utoipa::path(
+    get,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    params(SuppressionV1ListRequest),
+    responses(
+        (status = 200, description = "Returned the matching suppression entries", body=[SuppressionV1Entry])
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<SuppressionV1ListRequest>,
+) -> Result<Json<Vec<SuppressionV1Entry>>, AppError> {
+    let suppression = get_suppression()?;
+    Ok(Json(
+        suppression
+            .list(request.tenant, request.recipient, request.limit)
+            .await?,
+    ))
+}
+
+/// Allows the system operator to remove a recipient from the suppression list.
+#[utoipa::path(
+    delete,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Removed the requested entry"),
+        (status = 404, description = "There was no entry for the requested tenant and recipient"),
+    ),
+)]
+pub async fn delete(
+    _: TrustedIpRequired,
+    Json(request): Json<SuppressionV1DeleteRequest>,
+) -> Result<Response, AppError> {
+    let suppression = get_suppression()?;
+    let tenant = match &request.tenant {
+        Some(tenant) => format!(" for tenant {tenant}"),
+        None => String::new(),
+    };
+    let removed = suppression
+        .remove(request.tenant.clone(), request.recipient.clone())
+        .await?;
+    Ok(if removed {
+        (
+            StatusCode::OK,
+            format!("removed {}{tenant}", request.recipient),
+        )
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("suppression entry {}{tenant} not found", request.recipient),
+        )
+    }
+    .into_response())
+}
@@ -0,0 +1,188 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::throttles::{
+    LimitLeaseV1Entry, LimitV1Entry, ThrottleV1Entry, ThrottleV1KeyRequest,
+    ThrottleV1OverrideRequest, ThrottlesV1ListRequest, ThrottlesV1Response,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+use std::time::Duration;
+use throttle::introspect::{
+    list_throttles, remove_throttle_override, reset_throttle, set_throttle_override,
+};
+use throttle::limit::{list_limits, remove_limit_override, reset_limit, set_limit_override};
+use throttle::ThrottleSpec;
+
+fn key_matches(key: &str, wanted: Option<&str>) -> bool {
+    match wanted {
+        Some(wanted) => key.contains(wanted),
+        None => true,
+    }
+}
+
+/// Sub-second precision is just noise when the durations are formatted
+fn whole_seconds(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs())
+}
+
+/// Lists the throttle and limit keys that have been used by this node,
+/// along with their current state
+#[utoipa::path(
+    get,
+    tag="throttles",
+    path="/api/admin/throttles/v1",
+    params(ThrottlesV1ListRequest),
+    responses(
+        (status = 200, description = "Returned the matching keys", body=ThrottlesV1Response)
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<ThrottlesV1ListRequest>,
+) -> Result<Json<ThrottlesV1Response>, AppError> {
+    let wanted = request.key.as_deref();
+
+    let throttles = list_throttles()
+        .await?
+        .into_iter()
+        .filter(|state| key_matches(&state.key, wanted))
+        .map(|state| ThrottleV1Entry {
+            spec: ThrottleSpec {
+                limit: state.limit,
+                period: state.period.as_secs(),
+                max_burst: None,
+                pacing: state.pacing,
+            }
+            .as_string(),
+            key: state.key,
+            max_burst: state.max_burst,
+            remaining: state.remaining,
+            reset_after: state.reset_after,
+            idle: whole_seconds(state.idle),
+            override_expires: state.override_expires.map(whole_seconds),
+        })
+        .collect();
+
+    let limits = list_limits()
+        .await?
+        .into_iter()
+        .filter(|state| key_matches(&state.key, wanted))
+        .map(|state| LimitV1Entry {
+            key: state.key,
+            limit: state.limit,
+            lease_duration: state.duration,
+            leases: state
+                .leases
+                .into_iter()
+                .map(|lease| LimitLeaseV1Entry {
+                    uuid: lease.uuid,
+                    expires: whole_seconds(lease.expires),
+                })
+                .collect(),
+            idle: whole_seconds(state.idle),
+            override_expires: state.override_expires.map(whole_seconds),
+        })
+        .collect();
+
+    Ok(Json(ThrottlesV1Response { throttles, limits }))
+}
+
+/// Temporarily overrides the rate of a throttle key, or the limit
+/// of a limit key. The override applies only to this node.
+#[utoipa::path(
+    post,
+    tag="throttles",
+    path="/api/admin/throttles/v1/override",
+    responses(
+        (status = 200, description = "The override was applied"),
+        (status = 400, description = "The request was invalid"),
+    ),
+)]
+pub async fn set_override(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<ThrottleV1OverrideRequest>,
+) -> Response {
+    let key = &request.key;
+    let duration = request.duration;
+    match (&request.spec, request.limit) {
+        (Some(spec), None) => match ThrottleSpec::try_from(spec.as_str()) {
+            Ok(spec) => {
+                set_throttle_override(key, spec, request.duration);
+                (
+                    StatusCode::OK,
+                    format!(
+                        "throttle {key} is now {} for {duration:?}",
+                        spec.as_string()
+                    ),
+                )
+            }
+            Err(err) => (StatusCode::BAD_REQUEST, err),
+        },
+        (None, Some(limit)) => {
+            set_limit_override(key, limit, request.duration);
+            (
+                StatusCode::OK,
+                format!("limit {key} is now {limit} for {duration:?}"),
+            )
+        }
+        _ => (
+            StatusCode::BAD_REQUEST,
+            "exactly one of spec or limit must be specified".to_string(),
+        ),
+    }
+    .into_response()
+}
+
+/// Removes the override of a throttle or limit key
+#[utoipa::path(
+    delete,
+    tag="throttles",
+    path="/api/admin/throttles/v1/override",
+    responses(
+        (status = 200, description = "Removed the override"),
+        (status = 404, description = "There was no active override for the key"),
+    ),
+)]
+pub async fn delete_override(
+    _: TrustedIpRequired,
+    Json(request): Json<ThrottleV1KeyRequest>,
+) -> Response {
+    let key = &request.key;
+    let removed_throttle = remove_throttle_override(key);
+    let removed_limit = remove_limit_override(key);
+    if removed_throttle || removed_limit {
+        (StatusCode::OK, format!("removed override for {key}"))
+    } else {
+        (StatusCode::NOT_FOUND, format!("no override for {key}"))
+    }
+    .into_response()
+}
+
+/// Discards the accumulated state of a throttle key, or the leases
+/// held on a limit key. When redis is in use, this applies to all of
+/// the nodes that share the key.
+#[utoipa::path(
+    post,
+    tag="throttles",
+    path="/api/admin/throttles/v1/reset",
+    responses(
+        (status = 200, description = "The key was reset"),
+        (status = 404, description = "The key has not been used by this node"),
+    ),
+)]
+pub async fn reset(
+    _: TrustedIpRequired,
+    Json(request): Json<ThrottleV1KeyRequest>,
+) -> Result<Response, AppError> {
+    let key = &request.key;
+    let was_throttle = reset_throttle(key).await?;
+    let was_limit = reset_limit(key).await?;
+    Ok(if was_throttle || was_limit {
+        (StatusCode::OK, format!("reset {key}"))
+    } else {
+        (StatusCode::NOT_FOUND, format!("{key} is not a known key"))
+    }
+    .into_response())
+}
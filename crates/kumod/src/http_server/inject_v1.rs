@@ -1,7 +1,11 @@
 use crate::logging::{log_disposition, LogDisposition, RecordType};
+use crate::pressure::PressureLevel;
 use crate::queue::QueueManager;
+use crate::suppression::SuppressionAction;
 use anyhow::Context;
 use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
 use axum_client_ip::InsecureClientIp;
 use config::{any_err, get_or_create_sub_module, load_config, CallbackSignature, LuaConfig};
 use kumo_log_types::ResolvedAddress;
@@ -21,6 +25,7 @@ use spool::SpoolId;
 use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
+use tracing::Instrument;
 use utoipa::{ToResponse, ToSchema};
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
@@ -467,17 +472,41 @@ async fn process_recipient<'a>(
     message.set_meta("reception_protocol", "HTTP")?;
     message.set_meta("received_from", peer_address.to_string())?;
 
+    let trace_span = crate::message_trace::reception_span(&message, "HTTP");
+
     // call callback to assign to queue
     let sig = CallbackSignature::<message::Message, ()>::new("http_message_generated");
-    config.async_call_callback(&sig, message.clone()).await?;
+    config
+        .async_call_callback(&sig, message.clone())
+        .instrument(crate::message_trace::lua_span(
+            &trace_span,
+            "http_message_generated",
+        ))
+        .await?;
 
     // spool and insert to queue
     let queue_name = message.get_queue_name()?;
 
     if queue_name != "null" {
+        if let Some((action, entry)) = crate::suppression::check_message(&message).await {
+            crate::suppression::suppressed_counter_for_service("http_inject").inc();
+            match action {
+                SuppressionAction::Reject => {
+                    anyhow::bail!("recipient is suppressed: {}", entry.reason);
+                }
+                SuppressionAction::LogAndDrop => {
+                    crate::suppression::log_suppressed(message, &entry).await;
+                    return Ok(());
+                }
+            }
+        }
+
         let deferred_spool = false; // TODO: configurable somehow
         if !deferred_spool {
-            message.save().await?;
+            message
+                .save()
+                .instrument(crate::message_trace::spool_span(&trace_span))
+                .await?;
         }
         log_disposition(LogDisposition {
             kind: RecordType::Reception,
@@ -500,6 +529,7 @@ async fn process_recipient<'a>(
             tls_info: None,
             source_address: None,
         })
+        .instrument(trace_span)
         .await;
         rt_spawn(format!("http inject for {peer_address:?}"), move || {
             Ok(async move { QueueManager::insert(&queue_name, message).await })
@@ -582,7 +612,8 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     tag="inject",
     path="/api/inject/v1",
     responses(
-        (status = 200, description = "Message(s) injected successfully", body=InjectV1Response)
+        (status = 200, description = "Message(s) injected successfully", body=InjectV1Response),
+        (status = 429, description = "The system is under pressure and is shedding load. Try later")
     ),
 )]
 pub async fn inject_v1(
@@ -590,10 +621,17 @@ pub async fn inject_v1(
     InsecureClientIp(peer_address): InsecureClientIp,
     // Note: Json<> must be last in the param list
     Json(request): Json<InjectV1Request>,
-) -> Result<Json<InjectV1Response>, AppError> {
-    if kumo_server_memory::get_headroom() == 0 {
-        // Using too much memory
-        return Err(anyhow::anyhow!("load shedding").into());
+) -> Result<axum::response::Response, AppError> {
+    if kumo_server_memory::get_headroom() == 0
+        || crate::pressure::current_level() >= PressureLevel::Elevated
+    {
+        // Using too much memory, or otherwise under too much pressure
+        crate::pressure::shed_counter_for_service("http_inject").inc();
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            "load shedding: system under pressure. Try later",
+        )
+            .into_response());
     }
     let sender = EnvelopeAddress::parse(&request.envelope_sender).context("envelope_sender")?;
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -603,7 +641,8 @@ pub async fn inject_v1(
         Ok(async move { tx.send(inject_v1_impl(auth, sender, peer_address, request).await) })
     })
     .await?;
-    rx.await?
+    let result = rx.await?;
+    result.map(|json| json.into_response())
 }
 
 #[cfg(test)]
@@ -1,17 +1,24 @@
 use axum::routing::{delete, get, post};
 use axum::Router;
 use inject_v1::*;
+use kumo_api_types::bounce_classify::*;
 use kumo_api_types::rebind::*;
+use kumo_api_types::suppression::*;
+use kumo_api_types::throttles::*;
 use kumo_api_types::*;
 use kumo_server_common::http_server::RouterAndDocs;
 use spool::SpoolId;
 use utoipa::OpenApi;
 
+pub mod admin_bounce_classify_v1;
 pub mod admin_bounce_v1;
 pub mod admin_inspect_message;
 pub mod admin_rebind_v1;
+pub mod admin_reroute_v1;
+pub mod admin_suppression_v1;
 pub mod admin_suspend_ready_q_v1;
 pub mod admin_suspend_v1;
+pub mod admin_throttles_v1;
 pub mod admin_trace_smtp_client_v1;
 pub mod admin_trace_smtp_server_v1;
 pub mod inject_v1;
@@ -24,14 +31,25 @@ pub mod inject_v1;
         admin_bounce_v1::bounce_v1,
         admin_bounce_v1::bounce_v1_list,
         admin_bounce_v1::bounce_v1_delete,
+        admin_bounce_classify_v1::suggest,
         admin_inspect_message::inspect_v1,
         admin_rebind_v1::rebind_v1,
+        admin_reroute_v1::reroute,
+        admin_reroute_v1::list,
+        admin_reroute_v1::delete,
         admin_suspend_ready_q_v1::suspend,
         admin_suspend_ready_q_v1::list,
         admin_suspend_ready_q_v1::delete,
         admin_suspend_v1::suspend,
         admin_suspend_v1::list,
         admin_suspend_v1::delete,
+        admin_suppression_v1::add,
+        admin_suppression_v1::list,
+        admin_suppression_v1::delete,
+        admin_throttles_v1::list,
+        admin_throttles_v1::set_override,
+        admin_throttles_v1::delete_override,
+        admin_throttles_v1::reset,
     ),
     components(
         schemas(
@@ -47,16 +65,35 @@ pub mod inject_v1;
             BounceV1Response,
             BounceV1ListEntry,
             BounceV1CancelRequest,
+            BounceClassifySuggestion,
+            BounceClassifySuggestV1Response,
             InspectMessageV1Response,
+            MessageDeliveryAttempt,
             MessageInformation,
+            MessageQueueStatus,
+            MessageThrottle,
             RebindV1Request,
             RebindV1Response,
+            RerouteV1CancelRequest,
+            RerouteV1ListEntry,
+            RerouteV1Request,
+            RerouteV1Response,
             SuspendReadyQueueV1Request,
             SuspendV1Response,
             SuspendReadyQueueV1ListEntry,
             SuspendV1CancelRequest,
             SuspendV1ListEntry,
             SuspendV1Request,
+            SuppressionSource,
+            SuppressionV1Entry,
+            SuppressionV1AddRequest,
+            SuppressionV1DeleteRequest,
+            ThrottleV1Entry,
+            LimitLeaseV1Entry,
+            LimitV1Entry,
+            ThrottlesV1Response,
+            ThrottleV1OverrideRequest,
+            ThrottleV1KeyRequest,
         ),
         responses(InjectV1Response, BounceV1Response, InspectMessageV1Response),
     )
@@ -73,10 +110,36 @@ pub fn make_router() -> RouterAndDocs {
                 "/api/admin/bounce/v1",
                 delete(admin_bounce_v1::bounce_v1_delete),
             )
+            .route(
+                "/api/admin/bounce-classify/suggest/v1",
+                get(admin_bounce_classify_v1::suggest),
+            )
             .route("/api/admin/rebind/v1", post(admin_rebind_v1::rebind_v1))
+            .route("/api/admin/reroute/v1", post(admin_reroute_v1::reroute))
+            .route("/api/admin/reroute/v1", get(admin_reroute_v1::list))
+            .route("/api/admin/reroute/v1", delete(admin_reroute_v1::delete))
             .route("/api/admin/suspend/v1", post(admin_suspend_v1::suspend))
             .route("/api/admin/suspend/v1", get(admin_suspend_v1::list))
             .route("/api/admin/suspend/v1", delete(admin_suspend_v1::delete))
+            .route("/api/admin/suppression/v1", post(admin_suppression_v1::add))
+            .route("/api/admin/suppression/v1", get(admin_suppression_v1::list))
+            .route(
+                "/api/admin/suppression/v1",
+                delete(admin_suppression_v1::delete),
+            )
+            .route("/api/admin/throttles/v1", get(admin_throttles_v1::list))
+            .route(
+                "/api/admin/throttles/v1/override",
+                post(admin_throttles_v1::set_override),
+            )
+            .route(
+                "/api/admin/throttles/v1/override",
+                delete(admin_throttles_v1::delete_override),
+            )
+            .route(
+                "/api/admin/throttles/v1/reset",
+                post(admin_throttles_v1::reset),
+            )
             .route(
                 "/api/admin/suspend-ready-q/v1",
                 post(admin_suspend_ready_q_v1::suspend),
@@ -0,0 +1,382 @@
+//! Histograms of the time taken to deliver messages, so that questions
+//! such as "what is the p99 time from reception to delivery for tenant X"
+//! can be answered from the metrics.
+//!
+//! The histograms carry a configurable set of labels. Since each distinct
+//! combination of label values is a separate time series, every label is
+//! subject to a cardinality guard: once a label has seen
+//! `max_label_values` distinct values, any further new values are
+//! reported as `other`.
+use chrono::Utc;
+use domain_map::domain_has_suffix;
+use message::Message;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref LABEL_OVERFLOW: IntCounterVec = prometheus::register_int_counter_vec!(
+        "delivery_latency_label_overflow",
+        "number of latency observations whose label value was replaced \
+         by 'other' because the label reached max_label_values",
+        &["label"]).unwrap();
+}
+
+static METRICS: OnceCell<LatencyMetrics> = OnceCell::new();
+
+/// The label value used for values that exceed the cardinality
+/// guard, or that don't match any provider group
+const OTHER: &str = "other";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyMetricsParams {
+    /// Meta keys whose values are used as labels,
+    /// such as `tenant` or `campaign`
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// Whether to add a `domain` label holding the recipient domain
+    #[serde(default)]
+    pub domain: bool,
+
+    /// Maps provider group names to a list of domain suffixes.
+    /// When non-empty, a `provider` label is added whose value is the
+    /// first group with a suffix that matches either the recipient
+    /// domain or the site name of the destination.
+    #[serde(default)]
+    pub provider_groups: BTreeMap<String, Vec<String>>,
+
+    /// The maximum number of distinct values for any one label
+    #[serde(default = "LatencyMetricsParams::default_max_label_values")]
+    pub max_label_values: usize,
+
+    /// Bucket boundaries, in seconds, for the reception latency histograms
+    #[serde(default = "LatencyMetricsParams::default_latency_buckets")]
+    pub latency_buckets: Vec<f64>,
+
+    /// Bucket boundaries, in seconds, for the attempt duration histogram
+    #[serde(default = "LatencyMetricsParams::default_attempt_buckets")]
+    pub attempt_buckets: Vec<f64>,
+}
+
+impl LatencyMetricsParams {
+    fn default_max_label_values() -> usize {
+        100
+    }
+
+    fn default_latency_buckets() -> Vec<f64> {
+        vec![
+            1., 5., 10., 30., 60., 300., 600., 1800., 3600., 7200., 21600., 86400., 259200.,
+        ]
+    }
+
+    fn default_attempt_buckets() -> Vec<f64> {
+        vec![0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60., 120., 300.]
+    }
+
+    fn label_names(&self) -> Vec<String> {
+        let mut names = self.meta.clone();
+        if self.domain {
+            names.push("domain".to_string());
+        }
+        if !self.provider_groups.is_empty() {
+            names.push("provider".to_string());
+        }
+        names
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut seen = HashSet::new();
+        for name in self.label_names() {
+            let valid = name
+                .chars()
+                .next()
+                .map(|c| c.is_ascii_alphabetic() || c == '_')
+                .unwrap_or(false)
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                anyhow::bail!("{name} is not a valid metric label name");
+            }
+            if !seen.insert(name.clone()) {
+                anyhow::bail!("label {name} is specified more than once");
+            }
+        }
+        if self.max_label_values == 0 {
+            anyhow::bail!("max_label_values must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+impl Default for LatencyMetricsParams {
+    fn default() -> Self {
+        Self {
+            meta: vec![],
+            domain: false,
+            provider_groups: BTreeMap::new(),
+            max_label_values: Self::default_max_label_values(),
+            latency_buckets: Self::default_latency_buckets(),
+            attempt_buckets: Self::default_attempt_buckets(),
+        }
+    }
+}
+
+/// Limits the number of distinct values seen for each label
+struct CardinalityGuard {
+    max_values: usize,
+    seen: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl CardinalityGuard {
+    fn new(max_values: usize) -> Self {
+        Self {
+            max_values,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the value to use for the label
+    fn admit(&self, label: &str, value: String) -> String {
+        let mut seen = self.seen.lock();
+        let values = seen.entry(label.to_string()).or_default();
+        if values.contains(&value) {
+            return value;
+        }
+        if values.len() < self.max_values {
+            values.insert(value.clone());
+            return value;
+        }
+        LABEL_OVERFLOW.with_label_values(&[label]).inc();
+        OTHER.to_string()
+    }
+}
+
+/// Returns the first provider group with a suffix that matches
+/// either the domain or the site name
+fn provider_for<'a>(
+    groups: &'a BTreeMap<String, Vec<String>>,
+    domain: &str,
+    site: &str,
+) -> &'a str {
+    for (group, suffixes) in groups {
+        if suffixes
+            .iter()
+            .any(|suffix| domain_has_suffix(domain, suffix) || domain_has_suffix(site, suffix))
+        {
+            return group;
+        }
+    }
+    OTHER
+}
+
+struct LatencyMetrics {
+    params: LatencyMetricsParams,
+    guard: CardinalityGuard,
+    reception_to_first_attempt: HistogramVec,
+    reception_to_delivery: HistogramVec,
+    attempt_duration: HistogramVec,
+}
+
+fn register_histogram(
+    name: &str,
+    help: &str,
+    buckets: &[f64],
+    labels: &[&str],
+) -> anyhow::Result<HistogramVec> {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(name, help).buckets(buckets.to_vec()),
+        labels,
+    )?;
+    prometheus::register(Box::new(histogram.clone()))?;
+    Ok(histogram)
+}
+
+impl LatencyMetrics {
+    fn new(params: LatencyMetricsParams) -> anyhow::Result<Self> {
+        params.validate()?;
+        let label_names = params.label_names();
+        let labels: Vec<&str> = label_names.iter().map(|s| s.as_str()).collect();
+
+        Ok(Self {
+            reception_to_first_attempt: register_histogram(
+                "delivery_latency_reception_to_first_attempt",
+                "seconds from reception of a message to its first delivery attempt",
+                &params.latency_buckets,
+                &labels,
+            )?,
+            reception_to_delivery: register_histogram(
+                "delivery_latency_reception_to_delivery",
+                "seconds from reception of a message to its successful delivery",
+                &params.latency_buckets,
+                &labels,
+            )?,
+            attempt_duration: register_histogram(
+                "delivery_attempt_duration",
+                "seconds taken by each SMTP transaction",
+                &params.attempt_buckets,
+                &labels,
+            )?,
+            guard: CardinalityGuard::new(params.max_label_values),
+            params,
+        })
+    }
+
+    fn label_values(&self, msg: &Message, site: &str) -> Vec<String> {
+        let mut values = vec![];
+        for key in &self.params.meta {
+            let value = msg.get_meta_string(key.as_str()).ok().flatten();
+            values.push(self.guard.admit(key, value.unwrap_or_default()));
+        }
+
+        let domain = msg
+            .recipient()
+            .map(|r| r.domain().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        if self.params.domain {
+            values.push(self.guard.admit("domain", domain.clone()));
+        }
+        if !self.params.provider_groups.is_empty() {
+            values.push(provider_for(&self.params.provider_groups, &domain, site).to_string());
+        }
+        values
+    }
+}
+
+/// Enable the latency metrics with the specified labels.
+/// This can only be called once, and must be called before
+/// any messages are delivered; otherwise the metrics will
+/// already have been registered without labels.
+pub fn configure(params: LatencyMetricsParams) -> anyhow::Result<()> {
+    if METRICS.get().is_some() {
+        anyhow::bail!("latency metrics have already been configured");
+    }
+    let metrics = LatencyMetrics::new(params)?;
+    METRICS
+        .set(metrics)
+        .map_err(|_| anyhow::anyhow!("latency metrics have already been configured"))
+}
+
+fn get() -> Option<&'static LatencyMetrics> {
+    METRICS
+        .get_or_try_init(|| LatencyMetrics::new(LatencyMetricsParams::default()))
+        .map_err(|err| tracing::error!("failed to register latency metrics: {err:#}"))
+        .ok()
+}
+
+fn since_reception(msg: &Message) -> f64 {
+    (Utc::now() - msg.id().created())
+        .to_std()
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Called by log_disposition for the outcome of each delivery attempt
+pub fn observe_attempt_outcome(msg: &Message, site: &str, delivered: bool) {
+    let metrics = match get() {
+        Some(m) => m,
+        None => return,
+    };
+    let values = metrics.label_values(msg, site);
+    let values: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+    let elapsed = since_reception(msg);
+
+    // The attempt counter is incremented after the outcome
+    // is logged, so this is the first attempt
+    if msg.get_num_attempts() == 0 {
+        metrics
+            .reception_to_first_attempt
+            .with_label_values(&values)
+            .observe(elapsed);
+    }
+    if delivered {
+        metrics
+            .reception_to_delivery
+            .with_label_values(&values)
+            .observe(elapsed);
+    }
+}
+
+/// Called by the smtp dispatcher with the duration of each transaction
+pub fn observe_attempt_duration(msg: &Message, site: &str, duration: Duration) {
+    let metrics = match get() {
+        Some(m) => m,
+        None => return,
+    };
+    let values = metrics.label_values(msg, site);
+    let values: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+    metrics
+        .attempt_duration
+        .with_label_values(&values)
+        .observe(duration.as_secs_f64());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cardinality_guard() {
+        let guard = CardinalityGuard::new(2);
+        assert_eq!(guard.admit("tenant", "a".to_string()), "a");
+        assert_eq!(guard.admit("tenant", "b".to_string()), "b");
+        assert_eq!(guard.admit("tenant", "c".to_string()), OTHER);
+        assert_eq!(guard.admit("tenant", "a".to_string()), "a");
+        // Each label has its own limit
+        assert_eq!(guard.admit("campaign", "c".to_string()), "c");
+    }
+
+    #[test]
+    fn provider_groups() {
+        let groups: BTreeMap<String, Vec<String>> = [
+            (
+                "google".to_string(),
+                vec!["gmail.com".to_string(), ".google.com".to_string()],
+            ),
+            ("yahoo".to_string(), vec!["yahoodns.net".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(provider_for(&groups, "gmail.com", ""), "google");
+        assert_eq!(
+            provider_for(&groups, "example.com", "(alt1|alt2)?.aspmx.l.google.com"),
+            "google"
+        );
+        assert_eq!(
+            provider_for(&groups, "yahoo.com", "mta5.am0.yahoodns.net"),
+            "yahoo"
+        );
+        assert_eq!(provider_for(&groups, "notgmail.com", ""), OTHER);
+    }
+
+    #[test]
+    fn params_validation() {
+        let params = LatencyMetricsParams {
+            meta: vec!["tenant".to_string(), "not-valid".to_string()],
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+
+        let params = LatencyMetricsParams {
+            meta: vec!["domain".to_string()],
+            domain: true,
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+
+        let params = LatencyMetricsParams {
+            meta: vec!["tenant".to_string()],
+            domain: true,
+            provider_groups: [("google".to_string(), vec!["google.com".to_string()])]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        params.validate().unwrap();
+        assert_eq!(params.label_names(), vec!["tenant", "domain", "provider"]);
+    }
+}
@@ -1,8 +1,12 @@
+use crate::correlation::DeliveredMessage;
+use crate::logging::columnar::{ColumnarSchema, ParquetSegment};
 use crate::queue::QueueManager;
 use crate::smtp_server::RelayDisposition;
 use anyhow::{anyhow, Context};
 use async_channel::{Receiver, Sender};
-use bounce_classify::{BounceClass, BounceClassifier, BounceClassifierBuilder};
+use bounce_classify::{
+    BounceClass, BounceClassifier, BounceClassifierBuilder, PreDefinedBounceClass,
+};
 use chrono::Utc;
 use config::{any_err, from_lua_value, get_or_create_module, load_config, CallbackSignature};
 use kumo_log_types::rfc3464::ReportAction;
@@ -24,7 +28,7 @@ use std::fs::File;
 use std::future::Future;
 use std::io::Write;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::thread::JoinHandle;
@@ -32,6 +36,11 @@ use std::time::{Duration, Instant};
 use tokio::sync::Mutex as TokioMutex;
 use zstd::stream::write::Encoder;
 
+mod columnar;
+mod sink;
+
+pub use sink::LogSinkParams;
+
 static LOGGER: Lazy<Mutex<Vec<Arc<Logger>>>> = Lazy::new(|| Mutex::new(vec![]));
 static CLASSIFY: OnceCell<BounceClassifier> = OnceCell::new();
 pub static SHOULD_ENQ_LOG_RECORD_SIG: Lazy<CallbackSignature<(Message, String), bool>> =
@@ -41,9 +50,17 @@ pub static SHOULD_ENQ_LOG_RECORD_SIG: Lazy<CallbackSignature<(Message, String),
 #[serde(deny_unknown_fields)]
 pub struct ClassifierParams {
     pub files: Vec<String>,
+    /// The maximum number of distinct unclassified response templates
+    /// to track for rule suggestions
+    #[serde(default = "ClassifierParams::default_max_suggestions")]
+    pub max_suggestions: usize,
 }
 
 impl ClassifierParams {
+    fn default_max_suggestions() -> usize {
+        crate::classify_suggest::DEFAULT_CAPACITY
+    }
+
     pub fn register(&self) -> anyhow::Result<()> {
         let mut builder = BounceClassifierBuilder::new();
         for file_name in &self.files {
@@ -66,10 +83,19 @@ impl ClassifierParams {
             .set(classifier)
             .map_err(|_| anyhow::anyhow!("classifier already initialized"))?;
 
+        crate::classify_suggest::set_capacity(self.max_suggestions);
+
         Ok(())
     }
 }
 
+/// Classify a response using the configured bounce classifier, if any
+pub fn classify_response(response: &Response) -> Option<BounceClass> {
+    CLASSIFY
+        .get()
+        .map(|classifier| classifier.classify_response(response))
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct LogRecordParams {
@@ -147,6 +173,10 @@ pub struct LogFileParams {
     #[serde(default, with = "duration_serde")]
     pub max_segment_duration: Option<Duration>,
 
+    /// The format of the log file segments
+    #[serde(default)]
+    pub format: LogFileFormat,
+
     /// List of meta fields to capture in the log
     #[serde(default)]
     pub meta: Vec<String>,
@@ -165,6 +195,20 @@ pub struct LogFileParams {
     pub filter_event: Option<String>,
 }
 
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFileFormat {
+    /// zstd compressed, newline delimited JSON (or templated) records
+    Json,
+    /// Parquet, with a column per field of the log record
+    Parquet,
+}
+
+impl Default for LogFileFormat {
+    fn default() -> Self {
+        Self::Json
+    }
+}
+
 impl LogFileParams {
     fn default_max_file_size() -> u64 {
         1_000_000_000
@@ -191,6 +235,7 @@ pub struct Logger {
     enabled: HashMap<RecordType, bool>,
     filter_event: Option<String>,
     hook_name: Option<String>,
+    sink_name: Option<String>,
 }
 
 impl Logger {
@@ -262,6 +307,7 @@ impl Logger {
             enabled,
             filter_event: None,
             hook_name: Some(hook_name),
+            sink_name: None,
         };
 
         loggers.push(Arc::new(logger));
@@ -284,6 +330,23 @@ impl Logger {
             }
         }
 
+        let columnar_schema = match params.format {
+            LogFileFormat::Json => None,
+            LogFileFormat::Parquet => {
+                for (kind, per_rec) in &params.per_record {
+                    anyhow::ensure!(
+                        per_rec.template.is_none() && per_rec.segment_header.is_empty(),
+                        "template and segment_header cannot be used with the Parquet \
+                         format (found in per_record entry for {kind:?})"
+                    );
+                }
+                Some(Arc::new(ColumnarSchema::new(
+                    &params.meta,
+                    &params.headers,
+                )?))
+            }
+        };
+
         std::fs::create_dir_all(&params.log_dir)
             .with_context(|| format!("creating log directory {}", params.log_dir.display()))?;
 
@@ -312,6 +375,7 @@ impl Logger {
                         receiver,
                         template_engine,
                         file_map: HashMap::new(),
+                        columnar_schema,
                     };
                     state.logger_thread().await
                 });
@@ -325,12 +389,84 @@ impl Logger {
             enabled,
             filter_event,
             hook_name: None,
+            sink_name: None,
         };
 
         LOGGER.lock().push(Arc::new(logger));
         Ok(())
     }
 
+    pub fn init_sink(params: LogSinkParams) -> anyhow::Result<()> {
+        params.validate()?;
+        let mut loggers = LOGGER.lock();
+
+        if loggers
+            .iter()
+            .any(|existing| existing.sink_name.as_deref() == Some(params.name.as_str()))
+        {
+            anyhow::bail!(
+                "A log sink with name `{}` has already been registered",
+                params.name
+            );
+        }
+
+        let mut template_engine = Environment::new();
+        add_to_environment(&mut template_engine);
+
+        for (kind, per_rec) in &params.per_record {
+            if let Some(template_source) = &per_rec.template {
+                template_engine
+                    .add_template_owned(format!("{kind:?}"), template_source.clone())
+                    .with_context(|| {
+                        format!(
+                            "compiling template:\n{template_source}\nfor log record type {kind:?}"
+                        )
+                    })?;
+            }
+        }
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let filter_event = params.filter_event.clone();
+        let sink_name = params.name.to_string();
+        let (sender, receiver) = async_channel::bounded(params.back_pressure);
+        let mut state = sink::LogSinkState::new(params, receiver, template_engine)?;
+
+        let thread = std::thread::Builder::new()
+            .name("logger".to_string())
+            .spawn(move || {
+                tracing::debug!("started logger thread");
+                // The sink clients need io in addition to timers
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("create logger runtime");
+                runtime.block_on(async move {
+                    tracing::debug!("calling state.logger_thread()");
+                    state.logger_thread().await
+                });
+            })?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            hook_name: None,
+            sink_name: Some(sink_name),
+        };
+
+        loggers.push(Arc::new(logger));
+        Ok(())
+    }
+
     pub fn record_is_enabled(&self, kind: RecordType) -> bool {
         if let Some(enabled) = self.enabled.get(&kind) {
             return *enabled;
@@ -377,6 +513,23 @@ impl Logger {
         result
     }
 
+    /// Restore information from the original message, that was correlated
+    /// with an OOB or Feedback report, onto the log record.
+    /// Meta values from the original message replace those of the
+    /// report message itself.
+    fn apply_correlation(&self, record: &mut JsonLogRecord, delivered: &DeliveredMessage) {
+        for (name, value) in self.extract_meta(&delivered.meta) {
+            record.meta.insert(name, value);
+        }
+        if record.egress_pool.is_none() {
+            record.egress_pool = delivered.info.egress_pool.clone();
+        }
+        if record.egress_source.is_none() {
+            record.egress_source = delivered.info.egress_source.clone();
+        }
+        record.correlated_message.replace(delivered.info.clone());
+    }
+
     pub async fn extract_fields(
         &self,
         msg: &Message,
@@ -490,6 +643,7 @@ pub async fn log_rejection(args: LogRejection) {
             tls_protocol_version: None,
             tls_peer_subject_name: None,
             source_address: None,
+            correlated_message: None,
         };
         if let Err(err) = logger.log(record).await {
             tracing::error!("failed to log: {err:#}");
@@ -526,8 +680,34 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         source_address,
     } = args;
 
+    crate::attempt_history::record_attempt(
+        kind,
+        &msg,
+        site,
+        peer_address,
+        &response,
+        egress_pool,
+        egress_source,
+        source_address.as_ref(),
+    );
+    if delivery_protocol.is_some()
+        && matches!(
+            kind,
+            RecordType::Delivery | RecordType::TransientFailure | RecordType::Bounce
+        )
+    {
+        crate::latency_metrics::observe_attempt_outcome(&msg, site, kind == RecordType::Delivery);
+    }
+    // Recorded as an event in the current message_trace span, if any
+    tracing::info!(
+        target: kumo_server_common::diagnostic_logging::MESSAGE_TRACE_TARGET,
+        kind = ?kind,
+        site,
+        response = %response.to_single_line(),
+    );
+
     let loggers = Logger::get_loggers();
-    if loggers.is_empty() {
+    if loggers.is_empty() && crate::suppression::get().is_none() {
         return;
     }
 
@@ -546,6 +726,80 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         }
     }
 
+    crate::suppression::observe_disposition(
+        kind,
+        &msg,
+        &response,
+        delivery_protocol,
+        feedback_report.as_ref(),
+    )
+    .await;
+
+    if loggers.is_empty() {
+        return;
+    }
+
+    if matches!(kind, RecordType::Bounce | RecordType::TransientFailure)
+        && delivery_protocol.is_some()
+    {
+        if let Some(BounceClass::PreDefined(PreDefinedBounceClass::Uncategorized)) =
+            classify_response(&response)
+        {
+            crate::classify_suggest::record_unclassified(&response.to_single_line());
+        }
+    }
+
+    let correlated = match kind {
+        RecordType::Delivery => {
+            crate::correlation::record_delivery(&msg, egress_pool, egress_source);
+            None
+        }
+        RecordType::Feedback => match &feedback_report {
+            Some(report) => crate::correlation::correlate_report(
+                "Feedback",
+                report.original_message.as_deref(),
+                &report
+                    .original_rcpto_to
+                    .iter()
+                    .map(|r| r.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            None => None,
+        },
+        _ => None,
+    };
+
+    let oob_report = match relay_disposition {
+        Some(RelayDisposition { log_oob: true, .. }) if kind == RecordType::Reception => {
+            msg.parse_rfc3464().ok().flatten()
+        }
+        _ => None,
+    };
+
+    // Correlated separately for each failed recipient of the report,
+    // in the same order as report.per_recipient
+    let oob_correlated: Vec<Option<Arc<DeliveredMessage>>> = match &oob_report {
+        Some(report) => report
+            .per_recipient
+            .iter()
+            .map(|recip| {
+                if recip.action != ReportAction::Failed {
+                    return None;
+                }
+                let mut recipients = vec![recip.final_recipient.recipient.as_str()];
+                if let Some(original) = &recip.original_recipient {
+                    recipients.insert(0, original.recipient.as_str());
+                }
+                crate::correlation::correlate_report(
+                    "OOB",
+                    report.original_message.as_deref(),
+                    &recipients,
+                )
+            })
+            .collect(),
+        None => vec![],
+    };
+
     let now = Utc::now();
     let nodeid = kumo_server_common::nodeid::NodeId::get_uuid();
 
@@ -607,7 +861,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
             tls_peer_subject_name.replace(info.subject_name.clone());
         }
 
-        let record = JsonLogRecord {
+        let mut record = JsonLogRecord {
             kind,
             id: msg.id().to_string(),
             size: msg.get_data().len() as u64,
@@ -641,97 +895,101 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
             tls_protocol_version,
             tls_peer_subject_name,
             source_address: source_address.clone(),
+            correlated_message: None,
         };
+        if let Some(delivered) = &correlated {
+            logger.apply_correlation(&mut record, delivered);
+        }
         if let Err(err) = logger.log(record).await {
             tracing::error!("failed to log: {err:#}");
         }
 
-        if kind == RecordType::Reception {
-            if let Some(RelayDisposition { log_oob: true, .. }) = relay_disposition {
-                if let Ok(Some(report)) = msg.parse_rfc3464() {
-                    // This incoming bounce report is addressed to
-                    // the envelope from of the original message
-                    let sender = msg
-                        .recipient()
-                        .map(|addr| addr.to_string())
-                        .unwrap_or_else(|err| format!("{err:#}"));
-                    let queue = msg
-                        .get_queue_name()
-                        .unwrap_or_else(|err| format!("{err:#}"));
-
-                    for recip in &report.per_recipient {
-                        if recip.action != ReportAction::Failed {
-                            continue;
-                        }
+        if let Some(report) = &oob_report {
+            // This incoming bounce report is addressed to
+            // the envelope from of the original message
+            let sender = msg
+                .recipient()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|err| format!("{err:#}"));
+            let queue = msg
+                .get_queue_name()
+                .unwrap_or_else(|err| format!("{err:#}"));
 
-                        let enhanced_code = EnhancedStatusCode {
-                            class: recip.status.class,
-                            subject: recip.status.subject,
-                            detail: recip.status.detail,
-                        };
+            for (idx, recip) in report.per_recipient.iter().enumerate() {
+                if recip.action != ReportAction::Failed {
+                    continue;
+                }
 
-                        let (code, content) = match &recip.diagnostic_code {
-                            Some(diag) if diag.diagnostic_type == "smtp" => {
-                                if let Some((code, content)) = diag.diagnostic.split_once(' ') {
-                                    if let Ok(code) = code.parse() {
-                                        (code, content.to_string())
-                                    } else {
-                                        (550, diag.diagnostic.to_string())
-                                    }
-                                } else {
-                                    (550, diag.diagnostic.to_string())
-                                }
+                let enhanced_code = EnhancedStatusCode {
+                    class: recip.status.class,
+                    subject: recip.status.subject,
+                    detail: recip.status.detail,
+                };
+
+                let (code, content) = match &recip.diagnostic_code {
+                    Some(diag) if diag.diagnostic_type == "smtp" => {
+                        if let Some((code, content)) = diag.diagnostic.split_once(' ') {
+                            if let Ok(code) = code.parse() {
+                                (code, content.to_string())
+                            } else {
+                                (550, diag.diagnostic.to_string())
                             }
-                            _ => (550, "".to_string()),
-                        };
-
-                        let record = JsonLogRecord {
-                            kind: RecordType::OOB,
-                            id: msg.id().to_string(),
-                            size: 0,
-                            sender: sender.clone(),
-                            recipient: recip
-                                .original_recipient
-                                .as_ref()
-                                .unwrap_or(&recip.final_recipient)
-                                .recipient
-                                .to_string(),
-                            queue: queue.to_string(),
-                            site: site.to_string(),
-                            peer_address: Some(ResolvedAddress {
-                                name: report.per_message.reporting_mta.name.to_string(),
-                                addr: peer_address
-                                    .map(|a| a.addr)
-                                    .unwrap_or_else(|| Ipv4Addr::UNSPECIFIED.into()),
-                            }),
-                            response: Response {
-                                code,
-                                enhanced_code: Some(enhanced_code),
-                                content,
-                                command: None,
-                            },
-                            timestamp: recip.last_attempt_date.unwrap_or_else(|| Utc::now()),
-                            created: msg.id().created(),
-                            num_attempts: 0,
-                            egress_pool: None,
-                            egress_source: None,
-                            bounce_classification: BounceClass::default(),
-                            feedback_report: None,
-                            headers: headers.clone(),
-                            meta: meta.clone(),
-                            delivery_protocol: None,
-                            reception_protocol: reception_protocol.clone(),
-                            nodeid,
-                            tls_cipher: None,
-                            tls_protocol_version: None,
-                            tls_peer_subject_name: None,
-                            source_address: None,
-                        };
-
-                        if let Err(err) = logger.log(record).await {
-                            tracing::error!("failed to log: {err:#}");
+                        } else {
+                            (550, diag.diagnostic.to_string())
                         }
                     }
+                    _ => (550, "".to_string()),
+                };
+
+                let mut record = JsonLogRecord {
+                    kind: RecordType::OOB,
+                    id: msg.id().to_string(),
+                    size: 0,
+                    sender: sender.clone(),
+                    recipient: recip
+                        .original_recipient
+                        .as_ref()
+                        .unwrap_or(&recip.final_recipient)
+                        .recipient
+                        .to_string(),
+                    queue: queue.to_string(),
+                    site: site.to_string(),
+                    peer_address: Some(ResolvedAddress {
+                        name: report.per_message.reporting_mta.name.to_string(),
+                        addr: peer_address
+                            .map(|a| a.addr)
+                            .unwrap_or_else(|| Ipv4Addr::UNSPECIFIED.into()),
+                    }),
+                    response: Response {
+                        code,
+                        enhanced_code: Some(enhanced_code),
+                        content,
+                        command: None,
+                    },
+                    timestamp: recip.last_attempt_date.unwrap_or_else(|| Utc::now()),
+                    created: msg.id().created(),
+                    num_attempts: 0,
+                    egress_pool: None,
+                    egress_source: None,
+                    bounce_classification: BounceClass::default(),
+                    feedback_report: None,
+                    headers: headers.clone(),
+                    meta: meta.clone(),
+                    delivery_protocol: None,
+                    reception_protocol: reception_protocol.clone(),
+                    nodeid,
+                    tls_cipher: None,
+                    tls_protocol_version: None,
+                    tls_peer_subject_name: None,
+                    source_address: None,
+                    correlated_message: None,
+                };
+                if let Some(Some(delivered)) = oob_correlated.get(idx) {
+                    logger.apply_correlation(&mut record, delivered);
+                }
+
+                if let Err(err) = logger.log(record).await {
+                    tracing::error!("failed to log: {err:#}");
                 }
             }
         }
@@ -744,8 +1002,13 @@ struct FileNameKey {
     suffix: Option<String>,
 }
 
+enum SegmentFile {
+    Json(Encoder<'static, File>),
+    Parquet(ParquetSegment),
+}
+
 struct OpenedFile {
-    file: Encoder<'static, File>,
+    file: SegmentFile,
     name: PathBuf,
     written: u64,
     expires: Option<Instant>,
@@ -753,7 +1016,16 @@ struct OpenedFile {
 
 impl Drop for OpenedFile {
     fn drop(&mut self) {
-        self.file.do_finish().ok();
+        match &mut self.file {
+            SegmentFile::Json(file) => {
+                file.do_finish().ok();
+            }
+            SegmentFile::Parquet(segment) => {
+                if let Err(err) = segment.finish() {
+                    tracing::error!("failed to finish {}: {err:#}", self.name.display());
+                }
+            }
+        }
         mark_path_as_done(&self.name).ok();
         tracing::debug!("Flushed {:?}", self.name);
     }
@@ -904,11 +1176,40 @@ impl LogHookState {
     }
 }
 
+/// Parquet segments cannot be appended to, so each one must be a
+/// freshly created file. Several segments may be opened within the
+/// same second, so a sequence number is added to the name until
+/// we find one that is not already taken.
+fn open_unique_parquet_segment(
+    dir: &Path,
+    base_name: &str,
+    suffix: &str,
+) -> anyhow::Result<(PathBuf, File)> {
+    let mut seq = 0;
+    loop {
+        let name = dir.join(format!("{base_name}-{seq}{suffix}.parquet"));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&name)
+        {
+            Ok(f) => return Ok((name, f)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                seq += 1;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("open log file {name:?}"));
+            }
+        }
+    }
+}
+
 struct LogThreadState {
     params: LogFileParams,
     receiver: Receiver<LogCommand>,
     template_engine: Environment<'static>,
     file_map: HashMap<FileNameKey, OpenedFile>,
+    columnar_schema: Option<Arc<ColumnarSchema>>,
 }
 
 impl LogThreadState {
@@ -1042,21 +1343,41 @@ impl LogThreadState {
             let now = Utc::now();
 
             let mut base_name = now.format("%Y%m%d-%H%M%S").to_string();
-            if let Some(suffix) = &file_key.suffix {
-                base_name.push_str(suffix);
-            }
 
-            let name = file_key.log_dir.join(base_name);
+            let (name, f) = match &self.columnar_schema {
+                Some(_) => open_unique_parquet_segment(
+                    &file_key.log_dir,
+                    &base_name,
+                    file_key.suffix.as_deref().unwrap_or(""),
+                )?,
+                None => {
+                    if let Some(suffix) = &file_key.suffix {
+                        base_name.push_str(suffix);
+                    }
+                    let name = file_key.log_dir.join(base_name);
+                    let f = std::fs::OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(&name)
+                        .with_context(|| format!("open log file {name:?}"))?;
+                    (name, f)
+                }
+            };
 
-            let f = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&name)
-                .with_context(|| format!("open log file {name:?}"))?;
+            let segment = match &self.columnar_schema {
+                Some(schema) => SegmentFile::Parquet(ParquetSegment::new(
+                    f,
+                    schema.clone(),
+                    self.params.compression_level,
+                )?),
+                None => SegmentFile::Json(
+                    Encoder::new(f, self.params.compression_level)
+                        .context("set up zstd encoder")?,
+                ),
+            };
 
             let mut file = OpenedFile {
-                file: Encoder::new(f, self.params.compression_level)
-                    .context("set up zstd encoder")?,
+                file: segment,
                 name,
                 written: 0,
                 expires: self
@@ -1065,9 +1386,11 @@ impl LogThreadState {
                     .map(|duration| Instant::now() + duration),
             };
 
-            if let Some(per_rec) = self.per_record(record.kind) {
+            if let (Some(per_rec), SegmentFile::Json(encoder)) =
+                (self.per_record(record.kind), &mut file.file)
+            {
                 if !per_rec.segment_header.is_empty() {
-                    file.file
+                    encoder
                         .write_all(per_rec.segment_header.as_bytes())
                         .with_context(|| {
                             format!(
@@ -1084,6 +1407,20 @@ impl LogThreadState {
         let mut need_rotate = false;
 
         if let Some(file) = self.file_map.get_mut(&file_key) {
+            let encoder = match &mut file.file {
+                SegmentFile::Json(encoder) => encoder,
+                SegmentFile::Parquet(segment) => {
+                    file.written = segment
+                        .write_record(record)
+                        .with_context(|| format!("writing record to {}", file.name.display()))?;
+                    need_rotate = file.written >= self.params.max_file_size;
+                    if need_rotate {
+                        self.file_map.remove(&file_key);
+                    }
+                    return Ok(());
+                }
+            };
+
             let mut record_text = Vec::new();
             self.template_engine
                 .add_global("log_record", minijinja::Value::from_serialize(&record));
@@ -1098,7 +1435,7 @@ impl LogThreadState {
             if record_text.last() != Some(&b'\n') {
                 record_text.push(b'\n');
             }
-            file.file
+            encoder
                 .write_all(&record_text)
                 .with_context(|| format!("writing record to {}", file.name.display()))?;
             file.written += record_text.len() as u64;
@@ -1141,5 +1478,13 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_log_sink",
+        lua.create_function(move |lua, params: LuaValue| {
+            let params: LogSinkParams = from_lua_value(lua, params)?;
+            Logger::init_sink(params).map_err(any_err)
+        })?,
+    )?;
+
     Ok(())
 }
@@ -0,0 +1,736 @@
+//! Native log sinks, which batch log records and publish them directly
+//! to Kafka, AMQP or an HTTP endpoint, rather than routing each record
+//! through the `should_enqueue_log_record` event and the message queues.
+//!
+//! Records are rendered using the same per-record-type templates as
+//! local log files, and are accumulated into batches that are published
+//! when either `batch_size` records have been collected, or `linger` has
+//! elapsed since the first record was added to the batch.
+//!
+//! Batches that cannot be published are written to an overflow directory
+//! on disk and are retried periodically, oldest first. While there is a
+//! backlog in the overflow directory, new batches are appended to it
+//! rather than being published directly, so that a down target doesn't
+//! stall the sink for `timeout` on every batch.
+use super::{LogCommand, LogFileParams, LogRecordParams, CLASSIFY};
+use crate::http_deliver::{HttpAuth, ResolvedAuth};
+use anyhow::Context;
+use async_channel::Receiver;
+use chrono::Utc;
+use kumo_log_types::{JsonLogRecord, RecordType};
+use minijinja::{Environment, Template};
+use mod_amqp::lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use mod_amqp::lapin::{BasicProperties, Channel, Connection};
+use mod_kafka::rdkafka::producer::{FutureProducer, FutureRecord};
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref PUBLISHED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "log_sink_records_published",
+        "number of log records that were published by a log sink",
+        &["sink"]).unwrap();
+    static ref OVERFLOWED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "log_sink_records_overflowed",
+        "number of log records that were written to the overflow directory of a log sink",
+        &["sink"]).unwrap();
+    static ref DROPPED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "log_sink_records_dropped",
+        "number of log records that a log sink was unable to publish or buffer",
+        &["sink"]).unwrap();
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum LogSinkTarget {
+    Kafka {
+        /// librdkafka producer configuration, such as `bootstrap.servers`
+        config: HashMap<String, String>,
+        topic: String,
+    },
+    Amqp {
+        uri: String,
+        #[serde(default)]
+        exchange: String,
+        routing_key: String,
+    },
+    Http {
+        url: String,
+        #[serde(default)]
+        auth: Option<HttpAuth>,
+        /// Additional headers to include in each request
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSinkCompression {
+    None,
+    Zstd,
+}
+
+impl Default for LogSinkCompression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogSinkParams {
+    /// The unique name to identify this instance of the log sink
+    pub name: String,
+
+    pub target: LogSinkTarget,
+
+    /// The maximum number of records to include in a batch
+    #[serde(default = "LogSinkParams::default_batch_size")]
+    pub batch_size: usize,
+
+    /// How long to wait for more records before publishing
+    /// a batch that is smaller than batch_size
+    #[serde(default = "LogSinkParams::default_linger", with = "duration_serde")]
+    pub linger: Duration,
+
+    #[serde(default)]
+    pub compression: LogSinkCompression,
+
+    /// How long to wait for the target to accept a batch
+    #[serde(default = "LogSinkParams::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+
+    /// Where to buffer batches that could not be published.
+    /// If not set, such batches are discarded.
+    #[serde(default)]
+    pub overflow_dir: Option<PathBuf>,
+
+    /// The maximum number of bytes to buffer in the overflow directory
+    #[serde(default = "LogSinkParams::default_max_overflow_size")]
+    pub max_overflow_size: u64,
+
+    /// How long to wait between attempts to publish the batches
+    /// held in the overflow directory
+    #[serde(
+        default = "LogSinkParams::default_retry_interval",
+        with = "duration_serde"
+    )]
+    pub retry_interval: Duration,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture in the log
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture in the log
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, LogRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be published
+    /// to this sink
+    #[serde(default)]
+    pub filter_event: Option<String>,
+}
+
+impl LogSinkParams {
+    fn default_batch_size() -> usize {
+        1000
+    }
+
+    fn default_linger() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_max_overflow_size() -> u64 {
+        1_000_000_000
+    }
+
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.batch_size > 0, "batch_size must be larger than zero");
+        Ok(())
+    }
+
+    fn compress(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self.compression {
+            LogSinkCompression::None => Ok(data),
+            LogSinkCompression::Zstd => {
+                zstd::stream::encode_all(data.as_slice(), 0).context("zstd compressing batch")
+            }
+        }
+    }
+}
+
+/// The connection to the target of the sink
+enum SinkClient {
+    Kafka(FutureProducer),
+    Amqp {
+        // Keeps the connection alive for the channel
+        _connection: Connection,
+        channel: Channel,
+    },
+    Http {
+        client: reqwest::Client,
+        auth: Option<ResolvedAuth>,
+    },
+}
+
+impl SinkClient {
+    async fn connect(params: &LogSinkParams) -> anyhow::Result<Self> {
+        match &params.target {
+            LogSinkTarget::Kafka { config, .. } => {
+                let mut config = config.clone();
+                if params.compression == LogSinkCompression::Zstd {
+                    config
+                        .entry("compression.type".to_string())
+                        .or_insert_with(|| "zstd".to_string());
+                }
+                config
+                    .entry("message.timeout.ms".to_string())
+                    .or_insert_with(|| params.timeout.as_millis().to_string());
+                Ok(Self::Kafka(mod_kafka::build_producer(&config)?))
+            }
+            LogSinkTarget::Amqp { uri, .. } => {
+                let (connection, channel) =
+                    tokio::time::timeout(params.timeout, mod_amqp::connect(uri)).await??;
+                channel
+                    .confirm_select(ConfirmSelectOptions::default())
+                    .await
+                    .context("enabling publisher confirms")?;
+                Ok(Self::Amqp {
+                    _connection: connection,
+                    channel,
+                })
+            }
+            LogSinkTarget::Http { auth, .. } => {
+                let client = reqwest::Client::builder().timeout(params.timeout).build()?;
+                let auth = match auth {
+                    Some(auth) => Some(auth.resolve().await?),
+                    None => None,
+                };
+                Ok(Self::Http { client, auth })
+            }
+        }
+    }
+
+    async fn publish(&self, params: &LogSinkParams, batch: &[Vec<u8>]) -> anyhow::Result<()> {
+        match (self, &params.target) {
+            (Self::Kafka(producer), LogSinkTarget::Kafka { topic, .. }) => {
+                // Enqueue every record before waiting for any of them,
+                // so that the producer can batch them together
+                let mut deliveries = vec![];
+                for record in batch {
+                    let payload = record.strip_suffix(b"\n").unwrap_or(record);
+                    let delivery = producer
+                        .send_result(FutureRecord::<(), [u8]>::to(topic).payload(payload))
+                        .map_err(|(err, _record)| err)?;
+                    deliveries.push(delivery);
+                }
+                for delivery in deliveries {
+                    delivery
+                        .await
+                        .context("kafka producer was dropped")?
+                        .map_err(|(err, _msg)| err)?;
+                }
+                Ok(())
+            }
+            (
+                Self::Amqp { channel, .. },
+                LogSinkTarget::Amqp {
+                    exchange,
+                    routing_key,
+                    ..
+                },
+            ) => {
+                let payload = params.compress(batch.concat())?;
+                let mut properties =
+                    BasicProperties::default().with_content_type("application/x-ndjson".into());
+                if params.compression == LogSinkCompression::Zstd {
+                    properties = properties.with_content_encoding("zstd".into());
+                }
+                let confirmation = tokio::time::timeout(params.timeout, async {
+                    channel
+                        .basic_publish(
+                            exchange,
+                            routing_key,
+                            BasicPublishOptions::default(),
+                            &payload,
+                            properties,
+                        )
+                        .await?
+                        .await
+                })
+                .await??;
+                anyhow::ensure!(!confirmation.is_nack(), "batch was rejected by the broker");
+                Ok(())
+            }
+            (Self::Http { client, auth }, LogSinkTarget::Http { url, headers, .. }) => {
+                let body = params.compress(batch.concat())?;
+                let mut request = client
+                    .post(url)
+                    .header("Content-Type", "application/x-ndjson");
+                if params.compression == LogSinkCompression::Zstd {
+                    request = request.header("Content-Encoding", "zstd");
+                }
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                if let Some(auth) = auth {
+                    request = auth.apply(request, &body)?;
+                }
+                let response = request.body(body).send().await?;
+                let status = response.status();
+                if !status.is_success() {
+                    let text = response.text().await.unwrap_or_default();
+                    anyhow::bail!("HTTP {status} {}", text.trim());
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("log sink client does not match its target"),
+        }
+    }
+}
+
+/// Encode a batch of records for storage in the overflow directory.
+/// Each record is prefixed by its length, as templated records are
+/// not guaranteed to be free of embedded newlines.
+fn encode_batch(batch: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![];
+    for record in batch {
+        data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        data.extend_from_slice(record);
+    }
+    data
+}
+
+fn decode_batch(mut data: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut batch = vec![];
+    while !data.is_empty() {
+        anyhow::ensure!(data.len() >= 4, "truncated record length");
+        let (len, remainder) = data.split_at(4);
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        anyhow::ensure!(remainder.len() >= len, "truncated record");
+        let (record, remainder) = remainder.split_at(len);
+        batch.push(record.to_vec());
+        data = remainder;
+    }
+    Ok(batch)
+}
+
+/// The on-disk buffer of batches that are waiting to be published
+struct Overflow {
+    dir: PathBuf,
+    max_size: u64,
+    files: VecDeque<(PathBuf, u64)>,
+    total_size: u64,
+    seq: u64,
+}
+
+impl Overflow {
+    fn open(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating overflow directory {}", dir.display()))?;
+
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("reading dir {dir:?}"))? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(name) if name.starts_with('.') => {
+                    // An incomplete write from a prior run
+                    std::fs::remove_file(entry.path()).ok();
+                }
+                None => continue,
+                Some(_name) => {
+                    let metadata = entry.metadata()?;
+                    if metadata.is_file() {
+                        files.push((entry.path(), metadata.len()));
+                    }
+                }
+            }
+        }
+        // Names are timestamp based, so this puts the oldest first
+        files.sort();
+
+        Ok(Self {
+            dir,
+            max_size,
+            total_size: files.iter().map(|(_, size)| size).sum(),
+            files: files.into(),
+            seq: 0,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn push(&mut self, batch: &[Vec<u8>]) -> anyhow::Result<()> {
+        let data = encode_batch(batch);
+        let size = data.len() as u64;
+        anyhow::ensure!(
+            self.total_size + size <= self.max_size,
+            "overflow directory {} is full",
+            self.dir.display()
+        );
+
+        self.seq += 1;
+        let name = format!("{}-{:08}", Utc::now().format("%Y%m%d-%H%M%S%.6f"), self.seq);
+        let temp_path = self.dir.join(format!(".{name}"));
+        let path = self.dir.join(name);
+
+        std::fs::write(&temp_path, &data)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("renaming {} -> {}", temp_path.display(), path.display()))?;
+
+        self.files.push_back((path, size));
+        self.total_size += size;
+        Ok(())
+    }
+
+    fn front(&self) -> Option<anyhow::Result<Vec<Vec<u8>>>> {
+        let (path, _) = self.files.front()?;
+        Some(
+            std::fs::read(path)
+                .with_context(|| format!("reading {}", path.display()))
+                .and_then(|data| {
+                    decode_batch(&data).with_context(|| format!("decoding {}", path.display()))
+                }),
+        )
+    }
+
+    fn pop_front(&mut self) {
+        if let Some((path, size)) = self.files.pop_front() {
+            if let Err(err) = std::fs::remove_file(&path) {
+                tracing::error!("failed to remove {}: {err:#}", path.display());
+            }
+            self.total_size -= size;
+        }
+    }
+}
+
+pub(super) struct LogSinkState {
+    params: LogSinkParams,
+    receiver: Receiver<LogCommand>,
+    template_engine: Environment<'static>,
+    client: Option<SinkClient>,
+    batch: Vec<Vec<u8>>,
+    batch_deadline: Option<Instant>,
+    overflow: Option<Overflow>,
+    next_retry: Instant,
+}
+
+impl LogSinkState {
+    pub(super) fn new(
+        params: LogSinkParams,
+        receiver: Receiver<LogCommand>,
+        template_engine: Environment<'static>,
+    ) -> anyhow::Result<Self> {
+        let overflow = match &params.overflow_dir {
+            Some(dir) => Some(Overflow::open(dir.clone(), params.max_overflow_size)?),
+            None => None,
+        };
+        Ok(Self {
+            params,
+            receiver,
+            template_engine,
+            client: None,
+            batch: vec![],
+            batch_deadline: None,
+            overflow,
+            next_retry: Instant::now(),
+        })
+    }
+
+    fn has_backlog(&self) -> bool {
+        self.overflow
+            .as_ref()
+            .map(|overflow| !overflow.is_empty())
+            .unwrap_or(false)
+    }
+
+    fn get_deadline(&self) -> Option<Instant> {
+        let retry = if self.has_backlog() {
+            Some(self.next_retry)
+        } else {
+            None
+        };
+        match (self.batch_deadline, retry) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub(super) async fn logger_thread(&mut self) {
+        tracing::debug!("LogSinkParams: {:#?}", self.params);
+
+        loop {
+            let deadline = self.get_deadline();
+
+            let cmd = if let Some(deadline) = deadline {
+                tokio::select! {
+                    cmd = self.receiver.recv() => cmd,
+                    _ = tokio::time::sleep_until(deadline.into()) => {
+                        self.run_deadlines().await;
+                        continue;
+                    }
+                }
+            } else {
+                self.receiver.recv().await
+            };
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    break;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    tracing::debug!("LogCommand::Terminate received. Stopping log sink");
+                    break;
+                }
+                LogCommand::Record(record) => {
+                    if let Err(err) = self.do_record(record) {
+                        tracing::error!("failed to log: {err:#}");
+                    };
+                    if self.batch.len() >= self.params.batch_size {
+                        self.flush().await;
+                    }
+                }
+            }
+        }
+
+        tracing::debug!("Flushing any buffered records prior to completion");
+        self.flush().await;
+    }
+
+    async fn run_deadlines(&mut self) {
+        let now = Instant::now();
+        if self.batch_deadline.map(|d| d <= now).unwrap_or(false) {
+            self.flush().await;
+        }
+        if self.has_backlog() && self.next_retry <= now {
+            self.retry_overflow().await;
+        }
+    }
+
+    fn resolve_template<'a>(
+        params: &LogSinkParams,
+        template_engine: &'a Environment,
+        kind: RecordType,
+    ) -> Option<Template<'a, 'a>> {
+        if let Some(pr) = params.per_record.get(&kind) {
+            if pr.template.is_some() {
+                let label = format!("{kind:?}");
+                return template_engine.get_template(&label).ok();
+            }
+            return None;
+        }
+        if let Some(pr) = params.per_record.get(&RecordType::Any) {
+            if pr.template.is_some() {
+                return template_engine.get_template("Any").ok();
+            }
+        }
+        None
+    }
+
+    fn do_record(&mut self, mut record: JsonLogRecord) -> anyhow::Result<()> {
+        tracing::trace!("do_record {record:?}");
+
+        if let Some(classifier) = CLASSIFY.get() {
+            record.bounce_classification = classifier.classify_response(&record.response);
+        }
+
+        let mut record_text = Vec::new();
+        self.template_engine
+            .add_global("log_record", minijinja::Value::from_serialize(&record));
+
+        if let Some(template) =
+            Self::resolve_template(&self.params, &self.template_engine, record.kind)
+        {
+            template.render_to_write(&record, &mut record_text)?;
+        } else {
+            serde_json::to_writer(&mut record_text, &record).context("serializing record")?;
+        }
+        if record_text.last() != Some(&b'\n') {
+            record_text.push(b'\n');
+        }
+
+        if self.batch.is_empty() {
+            self.batch_deadline
+                .replace(Instant::now() + self.params.linger);
+        }
+        self.batch.push(record_text);
+        Ok(())
+    }
+
+    async fn publish(&mut self, batch: &[Vec<u8>]) -> anyhow::Result<()> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => SinkClient::connect(&self.params)
+                .await
+                .context("connecting to log sink target")?,
+        };
+        let result = client.publish(&self.params, batch).await;
+        // Discard the client on failure so that we reconnect
+        // on the next attempt
+        if result.is_ok() {
+            self.client.replace(client);
+        }
+        result
+    }
+
+    async fn flush(&mut self) {
+        self.batch_deadline.take();
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.batch);
+
+        if self.has_backlog() {
+            self.spill(&batch);
+            return;
+        }
+
+        match self.publish(&batch).await {
+            Ok(()) => {
+                PUBLISHED
+                    .with_label_values(&[&self.params.name])
+                    .inc_by(batch.len() as u64);
+            }
+            Err(err) => {
+                tracing::error!(
+                    "log sink {}: failed to publish batch of {} records: {err:#}",
+                    self.params.name,
+                    batch.len()
+                );
+                self.next_retry = Instant::now() + self.params.retry_interval;
+                self.spill(&batch);
+            }
+        }
+    }
+
+    /// Write a batch to the overflow directory, or discard it
+    /// if there is no overflow directory, or it is full
+    fn spill(&mut self, batch: &[Vec<u8>]) {
+        let result = match &mut self.overflow {
+            Some(overflow) => overflow.push(batch),
+            None => Err(anyhow::anyhow!("no overflow_dir is configured")),
+        };
+        match result {
+            Ok(()) => {
+                OVERFLOWED
+                    .with_label_values(&[&self.params.name])
+                    .inc_by(batch.len() as u64);
+            }
+            Err(err) => {
+                tracing::error!(
+                    "log sink {}: discarding batch of {} records: {err:#}",
+                    self.params.name,
+                    batch.len()
+                );
+                DROPPED
+                    .with_label_values(&[&self.params.name])
+                    .inc_by(batch.len() as u64);
+            }
+        }
+    }
+
+    /// Publish the batches held in the overflow directory, oldest first,
+    /// stopping at the first failure
+    async fn retry_overflow(&mut self) {
+        loop {
+            let batch = match self.overflow.as_ref().and_then(|overflow| overflow.front()) {
+                Some(Ok(batch)) => batch,
+                Some(Err(err)) => {
+                    tracing::error!("log sink {}: {err:#}", self.params.name);
+                    if let Some(overflow) = &mut self.overflow {
+                        overflow.pop_front();
+                    }
+                    continue;
+                }
+                None => return,
+            };
+
+            if let Err(err) = self.publish(&batch).await {
+                tracing::error!(
+                    "log sink {}: failed to publish batch from overflow: {err:#}",
+                    self.params.name
+                );
+                self.next_retry = Instant::now() + self.params.retry_interval;
+                return;
+            }
+
+            PUBLISHED
+                .with_label_values(&[&self.params.name])
+                .inc_by(batch.len() as u64);
+            if let Some(overflow) = &mut self.overflow {
+                overflow.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_encoding() {
+        let batch = vec![
+            b"{\"a\":1}\n".to_vec(),
+            b"multi\nline\n".to_vec(),
+            b"".to_vec(),
+        ];
+        let data = encode_batch(&batch);
+        assert_eq!(decode_batch(&data).unwrap(), batch);
+        assert!(decode_batch(&data[..data.len() - 1]).is_err());
+        assert!(decode_batch(&data[..2]).is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("overflow");
+        let first = vec![b"one\n".to_vec(), b"two\n".to_vec()];
+        let second = vec![b"three\n".to_vec()];
+
+        let mut overflow = Overflow::open(dir.clone(), 1024).unwrap();
+        assert!(overflow.is_empty());
+        overflow.push(&first).unwrap();
+        overflow.push(&second).unwrap();
+
+        // Batches persist across restarts, in order
+        let mut overflow = Overflow::open(dir.clone(), 1024).unwrap();
+        assert_eq!(overflow.front().unwrap().unwrap(), first);
+        overflow.pop_front();
+        assert_eq!(overflow.front().unwrap().unwrap(), second);
+        overflow.pop_front();
+        assert!(overflow.front().is_none());
+        assert_eq!(overflow.total_size, 0);
+
+        // Respects the size limit
+        let mut overflow = Overflow::open(dir.clone(), 10).unwrap();
+        assert!(overflow.push(&first).is_err());
+        assert!(overflow.is_empty());
+    }
+}
@@ -17,18 +17,27 @@ pub static VALIDATE_SIG: Lazy<CallbackSignature<(), ()>> =
     Lazy::new(|| CallbackSignature::new_with_multiple("validate_config"));
 
 mod accounting;
+mod attempt_history;
+mod classify_suggest;
+mod correlation;
 mod delivery_metrics;
 mod egress_source;
+mod http_deliver;
 mod http_server;
+mod latency_metrics;
 mod logging;
 mod lua_deliver;
+mod message_trace;
 mod metrics_helper;
 mod mod_kumo;
+mod pressure;
 mod queue;
 mod ready_queue;
 mod smtp_dispatcher;
 mod smtp_server;
 mod spool;
+mod suppression;
+mod warmup;
 
 /// KumoMTA Daemon.
 ///
@@ -71,6 +80,11 @@ struct Opt {
     #[arg(long, default_value = "full")]
     diag_format: DiagnosticFormat,
 
+    /// If set, export a trace for each message to the OTLP/HTTP
+    /// collector at this URL, such as `http://localhost:4318/v1/traces`.
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
     /// Instead of running the daemon, output the openapi spec json
     /// to stdout.
     #[arg(long)]
@@ -226,6 +240,7 @@ fn perform_init(opts: Opt) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>>
 
             LifeCycle::request_shutdown().await;
         } else {
+            crate::pressure::start_monitor().context("start pressure monitor")?;
             crate::spool::SpoolManager::get()
                 .start_spool()
                 .await
@@ -249,6 +264,8 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
             } else {
                 "kumod=info,kumo_server_common=info,kumo_server_runtime=info"
             },
+            otlp_endpoint: opts.otlp_endpoint.clone(),
+            service_name: "kumod",
         },
         lua_funcs: &[
             kumo_server_common::register,
@@ -256,6 +273,7 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
             crate::spool::register,
             crate::logging::register,
             message::dkim::register,
+            message::verp::register,
         ],
         policy: &opts.policy,
     }
@@ -0,0 +1,134 @@
+//! This module produces the spans that make up the trace of a message
+//! when OTLP export has been enabled via `--otlp-endpoint`.
+//!
+//! A trace is started when a message is received. Its W3C `traceparent`
+//! is stored in the `trace_context` meta key, so that the scheduling and
+//! delivery spans that happen later, possibly after a restart, can be
+//! attached to the same trace.
+//!
+//! All of the spans use the `message_trace` target, which is the only
+//! target exported via OTLP. When OTLP is not enabled, the helpers return
+//! disabled spans so that the cost is negligible.
+use kumo_server_common::diagnostic_logging::{otlp_enabled, MESSAGE_TRACE_TARGET};
+use message::Message;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The meta key that holds the trace context of a message
+pub const TRACE_CONTEXT_META: &str = "trace_context";
+
+/// The key used by the W3C trace context propagator
+const TRACEPARENT: &str = "traceparent";
+
+/// Start the trace for a newly received message.
+/// The trace context is recorded in the meta of the message.
+pub fn reception_span(msg: &Message, protocol: &str) -> Span {
+    if !otlp_enabled() {
+        return Span::none();
+    }
+    let span = tracing::info_span!(
+        target: MESSAGE_TRACE_TARGET,
+        parent: None,
+        "reception",
+        id = %msg.id(),
+        protocol,
+    );
+
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+    if let Some(traceparent) = carrier.remove(TRACEPARENT) {
+        if let Err(err) = msg.set_meta(TRACE_CONTEXT_META, traceparent) {
+            tracing::debug!("failed to record trace context for {}: {err:#}", msg.id());
+        }
+    }
+
+    span
+}
+
+/// Attach `span` to the trace recorded in the meta of the message.
+/// Returns a disabled span if the message has no trace context,
+/// such as when it was received before OTLP export was enabled.
+fn join_trace(msg: &Message, span: Span) -> Span {
+    let traceparent = match msg.get_meta_string(TRACE_CONTEXT_META) {
+        Ok(Some(traceparent)) => traceparent,
+        _ => return Span::none(),
+    };
+    let carrier: HashMap<String, String> = [(TRACEPARENT.to_string(), traceparent)]
+        .into_iter()
+        .collect();
+    let context = TraceContextPropagator::new().extract(&carrier);
+    span.set_parent(context);
+    span
+}
+
+/// A span covering a lua policy callback made on behalf of the message
+pub fn lua_span(parent: &Span, callback: &str) -> Span {
+    if parent.is_disabled() {
+        return Span::none();
+    }
+    tracing::info_span!(target: MESSAGE_TRACE_TARGET, parent: parent, "lua_callback", callback)
+}
+
+/// A span covering a lua policy callback made on behalf of the message
+/// outside of reception, such as when it is being scheduled or requeued
+pub fn message_lua_span(msg: &Message, callback: &str) -> Span {
+    if !otlp_enabled() {
+        return Span::none();
+    }
+    join_trace(
+        msg,
+        tracing::info_span!(
+            target: MESSAGE_TRACE_TARGET,
+            parent: None,
+            "lua_callback",
+            callback
+        ),
+    )
+}
+
+/// A span covering writing the message to the spool
+pub fn spool_span(parent: &Span) -> Span {
+    if parent.is_disabled() {
+        return Span::none();
+    }
+    tracing::info_span!(target: MESSAGE_TRACE_TARGET, parent: parent, "spool_store")
+}
+
+/// A span covering the insertion of the message into a scheduled queue
+pub fn schedule_span(msg: &Message, queue_name: &str) -> Span {
+    if !otlp_enabled() {
+        return Span::none();
+    }
+    join_trace(
+        msg,
+        tracing::info_span!(
+            target: MESSAGE_TRACE_TARGET,
+            parent: None,
+            "schedule",
+            queue = queue_name,
+            num_attempts = msg.get_num_attempts(),
+        ),
+    )
+}
+
+/// A span covering a delivery attempt. The SMTP transaction
+/// phases are recorded as children of this span, and the outcome of
+/// the attempt is recorded as an event by log_disposition.
+pub fn attempt_span(msg: &Message, ready_queue_name: &str) -> Span {
+    if !otlp_enabled() {
+        return Span::none();
+    }
+    join_trace(
+        msg,
+        tracing::info_span!(
+            target: MESSAGE_TRACE_TARGET,
+            parent: None,
+            "delivery_attempt",
+            queue = ready_queue_name,
+            attempt = msg.get_num_attempts() + 1,
+        ),
+    )
+}
@@ -13,6 +13,19 @@ lazy_static::lazy_static! {
             "total number of active connections ever made",
             &["service"]).unwrap()
     };
+    pub static ref TOTAL_CONN_REUSED: IntCounterVec = {
+        prometheus::register_int_counter_vec!(
+            "total_connection_reuse_count",
+            "total number of messages sent over an already established connection",
+            &["service"]).unwrap()
+    };
+    pub static ref TOTAL_CONN_LINGER_REUSED: IntCounterVec = {
+        prometheus::register_int_counter_vec!(
+            "total_connection_linger_reuse_count",
+            "total number of times an idle connection, kept open \
+             by idle_connection_linger, was reused",
+            &["service"]).unwrap()
+    };
     pub static ref TOTAL_MSGS_DELIVERED: IntCounterVec = {
         prometheus::register_int_counter_vec!(
             "total_messages_delivered",
@@ -71,6 +84,18 @@ pub fn connection_total_for_service(service: &str) -> IntCounter {
     TOTAL_CONN.get_metric_with_label_values(&[service]).unwrap()
 }
 
+pub fn connection_reuse_for_service(service: &str) -> IntCounter {
+    TOTAL_CONN_REUSED
+        .get_metric_with_label_values(&[service])
+        .unwrap()
+}
+
+pub fn connection_linger_reuse_for_service(service: &str) -> IntCounter {
+    TOTAL_CONN_LINGER_REUSED
+        .get_metric_with_label_values(&[service])
+        .unwrap()
+}
+
 pub fn total_msgs_received_for_service(service: &str) -> IntCounter {
     TOTAL_MSGS_RECVD
         .get_metric_with_label_values(&[service])
@@ -112,6 +137,10 @@ pub fn total_msgs_fail_for_service(service: &str) -> IntCounter {
 pub fn remove_metrics_for_service(service: &str) {
     CONN_GAUGE.remove_label_values(&[service]).ok();
     TOTAL_CONN.remove_label_values(&[service]).ok();
+    TOTAL_CONN_REUSED.remove_label_values(&[service]).ok();
+    TOTAL_CONN_LINGER_REUSED
+        .remove_label_values(&[service])
+        .ok();
     TOTAL_MSGS_DELIVERED.remove_label_values(&[service]).ok();
     TOTAL_MSGS_TRANSFAIL.remove_label_values(&[service]).ok();
     TOTAL_MSGS_FAIL.remove_label_values(&[service]).ok();
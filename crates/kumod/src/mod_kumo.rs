@@ -1,6 +1,11 @@
+use crate::attempt_history::AttemptHistoryParams;
+use crate::correlation::CorrelationParams;
 use crate::egress_source::{EgressPool, EgressSource};
-use crate::queue::QueueConfig;
+use crate::latency_metrics::LatencyMetricsParams;
+use crate::pressure::LoadSheddingParams;
+use crate::queue::{DeliveryProto, QueueConfig};
 use crate::smtp_server::{EsmtpDomain, EsmtpListenerParams, RejectError};
+use crate::suppression::SuppressionParams;
 use config::{any_err, from_lua_value, get_or_create_module};
 use kumo_api_types::egress_path::EgressPathConfig;
 use kumo_server_common::http_server::HttpListenerParams;
@@ -20,6 +25,8 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     crate::logging::SHOULD_ENQ_LOG_RECORD_SIG.register();
     crate::PRE_INIT_SIG.register();
     crate::VALIDATE_SIG.register();
+    crate::http_server::admin_bounce_v1::register(lua)?;
+    crate::http_server::admin_reroute_v1::register(lua)?;
     crate::http_server::admin_suspend_ready_q_v1::register(lua)?;
     crate::http_server::admin_suspend_v1::register(lua)?;
     crate::http_server::inject_v1::register(lua)?;
@@ -113,6 +120,9 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "make_queue_config",
         lua.create_function(move |lua, params: Value| {
             let config: QueueConfig = from_lua_value(lua, params)?;
+            if let DeliveryProto::Http { http } = &config.protocol {
+                http.validate().map_err(any_err)?;
+            }
             Ok(config)
         })?,
     )?;
@@ -121,6 +131,9 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "make_egress_source",
         lua.create_function(move |lua, params: Value| {
             let source: EgressSource = from_lua_value(lua, params)?;
+            if let Some(plan) = &source.warmup {
+                plan.validate().map_err(any_err)?;
+            }
             Ok(source)
         })?,
     )?;
@@ -142,6 +155,48 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_attempt_history",
+        lua.create_function(|lua, params: Value| {
+            let params: AttemptHistoryParams = from_lua_value(lua, params)?;
+            crate::attempt_history::configure(params);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_latency_metrics",
+        lua.create_function(|lua, params: Value| {
+            let params: LatencyMetricsParams = from_lua_value(lua, params)?;
+            crate::latency_metrics::configure(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_report_correlation",
+        lua.create_function(|lua, params: Value| {
+            let params: CorrelationParams = from_lua_value(lua, params)?;
+            crate::correlation::configure(params);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_suppression",
+        lua.create_function(|lua, params: Value| {
+            let params: SuppressionParams = from_lua_value(lua, params)?;
+            crate::suppression::configure(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_load_shedding",
+        lua.create_function(|lua, params: Value| {
+            let params: LoadSheddingParams = from_lua_value(lua, params)?;
+            crate::pressure::configure(params).map_err(any_err)
+        })?,
+    )?;
+
     kumo_mod.set(
         "make_throttle",
         lua.create_function(move |_lua, (name, spec): (String, String)| {
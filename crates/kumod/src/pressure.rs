@@ -0,0 +1,326 @@
+//! This module computes a unified "system pressure" signal that
+//! combines memory usage, spool storage utilization and the total
+//! number of messages in the ready queues.
+//!
+//! Each component is expressed as a ratio, where 1.0 represents the
+//! configured limit for that component, and the overall pressure is
+//! the largest of those ratios. The reception paths consult the
+//! resulting `PressureLevel` in order to shed load with temporary
+//! failures before the system is overwhelmed.
+use crate::ready_queue::ReadyQueueManager;
+use crate::spool::SpoolManager;
+use kumo_server_lifecycle::ShutdownSubcription;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref PRESSURE: Gauge = prometheus::register_gauge!(
+        "system_pressure",
+        "overall system pressure; 1.0 corresponds to the configured limit").unwrap();
+    static ref PRESSURE_LEVEL: IntGauge = prometheus::register_int_gauge!(
+        "system_pressure_level",
+        "system pressure level: 0=normal, 1=elevated (tempfailing transactions), \
+         2=critical (rejecting connections)").unwrap();
+    static ref PRESSURE_COMPONENT: GaugeVec = prometheus::register_gauge_vec!(
+        "system_pressure_component",
+        "system pressure by component; 1.0 corresponds to the configured limit",
+        &["component"]).unwrap();
+    static ref SHED_COUNT: IntCounterVec = prometheus::register_int_counter_vec!(
+        "load_shedding_rejection_count",
+        "number of connections, transactions or requests rejected due to system pressure",
+        &["service"]).unwrap();
+}
+
+static PARAMS: Lazy<Mutex<LoadSheddingParams>> =
+    Lazy::new(|| Mutex::new(LoadSheddingParams::default()));
+static LEVEL: AtomicU8 = AtomicU8::new(PressureLevel::Normal as u8);
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LoadSheddingParams {
+    /// When the overall pressure reaches this ratio, new
+    /// transactions are temporarily rejected
+    #[serde(default = "LoadSheddingParams::default_tempfail_threshold")]
+    pub tempfail_threshold: f64,
+
+    /// When the overall pressure reaches this ratio, new
+    /// connections are rejected
+    #[serde(default = "LoadSheddingParams::default_reject_threshold")]
+    pub reject_threshold: f64,
+
+    /// The fraction of the spool storage that may be used before
+    /// the spool component is considered to be at its limit
+    #[serde(default = "LoadSheddingParams::default_max_spool_disk_usage")]
+    pub max_spool_disk_usage: f64,
+
+    /// The total number of messages across all ready queues that is
+    /// considered to be the limit. If unset, ready queue depth does
+    /// not contribute to the pressure.
+    #[serde(default)]
+    pub max_ready_count: Option<usize>,
+
+    /// How often to re-evaluate the pressure
+    #[serde(
+        default = "LoadSheddingParams::default_check_interval",
+        with = "duration_serde"
+    )]
+    pub check_interval: Duration,
+}
+
+impl Default for LoadSheddingParams {
+    fn default() -> Self {
+        Self {
+            tempfail_threshold: Self::default_tempfail_threshold(),
+            reject_threshold: Self::default_reject_threshold(),
+            max_spool_disk_usage: Self::default_max_spool_disk_usage(),
+            max_ready_count: None,
+            check_interval: Self::default_check_interval(),
+        }
+    }
+}
+
+impl LoadSheddingParams {
+    fn default_tempfail_threshold() -> f64 {
+        0.9
+    }
+
+    fn default_reject_threshold() -> f64 {
+        1.0
+    }
+
+    fn default_max_spool_disk_usage() -> f64 {
+        0.95
+    }
+
+    fn default_check_interval() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.tempfail_threshold > 0.0 && self.reject_threshold > 0.0,
+            "tempfail_threshold and reject_threshold must be positive"
+        );
+        anyhow::ensure!(
+            self.tempfail_threshold <= self.reject_threshold,
+            "tempfail_threshold ({}) must not be larger than reject_threshold ({})",
+            self.tempfail_threshold,
+            self.reject_threshold
+        );
+        anyhow::ensure!(
+            self.max_spool_disk_usage > 0.0 && self.max_spool_disk_usage <= 1.0,
+            "max_spool_disk_usage must be in the range (0.0, 1.0]"
+        );
+        anyhow::ensure!(
+            self.max_ready_count != Some(0),
+            "max_ready_count must be larger than zero"
+        );
+        Ok(())
+    }
+}
+
+pub fn configure(params: LoadSheddingParams) -> anyhow::Result<()> {
+    params.validate()?;
+    *PARAMS.lock() = params;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[repr(u8)]
+pub enum PressureLevel {
+    /// Operating normally
+    Normal = 0,
+    /// New transactions should be temporarily rejected
+    Elevated = 1,
+    /// New connections should be rejected
+    Critical = 2,
+}
+
+impl PressureLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Self::Normal,
+            1 => Self::Elevated,
+            _ => Self::Critical,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SystemPressure {
+    /// Memory usage relative to the soft memory limit
+    pub memory: f64,
+    /// Spool storage usage relative to max_spool_disk_usage
+    pub spool: f64,
+    /// Total ready queue depth relative to max_ready_count
+    pub ready: f64,
+}
+
+impl SystemPressure {
+    pub fn overall(&self) -> f64 {
+        self.memory.max(self.spool).max(self.ready)
+    }
+
+    pub fn level(&self, params: &LoadSheddingParams) -> PressureLevel {
+        let overall = self.overall();
+        if overall >= params.reject_threshold {
+            PressureLevel::Critical
+        } else if overall >= params.tempfail_threshold {
+            PressureLevel::Elevated
+        } else {
+            PressureLevel::Normal
+        }
+    }
+}
+
+/// Returns the current pressure level
+pub fn current_level() -> PressureLevel {
+    PressureLevel::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Returns the counter that tracks the number of rejections
+/// due to load shedding for the given service
+pub fn shed_counter_for_service(service: &str) -> IntCounter {
+    SHED_COUNT.get_metric_with_label_values(&[service]).unwrap()
+}
+
+/// Returns the fraction of the filesystem holding `path` that is in use
+fn disk_usage(path: &Path) -> anyhow::Result<f64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    let total = stat.blocks() as f64;
+    if total == 0.0 {
+        return Ok(0.0);
+    }
+    let available = stat.blocks_available() as f64;
+    Ok(1.0 - (available / total))
+}
+
+async fn compute_pressure(params: &LoadSheddingParams) -> SystemPressure {
+    let memory = match kumo_server_memory::get_usage_and_limit() {
+        Ok((usage, limits)) => match limits.soft_limit {
+            Some(limit) if limit > 0 => usage.bytes as f64 / limit as f64,
+            _ => 0.0,
+        },
+        Err(err) => {
+            tracing::error!("unable to query memory info: {err:#}");
+            0.0
+        }
+    };
+
+    let mut spool: f64 = 0.0;
+    for path in SpoolManager::get().spool_paths().await {
+        match disk_usage(&path) {
+            Ok(usage) => {
+                spool = spool.max(usage / params.max_spool_disk_usage);
+            }
+            Err(err) => {
+                tracing::error!("unable to query disk usage for {}: {err:#}", path.display());
+            }
+        }
+    }
+
+    let ready = match params.max_ready_count {
+        Some(max) => ReadyQueueManager::total_ready_count() as f64 / max as f64,
+        None => 0.0,
+    };
+
+    SystemPressure {
+        memory,
+        spool,
+        ready,
+    }
+}
+
+async fn update_pressure() -> Duration {
+    let params = PARAMS.lock().clone();
+    let pressure = compute_pressure(&params).await;
+    let level = pressure.level(&params);
+
+    let prior = PressureLevel::from_u8(LEVEL.swap(level as u8, Ordering::Relaxed));
+    if prior != level {
+        tracing::warn!("system pressure level changed from {prior:?} to {level:?}: {pressure:?}");
+    }
+
+    PRESSURE.set(pressure.overall());
+    PRESSURE_LEVEL.set(level as i64);
+    for (component, value) in [
+        ("memory", pressure.memory),
+        ("spool", pressure.spool),
+        ("ready", pressure.ready),
+    ] {
+        PRESSURE_COMPONENT
+            .with_label_values(&[component])
+            .set(value);
+    }
+
+    params.check_interval
+}
+
+/// Spawn the task that periodically re-evaluates the system pressure
+pub fn start_monitor() -> anyhow::Result<()> {
+    kumo_server_runtime::spawn("system pressure monitor", async move {
+        let mut shutdown = ShutdownSubcription::get();
+        loop {
+            let interval = update_pressure().await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.shutting_down() => return,
+            };
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pressure_level() {
+        let params = LoadSheddingParams::default();
+
+        let pressure = SystemPressure {
+            memory: 0.5,
+            spool: 0.2,
+            ready: 0.0,
+        };
+        assert_eq!(pressure.overall(), 0.5);
+        assert_eq!(pressure.level(&params), PressureLevel::Normal);
+
+        let pressure = SystemPressure {
+            memory: 0.5,
+            spool: 0.95,
+            ready: 0.0,
+        };
+        assert_eq!(pressure.level(&params), PressureLevel::Elevated);
+
+        let pressure = SystemPressure {
+            memory: 0.5,
+            spool: 0.2,
+            ready: 1.5,
+        };
+        assert_eq!(pressure.level(&params), PressureLevel::Critical);
+    }
+
+    #[test]
+    fn validate_params() {
+        assert!(LoadSheddingParams::default().validate().is_ok());
+        assert!(LoadSheddingParams {
+            tempfail_threshold: 1.1,
+            ..LoadSheddingParams::default()
+        }
+        .validate()
+        .is_err());
+        assert!(LoadSheddingParams {
+            max_ready_count: Some(0),
+            ..LoadSheddingParams::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
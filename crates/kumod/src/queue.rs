@@ -1,19 +1,22 @@
 use crate::egress_source::{EgressPool, EgressPoolRoundRobin, RoundRobinResult};
+use crate::http_deliver::HttpDeliveryProtocol;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
 use crate::http_server::admin_rebind_v1::AdminRebindEntry;
+use crate::http_server::admin_reroute_v1::AdminRerouteEntry;
 use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
 use crate::logging::{log_disposition, LogDisposition, RecordType};
 use crate::lua_deliver::LuaDeliveryProtocol;
-use crate::ready_queue::{ReadyQueueManager, ReadyQueueName};
+use crate::ready_queue::{ReadyQueueHandle, ReadyQueueManager, ReadyQueueName};
 use crate::smtp_dispatcher::SmtpProtocol;
 use crate::spool::SpoolManager;
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use config::{load_config, CallbackSignature, LuaConfig};
 use kumo_server_common::config_handle::ConfigHandle;
 use kumo_server_lifecycle::{Activity, ShutdownSubcription};
 use kumo_server_runtime::{spawn, spawn_blocking, Runtime};
 use message::message::QueueNameComponents;
+use message::scheduling::SendingWindow;
 use message::Message;
 use mlua::prelude::*;
 use parking_lot::FairMutex as StdMutex;
@@ -28,7 +31,7 @@ use thiserror::Error;
 use throttle::{ThrottleResult, ThrottleSpec};
 use timeq::{PopResult, TimeQ, TimerError};
 use tokio::sync::Notify;
-use tracing::instrument;
+use tracing::{instrument, Instrument};
 
 lazy_static::lazy_static! {
     static ref MANAGER: StdMutex<QueueManager> = StdMutex::new(QueueManager::new());
@@ -62,6 +65,11 @@ lazy_static::lazy_static! {
             "number of times a message was delayed due to max_message_rate",
             &["queue"]).unwrap()
     };
+    static ref DELAY_DUE_TO_SENDING_WINDOW_COUNTER: IntCounterVec = {
+        prometheus::register_int_counter_vec!("delayed_due_to_sending_window",
+            "number of times a message was delayed due to a sending_window",
+            &["queue"]).unwrap()
+    };
     static ref DELAY_DUE_TO_THROTTLE_INSERT_READY_COUNTER: IntCounterVec = {
         prometheus::register_int_counter_vec!("delayed_due_to_throttle_insert_ready",
             "number of times a message was delayed due throttle_insert_ready_queue event",
@@ -90,6 +98,7 @@ struct ScheduledMetrics {
     by_tenant_campaign: Option<IntGauge>,
     delay_full: IntCounter,
     delay_message_rate: IntCounter,
+    delay_sending_window: IntCounter,
     delay_throttle_insert_ready: IntCounter,
 }
 
@@ -115,6 +124,8 @@ impl ScheduledMetrics {
             DELAY_DUE_TO_READY_QUEUE_FULL_COUNTER.get_metric_with_label_values(&[name])?;
         let delay_message_rate =
             DELAY_DUE_TO_MESSAGE_RATE_THROTTLE_COUNTER.get_metric_with_label_values(&[name])?;
+        let delay_sending_window =
+            DELAY_DUE_TO_SENDING_WINDOW_COUNTER.get_metric_with_label_values(&[name])?;
         let delay_throttle_insert_ready =
             DELAY_DUE_TO_THROTTLE_INSERT_READY_COUNTER.get_metric_with_label_values(&[name])?;
 
@@ -125,6 +136,7 @@ impl ScheduledMetrics {
             by_tenant_campaign,
             delay_full,
             delay_message_rate,
+            delay_sending_window,
             delay_throttle_insert_ready,
         })
     }
@@ -154,6 +166,7 @@ pub enum DeliveryProto {
     Smtp { smtp: SmtpProtocol },
     Maildir { maildir_path: std::path::PathBuf },
     Lua { custom_lua: LuaDeliveryProtocol },
+    Http { http: HttpDeliveryProtocol },
 }
 
 impl DeliveryProto {
@@ -162,6 +175,7 @@ impl DeliveryProto {
             Self::Smtp { .. } => "smtp_client",
             Self::Maildir { .. } => "maildir",
             Self::Lua { .. } => "lua",
+            Self::Http { .. } => "http",
         }
     }
 
@@ -171,6 +185,7 @@ impl DeliveryProto {
             Self::Smtp { .. } => proto_name.to_string(),
             Self::Maildir { maildir_path } => format!("{proto_name}:{}", maildir_path.display()),
             Self::Lua { custom_lua } => format!("{proto_name}:{}", custom_lua.constructor),
+            Self::Http { http } => format!("{proto_name}:{}", http.url),
         }
     }
 }
@@ -213,6 +228,11 @@ pub struct QueueConfig {
     #[serde(default)]
     pub max_message_rate: Option<ThrottleSpec>,
 
+    /// Restricts the times at which messages are allowed to move
+    /// from the scheduled queue and into the ready queue
+    #[serde(default)]
+    pub sending_window: Option<SendingWindow>,
+
     #[serde(default)]
     pub protocol: DeliveryProto,
 }
@@ -228,6 +248,7 @@ impl Default for QueueConfig {
             egress_pool: None,
             protocol: DeliveryProto::default(),
             max_message_rate: None,
+            sending_window: None,
         }
     }
 }
@@ -297,6 +318,89 @@ impl QueueConfig {
     }
 }
 
+/// Makes `msg` due at `next`, when its sending_window next opens.
+/// Being held by a sending_window is not a delivery attempt, so the
+/// number of attempts is left unchanged.
+async fn delay_for_sending_window(
+    msg: &Message,
+    next: DateTime<Utc>,
+    reason: String,
+) -> anyhow::Result<()> {
+    msg.set_due(Some(next)).await?;
+    crate::attempt_history::record_throttle(msg, reason);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use chrono::{Datelike, NaiveTime};
+    use message::scheduling::DaysOfWeek;
+
+    /// Creates a queue named `name`, without consulting the lua
+    /// configuration, and registers it with the QueueManager
+    pub fn make_queue(name: &str, queue_config: QueueConfig) -> QueueHandle {
+        static LIFE_CYCLE: std::sync::Once = std::sync::Once::new();
+        LIFE_CYCLE.call_once(|| std::mem::forget(kumo_server_lifecycle::LifeCycle::new()));
+
+        let pool = EgressPool {
+            name: "unspecified".to_string(),
+            entries: vec![],
+            ttl: Duration::from_secs(60),
+        };
+        let handle = Arc::new(Queue {
+            name: name.to_string(),
+            queue: StdMutex::new(TimeQ::new()),
+            last_change: StdMutex::new(Instant::now()),
+            queue_config: ConfigHandle::new(queue_config),
+            metrics: ScheduledMetrics::new(name).unwrap(),
+            activity: Activity::get(format!("Queue {name}")).unwrap(),
+            rr: EgressPoolRoundRobin::new(&pool),
+            reroute_rr: StdMutex::new(None),
+            ready_queue_names: StdMutex::new(HashMap::new()),
+        });
+        MANAGER
+            .lock()
+            .named
+            .insert(name.to_string(), QueueSlot::Handle(handle.clone()));
+        handle
+    }
+
+    /// Returns the number of messages in the scheduled queue
+    pub fn scheduled_count(queue: &Queue) -> usize {
+        queue.queue.lock().len()
+    }
+
+    /// Creates a message, as though it had been loaded from the
+    /// spool, for the scheduled queue named `queue_name`
+    pub fn make_message(queue_name: &str) -> Message {
+        let meta = serde_json::json!({
+            "sender": "sender@example.com",
+            "recipient": "recip@example.com",
+            "meta": {"queue": queue_name},
+        });
+        Message::new_from_spool(spool::SpoolId::new(), serde_json::to_vec(&meta).unwrap()).unwrap()
+    }
+
+    /// Returns a window that is open only on the day after tomorrow,
+    /// along with the time at which it opens
+    pub fn closed_window() -> (SendingWindow, DateTime<Utc>) {
+        let opens = (Utc::now() + chrono::Duration::try_days(2).unwrap())
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc();
+        let window = SendingWindow {
+            days_of_week: DaysOfWeek::from(opens.weekday()),
+            timezone: None,
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+            timezone_by_domain: Default::default(),
+        };
+        (window, opens)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -488,6 +592,35 @@ mod test {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn queue_sending_window_holds_message() {
+        let (window, opens) = test_support::closed_window();
+        let name = format!("sending-window-{}.example.com", uuid::Uuid::new_v4());
+        let queue = test_support::make_queue(
+            &name,
+            QueueConfig {
+                sending_window: Some(window),
+                ..Default::default()
+            },
+        );
+
+        let msg = test_support::make_message(&name);
+        msg.set_num_attempts(2);
+        queue.insert(msg.clone()).await.unwrap();
+
+        // Rather than being promoted to a ready queue, the message
+        // is held in the scheduled queue until the window opens
+        assert_eq!(test_support::scheduled_count(&queue), 1);
+        assert_eq!(msg.get_due(), Some(opens));
+        assert_eq!(msg.get_num_attempts(), 2);
+        let (_, throttle) = crate::attempt_history::lookup(msg.id());
+        assert_eq!(
+            throttle.unwrap().reason,
+            format!("sending_window for scheduled queue {name}")
+        );
+        QueueManager::remove(&name);
+    }
 }
 
 #[derive(Error, Debug)]
@@ -510,6 +643,9 @@ pub struct Queue {
     metrics: ScheduledMetrics,
     activity: Activity,
     rr: EgressPoolRoundRobin,
+    /// The round robin for the egress pool named by the
+    /// AdminRerouteEntry that currently applies to this queue
+    reroute_rr: StdMutex<Option<Arc<EgressPoolRoundRobin>>>,
     ready_queue_names: StdMutex<HashMap<String, Arc<CachedReadyQueueName>>>,
 }
 
@@ -540,7 +676,7 @@ impl Queue {
         let queue_config = Self::call_get_queue_config(&name, &mut config).await?;
 
         let pool = EgressPool::resolve(queue_config.egress_pool.as_deref(), &mut config).await?;
-        let rr = EgressPoolRoundRobin::new(&pool);
+        let rr = EgressPoolRoundRobin::resolve(&pool, &mut config).await?;
 
         let metrics = ScheduledMetrics::new(&name)?;
 
@@ -554,6 +690,7 @@ impl Queue {
             metrics,
             activity,
             rr,
+            reroute_rr: StdMutex::new(None),
             ready_queue_names: StdMutex::new(HashMap::new()),
         });
 
@@ -603,6 +740,10 @@ impl Queue {
                         &REBIND_MESSAGE_SIG,
                         (msg.clone(), rebind.request.data.clone()),
                     )
+                    .instrument(crate::message_trace::message_lua_span(
+                        msg,
+                        "rebind_message",
+                    ))
                     .await
             } else {
                 for (k, v) in &rebind.request.data {
@@ -890,8 +1031,56 @@ impl Queue {
         }
     }
 
+    /// Returns the next time at which `window` permits sending to the
+    /// domain of this queue, or None if sending is permitted now
+    fn sending_window_next_permitted(
+        &self,
+        window: Option<&SendingWindow>,
+    ) -> Option<DateTime<Utc>> {
+        let components = QueueNameComponents::parse(&self.name);
+        window?.next_permitted(components.domain, Utc::now())
+    }
+
+    /// If `next` is set, holds `msg` in the scheduled queue until the
+    /// `label` sending_window next opens at `next`, and returns true.
+    /// The number of attempts is not incremented.
+    pub async fn hold_for_sending_window(
+        &self,
+        msg: &Message,
+        next: Option<DateTime<Utc>>,
+        label: &str,
+    ) -> anyhow::Result<bool> {
+        let next = match next {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+
+        tracing::trace!(
+            "{} outside of {label} sending_window, due={next:?}",
+            self.name
+        );
+        delay_for_sending_window(
+            msg,
+            next,
+            format!("sending_window for {label} {}", self.name),
+        )
+        .await?;
+        self.metrics.delay_sending_window.inc();
+        self.force_into_delayed(msg.clone()).await?;
+        Ok(true)
+    }
+
     #[instrument(skip(self, msg))]
     async fn insert_ready(&self, msg: Message) -> anyhow::Result<()> {
+        let next =
+            self.sending_window_next_permitted(self.queue_config.borrow().sending_window.as_ref());
+        if self
+            .hold_for_sending_window(&msg, next, "scheduled queue")
+            .await?
+        {
+            return Ok(());
+        }
+
         if let Some(result) = self.check_message_rate_throttle().await? {
             if let Some(delay) = result.retry_after {
                 tracing::trace!("{} throttled message rate, delay={delay:?}", self.name);
@@ -902,6 +1091,10 @@ impl Queue {
                 msg.delay_by(delay).await?;
 
                 self.metrics.delay_message_rate.inc();
+                crate::attempt_history::record_throttle(
+                    &msg,
+                    format!("max_message_rate for scheduled queue {}", self.name),
+                );
 
                 return self.force_into_delayed(msg).await;
             }
@@ -910,6 +1103,10 @@ impl Queue {
         let mut config = load_config().await?;
         config
             .async_call_callback(&THROTTLE_INSERT_READY_SIG, msg.clone())
+            .instrument(crate::message_trace::message_lua_span(
+                &msg,
+                "throttle_insert_ready_queue",
+            ))
             .await?;
         if let Some(due) = msg.get_due() {
             let now = Utc::now();
@@ -919,6 +1116,13 @@ impl Queue {
                     self.name
                 );
                 self.metrics.delay_throttle_insert_ready.inc();
+                crate::attempt_history::record_throttle(
+                    &msg,
+                    format!(
+                        "throttle_insert_ready_queue for scheduled queue {}",
+                        self.name
+                    ),
+                );
                 return self.force_into_delayed(msg).await;
             }
         }
@@ -943,6 +1147,13 @@ impl Queue {
         Ok(())
     }
 
+    /// Returns the name of the ready queue used for messages sent
+    /// via `source`, if it has been resolved
+    pub fn ready_queue_name_for_source(&self, source: &str) -> Option<String> {
+        self.get_ready_queue_for_source(source)
+            .map(|cached| cached.name.name.clone())
+    }
+
     fn get_ready_queue_for_source(&self, source: &str) -> Option<Arc<CachedReadyQueueName>> {
         let mut ready_queue_names = self.ready_queue_names.lock();
         let name = ready_queue_names.get(source)?;
@@ -977,13 +1188,68 @@ impl Queue {
         Ok(cached)
     }
 
+    /// Returns the round robin for the egress pool that this queue
+    /// has been rerouted to, if any
+    async fn reroute_rr(&self) -> anyhow::Result<Option<Arc<EgressPoolRoundRobin>>> {
+        let egress_pool = match AdminRerouteEntry::get_for_queue_name(&self.name) {
+            Some(entry) => entry.egress_pool,
+            None => {
+                self.reroute_rr.lock().take();
+                return Ok(None);
+            }
+        };
+
+        if let Some(rr) = self.reroute_rr.lock().as_ref() {
+            if rr.name == egress_pool {
+                return Ok(Some(rr.clone()));
+            }
+        }
+
+        let mut config = load_config().await?;
+        let pool = EgressPool::resolve(Some(&egress_pool), &mut config).await?;
+        let rr = Arc::new(EgressPoolRoundRobin::resolve(&pool, &mut config).await?);
+        self.reroute_rr.lock().replace(rr.clone());
+        Ok(Some(rr))
+    }
+
+    /// Moves `msg` into `site`, unless the sending_window of its
+    /// egress path holds it in the scheduled queue
+    async fn insert_into_ready_queue(
+        &self,
+        site: &ReadyQueueHandle,
+        msg: Message,
+    ) -> anyhow::Result<()> {
+        let components = QueueNameComponents::parse(&self.name);
+        let next = site.sending_window_next_permitted(components.domain);
+        if self
+            .hold_for_sending_window(&msg, next, "egress path")
+            .await?
+        {
+            return Ok(());
+        }
+        site.insert(msg).map_err(|_| ReadyQueueFull.into())
+    }
+
     #[instrument(skip(self, msg))]
     async fn insert_ready_impl(&self, msg: Message) -> anyhow::Result<()> {
         tracing::trace!("insert_ready {}", msg.id());
 
         match &self.queue_config.borrow().protocol {
-            DeliveryProto::Smtp { .. } | DeliveryProto::Lua { .. } => {
-                let egress_source = match self.rr.next(&self.name, &self.queue_config).await {
+            DeliveryProto::Smtp { .. } | DeliveryProto::Lua { .. } | DeliveryProto::Http { .. } => {
+                let rerouted = match self.reroute_rr().await {
+                    Ok(rerouted) => rerouted,
+                    Err(err) => {
+                        tracing::error!(
+                            "failed to resolve reroute for {}, \
+                             using its configured egress pool: {err:#}",
+                            self.name
+                        );
+                        None
+                    }
+                };
+                let rr = rerouted.as_deref().unwrap_or(&self.rr);
+
+                let egress_source = match rr.next(&self.name, &self.queue_config).await {
                     RoundRobinResult::Source(source) => source,
                     RoundRobinResult::Delay(duration) => {
                         log_disposition(LogDisposition {
@@ -999,7 +1265,8 @@ impl Queue {
                                     detail: 4,
                                 }),
                                 content: format!(
-                                    "all possible sources for {} are suspended",
+                                    "all possible sources for {} are suspended \
+                                     or have exhausted their warm-up plans",
                                     self.name
                                 ),
                                 command: None,
@@ -1030,7 +1297,7 @@ impl Queue {
                                 }),
                                 content: format!(
                                     "no non-zero-weighted sources available for {}. {:?}",
-                                    self.name, self.rr,
+                                    self.name, rr,
                                 ),
                                 command: None,
                             },
@@ -1049,7 +1316,7 @@ impl Queue {
                 // Hot path: use cached source -> ready queue mapping
                 let ready_name = self.compute_ready_queue_name(&egress_source).await?;
                 if let Some(site) = ReadyQueueManager::get_by_ready_queue_name(&ready_name.name) {
-                    return site.insert(msg).map_err(|_| ReadyQueueFull.into());
+                    return self.insert_into_ready_queue(&site, msg).await;
                 }
 
                 // Miss: compute and establish a new queue
@@ -1057,12 +1324,12 @@ impl Queue {
                     &self.name,
                     &self.queue_config,
                     &egress_source,
-                    &self.rr.name,
+                    &rr.name,
                 )
                 .await
                 {
                     Ok(site) => {
-                        return site.insert(msg).map_err(|_| ReadyQueueFull.into());
+                        return self.insert_into_ready_queue(&site, msg).await;
                     }
                     Err(err) => {
                         log_disposition(LogDisposition {
@@ -1240,7 +1507,8 @@ impl QueueManager {
     pub async fn insert(name: &str, msg: Message) -> anyhow::Result<()> {
         tracing::trace!("QueueManager::insert");
         let entry = Self::resolve(name).await?;
-        entry.insert(msg).await
+        let span = crate::message_trace::schedule_span(&msg, name);
+        entry.insert(msg).instrument(span).await
     }
 
     /// Resolve a scheduled queue name to a handle,
@@ -1,5 +1,6 @@
 use crate::delivery_metrics::DeliveryMetrics;
 use crate::egress_source::EgressSource;
+use crate::http_deliver::HttpQueueDispatcher;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
 use crate::http_server::admin_suspend_ready_q_v1::{
     AdminSuspendReadyQEntry, AdminSuspendReadyQEntryRef,
@@ -11,6 +12,7 @@ use crate::smtp_dispatcher::{MxListEntry, OpportunisticInsecureTlsHandshakeError
 use crate::spool::SpoolManager;
 use anyhow::Context;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use config::{load_config, CallbackSignature};
 use crossbeam_queue::ArrayQueue;
 use dns_resolver::MailExchanger;
@@ -24,7 +26,7 @@ use message::Message;
 use parking_lot::FairMutex as StdMutex;
 use prometheus::IntGauge;
 use rfc5321::{EnhancedStatusCode, Response};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -32,7 +34,7 @@ use std::time::{Duration, Instant};
 use throttle::limit::{LimitLease, LimitSpec};
 use tokio::sync::Notify;
 use tokio::task::JoinHandle;
-use tracing::instrument; // TODO move to here
+use tracing::{instrument, Instrument}; // TODO move to here
 
 lazy_static::lazy_static! {
     static ref MANAGER: StdMutex<ReadyQueueManager> = StdMutex::new(ReadyQueueManager::new());
@@ -43,6 +45,7 @@ lazy_static::lazy_static! {
 }
 
 static READYQ_THREADS: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_READY_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub fn set_readyq_threads(n: usize) {
     READYQ_THREADS.store(n, Ordering::SeqCst);
@@ -64,12 +67,14 @@ impl Fifo {
     pub fn push(&self, msg: Message) -> Result<(), Message> {
         self.queue.push(msg)?;
         self.count.inc();
+        TOTAL_READY_COUNT.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
     pub fn pop(&self) -> Option<Message> {
         let msg = self.queue.pop()?;
         self.count.dec();
+        TOTAL_READY_COUNT.fetch_sub(1, Ordering::Relaxed);
         Some(msg)
     }
 
@@ -79,6 +84,7 @@ impl Fifo {
             messages.push(msg);
         }
         self.count.sub(messages.len() as i64);
+        TOTAL_READY_COUNT.fetch_sub(messages.len(), Ordering::Relaxed);
         messages
     }
 
@@ -104,7 +110,6 @@ impl ReadyQueueName {
 
 pub struct ReadyQueueConfig {
     pub name: String,
-    #[allow(unused)]
     pub site_name: String,
     pub path_config: EgressPathConfig,
     pub egress_source: EgressSource,
@@ -114,6 +119,8 @@ pub struct ReadyQueueConfig {
 #[derive(Default)]
 pub struct ReadyQueueManager {
     queues: HashMap<String, ReadyQueueHandle>,
+    /// The names of the ready queues for each site name
+    sites: HashMap<String, HashSet<String>>,
 }
 
 impl ReadyQueueManager {
@@ -125,6 +132,11 @@ impl ReadyQueueManager {
         MANAGER.lock().queues.len()
     }
 
+    /// Returns the total number of messages across all ready queues
+    pub fn total_ready_count() -> usize {
+        TOTAL_READY_COUNT.load(Ordering::Relaxed)
+    }
+
     pub async fn compute_queue_name(
         queue_name: &str,
         queue_config: &ConfigHandle<QueueConfig>,
@@ -241,6 +253,31 @@ impl ReadyQueueManager {
         manager.queues.get(name).cloned()
     }
 
+    /// Wakes the maintainers of the ready queues for `site_name`, so
+    /// that they can use a site connection lease that was released
+    fn notify_site(site_name: &str) {
+        let manager = MANAGER.lock();
+        let names = match manager.sites.get(site_name) {
+            Some(names) => names,
+            None => return,
+        };
+        for name in names {
+            if let Some(queue) = manager.queues.get(name) {
+                queue.notify_maintainer.notify_one();
+            }
+        }
+    }
+
+    fn remove_queue(&mut self, name: &str, site_name: &str) {
+        self.queues.remove(name);
+        if let Some(names) = self.sites.get_mut(site_name) {
+            names.remove(name);
+            if names.is_empty() {
+                self.sites.remove(site_name);
+            }
+        }
+    }
+
     pub fn get_by_ready_queue_name(name: &ReadyQueueName) -> Option<ReadyQueueHandle> {
         Self::get_by_name(&name.name)
     }
@@ -253,7 +290,7 @@ impl ReadyQueueManager {
     ) -> anyhow::Result<ReadyQueueHandle> {
         let ReadyQueueConfig {
             name,
-            site_name: _,
+            site_name,
             path_config,
             egress_source,
             mx,
@@ -262,7 +299,12 @@ impl ReadyQueueManager {
         let mut manager = MANAGER.lock();
         let activity = Activity::get(format!("ReadyQueueHandle {name}"))?;
 
-        let handle = manager.queues.entry(name.clone()).or_insert_with(|| {
+        let ReadyQueueManager { queues, sites } = &mut *manager;
+        let handle = queues.entry(name.clone()).or_insert_with(|| {
+            sites
+                .entry(site_name.clone())
+                .or_default()
+                .insert(name.clone());
             let notify_maintainer = Arc::new(Notify::new());
             QMAINT_RUNTIME
                 .spawn_non_blocking(format!("maintain {name}"), {
@@ -281,6 +323,7 @@ impl ReadyQueueManager {
             let notify_dispatcher = Arc::new(Notify::new());
             Arc::new(ReadyQueue {
                 name: name.clone(),
+                site_name,
                 queue_name_for_config_change_purposes_only: queue_name.to_string(),
                 ready,
                 mx,
@@ -364,7 +407,7 @@ impl ReadyQueueManager {
                 let mut mgr = MANAGER.lock();
                 if queue.reapable(&last_change, &suspend) {
                     tracing::debug!("reaping site {name}");
-                    mgr.queues.remove(&name);
+                    mgr.remove_queue(&name, &queue.site_name);
                     drop(mgr);
 
                     queue.reinsert_ready_queue("reap").await;
@@ -398,6 +441,7 @@ pub type ReadyQueueHandle = Arc<ReadyQueue>;
 
 pub struct ReadyQueue {
     name: String,
+    site_name: String,
     queue_name_for_config_change_purposes_only: String,
     ready: Arc<Fifo>,
     mx: Option<Arc<MailExchanger>>,
@@ -413,12 +457,30 @@ pub struct ReadyQueue {
     egress_source: EgressSource,
 }
 
+/// Returns the next time at which the sending_window of `path_config`
+/// permits sending to `domain`, or None if sending is permitted now
+fn sending_window_next_permitted(
+    path_config: &EgressPathConfig,
+    domain: &str,
+) -> Option<DateTime<Utc>> {
+    path_config
+        .sending_window
+        .as_ref()?
+        .next_permitted(domain, Utc::now())
+}
+
 impl ReadyQueue {
     #[allow(unused)]
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Returns the next time at which the sending_window of this egress
+    /// path permits sending to `domain`, or None if sending is permitted now
+    pub fn sending_window_next_permitted(&self, domain: &str) -> Option<DateTime<Utc>> {
+        sending_window_next_permitted(&self.path_config.borrow(), domain)
+    }
+
     pub fn insert(&self, msg: Message) -> Result<(), Message> {
         if low_memory() {
             msg.shrink().ok();
@@ -596,9 +658,19 @@ impl ReadyQueue {
                 limit: path_config.connection_limit,
                 duration: path_config.client_timeouts.total_message_send_duration(),
             };
+            let site_limit = path_config
+                .site_connection_limit
+                .map(|site_connection_limit| SiteLimit {
+                    spec: LimitSpec {
+                        limit: site_connection_limit,
+                        duration: limit.duration,
+                    },
+                    site_name: &self.site_name,
+                    per_node: path_config.site_connection_limit_per_node,
+                });
 
             for _ in current_connection_count..ideal {
-                match limit.acquire_lease(&self.name).await {
+                match ConnectionLeases::acquire(&self.name, &limit, site_limit.as_ref()).await {
                     Ok(lease) => {
                         // Open a new connection
                         let name = self.name.clone();
@@ -694,6 +766,14 @@ pub trait QueueDispatcher: Debug + Send {
     async fn have_more_connection_candidates(&mut self, dispatcher: &mut Dispatcher) -> bool;
 
     async fn close_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<bool>;
+
+    /// Called while an idle connection is lingering, in order to keep
+    /// it alive. Returns true if the connection remains usable.
+    /// The default implementation returns false, which means that the
+    /// dispatcher doesn't support lingering.
+    async fn keepalive(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        Ok(false)
+    }
 }
 
 pub struct Dispatcher {
@@ -718,7 +798,97 @@ pub struct Dispatcher {
     pub msg: Option<Message>,
     pub delivery_protocol: String,
     pub suspended: Option<AdminSuspendReadyQEntryRef>,
-    lease: LimitLease,
+    lease: ConnectionLeases,
+}
+
+/// The site_connection_limit of a ready queue
+struct SiteLimit<'a> {
+    spec: LimitSpec,
+    site_name: &'a str,
+    per_node: bool,
+}
+
+impl SiteLimit<'_> {
+    /// The mode is part of the key, so that the per-node leases are
+    /// not counted against the cluster-wide limit, and vice versa,
+    /// while the configuration is being changed
+    fn key(&self) -> String {
+        let mode = if self.per_node { "node" } else { "cluster" };
+        format!("site-connection-limit:{mode}:{}", self.site_name)
+    }
+}
+
+/// The leases that permit a Dispatcher to hold open a connection:
+/// one for its ready queue, and one for its site when the
+/// site_connection_limit is configured
+#[derive(Debug)]
+struct ConnectionLeases {
+    queue: LimitLease,
+    site: Option<(LimitLease, String)>,
+}
+
+impl ConnectionLeases {
+    /// Acquires the leases for a connection for the ready queue `name`.
+    /// If the site lease cannot be acquired, the queue lease is released.
+    async fn acquire(
+        name: &str,
+        limit: &LimitSpec,
+        site_limit: Option<&SiteLimit<'_>>,
+    ) -> anyhow::Result<Self> {
+        let mut queue = limit.acquire_lease(name).await?;
+        let site = match site_limit {
+            Some(site_limit) => {
+                let key = site_limit.key();
+                let result = if site_limit.per_node {
+                    site_limit.spec.acquire_local_lease(&key).await
+                } else {
+                    site_limit.spec.acquire_lease(&key).await
+                };
+                match result {
+                    Ok(lease) => Some((lease, site_limit.site_name.to_string())),
+                    Err(err) => {
+                        queue.release().await;
+                        return Err(err).context("acquiring site connection lease");
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(Self { queue, site })
+    }
+
+    async fn release(&mut self) {
+        self.queue.release().await;
+        if let Some((mut site, site_name)) = self.site.take() {
+            site.release().await;
+            ReadyQueueManager::notify_site(&site_name);
+        }
+    }
+
+    async fn extend(&self, duration: Duration) -> Result<(), throttle::Error> {
+        self.queue.extend(duration).await?;
+        if let Some((site, _)) = &self.site {
+            site.extend(duration).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionLeases {
+    fn drop(&mut self) {
+        // The Dispatcher was dropped without releasing its leases,
+        // such as when it encountered an error
+        if let Some((mut site, site_name)) = self.site.take() {
+            READYQ_RUNTIME
+                .spawn_non_blocking("ConnectionLeases::drop".to_string(), move || {
+                    Ok(async move {
+                        site.release().await;
+                        ReadyQueueManager::notify_site(&site_name);
+                    })
+                })
+                .ok();
+        }
+    }
 }
 
 impl Drop for Dispatcher {
@@ -767,13 +937,14 @@ impl Dispatcher {
         consecutive_connection_failures: Arc<AtomicUsize>,
         egress_source: EgressSource,
         egress_pool: String,
-        lease: LimitLease,
+        lease: ConnectionLeases,
     ) -> anyhow::Result<()> {
         let activity = Activity::get(format!("ready_queue Dispatcher {name}"))?;
 
         let delivery_protocol = match &queue_config.borrow().protocol {
             DeliveryProto::Smtp { .. } => "ESMTP".to_string(),
             DeliveryProto::Lua { .. } => "Lua".to_string(),
+            DeliveryProto::Http { .. } => "HTTP".to_string(),
             DeliveryProto::Maildir { .. } => "Maildir".to_string(),
         };
 
@@ -809,6 +980,7 @@ impl Dispatcher {
                 let lua_config = load_config().await?;
                 Box::new(LuaQueueDispatcher::new(lua_config, proto_config.clone()))
             }
+            DeliveryProto::Http { http } => Box::new(HttpQueueDispatcher::new(http.clone())?),
             DeliveryProto::Maildir { .. } => {
                 anyhow::bail!("Should not reach Dispatcher::run with DeliveryProto::Maildir")
             }
@@ -981,9 +1153,16 @@ impl Dispatcher {
             }
         };
 
+        if self.delivered_this_connection > 0 {
+            self.metrics.inc_connection_reuse();
+        }
         self.delivered_this_connection += 1;
 
-        if let Err(err) = queue_dispatcher.deliver_message(msg.clone(), self).await {
+        if let Err(err) = queue_dispatcher
+            .deliver_message(msg.clone(), self)
+            .instrument(crate::message_trace::attempt_span(&msg, &self.name))
+            .await
+        {
             // Transient failure; continue with another host
             tracing::debug!(
                 "failed to send message id {:?} to {}: {err:#}",
@@ -1020,6 +1199,10 @@ impl Dispatcher {
                 Ok(mut config) => {
                     let result: anyhow::Result<()> = config
                         .async_call_callback(&REQUEUE_MESSAGE_SIG, msg.clone())
+                        .instrument(crate::message_trace::message_lua_span(
+                            &msg,
+                            "message_requeued",
+                        ))
                         .await;
 
                     match result {
@@ -1064,6 +1247,21 @@ impl Dispatcher {
         queue.requeue_message(msg, increment_attempts, delay).await
     }
 
+    /// Returns `msg` to its scheduled queue until the egress path
+    /// sending_window next opens at `next`, without incrementing
+    /// the number of attempts
+    async fn hold_for_sending_window(
+        queue_name: &str,
+        msg: &Message,
+        next: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let queue = QueueManager::resolve(queue_name).await?;
+        queue
+            .hold_for_sending_window(msg, Some(next), "egress path")
+            .await?;
+        Ok(())
+    }
+
     #[instrument(skip(msg))]
     pub async fn reinsert_message(msg: Message) -> anyhow::Result<()> {
         if !msg.is_meta_loaded() {
@@ -1126,6 +1324,12 @@ impl Dispatcher {
                 );
                 kumo_chrono_helper::MINUTE
             });
+            for msg in &msgs {
+                crate::attempt_history::record_throttle(
+                    msg,
+                    format!("throttle for ready queue {}", self.name),
+                );
+            }
             READYQ_RUNTIME
                 .spawn("requeue for throttle".to_string(), move || {
                     Ok(async move {
@@ -1230,21 +1434,97 @@ impl Dispatcher {
         if self.msg.is_some() {
             return true;
         }
+        self.msg = self.pop_ready_message().await;
+        self.msg.is_some()
+    }
+
+    /// Pop the next message from the ready queue, taking care of
+    /// any admin bounces that apply to it.
+    /// The caller is responsible for the disposition of the
+    /// returned message.
+    pub async fn pop_ready_message(&self) -> Option<Message> {
         loop {
-            self.msg = self.ready.pop();
-            if let Some(msg) = &self.msg {
-                if let Ok(queue_name) = msg.get_queue_name() {
-                    if let Some(entry) = AdminBounceEntry::get_for_queue_name(&queue_name) {
-                        let msg = self.msg.take().unwrap();
-                        entry.log(msg.clone(), None).await;
-                        SpoolManager::remove_from_spool(*msg.id()).await.ok();
-                        continue;
+            let msg = self.ready.pop()?;
+            if let Ok(queue_name) = msg.get_queue_name() {
+                if let Some(entry) = AdminBounceEntry::get_for_queue_name(&queue_name) {
+                    entry.log(msg.clone(), None).await;
+                    SpoolManager::remove_from_spool(*msg.id()).await.ok();
+                    continue;
+                }
+
+                // The sending_window may have closed while the message
+                // was waiting in the ready queue
+                let components = QueueNameComponents::parse(&queue_name);
+                let next =
+                    sending_window_next_permitted(&self.path_config.borrow(), components.domain);
+                if let Some(next) = next {
+                    if let Err(err) = Self::hold_for_sending_window(&queue_name, &msg, next).await {
+                        tracing::error!(
+                            "{}: error holding {} for sending_window: {err:#}, \
+                             will requeue it instead",
+                            self.name,
+                            msg.id()
+                        );
+                        if let Err(err) = Self::requeue_message(msg, false, None).await {
+                            tracing::error!("error requeuing message: {err:#}");
+                        }
                     }
+                    continue;
+                }
+            }
+            return Some(msg);
+        }
+    }
+
+    /// Pop an additional message from the ready queue to send in the
+    /// same batch as the current message, subject to the same suspension
+    /// and max_message_rate checks that deliver_message applies to it.
+    /// Rather than waiting for a throttle, returns None so that the
+    /// remaining messages are left for subsequent calls to deliver_message.
+    /// The caller is responsible for the disposition of the
+    /// returned message.
+    pub async fn pop_ready_message_for_batch(&self) -> anyhow::Result<Option<Message>> {
+        if AdminSuspendReadyQEntry::get_for_queue_name(&self.name).is_some() {
+            return Ok(None);
+        }
+
+        // Pop before applying the throttle, so that we don't consume
+        // any of its capacity when there is nothing left to send
+        let msg = match self.pop_ready_message().await {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+
+        let path_config = self.path_config.borrow();
+        if let Some(throttle) = &path_config.max_message_rate {
+            match throttle
+                .throttle(format!("{}-message-rate", self.name))
+                .await
+                .context("apply max_message_rate throttle")
+            {
+                Ok(result) if result.throttled => {
+                    tracing::trace!("{} throttled message rate, ending batch", self.name);
+                    self.return_to_ready_queue(msg).await;
+                    return Ok(None);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.return_to_ready_queue(msg).await;
+                    return Err(err);
                 }
+            }
+        }
 
-                return true;
-            } else {
-                return false;
+        Ok(Some(msg))
+    }
+
+    /// Put back a message that was popped by pop_ready_message_for_batch
+    /// but could not be included in the batch. If the ready queue has
+    /// since filled up, the message is reinserted into its scheduled queue.
+    async fn return_to_ready_queue(&self, msg: Message) {
+        if let Err(msg) = self.ready.push(msg) {
+            if let Err(err) = Self::reinsert_message(msg).await {
+                tracing::error!("error reinserting message: {err:#}");
             }
         }
     }
@@ -1323,12 +1603,48 @@ impl Dispatcher {
             return Ok(true);
         }
 
-        let idle_timeout = self.path_config.borrow().client_timeouts.idle_timeout;
+        let (idle_timeout, send_duration, linger, keepalive_interval) = {
+            let path_config = self.path_config.borrow();
+            (
+                path_config.client_timeouts.idle_timeout,
+                path_config.client_timeouts.total_message_send_duration(),
+                path_config.idle_connection_linger.unwrap_or(Duration::ZERO),
+                path_config.idle_connection_keepalive_interval,
+            )
+        };
         let idle_deadline = tokio::time::Instant::now() + idle_timeout;
+        let linger_deadline = idle_deadline + linger;
+        let mut deadline = idle_deadline;
+        let mut lingering = false;
         loop {
             tokio::select! {
-                _ = tokio::time::sleep_until(idle_deadline) => {
-                    return Ok(false);
+                _ = tokio::time::sleep_until(deadline) => {
+                    if deadline >= linger_deadline {
+                        return Ok(false);
+                    }
+                    // We're past the idle_timeout but have been asked to
+                    // linger; keep the connection warm so that a subsequent
+                    // burst for this site can skip connection setup
+                    if self
+                        .lease
+                        .extend(keepalive_interval + send_duration)
+                        .await
+                        .is_err()
+                    {
+                        tracing::trace!(
+                            "{}: unable to extend lease while lingering, \
+                             closing out this connection",
+                            self.name,
+                        );
+                        return Ok(false);
+                    }
+                    if !queue_dispatcher.keepalive(self).await? {
+                        return Ok(false);
+                    }
+                    lingering = true;
+                    deadline = (tokio::time::Instant::now() + keepalive_interval)
+                        .min(linger_deadline);
+                    continue;
                 },
                 _ = self.notify_dispatcher.notified() => {
                     if self.activity.is_shutting_down() {
@@ -1345,6 +1661,9 @@ impl Dispatcher {
                         return Ok(false);
                     }
                     if self.obtain_message().await {
+                        if lingering {
+                            self.metrics.inc_connection_linger_reuse();
+                        }
                         return Ok(true);
                     }
                     // we raced with another dispatcher;
@@ -1374,6 +1693,149 @@ pub fn ideal_connection_count(queue_size: usize, connection_limit: usize) -> usi
 mod test {
     use super::*;
 
+    #[tokio::test]
+    async fn site_lease_failure_releases_queue_lease() {
+        let name = format!("site_lease_failure-{}", uuid::Uuid::new_v4());
+        let limit = LimitSpec {
+            limit: 1,
+            duration: Duration::from_secs(60),
+        };
+        let site_limit = SiteLimit {
+            spec: LimitSpec {
+                limit: 1,
+                duration: Duration::from_secs(60),
+            },
+            site_name: &name,
+            per_node: true,
+        };
+        assert_eq!(
+            site_limit.key(),
+            format!("site-connection-limit:node:{name}")
+        );
+
+        // Another queue for the same site holds the only site lease
+        let mut other =
+            ConnectionLeases::acquire(&format!("{name}-other"), &limit, Some(&site_limit))
+                .await
+                .unwrap();
+        assert!(ConnectionLeases::acquire(&name, &limit, Some(&site_limit))
+            .await
+            .is_err());
+
+        // The queue lease was released when the site lease could not
+        // be acquired, so it can be acquired again
+        let mut lease = ConnectionLeases::acquire(&name, &limit, None)
+            .await
+            .unwrap();
+        lease.release().await;
+
+        // Releasing the site lease permits the queue to connect
+        other.release().await;
+        let mut lease = ConnectionLeases::acquire(&name, &limit, Some(&site_limit))
+            .await
+            .unwrap();
+        lease.release().await;
+    }
+
+    #[tokio::test]
+    async fn dispatcher_sending_window_holds_message() {
+        use crate::queue::test_support::*;
+
+        let (_window, opens) = closed_window();
+        let name = format!("dispatcher-window-{}.example.com", uuid::Uuid::new_v4());
+        let queue = make_queue(&name, QueueConfig::default());
+
+        // A message that was already in the ready queue when the
+        // egress path window closed is returned to its scheduled queue
+        let msg = make_message(&name);
+        msg.set_num_attempts(1);
+        Dispatcher::hold_for_sending_window(&name, &msg, opens)
+            .await
+            .unwrap();
+
+        assert_eq!(scheduled_count(&queue), 1);
+        assert_eq!(msg.get_due(), Some(opens));
+        assert_eq!(msg.get_num_attempts(), 1);
+        QueueManager::remove(&name);
+    }
+
+    async fn make_dispatcher(name: &str, path_config: EgressPathConfig) -> Dispatcher {
+        let limit = LimitSpec {
+            limit: 1,
+            duration: Duration::from_secs(60),
+        };
+        Dispatcher {
+            name: name.to_string(),
+            queue_name_for_config_change_purposes_only: name.to_string(),
+            ready: Arc::new(Fifo::new(
+                16,
+                IntGauge::new("test_ready_count", "test").unwrap(),
+            )),
+            notify_dispatcher: Arc::new(Notify::new()),
+            path_config: ConfigHandle::new(path_config),
+            mx: None,
+            metrics: DeliveryMetrics::new(name, "test"),
+            shutting_down: ShutdownSubcription::get(),
+            activity: Activity::get(format!("Dispatcher {name}")).unwrap(),
+            egress_source: serde_json::from_value(serde_json::json!({"name": "unspecified"}))
+                .unwrap(),
+            egress_pool: "unspecified".to_string(),
+            delivered_this_connection: 0,
+            msg: None,
+            delivery_protocol: "HTTP".to_string(),
+            suspended: None,
+            lease: ConnectionLeases::acquire(name, &limit, None).await.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_respects_max_message_rate() {
+        use crate::queue::test_support::*;
+
+        let name = format!("batch-{}.example.com", uuid::Uuid::new_v4());
+        let _queue = make_queue(&name, QueueConfig::default());
+        let mut dispatcher = make_dispatcher(
+            &name,
+            EgressPathConfig {
+                max_message_rate: Some(throttle::ThrottleSpec::try_from("2/h").unwrap()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // An empty ready queue doesn't consume any of the throttle
+        assert!(dispatcher
+            .pop_ready_message_for_batch()
+            .await
+            .unwrap()
+            .is_none());
+
+        for _ in 0..3 {
+            dispatcher.ready.push(make_message(&name)).unwrap();
+        }
+        assert!(dispatcher
+            .pop_ready_message_for_batch()
+            .await
+            .unwrap()
+            .is_some());
+        assert!(dispatcher
+            .pop_ready_message_for_batch()
+            .await
+            .unwrap()
+            .is_some());
+
+        // The throttle is exhausted; the message remains in the
+        // ready queue for a subsequent batch
+        assert!(dispatcher
+            .pop_ready_message_for_batch()
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(dispatcher.ready.len(), 1);
+        dispatcher.lease.release().await;
+        QueueManager::remove(&name);
+    }
+
     fn compute_targets_for_limit(max_connections: usize) -> Vec<(usize, usize)> {
         let sizes = [
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 20, 32, 64, 128, 256, 400, 512, 1024,
@@ -23,6 +23,7 @@ use rfc5321::{
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use tracing::Level;
 use uuid::Uuid;
 
@@ -569,6 +570,34 @@ impl QueueDispatcher for SmtpDispatcher {
         }
     }
 
+    async fn keepalive(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(false),
+        };
+
+        match client.send_command(&rfc5321::Command::Noop(None)).await {
+            Ok(response) if response.code == 250 => {
+                tracing::trace!("{}: lingering connection is still alive", dispatcher.name);
+                Ok(true)
+            }
+            Ok(response) => {
+                self.tracer.diagnostic(Level::INFO, || {
+                    format!("NOOP keepalive was not accepted: {response:?}, closing connection")
+                });
+                self.close_connection(dispatcher).await?;
+                Ok(false)
+            }
+            Err(err) => {
+                self.tracer.diagnostic(Level::INFO, || {
+                    format!("NOOP keepalive failed: {err:#}, discarding connection")
+                });
+                self.client.take();
+                Ok(false)
+            }
+        }
+    }
+
     async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
         self.attempt_connection_impl(dispatcher)
             .await
@@ -628,13 +657,16 @@ impl QueueDispatcher for SmtpDispatcher {
         self.tracer
             .submit(|| SmtpClientTraceEventPayload::MessageObtained);
 
-        match self
+        let started = Instant::now();
+        let result = self
             .client
             .as_mut()
             .unwrap()
             .send_mail(sender, recipient, &*data)
-            .await
-        {
+            .await;
+        crate::latency_metrics::observe_attempt_duration(&msg, &dispatcher.name, started.elapsed());
+
+        match result {
             Err(ClientError::Rejected(mut response)) => {
                 let queue_name = msg.get_queue_name()?;
                 let components = QueueNameComponents::parse(&queue_name);
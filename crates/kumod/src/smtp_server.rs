@@ -2,8 +2,10 @@ use crate::http_server::admin_trace_smtp_server_v1::{
     SmtpServerTraceEvent, SmtpServerTraceEventPayload, SmtpServerTraceManager,
 };
 use crate::logging::{log_disposition, log_rejection, LogDisposition, LogRejection, RecordType};
+use crate::pressure::PressureLevel;
 use crate::queue::QueueManager;
 use crate::spool::SpoolManager;
+use crate::suppression::SuppressionAction;
 use anyhow::{anyhow, Context};
 use chrono::Utc;
 use cidr_map::CidrSet;
@@ -36,7 +38,7 @@ use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, instrument, Level};
+use tracing::{error, instrument, Instrument, Level};
 
 static CRLF: Lazy<Finder> = Lazy::new(|| Finder::new("\r\n"));
 
@@ -909,8 +911,11 @@ impl SmtpServer {
             }
             Some(a) => a,
         };
-        if kumo_server_memory::get_headroom() == 0 {
-            // Using too much memory
+        if kumo_server_memory::get_headroom() == 0
+            || crate::pressure::current_level() >= PressureLevel::Critical
+        {
+            // Using too much memory, or otherwise under too much pressure
+            crate::pressure::shed_counter_for_service("esmtp_listener").inc();
             self.write_response(
                 421,
                 format!("4.3.2 {} load shedding. Try later", self.params.hostname),
@@ -1248,6 +1253,20 @@ impl SmtpServer {
                         continue;
                     }
 
+                    if crate::pressure::current_level() >= PressureLevel::Elevated {
+                        crate::pressure::shed_counter_for_service("esmtp_listener").inc();
+                        self.write_response(
+                            451,
+                            format!(
+                                "4.3.2 {} system under pressure. Try later",
+                                self.params.hostname
+                            ),
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
+
                     let address = EnvelopeAddress::parse(&address.to_string())?;
                     if let Err(rej) = self
                         .call_callback::<(), _, _>(
@@ -1346,6 +1365,20 @@ impl SmtpServer {
                             .await?;
                         continue;
                     }
+                    let tenant =
+                        crate::suppression::tenant_for_connection_meta(&self.meta.clone_inner());
+                    if let Some(entry) =
+                        crate::suppression::check_rcpt(tenant, &address.to_string()).await
+                    {
+                        crate::suppression::suppressed_counter_for_service("esmtp_listener").inc();
+                        self.write_response(
+                            550,
+                            format!("5.7.1 recipient is suppressed: {}", entry.reason),
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
                     self.write_response(250, format!("OK {address:?}"), None)
                         .await?;
                     self.state
@@ -1523,12 +1556,17 @@ impl SmtpServer {
                 self.meta.clone_inner(),
                 Arc::new(body.into_boxed_slice()),
             )?;
+            let trace_span = crate::message_trace::reception_span(&message, protocol);
 
             if let Err(rej) = self
                 .call_callback::<(), _, _>(
                     "smtp_server_message_received",
                     (message.clone(), self.meta.clone()),
                 )
+                .instrument(crate::message_trace::lua_span(
+                    &trace_span,
+                    "smtp_server_message_received",
+                ))
                 .await?
             {
                 // Rejecting any one message from a batch in
@@ -1538,7 +1576,7 @@ impl SmtpServer {
                     .await?;
                 return Ok(());
             }
-            accepted_messages.push(message);
+            accepted_messages.push((message, trace_span));
         }
 
         // At this point we've nominally accepted the batch; let's
@@ -1547,8 +1585,9 @@ impl SmtpServer {
         let mut messages = vec![];
         let mut was_arf_or_oob = false;
         let mut black_holed = false;
+        let mut suppressed_rejects = vec![];
 
-        for message in accepted_messages {
+        for (message, trace_span) in accepted_messages {
             if self.params.trace_headers.supplemental_header {
                 let mut object = json!({
                     // Marker to identify encoded supplemental header
@@ -1598,9 +1637,32 @@ impl SmtpServer {
                 when: Utc::now(),
             });
 
+            if queue_name != "null" && relay_disposition.relay {
+                if let Some((action, entry)) = crate::suppression::check_message(&message).await {
+                    crate::suppression::suppressed_counter_for_service("esmtp_listener").inc();
+                    match action {
+                        SuppressionAction::Reject => {
+                            // The tenant may not have been known at RCPT TO
+                            // time, so this is our first opportunity to reject.
+                            // Whether it is logged as a Bounce depends on
+                            // whether the transaction as a whole is rejected.
+                            suppressed_rejects.push((message, entry));
+                        }
+                        SuppressionAction::LogAndDrop => {
+                            black_holed = true;
+                            crate::suppression::log_suppressed(message, &entry).await;
+                        }
+                    }
+                    continue;
+                }
+            }
+
             if queue_name != "null" {
                 if relay_disposition.relay && !self.params.deferred_spool {
-                    message.save().await?;
+                    message
+                        .save()
+                        .instrument(crate::message_trace::spool_span(&trace_span))
+                        .await?;
                 }
             }
 
@@ -1631,6 +1693,7 @@ impl SmtpServer {
                 tls_info: None, // TODO: populate with peer info
                 source_address: None,
             })
+            .instrument(trace_span)
             .await;
             if queue_name != "null" {
                 if relay_disposition.relay {
@@ -1648,9 +1711,29 @@ impl SmtpServer {
         }
 
         if !black_holed && !relayed_any && !was_arf_or_oob {
-            self.write_response(550, "5.7.1 relaying not permitted", Some("DATA".into()))
-                .await?;
+            // The response is logged as a Rejection; the suppressed
+            // messages were never accepted, so they are not bounced
+            match suppressed_rejects.first() {
+                Some((_, entry)) => {
+                    self.write_response(
+                        550,
+                        format!("5.7.1 recipient is suppressed: {}", entry.reason),
+                        Some("DATA".into()),
+                    )
+                    .await?;
+                }
+                None => {
+                    self.write_response(550, "5.7.1 relaying not permitted", Some("DATA".into()))
+                        .await?;
+                }
+            }
         } else {
+            // When some of the batch was accepted, we cannot reject the
+            // suppressed recipients individually after DATA, so they
+            // are bounced instead
+            for (message, entry) in suppressed_rejects {
+                crate::suppression::log_suppressed(message, &entry).await;
+            }
             let ids = ids.join(" ");
             self.write_response(250, format!("OK ids={ids}"), None)
                 .await?;
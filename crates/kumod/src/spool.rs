@@ -10,6 +10,7 @@ use mlua::{Lua, Value};
 use once_cell::sync::Lazy;
 use rfc5321::{EnhancedStatusCode, Response};
 use serde::Deserialize;
+use spool::compressed::{CompressedSpool, CompressionParams};
 use spool::local_disk::LocalDiskSpool;
 use spool::rocks::{RocksSpool, RocksSpoolParams};
 use spool::{get_data_spool, get_meta_spool, Spool as SpoolTrait, SpoolEntry, SpoolId};
@@ -41,6 +42,7 @@ impl std::ops::Deref for SpoolHandle {
 pub struct Spool {
     maintainer: StdMutex<Option<JoinHandle<()>>>,
     spool: Arc<dyn SpoolTrait + Send + Sync>,
+    path: PathBuf,
 }
 
 impl std::ops::Deref for Spool {
@@ -82,6 +84,8 @@ pub struct DefineSpoolParams {
     pub flush: bool,
     #[serde(default)]
     pub rocks_params: Option<RocksSpoolParams>,
+    #[serde(default)]
+    pub compression: Option<CompressionParams>,
 }
 
 async fn define_spool(params: DefineSpoolParams) -> anyhow::Result<()> {
@@ -137,20 +141,25 @@ impl SpoolManager {
             params.name,
             params.path.display()
         );
+        let mut spool: Arc<dyn SpoolTrait + Send + Sync> = match params.kind {
+            SpoolKind::LocalDisk => Arc::new(
+                LocalDiskSpool::new(&params.path, params.flush)
+                    .with_context(|| format!("Opening spool {}", params.name))?,
+            ),
+            SpoolKind::RocksDB => Arc::new(
+                RocksSpool::new(&params.path, params.flush, params.rocks_params)
+                    .with_context(|| format!("Opening spool {}", params.name))?,
+            ),
+        };
+        if let Some(compression) = params.compression {
+            spool = Arc::new(CompressedSpool::new(&params.name, spool, compression));
+        }
         self.named.lock().await.insert(
             params.name.to_string(),
             SpoolHandle(Arc::new(Spool {
                 maintainer: StdMutex::new(None),
-                spool: match params.kind {
-                    SpoolKind::LocalDisk => Arc::new(
-                        LocalDiskSpool::new(&params.path, params.flush)
-                            .with_context(|| format!("Opening spool {}", params.name))?,
-                    ),
-                    SpoolKind::RocksDB => Arc::new(
-                        RocksSpool::new(&params.path, params.flush, params.rocks_params)
-                            .with_context(|| format!("Opening spool {}", params.name))?,
-                    ),
-                },
+                spool,
+                path: params.path,
             })),
         );
         Ok(())
@@ -170,6 +179,16 @@ impl SpoolManager {
             .ok_or_else(|| anyhow::anyhow!("no spool named '{name}' has been defined"))
     }
 
+    /// Returns the storage paths of the defined spools
+    pub async fn spool_paths(&self) -> Vec<PathBuf> {
+        self.named
+            .lock()
+            .await
+            .values()
+            .map(|spool| spool.0.path.clone())
+            .collect()
+    }
+
     pub fn get_data_meta() -> (
         &'static Arc<dyn spool::Spool + Send + Sync>,
         &'static Arc<dyn spool::Spool + Send + Sync>,
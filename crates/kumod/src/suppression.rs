@@ -0,0 +1,617 @@
+//! This module implements a per-tenant suppression list, keyed by
+//! recipient address and persisted in sqlite.
+//!
+//! The list is populated automatically from bounces that have one of
+//! the configured bounce classifications and from feedback (ARF)
+//! reports, and can be managed via the admin API.
+//! Reception consults the list so that mail to suppressed recipients
+//! is either rejected or logged and dropped.
+use crate::logging::{log_disposition, LogDisposition, RecordType};
+use anyhow::Context;
+use bounce_classify::{BounceClass, PreDefinedBounceClass};
+use chrono::{DateTime, TimeZone, Utc};
+use kumo_api_types::suppression::{SuppressionSource, SuppressionV1Entry};
+use kumo_log_types::rfc5965::ARFReport;
+use message::message::QueueNameComponents;
+use message::Message;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use prometheus::{IntCounter, IntCounterVec};
+use rfc5321::{EnhancedStatusCode, Response};
+use serde::Deserialize;
+use sqlite::{Connection, ConnectionThreadSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref SUPPRESSED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "total_messages_suppressed",
+        "total number of recipients that were rejected or dropped due to the suppression list",
+        &["service"]).unwrap();
+    static ref ADDED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "suppression_list_additions",
+        "number of entries added to the suppression list",
+        &["source"]).unwrap();
+}
+
+static SUPPRESSION: Lazy<Mutex<Option<Arc<Suppression>>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuppressionAction {
+    /// Reject the recipient
+    Reject,
+    /// Accept the message, but log it as a bounce rather than queueing it
+    LogAndDrop,
+}
+
+impl Default for SuppressionAction {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionParams {
+    /// Where to store the suppression database
+    #[serde(default = "SuppressionParams::default_path")]
+    pub path: String,
+
+    /// Bounces with any of these classifications will cause the
+    /// recipient to be suppressed
+    #[serde(default = "SuppressionParams::default_bounce_classes")]
+    pub bounce_classes: Vec<BounceClass>,
+
+    /// How long a bounce-based entry remains active.
+    /// If unset, it never expires.
+    #[serde(default, with = "duration_serde")]
+    pub bounce_duration: Option<Duration>,
+
+    /// Whether feedback reports cause the recipient to be suppressed
+    #[serde(default = "SuppressionParams::default_complaints")]
+    pub complaints: bool,
+
+    /// How long a complaint-based entry remains active.
+    /// If unset, it never expires.
+    #[serde(default, with = "duration_serde")]
+    pub complaint_duration: Option<Duration>,
+
+    /// What to do when receiving mail for a suppressed recipient
+    #[serde(default)]
+    pub action: SuppressionAction,
+}
+
+impl SuppressionParams {
+    fn default_path() -> String {
+        "/var/spool/kumomta/suppression.db".to_string()
+    }
+
+    fn default_bounce_classes() -> Vec<BounceClass> {
+        vec![
+            PreDefinedBounceClass::InvalidRecipient.into(),
+            PreDefinedBounceClass::InactiveMailbox.into(),
+        ]
+    }
+
+    fn default_complaints() -> bool {
+        true
+    }
+}
+
+pub struct Suppression {
+    params: SuppressionParams,
+    store: SuppressionStore,
+}
+
+/// Configure the suppression list. Until this is called, the
+/// suppression list is disabled.
+pub fn configure(params: SuppressionParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    let store = SuppressionStore::open(&params.path)?;
+    store.prune_expired()?;
+    SUPPRESSION
+        .lock()
+        .replace(Arc::new(Suppression { params, store }));
+    Ok(())
+}
+
+pub fn get() -> Option<Arc<Suppression>> {
+    SUPPRESSION.lock().clone()
+}
+
+/// Normalize a recipient address for use as a key
+fn normalize_recipient(recipient: &str) -> String {
+    recipient.to_lowercase()
+}
+
+fn tenant_for_message(msg: &Message) -> Option<String> {
+    let queue_name = msg.get_queue_name().ok()?;
+    QueueNameComponents::parse(&queue_name)
+        .tenant
+        .map(|t| t.to_string())
+}
+
+/// Resolve the tenant from the connection metadata at RCPT TO time.
+/// The metadata of a received message is initialized from that of its
+/// connection, so this follows the same `queue` then `tenant` precedence
+/// that tenant_for_message applies via the queue name.
+pub fn tenant_for_connection_meta(meta: &serde_json::Value) -> Option<String> {
+    if let Some(queue_name) = meta.get("queue").and_then(|q| q.as_str()) {
+        return QueueNameComponents::parse(queue_name)
+            .tenant
+            .map(|t| t.to_string());
+    }
+    meta.get("tenant")
+        .and_then(|t| t.as_str())
+        .map(|t| t.to_string())
+}
+
+fn timestamp(t: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(t, 0).single().unwrap_or_default()
+}
+
+fn expiry(duration: Option<Duration>) -> Option<DateTime<Utc>> {
+    duration
+        .and_then(|d| chrono::Duration::from_std(d).ok())
+        .map(|d| Utc::now() + d)
+}
+
+pub struct SuppressionStore {
+    db: ConnectionThreadSafe,
+}
+
+impl SuppressionStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let db = Connection::open_thread_safe(path)
+            .with_context(|| format!("opening suppression database {path}"))?;
+
+        let query = r#"
+CREATE TABLE IF NOT EXISTS suppression (
+    tenant TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    source TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    bounce_classification TEXT,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    PRIMARY KEY (tenant, recipient)
+);
+        "#;
+
+        db.execute(query)?;
+        Ok(Self { db })
+    }
+
+    /// Add or replace an entry
+    pub fn add(&self, entry: &SuppressionV1Entry) -> anyhow::Result<()> {
+        let mut stmt = self.db.prepare(
+            "INSERT INTO suppression
+                (tenant, recipient, source, reason, bounce_classification, created, expires)
+                values ($tenant, $recipient, $source, $reason, $class, $created, $expires)
+                on conflict (tenant, recipient)
+                do update set source=$source, reason=$reason, bounce_classification=$class,
+                    created=$created, expires=$expires",
+        )?;
+        let source = serde_json::to_value(entry.source)?;
+        stmt.bind(("$tenant", entry.tenant.as_deref().unwrap_or("")))?;
+        stmt.bind(("$recipient", normalize_recipient(&entry.recipient).as_str()))?;
+        stmt.bind(("$source", source.as_str().unwrap_or("Admin")))?;
+        stmt.bind(("$reason", entry.reason.as_str()))?;
+        stmt.bind(("$class", entry.bounce_classification.as_deref()))?;
+        stmt.bind(("$created", entry.created.timestamp()))?;
+        stmt.bind(("$expires", entry.expires.map(|t| t.timestamp())))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    /// Remove an entry, returning true if it was present
+    pub fn remove(&self, tenant: Option<&str>, recipient: &str) -> anyhow::Result<bool> {
+        let mut stmt = self
+            .db
+            .prepare("DELETE FROM suppression where tenant=$tenant and recipient=$recipient")?;
+        stmt.bind(("$tenant", tenant.unwrap_or("")))?;
+        stmt.bind(("$recipient", normalize_recipient(recipient).as_str()))?;
+        stmt.next()?;
+        Ok(self.db.change_count() > 0)
+    }
+
+    /// Find the active entry that applies to the recipient for the
+    /// specified tenant. Entries that apply to a specific tenant
+    /// take precedence over those that apply to all tenants.
+    pub fn lookup(
+        &self,
+        tenant: Option<&str>,
+        recipient: &str,
+    ) -> anyhow::Result<Option<SuppressionV1Entry>> {
+        let mut stmt = self.db.prepare(
+            "SELECT * from suppression where
+                recipient=$recipient and tenant in ($tenant, '')
+                and (expires is null or expires > $now)
+                order by tenant desc limit 1",
+        )?;
+        stmt.bind(("$recipient", normalize_recipient(recipient).as_str()))?;
+        stmt.bind(("$tenant", tenant.unwrap_or("")))?;
+        stmt.bind(("$now", Utc::now().timestamp()))?;
+        let mut entries = Self::read_entries(&mut stmt)?;
+        Ok(entries.pop())
+    }
+
+    pub fn list(
+        &self,
+        tenant: Option<&str>,
+        recipient: Option<&str>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        let mut stmt = self.db.prepare(
+            "SELECT * from suppression where
+                ($tenant is null or tenant=$tenant)
+                and ($recipient is null or recipient=$recipient)
+                and (expires is null or expires > $now)
+                order by tenant, recipient limit $limit",
+        )?;
+        let recipient = recipient.map(normalize_recipient);
+        stmt.bind(("$tenant", tenant))?;
+        stmt.bind(("$recipient", recipient.as_deref()))?;
+        stmt.bind(("$now", Utc::now().timestamp()))?;
+        stmt.bind(("$limit", limit.map(|l| l as i64).unwrap_or(-1)))?;
+        Self::read_entries(&mut stmt)
+    }
+
+    pub fn prune_expired(&self) -> anyhow::Result<()> {
+        let mut stmt = self
+            .db
+            .prepare("DELETE FROM suppression where expires is not null and expires <= $now")?;
+        stmt.bind(("$now", Utc::now().timestamp()))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    fn read_entries(stmt: &mut sqlite::Statement) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        let mut entries = vec![];
+        while let sqlite::State::Row = stmt.next()? {
+            let tenant: String = stmt.read("tenant")?;
+            let source: String = stmt.read("source")?;
+            let expires: Option<i64> = stmt.read("expires")?;
+            entries.push(SuppressionV1Entry {
+                tenant: if tenant.is_empty() {
+                    None
+                } else {
+                    Some(tenant)
+                },
+                recipient: stmt.read("recipient")?,
+                source: serde_json::from_value(serde_json::Value::String(source))?,
+                reason: stmt.read("reason")?,
+                bounce_classification: stmt.read("bounce_classification")?,
+                created: timestamp(stmt.read("created")?),
+                expires: expires.map(timestamp),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+impl Suppression {
+    pub fn action(&self) -> SuppressionAction {
+        self.params.action
+    }
+
+    pub async fn add(self: &Arc<Self>, entry: SuppressionV1Entry) -> anyhow::Result<()> {
+        let suppression = Arc::clone(self);
+        let source = format!("{:?}", entry.source);
+        tokio::task::spawn_blocking(move || suppression.store.add(&entry)).await??;
+        ADDED.with_label_values(&[&source]).inc();
+        Ok(())
+    }
+
+    pub async fn remove(
+        self: &Arc<Self>,
+        tenant: Option<String>,
+        recipient: String,
+    ) -> anyhow::Result<bool> {
+        let suppression = Arc::clone(self);
+        tokio::task::spawn_blocking(move || suppression.store.remove(tenant.as_deref(), &recipient))
+            .await?
+    }
+
+    pub async fn lookup(
+        self: &Arc<Self>,
+        tenant: Option<String>,
+        recipient: String,
+    ) -> anyhow::Result<Option<SuppressionV1Entry>> {
+        let suppression = Arc::clone(self);
+        tokio::task::spawn_blocking(move || suppression.store.lookup(tenant.as_deref(), &recipient))
+            .await?
+    }
+
+    pub async fn list(
+        self: &Arc<Self>,
+        tenant: Option<String>,
+        recipient: Option<String>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        let suppression = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            suppression.store.prune_expired()?;
+            suppression
+                .store
+                .list(tenant.as_deref(), recipient.as_deref(), limit)
+        })
+        .await?
+    }
+}
+
+/// Returns the counter that tracks the number of recipients that
+/// were suppressed at reception for the given service
+pub fn suppressed_counter_for_service(service: &str) -> IntCounter {
+    SUPPRESSED.get_metric_with_label_values(&[service]).unwrap()
+}
+
+/// Check whether the recipient should be rejected at RCPT TO time.
+/// This only applies when the configured action is Reject.
+pub async fn check_rcpt(tenant: Option<String>, recipient: &str) -> Option<SuppressionV1Entry> {
+    let suppression = get()?;
+    if suppression.action() != SuppressionAction::Reject {
+        return None;
+    }
+    match suppression.lookup(tenant, recipient.to_string()).await {
+        Ok(entry) => entry,
+        Err(err) => {
+            tracing::error!("error checking suppression list for {recipient}: {err:#}");
+            None
+        }
+    }
+}
+
+/// Check whether a received message is for a suppressed recipient
+pub async fn check_message(msg: &Message) -> Option<(SuppressionAction, SuppressionV1Entry)> {
+    let suppression = get()?;
+    let recipient = msg.recipient().ok()?.to_string();
+    match suppression
+        .lookup(tenant_for_message(msg), recipient.clone())
+        .await
+    {
+        Ok(entry) => entry.map(|entry| (suppression.action(), entry)),
+        Err(err) => {
+            tracing::error!("error checking suppression list for {recipient}: {err:#}");
+            None
+        }
+    }
+}
+
+pub fn suppressed_response(entry: &SuppressionV1Entry) -> Response {
+    Response {
+        code: 550,
+        enhanced_code: Some(EnhancedStatusCode {
+            class: 5,
+            subject: 7,
+            detail: 1,
+        }),
+        content: format!("recipient is suppressed: {}", entry.reason),
+        command: None,
+    }
+}
+
+/// Log a Bounce record for a message that is being dropped because
+/// its recipient is suppressed
+pub async fn log_suppressed(msg: Message, entry: &SuppressionV1Entry) {
+    log_disposition(LogDisposition {
+        kind: RecordType::Bounce,
+        msg,
+        site: "localhost",
+        peer_address: None,
+        response: suppressed_response(entry),
+        egress_pool: None,
+        egress_source: None,
+        relay_disposition: None,
+        delivery_protocol: None,
+        tls_info: None,
+        source_address: None,
+    })
+    .await;
+}
+
+/// Extract the complaining recipient and tenant from a feedback report.
+/// The supplemental trace header, if present, is preferred as it
+/// reflects the original envelope.
+fn complaint_recipient(report: &ARFReport) -> Option<(Option<String>, String)> {
+    let trace = report.supplemental_trace.as_ref();
+    let recipient = trace
+        .and_then(|t| t.get("recipient"))
+        .and_then(|r| r.as_str())
+        .map(|r| r.to_string())
+        .or_else(|| report.original_rcpto_to.first().cloned())?;
+    let recipient = recipient
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string();
+    let tenant = trace
+        .and_then(|t| t.get("tenant"))
+        .and_then(|t| t.as_str())
+        .map(|t| t.to_string());
+    Some((tenant, recipient))
+}
+
+/// Called by log_disposition to automatically populate the suppression
+/// list from bounces and feedback reports
+pub async fn observe_disposition(
+    kind: RecordType,
+    msg: &Message,
+    response: &Response,
+    delivery_protocol: Option<&str>,
+    feedback_report: Option<&ARFReport>,
+) {
+    let suppression = match get() {
+        Some(s) => s,
+        None => return,
+    };
+
+    let entry = match kind {
+        // Only consider bounces that resulted from a delivery attempt;
+        // administrative and bulk operations don't tell us anything
+        // about the recipient
+        RecordType::Bounce if delivery_protocol.is_some() => {
+            let class = match crate::logging::classify_response(response) {
+                Some(class) => class,
+                None => return,
+            };
+            if !suppression.params.bounce_classes.contains(&class) {
+                return;
+            }
+            let recipient = match msg.recipient() {
+                Ok(r) => r.to_string(),
+                Err(_) => return,
+            };
+            SuppressionV1Entry {
+                tenant: tenant_for_message(msg),
+                recipient,
+                source: SuppressionSource::Bounce,
+                reason: response.to_single_line(),
+                bounce_classification: Some(class.into()),
+                created: Utc::now(),
+                expires: expiry(suppression.params.bounce_duration),
+            }
+        }
+        RecordType::Feedback if suppression.params.complaints => {
+            let report = match feedback_report {
+                Some(report) => report,
+                None => return,
+            };
+            let (tenant, recipient) = match complaint_recipient(report) {
+                Some(r) => r,
+                None => return,
+            };
+            SuppressionV1Entry {
+                tenant,
+                recipient,
+                source: SuppressionSource::Complaint,
+                reason: format!("{} feedback report", report.feedback_type),
+                bounce_classification: None,
+                created: Utc::now(),
+                expires: expiry(suppression.params.complaint_duration),
+            }
+        }
+        _ => return,
+    };
+
+    if let Err(err) = suppression.add(entry).await {
+        tracing::error!("failed to add to suppression list: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(tenant: Option<&str>, recipient: &str, reason: &str) -> SuppressionV1Entry {
+        SuppressionV1Entry {
+            tenant: tenant.map(|t| t.to_string()),
+            recipient: recipient.to_string(),
+            source: SuppressionSource::Admin,
+            reason: reason.to_string(),
+            bounce_classification: None,
+            created: timestamp(Utc::now().timestamp()),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn store() -> anyhow::Result<()> {
+        let store = SuppressionStore::open(":memory:")?;
+
+        store.add(&entry(None, "All@Example.com", "global"))?;
+        store.add(&entry(Some("mytenant"), "all@example.com", "tenant"))?;
+        store.add(&entry(
+            Some("mytenant"),
+            "tenant@example.com",
+            "tenant only",
+        ))?;
+        store.add(&SuppressionV1Entry {
+            expires: Some(Utc::now() - chrono::Duration::seconds(10)),
+            ..entry(None, "expired@example.com", "expired")
+        })?;
+
+        // Tenant specific entries take precedence
+        let found = store.lookup(Some("mytenant"), "all@example.com")?.unwrap();
+        assert_eq!(found.reason, "tenant");
+        let found = store.lookup(Some("other"), "ALL@example.com")?.unwrap();
+        assert_eq!(found.reason, "global");
+        let found = store.lookup(None, "all@example.com")?.unwrap();
+        assert_eq!(found.reason, "global");
+
+        assert!(store.lookup(None, "tenant@example.com")?.is_none());
+        assert!(store
+            .lookup(Some("mytenant"), "tenant@example.com")?
+            .is_some());
+        assert!(store.lookup(None, "expired@example.com")?.is_none());
+
+        assert_eq!(store.list(None, None, None)?.len(), 3);
+        assert_eq!(store.list(Some("mytenant"), None, None)?.len(), 2);
+        assert_eq!(store.list(None, Some("all@example.com"), Some(1))?.len(), 1);
+
+        assert!(store.remove(None, "all@example.com")?);
+        assert!(!store.remove(None, "all@example.com")?);
+        assert!(store.lookup(Some("other"), "all@example.com")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn complaint() {
+        let report = ARFReport {
+            feedback_type: "abuse".to_string(),
+            user_agent: "test".to_string(),
+            version: "1".to_string(),
+            arrival_date: None,
+            incidents: None,
+            original_envelope_id: None,
+            original_mail_from: None,
+            reporting_mta: None,
+            source_ip: None,
+            authentication_results: vec![],
+            original_rcpto_to: vec!["<user@example.com>".to_string()],
+            reported_domain: vec![],
+            reported_uri: vec![],
+            extensions: Default::default(),
+            original_message: None,
+            supplemental_trace: None,
+        };
+        assert_eq!(
+            complaint_recipient(&report),
+            Some((None, "user@example.com".to_string()))
+        );
+
+        let report = ARFReport {
+            supplemental_trace: Some(serde_json::json!({
+                "recipient": "other@example.com",
+                "tenant": "mytenant",
+            })),
+            ..report
+        };
+        assert_eq!(
+            complaint_recipient(&report),
+            Some((
+                Some("mytenant".to_string()),
+                "other@example.com".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn connection_tenant() {
+        assert_eq!(tenant_for_connection_meta(&serde_json::json!({})), None);
+        assert_eq!(
+            tenant_for_connection_meta(&serde_json::json!({"tenant": "mytenant"})),
+            Some("mytenant".to_string())
+        );
+        // An explicit queue takes precedence, as it does for the message
+        assert_eq!(
+            tenant_for_connection_meta(&serde_json::json!({
+                "tenant": "mytenant",
+                "queue": "campaign:othertenant@example.com",
+            })),
+            Some("othertenant".to_string())
+        );
+    }
+}
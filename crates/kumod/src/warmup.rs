@@ -0,0 +1,451 @@
+//! Warm-up plans ramp up the volume of mail sent from a new egress
+//! source over a number of days, with separate schedules for the
+//! major mailbox providers.
+//!
+//! The plan is evaluated each time a message is assigned to a source,
+//! using throttles whose limits are taken from the step of the schedule
+//! that applies today. When the plan for a source is exhausted, the
+//! round robin moves the message to another member of the pool, and only
+//! delays it when every member is exhausted.
+use chrono::{NaiveDate, Utc};
+use domain_map::domain_has_suffix;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use throttle::ThrottleSpec;
+
+lazy_static::lazy_static! {
+    static ref WARMUP_DAY: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_day",
+        "the current day of the warm-up plan for an egress source, \
+         or 0 if the plan has not yet started",
+        &["source"]).unwrap();
+    static ref WARMUP_LIMIT: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_limit",
+        "the number of messages per period permitted by today's step \
+         of the warm-up plan for an egress source",
+        &["source", "provider", "period"]).unwrap();
+    static ref WARMUP_ADMITTED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "egress_source_warmup_admitted",
+        "number of messages assigned to an egress source within its warm-up plan",
+        &["source", "provider"]).unwrap();
+    static ref WARMUP_SPILLOVER: IntCounterVec = prometheus::register_int_counter_vec!(
+        "egress_source_warmup_spillover",
+        "number of times that a message was not assigned to an egress source \
+         because its warm-up plan was exhausted",
+        &["source", "provider"]).unwrap();
+}
+
+/// The provider label used for destinations that don't match
+/// any of the providers in the plan
+const DEFAULT_PROVIDER: &str = "default";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupStep {
+    /// The day of the plan from which this step applies.
+    /// Day 1 is the start_date of the plan.
+    pub day: u32,
+
+    /// The maximum number of messages per day
+    #[serde(default)]
+    pub per_day: Option<u64>,
+
+    /// The maximum number of messages per hour
+    #[serde(default)]
+    pub per_hour: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupProvider {
+    /// Domain suffixes that identify the provider. They are matched
+    /// against both the recipient domain and the site name of the
+    /// destination, so that domains hosted by the provider are
+    /// also matched.
+    pub domain_suffixes: Vec<String>,
+
+    pub schedule: Vec<WarmupStep>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupPlan {
+    /// The date, in UTC, on which the source started sending.
+    /// This is day 1 of the plan.
+    pub start_date: NaiveDate,
+
+    /// The schedule for destinations that don't match any of
+    /// the providers. If empty, those destinations are not limited
+    /// once the plan has started.
+    #[serde(default)]
+    pub schedule: Vec<WarmupStep>,
+
+    /// Schedules for specific providers, keyed by provider name.
+    /// If more than one provider matches a destination, the
+    /// first one in name order is used.
+    #[serde(default)]
+    pub providers: BTreeMap<String, WarmupProvider>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WarmupDecision {
+    /// The message can be sent from the source
+    Proceed,
+    /// The plan is exhausted; it will have capacity again
+    /// after this duration
+    Exhausted(Duration),
+}
+
+fn validate_schedule(label: &str, schedule: &[WarmupStep]) -> anyhow::Result<()> {
+    let mut prior = 0;
+    for step in schedule {
+        if step.day <= prior {
+            anyhow::bail!(
+                "warmup schedule {label}: days must be greater than 0 \
+                 and in increasing order, but day {} follows day {prior}",
+                step.day
+            );
+        }
+        prior = step.day;
+    }
+    Ok(())
+}
+
+/// Returns the step that applies on `day`: the last step whose day
+/// is not after `day`, or the first step if the plan hasn't reached it yet.
+fn step_for(schedule: &[WarmupStep], day: i64) -> Option<&WarmupStep> {
+    schedule
+        .iter()
+        .rev()
+        .find(|step| i64::from(step.day) <= day)
+        .or_else(|| schedule.first())
+}
+
+impl WarmupPlan {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_schedule(DEFAULT_PROVIDER, &self.schedule)?;
+        for (name, provider) in &self.providers {
+            if name == DEFAULT_PROVIDER {
+                anyhow::bail!("warmup provider name '{DEFAULT_PROVIDER}' is reserved");
+            }
+            if provider.domain_suffixes.is_empty() {
+                anyhow::bail!("warmup provider {name} has no domain_suffixes");
+            }
+            validate_schedule(name, &provider.schedule)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the day of the plan; day 1 is the start_date.
+    /// Days before the start date are 0 or negative.
+    fn day_number(&self, today: NaiveDate) -> i64 {
+        (today - self.start_date).num_days() + 1
+    }
+
+    /// Returns the provider name and its schedule for the destination
+    fn schedule_for(&self, domain: &str, site: &str) -> (&str, &[WarmupStep]) {
+        for (name, provider) in &self.providers {
+            if provider
+                .domain_suffixes
+                .iter()
+                .any(|suffix| domain_has_suffix(domain, suffix) || domain_has_suffix(site, suffix))
+            {
+                return (name, &provider.schedule);
+            }
+        }
+        (DEFAULT_PROVIDER, &self.schedule)
+    }
+
+    /// Decide whether a message for the destination can be sent from
+    /// `source` without exceeding the plan. If it can, it is counted
+    /// against today's limits.
+    pub async fn admit(
+        &self,
+        source: &str,
+        domain: &str,
+        site: &str,
+    ) -> anyhow::Result<WarmupDecision> {
+        let now = Utc::now();
+        let day = self.day_number(now.date_naive());
+        WARMUP_DAY.with_label_values(&[source]).set(day.max(0));
+
+        let (provider, schedule) = self.schedule_for(domain, site);
+
+        if day < 1 {
+            // The source isn't due to start sending yet
+            let start = self
+                .start_date
+                .and_hms_opt(0, 0, 0)
+                .expect("midnight is valid")
+                .and_utc();
+            return Ok(spillover(
+                source,
+                provider,
+                (start - now).to_std().unwrap_or_default(),
+            ));
+        }
+
+        let step = match step_for(schedule, day) {
+            Some(step) => step,
+            None => return Ok(WarmupDecision::Proceed),
+        };
+
+        let limits = [(step.per_hour, 3600, "hour"), (step.per_day, 86400, "day")];
+        for (limit, period, label) in limits {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+            WARMUP_LIMIT
+                .with_label_values(&[source, provider, label])
+                .set(limit as i64);
+            if limit == 0 {
+                return Ok(spillover(source, provider, Duration::from_secs(period)));
+            }
+        }
+
+        // Check all of the limits before consuming anything, so that
+        // a message refused by one exhausted limit doesn't also use up
+        // the allowance of the others. Only then is the message counted
+        // against all of them.
+        for quantity in [0, 1] {
+            for (limit, period, label) in limits {
+                let limit = match limit {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                if let Some(delay) =
+                    warmup_throttle(source, provider, label, limit, period, quantity).await?
+                {
+                    return Ok(spillover(source, provider, delay));
+                }
+            }
+        }
+
+        WARMUP_ADMITTED.with_label_values(&[source, provider]).inc();
+        Ok(WarmupDecision::Proceed)
+    }
+}
+
+fn spillover(source: &str, provider: &str, delay: Duration) -> WarmupDecision {
+    WARMUP_SPILLOVER
+        .with_label_values(&[source, provider])
+        .inc();
+    WarmupDecision::Exhausted(delay)
+}
+
+/// Applies `quantity` to the warm-up throttle for the period,
+/// returning the delay until it has capacity if it is exhausted.
+/// A `quantity` of 0 checks for capacity without consuming it.
+async fn warmup_throttle(
+    source: &str,
+    provider: &str,
+    label: &str,
+    limit: u64,
+    period: u64,
+    quantity: u64,
+) -> anyhow::Result<Option<Duration>> {
+    let result = ThrottleSpec {
+        limit,
+        period,
+        max_burst: None,
+        pacing: false,
+    }
+    .throttle_quantity(format!("warmup:{source}:{provider}:{label}"), quantity)
+    .await?;
+    if quantity == 0 {
+        // A query is never throttled; the throttle is exhausted when it
+        // has nothing remaining, and it frees up a token at least once
+        // per emission interval
+        if result.remaining == 0 {
+            let interval = Duration::from_secs((period / limit).max(1));
+            return Ok(Some(
+                result.reset_after.min(interval).max(Duration::from_secs(1)),
+            ));
+        }
+        return Ok(None);
+    }
+    Ok(result.retry_after)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn step(day: u32, per_day: u64) -> WarmupStep {
+        WarmupStep {
+            day,
+            per_day: Some(per_day),
+            per_hour: None,
+        }
+    }
+
+    fn plan() -> WarmupPlan {
+        WarmupPlan {
+            start_date: NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+            schedule: vec![step(1, 1000), step(3, 5000)],
+            providers: [(
+                "google".to_string(),
+                WarmupProvider {
+                    domain_suffixes: vec!["gmail.com".to_string(), "google.com".to_string()],
+                    schedule: vec![step(1, 50), step(2, 100), step(5, 500)],
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn schedule_lookup() {
+        let plan = plan();
+        plan.validate().unwrap();
+
+        assert_eq!(
+            plan.day_number(NaiveDate::from_ymd_opt(2024, 5, 1).unwrap()),
+            1
+        );
+        assert_eq!(
+            plan.day_number(NaiveDate::from_ymd_opt(2024, 4, 30).unwrap()),
+            0
+        );
+        assert_eq!(
+            plan.day_number(NaiveDate::from_ymd_opt(2024, 5, 10).unwrap()),
+            10
+        );
+
+        let (provider, schedule) = plan.schedule_for("example.com", "mx.l.google.com");
+        assert_eq!(provider, "google");
+        assert_eq!(step_for(schedule, 0).unwrap().per_day, Some(50));
+        assert_eq!(step_for(schedule, 4).unwrap().per_day, Some(100));
+        assert_eq!(step_for(schedule, 30).unwrap().per_day, Some(500));
+
+        let (provider, schedule) = plan.schedule_for("example.com", "mx.example.com");
+        assert_eq!(provider, DEFAULT_PROVIDER);
+        assert_eq!(step_for(schedule, 2).unwrap().per_day, Some(1000));
+        assert_eq!(step_for(schedule, 3).unwrap().per_day, Some(5000));
+
+        assert_eq!(step_for(&[], 3), None);
+    }
+
+    #[tokio::test]
+    async fn daily_limit_checked_before_hourly() {
+        let plan = WarmupPlan {
+            start_date: Utc::now().date_naive(),
+            schedule: vec![WarmupStep {
+                day: 1,
+                per_day: Some(1),
+                per_hour: Some(2),
+            }],
+            providers: BTreeMap::new(),
+        };
+        let source = "warmup-ordering";
+
+        assert_eq!(
+            plan.admit(source, "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Proceed
+        );
+        assert!(matches!(
+            plan.admit(source, "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Exhausted(_)
+        ));
+
+        // The message refused by the daily limit didn't use
+        // up the remaining hourly allowance
+        assert_eq!(
+            warmup_throttle(source, DEFAULT_PROVIDER, "hour", 2, 3600, 0)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn hourly_limit_checked_before_daily() {
+        let plan = WarmupPlan {
+            start_date: Utc::now().date_naive(),
+            schedule: vec![WarmupStep {
+                day: 1,
+                per_day: Some(2),
+                per_hour: Some(1),
+            }],
+            providers: BTreeMap::new(),
+        };
+        let source = "warmup-hourly-ordering";
+
+        assert_eq!(
+            plan.admit(source, "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Proceed
+        );
+        assert!(matches!(
+            plan.admit(source, "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Exhausted(_)
+        ));
+
+        // The message refused by the hourly limit didn't use
+        // up the remaining daily allowance
+        assert_eq!(
+            warmup_throttle(source, DEFAULT_PROVIDER, "day", 2, 86400, 0)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn start_date_applies_without_schedule() {
+        let plan = WarmupPlan {
+            start_date: Utc::now().date_naive() + chrono::Duration::try_days(2).unwrap(),
+            schedule: vec![],
+            providers: BTreeMap::new(),
+        };
+        assert!(matches!(
+            plan.admit("warmup-not-started", "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Exhausted(delay) if delay > Duration::from_secs(86400)
+        ));
+
+        let plan = WarmupPlan {
+            start_date: Utc::now().date_naive(),
+            ..plan
+        };
+        assert_eq!(
+            plan.admit("warmup-not-started", "example.com", "mx.example.com")
+                .await
+                .unwrap(),
+            WarmupDecision::Proceed
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let mut bad = plan();
+        bad.schedule = vec![step(2, 1), step(2, 2)];
+        assert!(bad.validate().is_err());
+
+        let mut bad = plan();
+        bad.schedule = vec![step(0, 1)];
+        assert!(bad.validate().is_err());
+
+        let mut bad = plan();
+        bad.providers.insert(
+            DEFAULT_PROVIDER.to_string(),
+            WarmupProvider {
+                domain_suffixes: vec!["example.com".to_string()],
+                schedule: vec![],
+            },
+        );
+        assert!(bad.validate().is_err());
+    }
+}
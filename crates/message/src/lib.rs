@@ -3,6 +3,8 @@ pub mod address;
 pub mod dkim;
 pub mod message;
 pub mod scheduling;
+#[cfg(feature = "impl")]
+pub mod verp;
 
 pub use crate::address::EnvelopeAddress;
 pub use crate::message::Message;
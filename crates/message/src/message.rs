@@ -2,6 +2,8 @@ use crate::address::HeaderAddressList;
 #[cfg(feature = "impl")]
 use crate::dkim::Signer;
 use crate::scheduling::Scheduling;
+#[cfg(feature = "impl")]
+use crate::verp::VerpParams;
 use crate::EnvelopeAddress;
 use anyhow::Context;
 use chrono::{DateTime, Utc};
@@ -1039,6 +1041,14 @@ impl UserData for Message {
             Ok(this.dkim_sign(&signer).map_err(any_err)?)
         });
 
+        methods.add_async_method(
+            "set_verp_sender",
+            |lua, this, params: mlua::Value| async move {
+                let params: VerpParams = from_lua_value(lua, params)?;
+                this.set_verp_sender(&params).await.map_err(any_err)
+            },
+        );
+
         methods.add_method(
             "add_authentication_results",
             move |lua, this, (serv_id, results): (String, mlua::Value)| {
@@ -3,6 +3,7 @@ use chrono::{DateTime, Datelike, FixedOffset, LocalResult, TimeZone, Timelike, U
 use chrono_tz::Tz;
 use kumo_chrono_helper::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 bitflags::bitflags! {
@@ -169,6 +170,143 @@ impl Scheduling {
     }
 }
 
+/// A window of permitted sending times that applies to all of the
+/// messages in a queue or egress path, rather than to an individual
+/// message. The timezone in which the window is evaluated can be
+/// chosen based on the recipient domain.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "SendingWindowUnchecked", into = "SendingWindowUnchecked")]
+pub struct SendingWindow {
+    pub days_of_week: DaysOfWeek,
+    /// The timezone to use when the recipient domain doesn't match
+    /// any entry in timezone_by_domain. Defaults to UTC.
+    pub timezone: Option<Tz>,
+    /// Always earlier than end
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Maps a domain to the timezone of its recipients.
+    /// An entry also matches the subdomains of its domain,
+    /// with the longest matching entry taking precedence.
+    /// Domains are case insensitive and are lowercased when loaded.
+    pub timezone_by_domain: BTreeMap<String, Tz>,
+}
+
+/// This is the type that we actually use to deserialize SendingWindow.
+/// The validation is performed by the TryFrom impl that is used to
+/// convert to the checked form above.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct SendingWindowUnchecked {
+    #[serde(
+        rename = "dow",
+        default = "SendingWindowUnchecked::default_days_of_week"
+    )]
+    days_of_week: DaysOfWeek,
+    #[serde(rename = "tz", default)]
+    timezone: Option<Tz>,
+    start: NaiveTime,
+    end: NaiveTime,
+    #[serde(default, deserialize_with = "deserialize_timezone_by_domain")]
+    timezone_by_domain: BTreeMap<String, Tz>,
+}
+
+impl TryFrom<SendingWindowUnchecked> for SendingWindow {
+    type Error = anyhow::Error;
+    fn try_from(window: SendingWindowUnchecked) -> anyhow::Result<SendingWindow> {
+        // A window that wraps past midnight would never be satisfied,
+        // and would hold the messages until they expire
+        anyhow::ensure!(
+            window.start < window.end,
+            "sending_window start ({}) must be earlier than its end ({})",
+            window.start,
+            window.end
+        );
+        anyhow::ensure!(
+            !window.days_of_week.is_empty(),
+            "sending_window dow must include at least one day"
+        );
+        Ok(SendingWindow {
+            days_of_week: window.days_of_week,
+            timezone: window.timezone,
+            start: window.start,
+            end: window.end,
+            timezone_by_domain: window.timezone_by_domain,
+        })
+    }
+}
+
+impl From<SendingWindow> for SendingWindowUnchecked {
+    fn from(window: SendingWindow) -> SendingWindowUnchecked {
+        SendingWindowUnchecked {
+            days_of_week: window.days_of_week,
+            timezone: window.timezone,
+            start: window.start,
+            end: window.end,
+            timezone_by_domain: window.timezone_by_domain,
+        }
+    }
+}
+
+fn deserialize_timezone_by_domain<'de, D>(deserializer: D) -> Result<BTreeMap<String, Tz>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let map = BTreeMap::<String, Tz>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(domain, tz)| (domain.to_ascii_lowercase(), tz))
+        .collect())
+}
+
+impl SendingWindowUnchecked {
+    fn default_days_of_week() -> DaysOfWeek {
+        DaysOfWeek::all()
+    }
+}
+
+impl SendingWindow {
+    /// Returns the timezone in which the window is evaluated
+    /// for recipients in `domain`
+    pub fn timezone_for_domain(&self, domain: &str) -> Tz {
+        let domain = domain.to_ascii_lowercase();
+        let mut candidate = domain.as_str();
+        loop {
+            if let Some(tz) = self.timezone_by_domain.get(candidate) {
+                return *tz;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => break,
+            }
+        }
+        self.timezone.unwrap_or(Tz::UTC)
+    }
+
+    fn scheduling_for_domain(&self, domain: &str) -> Scheduling {
+        Scheduling {
+            restriction: Some(ScheduleRestriction {
+                days_of_week: self.days_of_week,
+                timezone: self.timezone_for_domain(domain),
+                start: self.start,
+                end: self.end,
+            }),
+            first_attempt: None,
+        }
+    }
+
+    /// If `dt` is outside the window for recipients in `domain`,
+    /// returns the next time at which sending is permitted.
+    /// Returns None if `dt` is within the window.
+    pub fn next_permitted(&self, domain: &str, dt: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let sched = self.scheduling_for_domain(domain);
+        if sched.is_within_schedule(dt) {
+            None
+        } else {
+            Some(sched.adjust_for_schedule(dt))
+        }
+    }
+}
+
 const DAYS: &[(&str, DaysOfWeek)] = &[
     ("Monday", DaysOfWeek::MON),
     ("Tuesday", DaysOfWeek::TUE),
@@ -386,4 +524,87 @@ mod test {
         // Expected to round into Friday, later that week
         k9::assert_equal!(adjusted.to_string(), "2023-03-31 09:00:00 MST");
     }
+
+    #[test]
+    fn sending_window_validation() {
+        let err =
+            serde_json::from_str::<SendingWindow>(r#"{"start": "20:00:00", "end": "08:00:00"}"#)
+                .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("start (20:00:00) must be earlier than its end (08:00:00)"),
+            "{err}"
+        );
+
+        assert!(serde_json::from_str::<SendingWindow>(
+            r#"{"start": "08:00:00", "end": "08:00:00"}"#,
+        )
+        .is_err());
+
+        assert!(serde_json::from_str::<SendingWindow>(
+            r#"{"dow": "", "start": "08:00:00", "end": "20:00:00"}"#,
+        )
+        .is_err());
+
+        let window: SendingWindow =
+            serde_json::from_str(r#"{"start": "08:00:00", "end": "20:00:00"}"#).unwrap();
+        k9::assert_equal!(window.days_of_week, DaysOfWeek::all());
+        // and it round trips
+        let json = serde_json::to_string(&window).unwrap();
+        k9::assert_equal!(
+            serde_json::from_str::<SendingWindow>(&json).unwrap(),
+            window
+        );
+    }
+
+    #[test]
+    fn sending_window_by_domain() {
+        let window: SendingWindow = serde_json::from_str(
+            r#"{
+                "dow": "Mon,Tue,Wed,Thu,Fri",
+                "start": "08:00:00",
+                "end": "20:00:00",
+                "timezone_by_domain": {
+                    "example.com": "America/Phoenix",
+                    "UK.Example.com": "Europe/London"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        k9::assert_equal!(window.timezone_for_domain("other.com"), Tz::UTC);
+        k9::assert_equal!(
+            window.timezone_for_domain("mail.Example.com").name(),
+            "America/Phoenix"
+        );
+        k9::assert_equal!(
+            window.timezone_for_domain("uk.example.com").name(),
+            "Europe/London"
+        );
+
+        // This is a Tuesday, 07:00 in Phoenix and 15:00 in London
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-03-28T14:00:00Z")
+            .unwrap()
+            .into();
+        k9::assert_equal!(window.next_permitted("uk.example.com", now), None);
+        k9::assert_equal!(
+            window
+                .next_permitted("example.com", now)
+                .unwrap()
+                .to_rfc3339(),
+            "2023-03-28T15:00:00+00:00"
+        );
+
+        // This is a Friday, 21:00 UTC, so the next window is Monday morning
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-03-31T21:00:00Z")
+            .unwrap()
+            .into();
+        k9::assert_equal!(
+            window
+                .next_permitted("other.com", now)
+                .unwrap()
+                .to_rfc3339(),
+            "2023-04-03T08:00:00+00:00"
+        );
+    }
 }
@@ -0,0 +1,300 @@
+//! Signed VERP (Variable Envelope Return Path) addresses.
+//!
+//! The envelope sender of a message is rewritten to the form
+//! `PREFIX+TOKEN-RECIPIENT@DOMAIN`, where `RECIPIENT` is the original
+//! recipient address with its `@` replaced by `=`, and `TOKEN` is
+//! the base32 encoding of the spool id of the message followed by a
+//! truncated HMAC-SHA256 over the spool id and recipient.
+//!
+//! When a bounce is later received for that address, the token can be
+//! verified to recover the spool id and recipient of the original
+//! message, and to reject forged or expired addresses.
+use crate::{EnvelopeAddress, Message};
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use data_encoding::BASE32_NOPAD;
+use data_loader::KeySource;
+use lruttl::LruCacheWithTtl;
+use mlua::{Lua, LuaSerdeExt, Value};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use spool::SpoolId;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref KEY_CACHE: LruCacheWithTtl<KeySource, Arc<Vec<u8>>> = LruCacheWithTtl::new(128);
+}
+
+/// The number of bytes of the HMAC that are included in the token.
+/// This is kept short so that the token doesn't consume too much of
+/// the 64 octet local part limit from RFC 5321; together with the
+/// spool id it encodes to exactly 32 base32 characters with no padding.
+const MAC_LEN: usize = 4;
+const SPOOL_ID_LEN: usize = 16;
+/// The length of the base32 encoded token
+const TOKEN_LEN: usize = ((SPOOL_ID_LEN + MAC_LEN) * 8 + 4) / 5;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VerpParams {
+    /// The domain to use for the generated envelope sender
+    pub domain: String,
+
+    /// The local part prefix
+    #[serde(default = "VerpParams::default_prefix")]
+    pub prefix: String,
+
+    /// The key used to sign and verify tokens
+    pub key: KeySource,
+
+    /// When decoding, tokens that belong to messages that were created
+    /// longer ago than this are considered to be expired
+    #[serde(default = "VerpParams::default_max_age", with = "duration_serde")]
+    pub max_age: Duration,
+
+    /// How long to cache the key data
+    #[serde(default = "VerpParams::default_ttl", with = "duration_serde")]
+    pub ttl: Duration,
+}
+
+impl VerpParams {
+    fn default_prefix() -> String {
+        "bounces".to_string()
+    }
+
+    fn default_max_age() -> Duration {
+        Duration::from_secs(30 * 86400)
+    }
+
+    fn default_ttl() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    async fn get_key(&self) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some(key) = KEY_CACHE.get(&self.key) {
+            return Ok(key);
+        }
+        let data = self
+            .key
+            .get()
+            .await
+            .map_err(|err| anyhow::anyhow!("{:?}: {err:#}", self.key))?;
+        Ok(KEY_CACHE.insert(self.key.clone(), Arc::new(data), Instant::now() + self.ttl))
+    }
+}
+
+/// The information recovered from a valid VERP address
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct VerpAddress {
+    pub id: SpoolId,
+    pub recipient: String,
+}
+
+fn compute_mac(key: &[u8], id: &SpoolId, recipient: &str) -> anyhow::Result<Vec<u8>> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    signer.update(id.as_bytes())?;
+    signer.update(recipient.to_ascii_lowercase().as_bytes())?;
+    let mut mac = signer.sign_to_vec()?;
+    mac.truncate(MAC_LEN);
+    Ok(mac)
+}
+
+/// Produce the signed VERP address for the given spool id and recipient
+pub fn encode_address(
+    key: &[u8],
+    prefix: &str,
+    domain: &str,
+    id: &SpoolId,
+    recipient: &str,
+) -> anyhow::Result<String> {
+    let (user, recip_domain) = recipient
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow::anyhow!("invalid recipient address {recipient}"))?;
+
+    let mut token = id.as_bytes().to_vec();
+    token.extend_from_slice(&compute_mac(key, id, recipient)?);
+    let token = BASE32_NOPAD.encode(&token).to_ascii_lowercase();
+
+    Ok(format!("{prefix}+{token}-{user}={recip_domain}@{domain}"))
+}
+
+/// Decode and verify a VERP address.
+/// Returns Ok(None) if the address is not a VERP address using the
+/// specified prefix.
+/// Returns an error if the address is malformed, the signature doesn't
+/// match, or if the original message is older than `max_age`.
+pub fn decode_address(
+    key: &[u8],
+    prefix: &str,
+    address: &str,
+    max_age: Duration,
+) -> anyhow::Result<Option<VerpAddress>> {
+    let (local_part, _domain) = match address.rsplit_once('@') {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let remainder = match local_part.split_once('+') {
+        Some((p, remainder)) if p.eq_ignore_ascii_case(prefix) => remainder,
+        _ => return Ok(None),
+    };
+
+    anyhow::ensure!(
+        remainder.len() > TOKEN_LEN + 1 && remainder.as_bytes()[TOKEN_LEN] == b'-',
+        "malformed VERP address"
+    );
+    let (token, recipient) = remainder.split_at(TOKEN_LEN);
+    let (user, recip_domain) = recipient[1..]
+        .rsplit_once('=')
+        .ok_or_else(|| anyhow::anyhow!("malformed VERP address"))?;
+    let recipient = format!("{user}@{recip_domain}");
+
+    let token = BASE32_NOPAD
+        .decode(token.to_ascii_uppercase().as_bytes())
+        .map_err(|_| anyhow::anyhow!("malformed VERP token"))?;
+    anyhow::ensure!(
+        token.len() == SPOOL_ID_LEN + MAC_LEN,
+        "malformed VERP token"
+    );
+    let (id, mac) = token.split_at(SPOOL_ID_LEN);
+    let id = SpoolId::from_slice(id).ok_or_else(|| anyhow::anyhow!("malformed VERP token"))?;
+
+    let expected = compute_mac(key, &id, &recipient)?;
+    anyhow::ensure!(memcmp::eq(&expected, mac), "VERP token signature mismatch");
+
+    let age = chrono::Utc::now()
+        .signed_duration_since(id.created())
+        .to_std()
+        .unwrap_or_default();
+    anyhow::ensure!(age <= max_age, "VERP token has expired");
+
+    Ok(Some(VerpAddress { id, recipient }))
+}
+
+impl Message {
+    /// Rewrite the envelope sender of the message to the signed
+    /// VERP address for its spool id and recipient
+    pub async fn set_verp_sender(&self, params: &VerpParams) -> anyhow::Result<()> {
+        let key = params.get_key().await?;
+        let recipient = self.recipient()?.to_string();
+        let address = encode_address(&key, &params.prefix, &params.domain, self.id(), &recipient)?;
+        self.set_sender(EnvelopeAddress::parse(&address)?)
+    }
+}
+
+pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
+    let verp_mod = get_or_create_sub_module(lua, "verp")?;
+    verp_mod.set(
+        "decode_address",
+        lua.create_async_function(|lua, (address, params): (Value, Value)| async move {
+            let address = match address {
+                Value::UserData(ud) => ud.borrow::<EnvelopeAddress>()?.to_string(),
+                Value::String(s) => s.to_str()?.to_string(),
+                _ => {
+                    return Err(mlua::Error::external(
+                        "decode_address: address must be a string or EnvelopeAddress",
+                    ))
+                }
+            };
+            let params: VerpParams = from_lua_value(lua, params)?;
+            let key = params.get_key().await.map_err(any_err)?;
+            match decode_address(&key, &params.prefix, &address, params.max_age).map_err(any_err)? {
+                Some(decoded) => lua.to_value(&decoded),
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &[u8] = b"the-key";
+
+    #[test]
+    fn round_trip() {
+        let id = SpoolId::new();
+        let address = encode_address(
+            KEY,
+            "bounces",
+            "bounce.example.com",
+            &id,
+            "User@example.com",
+        )
+        .unwrap();
+        assert!(address.starts_with("bounces+"));
+        assert!(address.ends_with("-User=example.com@bounce.example.com"));
+        assert_eq!(TOKEN_LEN, 32);
+        assert_eq!(
+            address.len(),
+            "bounces+".len() + TOKEN_LEN + "-User=example.com@bounce.example.com".len()
+        );
+
+        let max_age = Duration::from_secs(86400);
+        let decoded = decode_address(KEY, "bounces", &address, max_age)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            decoded,
+            VerpAddress {
+                id,
+                recipient: "User@example.com".to_string()
+            }
+        );
+
+        // Some systems will fold the case of the local part
+        let decoded = decode_address(KEY, "bounces", &address.to_ascii_uppercase(), max_age)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.id, id);
+        assert_eq!(decoded.recipient, "USER@EXAMPLE.COM");
+
+        // Not a VERP address
+        assert_eq!(
+            decode_address(KEY, "bounces", "user@example.com", max_age).unwrap(),
+            None
+        );
+        assert_eq!(
+            decode_address(KEY, "other", &address, max_age).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn forged() {
+        let id = SpoolId::new();
+        let max_age = Duration::from_secs(86400);
+        let address =
+            encode_address(KEY, "bounces", "example.com", &id, "user@example.com").unwrap();
+
+        // Wrong key
+        assert!(decode_address(b"other-key", "bounces", &address, max_age).is_err());
+
+        // Recipient was tampered with
+        let tampered = address.replace("-user=", "-someone=");
+        assert!(decode_address(KEY, "bounces", &tampered, max_age).is_err());
+
+        // Malformed token
+        assert!(decode_address(
+            KEY,
+            "bounces",
+            "bounces+abc-user=example.com@example.com",
+            max_age
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn expired() {
+        let id = SpoolId::new();
+        let address =
+            encode_address(KEY, "bounces", "example.com", &id, "user@example.com").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(decode_address(KEY, "bounces", &address, Duration::from_secs(0)).is_err());
+    }
+}
@@ -7,6 +7,8 @@ use mlua::{Lua, LuaSerdeExt, UserDataMethods, Value};
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 
+pub use lapin;
+
 #[derive(Deserialize, Debug)]
 struct PublishParams {
     routing_key: String,
@@ -130,19 +132,25 @@ impl LuaUserData for Confirm {
     }
 }
 
+/// Connect to the broker at `uri` and open a channel.
+/// This is shared with the native AMQP log sink in kumod.
+pub async fn connect(uri: &str) -> anyhow::Result<(Connection, Channel)> {
+    let options = ConnectionProperties::default()
+        .with_executor(tokio_executor_trait::Tokio::current())
+        .with_reactor(tokio_reactor_trait::Tokio);
+
+    let connection = Connection::connect(uri, options).await?;
+    let channel = connection.create_channel().await?;
+    Ok((connection, channel))
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let amqp_mod = get_or_create_sub_module(lua, "amqp")?;
 
     amqp_mod.set(
         "build_client",
         lua.create_async_function(|_, uri: String| async move {
-            let options = ConnectionProperties::default()
-                .with_executor(tokio_executor_trait::Tokio::current())
-                .with_reactor(tokio_reactor_trait::Tokio);
-
-            let connection = Connection::connect(&uri, options).await.map_err(any_err)?;
-
-            let channel = connection.create_channel().await.map_err(any_err)?;
+            let (connection, channel) = connect(&uri).await.map_err(any_err)?;
 
             Ok(AMQPClient {
                 holder: Arc::new(ChannelHolder {
@@ -10,6 +10,8 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+pub use rdkafka;
+
 #[derive(Clone)]
 struct Producer {
     producer: Arc<Mutex<Option<Arc<FutureProducer>>>>,
@@ -101,18 +103,23 @@ impl LuaUserData for Producer {
     }
 }
 
+/// Build a producer from a set of librdkafka configuration properties.
+/// This is shared with the native kafka log sink in kumod.
+pub fn build_producer(config: &HashMap<String, String>) -> anyhow::Result<FutureProducer> {
+    let mut builder = ClientConfig::new();
+    for (k, v) in config {
+        builder.set(k, v);
+    }
+    Ok(builder.create()?)
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let kafka_mod = get_or_create_sub_module(lua, "kafka")?;
 
     kafka_mod.set(
         "build_producer",
         lua.create_async_function(|_, config: HashMap<String, String>| async move {
-            let mut builder = ClientConfig::new();
-            for (k, v) in config {
-                builder.set(k, v);
-            }
-
-            let producer = builder.create().map_err(any_err)?;
+            let producer = build_producer(&config).map_err(any_err)?;
 
             Ok(Producer {
                 producer: Arc::new(Mutex::new(Some(Arc::new(producer)))),
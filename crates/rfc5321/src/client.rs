@@ -21,10 +21,15 @@ use tokio_rustls::rustls::{
     Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName, SupportedCipherSuite,
 };
 use tokio_rustls::TlsConnector;
-use tracing::Level;
+use tracing::{Instrument, Level};
 
 pub use {openssl, tokio_rustls};
 
+/// The tracing target for per-message trace spans. This crate doesn't
+/// depend on kumo-server-common, so this mirrors its
+/// `diagnostic_logging::MESSAGE_TRACE_TARGET`.
+const MESSAGE_TRACE_TARGET: &str = "message_trace";
+
 const MAX_LINE_LEN: usize = 4096;
 
 #[derive(Error, Debug)]
@@ -597,6 +602,8 @@ impl SmtpClient {
         recipient: RECIP,
         data: B,
     ) -> Result<Response, ClientError> {
+        // The spans for the transaction phases use MESSAGE_TRACE_TARGET
+        // so that they can be included in per-message traces
         let mut responses = self
             .pipeline_commands(vec![
                 Command::Rset,
@@ -610,6 +617,7 @@ impl SmtpClient {
                 },
                 Command::Data,
             ])
+            .instrument(tracing::info_span!(target: MESSAGE_TRACE_TARGET, "smtp_envelope"))
             .await;
 
         if responses.is_empty() {
@@ -639,6 +647,18 @@ impl SmtpClient {
         }
 
         let data: &[u8] = data.as_ref();
+        self.send_data(data)
+            .instrument(tracing::info_span!(
+                target: MESSAGE_TRACE_TARGET,
+                "smtp_data",
+                size = data.len()
+            ))
+            .await
+    }
+
+    /// Transmit the message content, after DATA has been accepted,
+    /// and return the final response
+    async fn send_data(&mut self, data: &[u8]) -> Result<Response, ClientError> {
         let stuffed;
 
         let data = match apply_dot_stuffing(data) {
@@ -0,0 +1,253 @@
+//! A Spool implementation that wraps another Spool and transparently
+//! compresses the stored data using zstd.
+//!
+//! Compressed entries are prefixed with a short header so that
+//! entries that were written before compression was enabled, or
+//! that were too small to be worth compressing, remain readable.
+use crate::{Spool, SpoolEntry, SpoolId};
+use anyhow::Context;
+use async_trait::async_trait;
+use flume::Sender;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Prefixed to compressed entries. The leading NUL byte cannot
+/// appear at the start of either the JSON metadata or an RFC 5322
+/// message, so it cannot be confused with legacy uncompressed data.
+const MAGIC: &[u8] = b"\0KZS1";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionParams {
+    /// Entries smaller than this number of bytes are stored verbatim
+    #[serde(default = "CompressionParams::default_threshold")]
+    pub threshold: usize,
+
+    /// The zstd compression level
+    #[serde(default = "CompressionParams::default_level")]
+    pub level: i32,
+}
+
+impl Default for CompressionParams {
+    fn default() -> Self {
+        Self {
+            threshold: Self::default_threshold(),
+            level: Self::default_level(),
+        }
+    }
+}
+
+impl CompressionParams {
+    fn default_threshold() -> usize {
+        4096
+    }
+
+    fn default_level() -> i32 {
+        3
+    }
+}
+
+pub struct CompressedSpool {
+    inner: Arc<dyn Spool + Send + Sync>,
+    params: CompressionParams,
+    compressed_bytes: metrics::Counter,
+    uncompressed_bytes: metrics::Counter,
+}
+
+impl CompressedSpool {
+    pub fn new(name: &str, inner: Arc<dyn Spool + Send + Sync>, params: CompressionParams) -> Self {
+        metrics::describe_counter!(
+            "spool_compressed_bytes",
+            "number of bytes written to the spool after compression"
+        );
+        metrics::describe_counter!(
+            "spool_uncompressed_bytes",
+            "number of bytes passed to the spool for compression, prior to compression"
+        );
+        Self {
+            inner,
+            params,
+            compressed_bytes: metrics::counter!(
+                "spool_compressed_bytes",
+                "spool" => name.to_string()
+            ),
+            uncompressed_bytes: metrics::counter!(
+                "spool_uncompressed_bytes",
+                "spool" => name.to_string()
+            ),
+        }
+    }
+}
+
+/// Compress data, returning None if the compressed form would
+/// not be any smaller than the input
+fn compress(data: &[u8], level: i32) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut output = MAGIC.to_vec();
+    zstd::stream::copy_encode(data, &mut output, level)?;
+    if output.len() >= data.len() {
+        return Ok(None);
+    }
+    Ok(Some(output))
+}
+
+/// Decompress data if it has our header, otherwise return it unchanged
+fn decompress(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match data.strip_prefix(MAGIC) {
+        Some(compressed) => Ok(zstd::stream::decode_all(compressed)?),
+        None => Ok(data),
+    }
+}
+
+#[async_trait]
+impl Spool for CompressedSpool {
+    async fn load(&self, id: SpoolId) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.load(id).await?;
+        if !data.starts_with(MAGIC) {
+            return Ok(data);
+        }
+        tokio::task::Builder::new()
+            .name("CompressedSpool load")
+            .spawn_blocking(move || {
+                decompress(data).with_context(|| format!("failed to decompress {id}"))
+            })?
+            .await?
+    }
+
+    async fn remove(&self, id: SpoolId) -> anyhow::Result<()> {
+        self.inner.remove(id).await
+    }
+
+    async fn store(
+        &self,
+        id: SpoolId,
+        data: Arc<Box<[u8]>>,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        if data.len() < self.params.threshold {
+            return self.inner.store(id, data, force_sync).await;
+        }
+
+        let level = self.params.level;
+        let uncompressed_len = data.len();
+        let compressed = tokio::task::Builder::new()
+            .name("CompressedSpool store")
+            .spawn_blocking({
+                let data = Arc::clone(&data);
+                move || compress(&data, level).with_context(|| format!("failed to compress {id}"))
+            })?
+            .await??;
+
+        match compressed {
+            Some(compressed) => {
+                self.uncompressed_bytes.increment(uncompressed_len as u64);
+                self.compressed_bytes.increment(compressed.len() as u64);
+                self.inner
+                    .store(id, Arc::new(compressed.into_boxed_slice()), force_sync)
+                    .await
+            }
+            None => self.inner.store(id, data, force_sync).await,
+        }
+    }
+
+    fn enumerate(&self, sender: Sender<SpoolEntry>) -> anyhow::Result<()> {
+        let (tx, rx) = flume::bounded(32);
+        self.inner.enumerate(tx)?;
+        tokio::task::Builder::new()
+            .name("CompressedSpool enumerate")
+            .spawn_blocking(move || -> anyhow::Result<()> {
+                while let Ok(entry) = rx.recv() {
+                    let entry = match entry {
+                        SpoolEntry::Item { id, data } => match decompress(data) {
+                            Ok(data) => SpoolEntry::Item { id, data },
+                            Err(err) => SpoolEntry::Corrupt {
+                                id,
+                                error: format!("failed to decompress: {err:#}"),
+                            },
+                        },
+                        corrupt => corrupt,
+                    };
+                    sender
+                        .send(entry)
+                        .map_err(|err| anyhow::anyhow!("failed to send SpoolEntry: {err:#}"))?;
+                }
+                Ok(())
+            })?;
+        Ok(())
+    }
+
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        self.inner.cleanup().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+
+    #[tokio::test]
+    async fn compressed_spool() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let inner: Arc<dyn Spool + Send + Sync> =
+            Arc::new(LocalDiskSpool::new(&location.path(), false)?);
+        let spool = CompressedSpool::new(
+            "test",
+            Arc::clone(&inner),
+            CompressionParams {
+                threshold: 64,
+                level: 3,
+            },
+        );
+
+        let small = b"too small to compress".to_vec();
+        let large = "a highly compressible newsletter ".repeat(100).into_bytes();
+
+        let small_id = SpoolId::new();
+        let large_id = SpoolId::new();
+        let legacy_id = SpoolId::new();
+
+        spool
+            .store(small_id, Arc::new(small.clone().into_boxed_slice()), false)
+            .await?;
+        spool
+            .store(large_id, Arc::new(large.clone().into_boxed_slice()), false)
+            .await?;
+        // Simulate an entry that was stored prior to enabling compression
+        inner
+            .store(legacy_id, Arc::new(large.clone().into_boxed_slice()), false)
+            .await?;
+
+        // Verify what actually made it to the underlying storage
+        assert_eq!(inner.load(small_id).await?, small);
+        let raw = inner.load(large_id).await?;
+        assert!(raw.starts_with(MAGIC));
+        assert!(raw.len() < large.len());
+
+        // And that we get the original data back out
+        assert_eq!(spool.load(small_id).await?, small);
+        assert_eq!(spool.load(large_id).await?, large);
+        assert_eq!(spool.load(legacy_id).await?, large);
+
+        let (tx, rx) = flume::bounded(32);
+        spool.enumerate(tx)?;
+        let mut count = 0;
+        while let Ok(item) = rx.recv_async().await {
+            match item {
+                SpoolEntry::Item { id, data } => {
+                    if id == small_id {
+                        assert_eq!(data, small);
+                    } else {
+                        assert_eq!(data, large);
+                    }
+                    count += 1;
+                }
+                SpoolEntry::Corrupt { id, error } => {
+                    anyhow::bail!("Corrupt: {id}: {error}");
+                }
+            }
+        }
+        assert_eq!(count, 3);
+
+        Ok(())
+    }
+}
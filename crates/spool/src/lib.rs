@@ -3,6 +3,7 @@ use flume::Sender;
 use once_cell::sync::OnceCell;
 use std::sync::Arc;
 
+pub mod compressed;
 pub mod local_disk;
 #[cfg(feature = "rocksdb")]
 pub mod rocks;
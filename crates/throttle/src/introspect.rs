@@ -0,0 +1,440 @@
+//! Tracks the throttle keys that have been used by this process, so
+//! that their state can be reported by the admin API, and allows the
+//! parameters of a key to be temporarily overridden, or its state to be
+//! reset, during incident response.
+//!
+//! Neither the memory store nor redis-cell provide a way to enumerate
+//! their keys, so a key is only known here once it has been used by
+//! this process.
+use crate::{throttle_store, Error, ThrottleResult, ThrottleSpec, REDIS};
+use once_cell::sync::Lazy;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys that have not been used for this long are forgotten, unless
+/// their period is longer than this
+pub(crate) const FORGET_IDLE_KEYS_AFTER: Duration = Duration::from_secs(3600);
+
+/// How often the idle keys are pruned as new keys are recorded
+const PRUNE_IDLE_KEYS_INTERVAL: Duration = Duration::from_secs(60);
+
+static KEYS: Lazy<Mutex<KeyMap<KeyEntry>>> = Lazy::new(|| Mutex::new(KeyMap::new()));
+static OVERRIDES: Lazy<Overrides<ThrottleSpec>> = Lazy::new(Overrides::new);
+
+pub(crate) trait IdleKey {
+    fn is_idle(&self, now: Instant) -> bool;
+}
+
+/// The keys that have been used by this process. The idle keys are
+/// pruned from time to time as new keys are recorded, so that they
+/// don't accumulate in a process that uses many distinct keys.
+pub(crate) struct KeyMap<T> {
+    pub keys: HashMap<String, T>,
+    next_prune: Instant,
+}
+
+impl<T: IdleKey> KeyMap<T> {
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+            next_prune: Instant::now() + PRUNE_IDLE_KEYS_INTERVAL,
+        }
+    }
+
+    /// Returns the entry for `key`, creating it via `make` if the key
+    /// is new. The key is only copied when it is new.
+    pub fn get_or_insert_with<F: FnOnce() -> T>(
+        &mut self,
+        key: &str,
+        now: Instant,
+        make: F,
+    ) -> &mut T {
+        if !self.keys.contains_key(key) {
+            if now >= self.next_prune {
+                self.prune(now);
+            }
+            self.keys.insert(key.to_string(), make());
+        }
+        self.keys.get_mut(key).expect("inserted above")
+    }
+
+    pub fn prune(&mut self, now: Instant) {
+        self.keys.retain(|_, entry| !entry.is_idle(now));
+        self.next_prune = now + PRUNE_IDLE_KEYS_INTERVAL;
+    }
+}
+
+/// Temporary overrides of the parameters of keys
+pub(crate) struct Overrides<T> {
+    map: Mutex<HashMap<String, (T, Instant)>>,
+    /// Whether there may be any overrides, so that the lock
+    /// doesn't need to be taken in the usual case where there
+    /// are none
+    active: AtomicBool,
+}
+
+impl<T: Copy> Overrides<T> {
+    pub fn new() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the override for `key` and when it expires,
+    /// unless it has already expired
+    pub fn get(&self, key: &str, now: Instant) -> Option<(T, Instant)> {
+        if !self.active.load(Ordering::Acquire) {
+            return None;
+        }
+        let mut map = self.map.lock().unwrap();
+        match map.get(key).copied() {
+            Some((value, expires)) if expires > now => Some((value, expires)),
+            Some(_) => {
+                map.remove(key);
+                self.active.store(!map.is_empty(), Ordering::Release);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn set(&self, key: &str, value: T, expires: Instant) {
+        let mut map = self.map.lock().unwrap();
+        map.insert(key.to_string(), (value, expires));
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// Removes the override for `key`, returning false if there
+    /// was no active override
+    pub fn remove(&self, key: &str, now: Instant) -> bool {
+        let mut map = self.map.lock().unwrap();
+        let removed = map.remove(key);
+        self.active.store(!map.is_empty(), Ordering::Release);
+        match removed {
+            Some((_, expires)) => expires > now,
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct KeyEntry {
+    limit: u64,
+    period: Duration,
+    max_burst: u64,
+    pacing: bool,
+    last_used: Instant,
+}
+
+impl IdleKey for KeyEntry {
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_used) > self.period.max(FORGET_IDLE_KEYS_AFTER)
+    }
+}
+
+/// The store key and parameters that should be used for a throttle
+struct Effective<'a> {
+    store_key: Cow<'a, str>,
+    limit: u64,
+    period: Duration,
+    max_burst: u64,
+    pacing: bool,
+    override_expires: Option<Instant>,
+}
+
+fn effective<'a>(key: &'a str, entry: &KeyEntry, now: Instant) -> Effective<'a> {
+    match OVERRIDES.get(key, now) {
+        Some((spec, expires)) => {
+            let limit = spec.limit;
+            let period = spec.period;
+            let pacing = spec.pacing;
+            let max_burst = if pacing {
+                0
+            } else {
+                spec.max_burst.unwrap_or(limit)
+            };
+            // The overridden parameters must not share the
+            // state of the original parameters
+            let store_key = if pacing {
+                format!("{key}#override:pace:{limit}:{period}")
+            } else {
+                format!("{key}#override:{limit}:{max_burst}:{period}")
+            };
+            Effective {
+                store_key: Cow::Owned(store_key),
+                limit,
+                period: Duration::from_secs(period),
+                max_burst,
+                pacing,
+                override_expires: Some(expires),
+            }
+        }
+        None => Effective {
+            store_key: Cow::Borrowed(key),
+            limit: entry.limit,
+            period: entry.period,
+            max_burst: entry.max_burst,
+            pacing: entry.pacing,
+            override_expires: None,
+        },
+    }
+}
+
+/// Records the use of `key` and returns the result of applying
+/// any override to its parameters
+pub(crate) async fn throttle_tracked(
+    key: &str,
+    limit: u64,
+    period: Duration,
+    max_burst: u64,
+    pacing: bool,
+    quantity: Option<u64>,
+) -> Result<ThrottleResult, Error> {
+    let now = Instant::now();
+    let e = {
+        let mut keys = KEYS.lock().unwrap();
+        let entry = keys.get_or_insert_with(key, now, || KeyEntry {
+            limit,
+            period,
+            max_burst,
+            pacing,
+            last_used: now,
+        });
+        entry.limit = limit;
+        entry.period = period;
+        entry.max_burst = max_burst;
+        entry.pacing = pacing;
+        entry.last_used = now;
+        effective(key, entry, now)
+    };
+
+    throttle_store(
+        &e.store_key,
+        e.limit,
+        e.period,
+        e.max_burst,
+        e.pacing,
+        quantity,
+    )
+    .await
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleState {
+    /// The key that was passed to `throttle`
+    pub key: String,
+    /// The effective limit, which is the overridden limit
+    /// if an override is active
+    pub limit: u64,
+    /// The effective period
+    pub period: Duration,
+    /// The effective max_burst
+    pub max_burst: u64,
+    /// Whether the effective parameters use pacing
+    pub pacing: bool,
+    /// The number of tokens that are currently available
+    pub remaining: u64,
+    /// How long until the throttle returns to its full capacity
+    pub reset_after: Duration,
+    /// How long ago the key was last used
+    pub idle: Duration,
+    /// How long until the active override expires
+    pub override_expires: Option<Duration>,
+}
+
+/// Returns the current state of the throttle keys that have been
+/// used by this process. Querying the state does not consume any tokens.
+pub async fn list_throttles() -> Result<Vec<ThrottleState>, Error> {
+    let now = Instant::now();
+    let keys: Vec<(String, KeyEntry)> = {
+        let mut keys = KEYS.lock().unwrap();
+        keys.prune(now);
+        keys.keys
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect()
+    };
+
+    let mut result = vec![];
+    for (key, entry) in keys {
+        let e = effective(&key, &entry, now);
+        let state = throttle_store(
+            &e.store_key,
+            e.limit,
+            e.period,
+            e.max_burst,
+            e.pacing,
+            Some(0),
+        )
+        .await?;
+        result.push(ThrottleState {
+            limit: e.limit,
+            period: e.period,
+            max_burst: e.max_burst,
+            pacing: e.pacing,
+            remaining: state.remaining,
+            reset_after: state.reset_after,
+            idle: now.duration_since(entry.last_used),
+            override_expires: e
+                .override_expires
+                .map(|expires| expires.saturating_duration_since(now)),
+            key,
+        });
+    }
+    result.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(result)
+}
+
+/// Discards the accumulated state of `key`, so that it returns to its
+/// full capacity. When redis is in use, the reset applies to all of the
+/// nodes that share the key.
+/// Returns false if the key has not been used by this process.
+pub async fn reset_throttle(key: &str) -> Result<bool, Error> {
+    let now = Instant::now();
+    let entry = match KEYS.lock().unwrap().keys.get(key) {
+        Some(entry) => *entry,
+        None => return Ok(false),
+    };
+    let e = effective(key, &entry, now);
+
+    if let Some(redis) = REDIS.get().cloned() {
+        let mut cmd = mod_redis::cmd("DEL");
+        cmd.arg(key);
+        if e.store_key != key {
+            cmd.arg(&*e.store_key);
+        }
+        redis.query(cmd).await?;
+    } else {
+        crate::forget_memory_key(key);
+        crate::forget_memory_key(&e.store_key);
+    }
+
+    Ok(true)
+}
+
+/// Temporarily replaces the parameters of `key` with those of `spec`.
+/// While the override is active, the key accumulates its state separately
+/// from its regular state.
+/// Overrides apply only to this process, even when redis is in use.
+pub fn set_throttle_override(key: &str, spec: ThrottleSpec, duration: Duration) {
+    OVERRIDES.set(key, spec, Instant::now() + duration);
+}
+
+/// Removes the override for `key`, returning false if there was
+/// no active override
+pub fn remove_throttle_override(key: &str) -> bool {
+    OVERRIDES.remove(key, Instant::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_map_prunes_idle_keys() {
+        let start = Instant::now();
+        let entry = |last_used| KeyEntry {
+            limit: 1,
+            period: Duration::from_secs(1),
+            max_burst: 1,
+            pacing: false,
+            last_used,
+        };
+        let mut map = KeyMap::new();
+        map.get_or_insert_with("old", start, || entry(start));
+        map.get_or_insert_with("old", start, || unreachable!());
+
+        // Recording a new key prunes the keys that have become idle
+        let later = start + FORGET_IDLE_KEYS_AFTER + PRUNE_IDLE_KEYS_INTERVAL;
+        map.get_or_insert_with("new", later, || entry(later));
+        assert_eq!(map.keys.keys().collect::<Vec<_>>(), vec!["new"]);
+    }
+
+    #[test]
+    fn overrides_expire() {
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(60);
+        let overrides = Overrides::new();
+        assert_eq!(overrides.get("key", now), None);
+
+        overrides.set("key", 10, expires);
+        assert_eq!(overrides.get("key", now), Some((10, expires)));
+        assert_eq!(overrides.get("other", now), None);
+        assert!(overrides.active.load(Ordering::Acquire));
+
+        // The expired override is discarded
+        assert_eq!(overrides.get("key", expires), None);
+        assert!(!overrides.active.load(Ordering::Acquire));
+        assert!(!overrides.remove("key", now));
+    }
+
+    #[tokio::test]
+    async fn override_and_reset() {
+        let key = format!("override_and_reset-{}", uuid::Uuid::new_v4());
+        let period = Duration::from_secs(60);
+
+        for _ in 0..5 {
+            let result = throttle_tracked(&key, 5, period, 5, false, None)
+                .await
+                .unwrap();
+            assert!(!result.throttled);
+        }
+        let result = throttle_tracked(&key, 5, period, 5, false, None)
+            .await
+            .unwrap();
+        assert!(result.throttled);
+
+        let state = list_throttles()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.key == key)
+            .unwrap();
+        assert_eq!(state.limit, 5);
+        assert_eq!(state.remaining, 0);
+        assert_eq!(state.override_expires, None);
+
+        // The override has its own state, so it is not throttled
+        set_throttle_override(&key, ThrottleSpec::try_from("10/m").unwrap(), period);
+        let result = throttle_tracked(&key, 5, period, 5, false, None)
+            .await
+            .unwrap();
+        assert!(!result.throttled);
+        let state = list_throttles()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.key == key)
+            .unwrap();
+        assert_eq!(state.limit, 10);
+        assert_eq!(state.remaining, 9);
+        assert!(state.override_expires.is_some());
+
+        // Removing the override returns to the original state
+        assert!(remove_throttle_override(&key));
+        assert!(!remove_throttle_override(&key));
+        let result = throttle_tracked(&key, 5, period, 5, false, None)
+            .await
+            .unwrap();
+        assert!(result.throttled);
+
+        assert!(reset_throttle(&key).await.unwrap());
+        // The state is discarded, rather than being left behind
+        assert!(!crate::MEMORY
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .contains_key(&key));
+        let result = throttle_tracked(&key, 5, period, 5, false, None)
+            .await
+            .unwrap();
+        assert!(!result.throttled);
+
+        assert!(!reset_throttle("no-such-key").await.unwrap());
+    }
+}
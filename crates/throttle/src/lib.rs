@@ -9,17 +9,26 @@ use once_cell::sync::OnceCell;
 #[cfg(feature = "impl")]
 use redis_cell_impl::{time, MemoryStore, Rate, RateLimiter, RateQuota};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "impl")]
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 #[cfg(feature = "impl")]
 use std::sync::Mutex;
 use std::time::Duration;
 use thiserror::Error;
 
+#[cfg(feature = "impl")]
+pub mod introspect;
 #[cfg(feature = "impl")]
 pub mod limit;
+#[cfg(feature = "impl")]
+mod pace;
 
+/// Each key has its own store, so that its state can be discarded
+/// by `introspect::reset_throttle`
 #[cfg(feature = "impl")]
-static MEMORY: OnceCell<Mutex<MemoryStore>> = OnceCell::new();
+static MEMORY: OnceCell<Mutex<HashMap<String, MemoryStore>>> = OnceCell::new();
 #[cfg(feature = "impl")]
 static REDIS: OnceCell<RedisConnection> = OnceCell::new();
 
@@ -38,24 +47,118 @@ pub enum Error {
     NonExistentLease,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Hash)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
 #[serde(try_from = "String")]
 pub struct ThrottleSpec {
     pub limit: u64,
     /// Period, in seconds
     pub period: u64,
     pub max_burst: Option<u64>,
+    /// If true, the actions are spaced evenly across the period
+    /// rather than being permitted in a burst. `max_burst` is ignored.
+    pub pacing: bool,
+}
+
+/// The hash of a spec is part of the hash of the shaping rules that
+/// contain it, which tsa-daemon uses to key its persisted state.
+/// pacing was added later, so it is hashed only when it is set, in
+/// order that the hash of a spec without it is unchanged.
+impl Hash for ThrottleSpec {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.limit.hash(state);
+        self.period.hash(state);
+        self.max_burst.hash(state);
+        if self.pacing {
+            self.pacing.hash(state);
+        }
+    }
 }
 
 #[cfg(feature = "impl")]
 impl ThrottleSpec {
     pub async fn throttle<S: AsRef<str>>(&self, key: S) -> Result<ThrottleResult, Error> {
+        self.throttle_quantity(key, 1).await
+    }
+
+    /// Like `throttle`, but adds `quantity` tokens rather than 1.
+    /// A `quantity` of 0 queries the throttle without consuming any
+    /// of its capacity; such a query is never reported as throttled,
+    /// so check `remaining` to see whether there is capacity left.
+    pub async fn throttle_quantity<S: AsRef<str>>(
+        &self,
+        key: S,
+        quantity: u64,
+    ) -> Result<ThrottleResult, Error> {
         let key = key.as_ref();
         let limit = self.limit;
         let period = self.period;
+        let period_duration = Duration::from_secs(period);
+        if self.pacing {
+            let key = format!("{key}:pace:{limit}:{period}");
+            return introspect::throttle_tracked(
+                &key,
+                limit,
+                period_duration,
+                0,
+                true,
+                Some(quantity),
+            )
+            .await;
+        }
         let max_burst = self.max_burst.unwrap_or(limit);
         let key = format!("{key}:{limit}:{max_burst}:{period}");
-        throttle(&key, limit, Duration::from_secs(period), max_burst, Some(1)).await
+        throttle(&key, limit, period_duration, max_burst, Some(quantity)).await
+    }
+}
+
+impl ThrottleSpec {
+    /// Returns the spec in the `limit/period` form that is accepted
+    /// by `ThrottleSpec::try_from`. max_burst is represented by a
+    /// `,max_burst=N` suffix when it differs from the limit.
+    pub fn as_string(&self) -> String {
+        let pace = if self.pacing { "pace:" } else { "" };
+        let burst = match self.max_burst {
+            Some(burst) if burst != self.limit && !self.pacing => format!(",max_burst={burst}"),
+            _ => String::new(),
+        };
+        let period = match self.period {
+            86400 => "d",
+            3600 => "h",
+            60 => "m",
+            1 => "s",
+            period => {
+                return format!("{pace}{}/d{burst}", self.limit * 86400 / period.max(1));
+            }
+        };
+        format!("{pace}{}/{period}{burst}", self.limit)
+    }
+
+    /// Returns a spec with half of the rate of this one.
+    /// The period is widened where necessary to keep the limit
+    /// a whole number. Returns None if the rate cannot be halved
+    /// within a period of a day, such as for `1/d`, as the result
+    /// could not be expressed in the `limit/period` form.
+    pub fn halved(&self) -> Option<Self> {
+        let mut limit = self.limit;
+        let mut period = self.period;
+        while limit % 2 == 1 {
+            let factor = match period {
+                1 | 60 => 60,
+                3600 => 24,
+                _ => break,
+            };
+            limit *= factor;
+            period *= factor;
+        }
+        if limit < 2 {
+            return None;
+        }
+        Some(Self {
+            limit: limit / 2,
+            period,
+            max_burst: self.max_burst.map(|burst| (burst / 2).max(1)),
+            pacing: self.pacing,
+        })
     }
 }
 
@@ -69,7 +172,25 @@ impl TryFrom<String> for ThrottleSpec {
 impl TryFrom<&str> for ThrottleSpec {
     type Error = String;
     fn try_from(s: &str) -> Result<Self, String> {
-        let (limit, period) = s
+        let (pacing, spec) = match s.strip_prefix("pace:") {
+            Some(spec) => (true, spec),
+            None => (false, s),
+        };
+        let (spec, max_burst) = match spec.split_once(",max_burst=") {
+            Some((spec, burst)) => {
+                let burst = burst
+                    .parse::<u64>()
+                    .map_err(|err| format!("invalid max_burst '{burst}': {err:#}"))?;
+                if burst == 0 {
+                    return Err(format!(
+                        "invalid ThrottleSpec `{s}`: max_burst must be greater than 0!"
+                    ));
+                }
+                (spec, Some(burst))
+            }
+            None => (spec, None),
+        };
+        let (limit, period) = spec
             .split_once("/")
             .ok_or_else(|| format!("expected 'limit/period', got {s}"))?;
 
@@ -103,7 +224,8 @@ impl TryFrom<&str> for ThrottleSpec {
         Ok(Self {
             limit,
             period,
-            max_burst: None,
+            max_burst,
+            pacing,
         })
     }
 }
@@ -133,16 +255,20 @@ fn local_throttle(
     max_burst: u64,
     quantity: Option<u64>,
 ) -> Result<ThrottleResult, Error> {
-    let mut store = MEMORY
-        .get_or_init(|| Mutex::new(MemoryStore::new()))
+    let mut stores = MEMORY
+        .get_or_init(|| Mutex::new(HashMap::new()))
         .lock()
         .unwrap();
+    if !stores.contains_key(key) {
+        stores.insert(key.to_string(), MemoryStore::new());
+    }
+    let store = stores.get_mut(key).expect("inserted above");
     let max_rate = Rate::per_period(
         limit as i64,
         time::Duration::try_from(period).map_err(|err| Error::Generic(format!("{err:#}")))?,
     );
     let mut limiter = RateLimiter::new(
-        &mut *store,
+        store,
         &RateQuota {
             max_burst: max_burst.min(limit - 1) as i64,
             max_rate,
@@ -226,6 +352,10 @@ async fn redis_throttle(
 ///                 to spread out across time.
 /// * `quantity` - how many tokens to add to the throttle. If omitted,
 ///                1 token is added.
+///
+/// The use of `key` is recorded so that its state can be reported by
+/// `introspect::list_throttles`, and any override that was set via
+/// `introspect::set_throttle_override` is applied.
 #[cfg(feature = "impl")]
 pub async fn throttle(
     key: &str,
@@ -234,13 +364,36 @@ pub async fn throttle(
     max_burst: u64,
     quantity: Option<u64>,
 ) -> Result<ThrottleResult, Error> {
-    if let Some(redis) = REDIS.get().cloned() {
+    introspect::throttle_tracked(key, limit, period, max_burst, false, quantity).await
+}
+
+#[cfg(feature = "impl")]
+pub(crate) async fn throttle_store(
+    key: &str,
+    limit: u64,
+    period: Duration,
+    max_burst: u64,
+    pacing: bool,
+    quantity: Option<u64>,
+) -> Result<ThrottleResult, Error> {
+    if pacing {
+        pace::pace(key, limit, period, quantity).await
+    } else if let Some(redis) = REDIS.get().cloned() {
         redis_throttle(redis, key, limit, period, max_burst, quantity).await
     } else {
         local_throttle(key, limit, period, max_burst, quantity)
     }
 }
 
+/// Discards the state of `key` held in the memory stores
+#[cfg(feature = "impl")]
+pub(crate) fn forget_memory_key(key: &str) {
+    if let Some(stores) = MEMORY.get() {
+        stores.lock().unwrap().remove(key);
+    }
+    pace::forget_memory_key(key);
+}
+
 #[cfg(feature = "impl")]
 pub fn use_redis(conn: RedisConnection) -> Result<(), Error> {
     REDIS
@@ -323,6 +476,7 @@ mod test {
                 limit: 100,
                 period: 3600,
                 max_burst: None,
+                pacing: false,
             }
         );
         assert_eq!(
@@ -331,6 +485,7 @@ mod test {
                 limit: 100,
                 period: 3600,
                 max_burst: None,
+                pacing: false,
             }
         );
         assert_eq!(
@@ -342,4 +497,101 @@ mod test {
             "invalid limit 'three': invalid digit found in string".to_string()
         );
     }
+
+    #[test]
+    fn throttle_spec_max_burst() {
+        let spec = ThrottleSpec::try_from("1,000/h,max_burst=10").unwrap();
+        assert_eq!(
+            spec,
+            ThrottleSpec {
+                limit: 1000,
+                period: 3600,
+                max_burst: Some(10),
+                pacing: false,
+            }
+        );
+        assert_eq!(spec.as_string(), "1000/h,max_burst=10");
+        assert_eq!(ThrottleSpec::try_from(spec.as_string()).unwrap(), spec);
+
+        // A burst equal to the limit is the default, and is omitted
+        assert_eq!(
+            ThrottleSpec::try_from("100/m,max_burst=100")
+                .unwrap()
+                .as_string(),
+            "100/m"
+        );
+        assert_eq!(
+            ThrottleSpec::try_from("100/m,max_burst=x").unwrap_err(),
+            "invalid max_burst 'x': invalid digit found in string".to_string()
+        );
+    }
+
+    #[test]
+    fn throttle_spec_pacing() {
+        let spec = ThrottleSpec::try_from("pace:100/m").unwrap();
+        assert_eq!(
+            spec,
+            ThrottleSpec {
+                limit: 100,
+                period: 60,
+                max_burst: None,
+                pacing: true,
+            }
+        );
+        assert_eq!(spec.as_string(), "pace:100/m");
+        assert_eq!(spec.halved().unwrap().as_string(), "pace:50/m");
+        assert_eq!(
+            ThrottleSpec::try_from("pace:100/our").unwrap_err(),
+            "unknown period quantity our".to_string()
+        );
+    }
+
+    #[test]
+    fn throttle_spec_hash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash<T: Hash>(value: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        // The hash that was derived before pacing was added
+        #[derive(Hash)]
+        struct PriorThrottleSpec {
+            limit: u64,
+            period: u64,
+            max_burst: Option<u64>,
+        }
+
+        let spec = ThrottleSpec::try_from("100/m").unwrap();
+        assert_eq!(
+            hash(&spec),
+            hash(&PriorThrottleSpec {
+                limit: 100,
+                period: 60,
+                max_burst: None,
+            })
+        );
+        assert_ne!(
+            hash(&spec),
+            hash(&ThrottleSpec::try_from("pace:100/m").unwrap())
+        );
+    }
+
+    #[test]
+    fn throttle_spec_halved() {
+        let halve = |s: &str| {
+            ThrottleSpec::try_from(s)
+                .unwrap()
+                .halved()
+                .map(|spec| spec.as_string())
+        };
+        assert_eq!(halve("100/h").as_deref(), Some("50/h"));
+        assert_eq!(halve("5/m").as_deref(), Some("150/h"));
+        assert_eq!(halve("1/s").as_deref(), Some("30/m"));
+        assert_eq!(halve("1/h").as_deref(), Some("12/d"));
+        assert_eq!(halve("3/d").as_deref(), Some("1/d"));
+        assert_eq!(halve("1/d"), None);
+    }
 }
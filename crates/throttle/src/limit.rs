@@ -1,6 +1,7 @@
+use crate::introspect::{IdleKey, KeyMap, Overrides, FORGET_IDLE_KEYS_AFTER};
 use crate::{Error, REDIS};
 use anyhow::anyhow;
-use mod_redis::{RedisConnection, Script};
+use mod_redis::{FromRedisValue, RedisConnection, Script};
 use once_cell::sync::{Lazy, OnceCell};
 use std::collections::HashMap;
 use std::sync::Mutex;
@@ -8,6 +9,10 @@ use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
 static MEMORY: OnceCell<Mutex<MemoryStore>> = OnceCell::new();
+/// The limit keys that have been used by this process
+static KEYS: Lazy<Mutex<KeyMap<LimitKeyEntry>>> = Lazy::new(|| Mutex::new(KeyMap::new()));
+/// The overridden limit of a key, and when the override expires
+static OVERRIDES: Lazy<Overrides<usize>> = Lazy::new(Overrides::new);
 
 static ACQUIRE_SCRIPT: Lazy<Script> = Lazy::new(|| {
     Script::new(
@@ -58,14 +63,34 @@ enum Backend {
 }
 
 impl LimitSpec {
+    /// Acquires a lease on `key`. The use of the key is recorded so that
+    /// it can be reported by `list_limits`, and any override that was set
+    /// via `set_limit_override` is applied.
     pub async fn acquire_lease<S: AsRef<str>>(&self, key: S) -> Result<LimitLease, Error> {
+        let key = key.as_ref();
+        let spec = LimitSpec {
+            limit: record_limit_key(key, self, false),
+            duration: self.duration,
+        };
         if let Some(redis) = REDIS.get().cloned() {
-            self.acquire_lease_redis(redis, key.as_ref()).await
+            spec.acquire_lease_redis(redis, key).await
         } else {
-            self.acquire_lease_memory(key.as_ref()).await
+            spec.acquire_lease_memory(key).await
         }
     }
 
+    /// Like `acquire_lease`, except that the lease is always held in
+    /// memory, so that the limit applies only to this process even when
+    /// redis is in use.
+    pub async fn acquire_local_lease<S: AsRef<str>>(&self, key: S) -> Result<LimitLease, Error> {
+        let key = key.as_ref();
+        let spec = LimitSpec {
+            limit: record_limit_key(key, self, true),
+            duration: self.duration,
+        };
+        spec.acquire_lease_memory(key).await
+    }
+
     pub async fn acquire_lease_redis(
         &self,
         conn: RedisConnection,
@@ -243,6 +268,180 @@ impl Drop for LimitLease {
     }
 }
 
+#[derive(Clone, Copy)]
+struct LimitKeyEntry {
+    limit: usize,
+    duration: Duration,
+    last_used: Instant,
+    /// The leases are held in memory even when redis is in use
+    local: bool,
+}
+
+impl IdleKey for LimitKeyEntry {
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_used) > self.duration.max(FORGET_IDLE_KEYS_AFTER)
+    }
+}
+
+/// Records the use of `key` with `spec`, and returns the limit
+/// that should be applied to it
+fn record_limit_key(key: &str, spec: &LimitSpec, local: bool) -> usize {
+    let now = Instant::now();
+    let entry = LimitKeyEntry {
+        limit: spec.limit,
+        duration: spec.duration,
+        last_used: now,
+        local,
+    };
+    *KEYS.lock().unwrap().get_or_insert_with(key, now, || entry) = entry;
+    effective_limit(key, spec.limit, now).0
+}
+
+/// Returns the limit of `key` after applying any override,
+/// and when that override expires
+fn effective_limit(key: &str, limit: usize, now: Instant) -> (usize, Option<Instant>) {
+    match OVERRIDES.get(key, now) {
+        Some((limit, expires)) => (limit, Some(expires)),
+        None => (limit, None),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseHolder {
+    pub uuid: Uuid,
+    /// How long until the lease expires, unless it is extended
+    pub expires: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitState {
+    /// The key that was passed to `acquire_lease`
+    pub key: String,
+    /// The effective limit, which is the overridden limit
+    /// if an override is active
+    pub limit: usize,
+    /// The initial duration of a lease
+    pub duration: Duration,
+    /// The currently held leases
+    pub leases: Vec<LeaseHolder>,
+    /// How long ago a lease was last requested for the key
+    pub idle: Duration,
+    /// How long until the active override expires
+    pub override_expires: Option<Duration>,
+}
+
+fn redis_for_key(local: bool) -> Option<RedisConnection> {
+    if local {
+        None
+    } else {
+        REDIS.get().cloned()
+    }
+}
+
+async fn list_leases(key: &str, local: bool) -> Result<Vec<LeaseHolder>, Error> {
+    let mut leases = vec![];
+    if let Some(redis) = redis_for_key(local) {
+        let now_ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        let mut cmd = mod_redis::cmd("ZRANGE");
+        cmd.arg(key)
+            .arg(now_ts)
+            .arg("+inf")
+            .arg("BYSCORE")
+            .arg("WITHSCORES");
+        let value = redis.query(cmd).await?;
+        for (uuid, expires_ts) in <Vec<(String, f64)> as FromRedisValue>::from_redis_value(&value)?
+        {
+            let uuid = Uuid::parse_str(&uuid).map_err(|err| anyhow!("{err:#}"))?;
+            leases.push(LeaseHolder {
+                uuid,
+                expires: Duration::from_secs_f64((expires_ts - now_ts).max(0.0)),
+            });
+        }
+    } else if let Some(store) = MEMORY.get() {
+        let now = Instant::now();
+        let mut store = store.lock().unwrap();
+        if let Some(set) = store.get(key) {
+            set.expire_old();
+            for (uuid, expires) in &set.members {
+                leases.push(LeaseHolder {
+                    uuid: *uuid,
+                    expires: expires.saturating_duration_since(now),
+                });
+            }
+        }
+    }
+    leases.sort_by(|a, b| a.expires.cmp(&b.expires));
+    Ok(leases)
+}
+
+/// Returns the current state of the limit keys that have been
+/// used by this process, including the holders of their leases.
+/// When redis is in use, the leases held by other nodes are included,
+/// except for keys that were acquired via `acquire_local_lease`.
+pub async fn list_limits() -> Result<Vec<LimitState>, Error> {
+    let now = Instant::now();
+    let keys: Vec<(String, LimitKeyEntry)> = {
+        let mut keys = KEYS.lock().unwrap();
+        keys.prune(now);
+        keys.keys
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect()
+    };
+
+    let mut result = vec![];
+    for (key, entry) in keys {
+        let (limit, override_expires) = effective_limit(&key, entry.limit, now);
+        let leases = list_leases(&key, entry.local).await?;
+        result.push(LimitState {
+            key,
+            limit,
+            duration: entry.duration,
+            leases,
+            idle: now.duration_since(entry.last_used),
+            override_expires: override_expires
+                .map(|expires| expires.saturating_duration_since(now)),
+        });
+    }
+    result.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(result)
+}
+
+/// Discards all of the leases held on `key`. The holders of those
+/// leases are not notified; they will fail to extend their leases.
+/// When redis is in use, the reset applies to all of the nodes that
+/// share the key.
+/// Returns false if the key has not been used by this process.
+pub async fn reset_limit(key: &str) -> Result<bool, Error> {
+    let local = match KEYS.lock().unwrap().keys.get(key) {
+        Some(entry) => entry.local,
+        None => return Ok(false),
+    };
+    if let Some(redis) = redis_for_key(local) {
+        let mut cmd = mod_redis::cmd("DEL");
+        cmd.arg(key);
+        redis.query(cmd).await?;
+    } else if let Some(store) = MEMORY.get() {
+        store.lock().unwrap().sets.remove(key);
+    }
+    Ok(true)
+}
+
+/// Temporarily replaces the limit of `key`.
+/// Overrides apply only to this process, even when redis is in use.
+pub fn set_limit_override(key: &str, limit: usize, duration: Duration) {
+    OVERRIDES.set(key, limit, Instant::now() + duration);
+}
+
+/// Removes the override for `key`, returning false if there was
+/// no active override
+pub fn remove_limit_override(key: &str) -> bool {
+    OVERRIDES.remove(key, Instant::now())
+}
+
 struct LeaseSet {
     members: HashMap<Uuid, Instant>,
 }
@@ -340,6 +539,75 @@ mod test {
         let _lease4 = limit.acquire_lease_memory(&key).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_override_and_reset() {
+        let limit = LimitSpec {
+            limit: 1,
+            duration: Duration::from_secs(60),
+        };
+
+        let key = format!("test_override_and_reset-{}", Uuid::new_v4());
+        let lease1 = limit.acquire_lease(&key).await.unwrap();
+        assert!(limit.acquire_lease(&key).await.is_err());
+
+        let state = list_limits()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.key == key)
+            .unwrap();
+        assert_eq!(state.limit, 1);
+        assert_eq!(state.leases.len(), 1);
+        assert_eq!(state.leases[0].uuid, lease1.uuid);
+
+        set_limit_override(&key, 2, Duration::from_secs(60));
+        let _lease2 = limit.acquire_lease(&key).await.unwrap();
+        assert!(limit.acquire_lease(&key).await.is_err());
+        assert!(remove_limit_override(&key));
+        assert!(!remove_limit_override(&key));
+
+        assert!(reset_limit(&key).await.unwrap());
+        let state = list_limits()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.key == key)
+            .unwrap();
+        assert!(state.leases.is_empty());
+        let _lease3 = limit.acquire_lease(&key).await.unwrap();
+
+        assert!(!reset_limit("no-such-key").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_local_lease() {
+        let limit = LimitSpec {
+            limit: 1,
+            duration: Duration::from_secs(60),
+        };
+
+        let key = format!("test_local_lease-{}", Uuid::new_v4());
+        let mut lease1 = limit.acquire_local_lease(&key).await.unwrap();
+        assert!(limit.acquire_local_lease(&key).await.is_err());
+
+        // The key is recorded as local, so that its leases are
+        // listed and reset from memory even when redis is in use
+        assert!(KEYS.lock().unwrap().keys[&key].local);
+        let state = list_limits()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.key == key)
+            .unwrap();
+        assert_eq!(state.leases.len(), 1);
+        assert_eq!(state.leases[0].uuid, lease1.uuid);
+
+        lease1.release().await;
+        let _lease2 = limit.acquire_local_lease(&key).await.unwrap();
+        assert!(reset_limit(&key).await.unwrap());
+        let _lease3 = limit.acquire_local_lease(&key).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_redis() {
         if which::which("redis-server").is_err() {
@@ -0,0 +1,279 @@
+//! Implements the pacing mode of `ThrottleSpec`, which spaces actions
+//! evenly across the period rather than permitting a burst at the start
+//! of it: `pace:100/m` permits one action every 600ms.
+//!
+//! This is the generic cell rate algorithm with no burst tolerance.
+//! The state of a key is its theoretical arrival time (TAT): the time,
+//! in microseconds since the unix epoch, before which no further action
+//! is permitted. The memory and redis implementations use the same
+//! integer arithmetic so that they produce identical results.
+//! When redis is in use, the time is taken from the redis server, so that
+//! the nodes that share a key are not affected by the skew between
+//! their clocks.
+use crate::{Error, ThrottleResult, REDIS};
+use mod_redis::{FromRedisValue, RedisConnection, Script};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Keys whose TAT is further in the past than this are removed
+/// from the memory store. They behave the same as absent keys.
+const PRUNE_AFTER_US: u64 = 3600 * 1_000_000;
+/// How often to prune the memory store
+const PRUNE_INTERVAL_US: u64 = 60 * 1_000_000;
+
+static MEMORY: Lazy<Mutex<MemoryStore>> = Lazy::new(|| {
+    Mutex::new(MemoryStore {
+        tats: HashMap::new(),
+        last_prune: 0,
+    })
+});
+
+static PACE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+-- TIME is non-deterministic, so the effects of the script must be
+-- replicated rather than the script itself. This is the default
+-- from redis 5 onwards.
+redis.replicate_commands()
+local time = redis.call("TIME")
+local now = (tonumber(time[1]) * 1000000) + tonumber(time[2])
+local interval = tonumber(ARGV[1])
+local quantity = tonumber(ARGV[2])
+local tat = tonumber(redis.call("GET", KEYS[1])) or 0
+local throttled = 0
+
+if quantity > 0 then
+  if tat > now then
+    throttled = 1
+  else
+    tat = now + (interval * quantity)
+    -- Once the TAT has passed, the key behaves the same as an
+    -- absent key, so there is no need to keep it much longer
+    local ttl = math.ceil((tat - now) / 1000000) + 60
+    redis.call("SET", KEYS[1], string.format("%d", tat), "EX", ttl)
+  end
+end
+
+return {throttled, tat, now}
+"#,
+    )
+});
+
+struct MemoryStore {
+    tats: HashMap<String, u64>,
+    last_prune: u64,
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the interval between actions, in microseconds
+fn interval_us(limit: u64, period: Duration) -> u64 {
+    ((period.as_micros() as u64) / limit.max(1)).max(1)
+}
+
+/// Applies `quantity` to a key whose TAT is `tat` at time `now`.
+/// Returns the new TAT and whether the action was throttled.
+/// A `quantity` of 0 queries the state without changing it.
+fn apply(tat: u64, now: u64, interval: u64, quantity: u64) -> (u64, bool) {
+    if quantity == 0 {
+        (tat, false)
+    } else if tat > now {
+        (tat, true)
+    } else {
+        (now + interval * quantity, false)
+    }
+}
+
+fn make_result(tat: u64, now: u64, throttled: bool, limit: u64) -> ThrottleResult {
+    // Unlike local_throttle, the durations are not rounded up to whole
+    // seconds, as that would defeat the purpose of pacing
+    let wait = Duration::from_micros(tat.saturating_sub(now));
+    ThrottleResult {
+        throttled,
+        limit,
+        remaining: if tat > now { 0 } else { 1 },
+        reset_after: wait,
+        retry_after: if throttled { Some(wait) } else { None },
+    }
+}
+
+fn memory_pace(key: &str, limit: u64, period: Duration, quantity: u64, now: u64) -> ThrottleResult {
+    let mut store = MEMORY.lock().unwrap();
+
+    if now.saturating_sub(store.last_prune) > PRUNE_INTERVAL_US {
+        store
+            .tats
+            .retain(|_, tat| now.saturating_sub(*tat) < PRUNE_AFTER_US);
+        store.last_prune = now;
+    }
+
+    let tat = store.tats.get(key).copied().unwrap_or(0);
+    let (tat, throttled) = apply(tat, now, interval_us(limit, period), quantity);
+    if quantity > 0 && !throttled {
+        store.tats.insert(key.to_string(), tat);
+    }
+    make_result(tat, now, throttled, limit)
+}
+
+/// Discards the state of `key` held in the memory store
+pub(crate) fn forget_memory_key(key: &str) {
+    MEMORY.lock().unwrap().tats.remove(key);
+}
+
+/// The outcome of the pace script: the new TAT, whether the action
+/// was throttled, and the time on the redis server
+struct RedisPace {
+    tat: u64,
+    throttled: bool,
+    now: u64,
+}
+
+async fn redis_apply(
+    conn: RedisConnection,
+    key: &str,
+    interval: u64,
+    quantity: u64,
+) -> Result<RedisPace, Error> {
+    let mut script = PACE_SCRIPT.prepare_invoke();
+    script.key(key).arg(interval).arg(quantity);
+    let result = conn.invoke_script(script).await?;
+    let result = <Vec<i64> as FromRedisValue>::from_redis_value(&result)?;
+    Ok(RedisPace {
+        throttled: result[0] != 0,
+        tat: result[1].max(0) as u64,
+        now: result[2].max(0) as u64,
+    })
+}
+
+async fn redis_pace(
+    conn: RedisConnection,
+    key: &str,
+    limit: u64,
+    period: Duration,
+    quantity: u64,
+) -> Result<ThrottleResult, Error> {
+    let pace = redis_apply(conn, key, interval_us(limit, period), quantity).await?;
+    Ok(make_result(pace.tat, pace.now, pace.throttled, limit))
+}
+
+/// Applies the pacing throttle for `key`, permitting at most one
+/// action every `period / limit`.
+/// If `quantity` is omitted, 1 action is applied.
+pub(crate) async fn pace(
+    key: &str,
+    limit: u64,
+    period: Duration,
+    quantity: Option<u64>,
+) -> Result<ThrottleResult, Error> {
+    let quantity = quantity.unwrap_or(1);
+    if let Some(redis) = REDIS.get().cloned() {
+        redis_pace(redis, key, limit, period, quantity).await
+    } else {
+        Ok(memory_pace(key, limit, period, quantity, now_us()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mod_redis::test::RedisServer;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, TestRunner};
+    use uuid::Uuid;
+
+    fn periods() -> impl Strategy<Value = Duration> {
+        prop_oneof![
+            Just(Duration::from_secs(1)),
+            Just(Duration::from_secs(60)),
+            Just(Duration::from_secs(3600)),
+        ]
+    }
+
+    /// Sequences of (microseconds to advance the clock, quantity)
+    fn steps() -> impl Strategy<Value = Vec<(u64, u64)>> {
+        prop::collection::vec((0..2_000_000u64, 0..3u64), 1..50)
+    }
+
+    #[test]
+    fn pace_100_per_minute() {
+        let key = format!("pace_100_per_minute-{}", Uuid::new_v4());
+        let period = Duration::from_secs(60);
+        let start = now_us();
+
+        assert!(!memory_pace(&key, 100, period, 1, start).throttled);
+        let result = memory_pace(&key, 100, period, 1, start + 1_000);
+        assert!(result.throttled);
+        assert_eq!(result.retry_after, Some(Duration::from_micros(599_000)));
+        assert!(memory_pace(&key, 100, period, 1, start + 599_999).throttled);
+        assert!(!memory_pace(&key, 100, period, 1, start + 600_000).throttled);
+    }
+
+    proptest! {
+        /// Permitted actions are never closer together than the interval,
+        /// and an action is always permitted once the interval has elapsed
+        #[test]
+        fn memory_spacing(limit in 1..1000u64, period in periods(), steps in steps()) {
+            let key = format!("memory_spacing-{}", Uuid::new_v4());
+            let interval = interval_us(limit, period);
+            let mut now = now_us();
+            let mut next_permitted = 0;
+
+            for (advance, quantity) in steps {
+                now += advance;
+                let result = memory_pace(&key, limit, period, quantity, now);
+                if quantity == 0 {
+                    prop_assert!(!result.throttled);
+                    continue;
+                }
+                prop_assert_eq!(result.throttled, now < next_permitted);
+                if !result.throttled {
+                    next_permitted = now + interval * quantity;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn memory_and_redis_agree() {
+        if which::which("redis-server").is_err() {
+            return;
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let redis = runtime.block_on(RedisServer::spawn()).unwrap();
+        let conn = runtime.block_on(redis.connection()).unwrap();
+
+        let mut runner = TestRunner::new(Config {
+            cases: 32,
+            ..Config::default()
+        });
+        // The redis script uses the clock of the redis server, so the
+        // time is advanced by sleeping, and the memory store is given
+        // the time that redis used. The intervals are kept short so
+        // that both throttled and permitted actions are exercised.
+        let steps = prop::collection::vec((0..2_000u64, 0..3u64), 1..50);
+        runner
+            .run(&(100..1000u64, steps), |(limit, steps)| {
+                let key = format!("memory_and_redis_agree-{}", Uuid::new_v4());
+                let period = Duration::from_secs(1);
+                let interval = interval_us(limit, period);
+                for (advance, quantity) in steps {
+                    std::thread::sleep(Duration::from_micros(advance));
+                    let pace = runtime
+                        .block_on(redis_apply(conn.clone(), &key, interval, quantity))
+                        .unwrap();
+                    let redis = make_result(pace.tat, pace.now, pace.throttled, limit);
+                    let memory = memory_pace(&key, limit, period, quantity, pace.now);
+                    prop_assert_eq!(memory, redis);
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+}
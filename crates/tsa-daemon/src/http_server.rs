@@ -3,10 +3,14 @@ use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Json, Router};
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use config::CallbackSignature;
-use kumo_api_types::shaping::{Action, EgressPathConfigValue, Regex, Rule, Shaping, Trigger};
-use kumo_api_types::tsa::{ReadyQSuspension, SchedQSuspension, SuspensionEntry, Suspensions};
+use kumo_api_types::shaping::{
+    Action, BackoffParams, EgressPathConfigValue, Regex, Rule, Shaping, TriggerHistory,
+};
+use kumo_api_types::tsa::{
+    ReadyQSuspension, SchedQBounce, SchedQReroute, SchedQSuspension, SuspensionEntry, Suspensions,
+};
 use kumo_log_types::*;
 use kumo_server_common::http_server::auth::TrustedIpRequired;
 use kumo_server_common::http_server::{AppError, RouterAndDocs};
@@ -21,13 +25,14 @@ use sqlite::{Connection, ConnectionThreadSafe};
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Mutex;
+use throttle::ThrottleSpec;
 use tokio::sync::broadcast::{channel, Sender};
 use toml_edit::{value, Value as TomlValue};
 use utoipa::OpenApi;
 
 pub static DB_PATH: Lazy<Mutex<String>> =
     Lazy::new(|| Mutex::new("/var/spool/kumomta/tsa.db".to_string()));
-static HISTORY: Lazy<ConnectionThreadSafe> = Lazy::new(|| open_history_db().unwrap());
+pub(crate) static HISTORY: Lazy<ConnectionThreadSafe> = Lazy::new(|| open_history_db().unwrap());
 static SUSPENSION_TX: Lazy<SuspensionSubscriberMgr> = Lazy::new(|| SuspensionSubscriberMgr::new());
 
 fn open_history_db() -> anyhow::Result<ConnectionThreadSafe> {
@@ -43,6 +48,22 @@ CREATE TABLE IF NOT EXISTS event_history (
     PRIMARY KEY (rule_hash, record_hash)
 );
 
+-- Every delivery attempt on the path of a rule with a ratio
+-- trigger, and whether it counted towards the ratio
+CREATE TABLE IF NOT EXISTS attempt_history (
+    rule_hash text,
+    record_hash text,
+    ts int,
+    matched bool,
+    PRIMARY KEY (rule_hash, record_hash)
+);
+
+-- Peers page through the history in this order
+CREATE INDEX IF NOT EXISTS event_history_ts
+    ON event_history (ts, rule_hash, record_hash);
+CREATE INDEX IF NOT EXISTS attempt_history_ts
+    ON attempt_history (ts, rule_hash, record_hash);
+
 CREATE TABLE IF NOT EXISTS config (
     rule_hash text,
     site_name text,
@@ -75,6 +96,56 @@ CREATE TABLE IF NOT EXISTS sched_q_suspensions (
     PRIMARY KEY (rule_hash, campaign, tenant, domain)
 );
 
+-- tenant and campaign are stored as empty strings rather
+-- than NULL when they are not set, so that the primary key
+-- can detect conflicts
+CREATE TABLE IF NOT EXISTS sched_q_bounces (
+    rule_hash text,
+    campaign text,
+    tenant text,
+    domain text,
+    reason text,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, campaign, tenant, domain)
+);
+
+CREATE TABLE IF NOT EXISTS sched_q_reroutes (
+    rule_hash text,
+    campaign text,
+    tenant text,
+    domain text,
+    egress_pool text,
+    reason text,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, campaign, tenant, domain)
+);
+
+-- The rate is base_rate halved `steps` times; one step is
+-- recovered for each `recovery` seconds that pass without
+-- the rule triggering again
+CREATE TABLE IF NOT EXISTS backoff (
+    rule_hash text,
+    site_name text,
+    reason text,
+    domain text,
+    mx_rollup bool,
+    source text,
+    base_rate text,
+    steps int,
+    recovery int,
+    last_stepped DATETIME,
+    last_triggered DATETIME,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, site_name)
+);
+
+-- Tenant suspensions without a campaign used to be stored
+-- with a NULL campaign, which never conflicts with another row.
+-- Convert them to the empty string; where an equivalent row
+-- already exists, the NULL one is a duplicate and is removed.
+UPDATE OR IGNORE sched_q_suspensions SET campaign='' WHERE campaign IS NULL;
+DELETE FROM sched_q_suspensions WHERE campaign IS NULL;
+
     "#;
 
     db.execute(query)?;
@@ -92,7 +163,9 @@ pub fn make_router() -> RouterAndDocs {
             .route("/publish_log_v1", post(publish_log_v1))
             .route("/get_config_v1/shaping.toml", get(get_config_v1))
             .route("/get_suspension_v1/suspended.json", get(get_suspension_v1))
-            .route("/subscribe_suspension_v1", get(subscribe_suspension_v1)),
+            .route("/subscribe_suspension_v1", get(subscribe_suspension_v1))
+            .route("/get_state_v1", get(crate::peer::get_state_v1))
+            .route("/get_history_v1", get(crate::peer::get_history_v1)),
         docs: ApiDoc::openapi(),
     }
 }
@@ -171,6 +244,9 @@ fn create_tenant_suspension(
     } else {
         None
     };
+    // Stored as an empty string rather than NULL, so that the
+    // primary key can detect conflicts
+    let stored_campaign = campaign.unwrap_or_default();
 
     let mut upsert = HISTORY
         .prepare(
@@ -187,7 +263,7 @@ fn create_tenant_suspension(
     let expires_str = expires.to_rfc3339();
 
     upsert.bind(("$hash", rule_hash))?;
-    upsert.bind(("$campaign", campaign))?;
+    upsert.bind(("$campaign", stored_campaign))?;
     upsert.bind(("$tenant", tenant))?;
     upsert.bind(("$domain", components.domain))?;
 
@@ -257,48 +333,357 @@ fn create_ready_q_suspension(
     Ok(())
 }
 
-fn insert_record(rule_hash: &str, record: &JsonLogRecord, record_hash: &str) -> anyhow::Result<()> {
-    let unix: i64 = record.timestamp.format("%s").to_string().parse()?;
-    let mut insert = HISTORY
-        .prepare("INSERT INTO event_history (rule_hash, record_hash, ts) values (?, ?, ?)")?;
-    insert.bind((1, rule_hash))?;
-    insert.bind((2, record_hash))?;
-    insert.bind((3, unix))?;
-    insert.next()?;
+/// Returns the (tenant, campaign, domain, reason) scope of a bounce
+/// or reroute that applies to the scheduled queue of the record
+fn sched_q_scope(rule: &Rule, record: &JsonLogRecord) -> (String, String, String, String) {
+    let components = QueueNameComponents::parse(&record.queue);
+    let tenant = components.tenant.unwrap_or_default().to_string();
+    let campaign = components.campaign.unwrap_or_default().to_string();
+
+    let mut reason = format!(
+        "automation rule: {} domain={}",
+        regex_list_to_string(&rule.regex),
+        components.domain
+    );
+    if !tenant.is_empty() {
+        reason.push_str(&format!(" tenant={tenant}"));
+    }
+    if !campaign.is_empty() {
+        reason.push_str(&format!(" campaign={campaign}"));
+    }
+
+    (tenant, campaign, components.domain.to_string(), reason)
+}
+
+pub(crate) fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn create_sched_q_bounce(
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+) -> anyhow::Result<()> {
+    let (tenant, campaign, domain, reason) = sched_q_scope(rule, record);
+
+    let mut upsert = HISTORY
+        .prepare(
+            "INSERT INTO sched_q_bounces
+                 (rule_hash, campaign, tenant, domain, reason, expires)
+                 VALUES
+                 ($hash, $campaign, $tenant, $domain, $reason, $expires)
+                 ON CONFLICT (rule_hash, campaign, tenant, domain)
+                 DO UPDATE SET expires=$expires",
+        )
+        .context("prepare sched_q_bounces upsert")?;
+
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$campaign", campaign.as_str()))?;
+    upsert.bind(("$tenant", tenant.as_str()))?;
+    upsert.bind(("$domain", domain.as_str()))?;
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
+
+    upsert.next().context("execute sched_q_bounces upsert")?;
+
+    SuspensionSubscriberMgr::submit(SuspensionEntry::Bounce(SchedQBounce {
+        rule_hash: rule_hash.to_string(),
+        domain,
+        tenant: non_empty(tenant),
+        campaign: non_empty(campaign),
+        reason,
+        expires,
+    }));
+
     Ok(())
 }
 
-fn prune_old_records(rule: &Rule, rule_hash: &str) -> anyhow::Result<()> {
-    match rule.trigger {
-        Trigger::Immediate => Ok(()),
-        Trigger::Threshold(spec) => {
-            let mut query = HISTORY.prepare(
-                "delete from event_history where rule_hash = ? and ts < unixepoch() - ?",
-            )?;
-            query.bind((1, rule_hash))?;
-            // Keep up to 2x the period
-            query.bind((2, 2 * spec.period as i64))?;
-            query.next()?;
-            Ok(())
+fn create_sched_q_reroute(
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+    egress_pool: &str,
+) -> anyhow::Result<()> {
+    let (tenant, campaign, domain, reason) = sched_q_scope(rule, record);
+
+    let mut upsert = HISTORY
+        .prepare(
+            "INSERT INTO sched_q_reroutes
+                 (rule_hash, campaign, tenant, domain, egress_pool, reason, expires)
+                 VALUES
+                 ($hash, $campaign, $tenant, $domain, $pool, $reason, $expires)
+                 ON CONFLICT (rule_hash, campaign, tenant, domain)
+                 DO UPDATE SET expires=$expires, egress_pool=$pool",
+        )
+        .context("prepare sched_q_reroutes upsert")?;
+
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$campaign", campaign.as_str()))?;
+    upsert.bind(("$tenant", tenant.as_str()))?;
+    upsert.bind(("$domain", domain.as_str()))?;
+    upsert.bind(("$pool", egress_pool))?;
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
+
+    upsert.next().context("execute sched_q_reroutes upsert")?;
+
+    SuspensionSubscriberMgr::submit(SuspensionEntry::Reroute(SchedQReroute {
+        rule_hash: rule_hash.to_string(),
+        domain,
+        tenant: non_empty(tenant),
+        campaign: non_empty(campaign),
+        egress_pool: egress_pool.to_string(),
+        reason,
+        expires,
+    }));
+
+    Ok(())
+}
+
+/// Returns the number of halvings that remain in effect at `now`,
+/// given that the rule last triggered at `last_triggered` and that
+/// one step is recovered per `recovery` period of quiet
+fn effective_backoff_steps(
+    steps: i64,
+    recovery: i64,
+    last_triggered: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> i64 {
+    let quiet = (now - last_triggered).num_seconds().max(0);
+    let recovered = quiet / recovery.max(1);
+    (steps - recovered).max(0)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_backoff(
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+    params: &BackoffParams,
+    shaping: &Shaping,
+    domain: &str,
+    site_name: &str,
+    source: &str,
+    provider: Option<&str>,
+) -> anyhow::Result<()> {
+    let base_rate = match params.max_message_rate {
+        Some(rate) => rate,
+        None => match shaping.get_max_message_rate(domain, source, site_name, provider)? {
+            Some(rate) => rate,
+            None => {
+                tracing::error!(
+                    "Cannot apply Backoff for {rule:?} to {} because \
+                     there is no max_message_rate to back off from",
+                    record.site
+                );
+                return Ok(());
+            }
+        },
+    };
+
+    let now = record.timestamp;
+    let recovery = rule.duration.as_secs().max(1) as i64;
+
+    let mut query = HISTORY.prepare(
+        "SELECT steps, last_stepped, last_triggered from backoff
+             where rule_hash = ? and site_name = ?",
+    )?;
+    query.bind((1, rule_hash))?;
+    query.bind((2, record.site.as_str()))?;
+
+    let mut steps = 0;
+    let mut last_stepped = None;
+    if let Ok(sqlite::State::Row) = query.next() {
+        let prior_steps: i64 = query.read("steps")?;
+        let stepped: String = query.read("last_stepped")?;
+        let triggered: String = query.read("last_triggered")?;
+        let triggered = DateTime::parse_from_rfc3339(&triggered)?.to_utc();
+        steps = effective_backoff_steps(prior_steps, recovery, triggered, now);
+        if steps > 0 {
+            last_stepped = Some(DateTime::parse_from_rfc3339(&stepped)?.to_utc());
         }
     }
+    drop(query);
+
+    // Halve the rate again, unless we only recently did so, or we
+    // have already reached the limit
+    let hold = chrono::Duration::from_std(params.hold)?;
+    let max_steps = i64::from(params.max_steps);
+    let last_stepped = match last_stepped {
+        Some(stepped) if now - stepped < hold => stepped,
+        Some(stepped) if steps >= max_steps => stepped,
+        _ => {
+            steps = (steps + 1).min(max_steps);
+            now
+        }
+    };
+
+    let expires = now + chrono::Duration::seconds(recovery * steps);
+
+    let mut upsert = HISTORY.prepare(
+        "INSERT INTO backoff
+                 (rule_hash, site_name, domain, mx_rollup, source, base_rate, steps,
+                  recovery, last_stepped, last_triggered, reason, expires)
+                 VALUES
+                 ($hash, $site, $domain, $mx_rollup, $source, $base_rate, $steps,
+                  $recovery, $last_stepped, $last_triggered, $reason, $expires)
+                 ON CONFLICT (rule_hash, site_name)
+                 DO UPDATE SET base_rate=$base_rate, steps=$steps, recovery=$recovery,
+                    last_stepped=$last_stepped, last_triggered=$last_triggered,
+                    expires=$expires",
+    )?;
+
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$site", record.site.as_str()))?;
+    upsert.bind(("$domain", domain))?;
+    upsert.bind(("$mx_rollup", if rule.was_rollup { 1 } else { 0 }))?;
+    upsert.bind(("$source", source))?;
+    upsert.bind(("$base_rate", base_rate.as_string().as_str()))?;
+    upsert.bind(("$steps", steps))?;
+    upsert.bind(("$recovery", recovery))?;
+    upsert.bind(("$last_stepped", last_stepped.to_rfc3339().as_str()))?;
+    upsert.bind(("$last_triggered", now.to_rfc3339().as_str()))?;
+
+    let reason = format!(
+        "automation rule: {} backoff",
+        regex_list_to_string(&rule.regex)
+    );
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$expires", expires.to_rfc3339().as_str()))?;
+
+    upsert.next()?;
+
+    Ok(())
 }
 
-fn count_matching_records(rule: &Rule, rule_hash: &str) -> anyhow::Result<u64> {
-    match rule.trigger {
-        Trigger::Immediate => Ok(0),
-        Trigger::Threshold(spec) => {
-            let mut query = HISTORY.prepare(
-                "SELECT COUNT(ts) from event_history where rule_hash = ? and ts >= unixepoch() - ?",
-            )?;
-            query.bind((1, rule_hash))?;
-            query.bind((2, spec.period as i64))?;
-            query.next()?;
+/// The TriggerHistory of a rule for a path, held in the HISTORY db
+struct RuleHistory<'a> {
+    rule_hash: &'a str,
+    record_hash: &'a str,
+}
+
+impl TriggerHistory for RuleHistory<'_> {
+    fn record_event(&mut self, ts: i64) -> anyhow::Result<()> {
+        let mut insert = HISTORY.prepare(
+            // The record may have been replicated from a peer that
+            // received it first
+            "INSERT OR IGNORE INTO event_history (rule_hash, record_hash, ts) values (?, ?, ?)",
+        )?;
+        insert.bind((1, self.rule_hash))?;
+        insert.bind((2, self.record_hash))?;
+        insert.bind((3, ts))?;
+        insert.next()?;
+        Ok(())
+    }
+
+    fn prune_events(&mut self, ts: i64) -> anyhow::Result<()> {
+        let mut query =
+            HISTORY.prepare("delete from event_history where rule_hash = ? and ts < ?")?;
+        query.bind((1, self.rule_hash))?;
+        query.bind((2, ts))?;
+        query.next()?;
+        Ok(())
+    }
+
+    fn count_events(&mut self, ts: i64) -> anyhow::Result<u64> {
+        let mut query = HISTORY
+            .prepare("SELECT COUNT(ts) from event_history where rule_hash = ? and ts >= ?")?;
+        query.bind((1, self.rule_hash))?;
+        query.bind((2, ts))?;
+        query.next()?;
+
+        let count: i64 = query.read(0)?;
+        Ok(count as u64)
+    }
+
+    fn record_attempt(&mut self, ts: i64, matched: bool) -> anyhow::Result<()> {
+        let mut insert = HISTORY.prepare(
+            "INSERT OR IGNORE INTO attempt_history (rule_hash, record_hash, ts, matched)
+                 values (?, ?, ?, ?)",
+        )?;
+        insert.bind((1, self.rule_hash))?;
+        insert.bind((2, self.record_hash))?;
+        insert.bind((3, ts))?;
+        insert.bind((4, if matched { 1 } else { 0 }))?;
+        insert.next()?;
+        Ok(())
+    }
+
+    fn prune_attempts(&mut self, ts: i64) -> anyhow::Result<()> {
+        let mut query =
+            HISTORY.prepare("delete from attempt_history where rule_hash = ? and ts < ?")?;
+        query.bind((1, self.rule_hash))?;
+        query.bind((2, ts))?;
+        query.next()?;
+        Ok(())
+    }
+
+    fn count_attempts(&mut self, start: i64, end: i64) -> anyhow::Result<(u64, u64)> {
+        let mut query = HISTORY.prepare(
+            "SELECT COUNT(ts) as total, COALESCE(SUM(matched), 0) as matched
+                 from attempt_history where rule_hash = ? and ts > ? and ts <= ?",
+        )?;
+        query.bind((1, self.rule_hash))?;
+        query.bind((2, start))?;
+        query.bind((3, end))?;
+        query.next()?;
+
+        let total: i64 = query.read("total")?;
+        let matched: i64 = query.read("matched")?;
+        Ok((matched as u64, total as u64))
+    }
+}
 
-            let count: i64 = query.read(0)?;
-            Ok(count as u64)
+#[allow(clippy::too_many_arguments)]
+fn apply_actions(
+    rule_hash: &str,
+    m: &Rule,
+    record: &JsonLogRecord,
+    shaping: &Shaping,
+    domain: &str,
+    site_name: &str,
+    source: &str,
+    provider: Option<&str>,
+) -> anyhow::Result<()> {
+    for action in &m.action {
+        tracing::info!("{action:?} for {record:?}");
+        match action {
+            Action::Suspend => {
+                create_ready_q_suspension(rule_hash, m, record, source)?;
+            }
+            Action::SuspendTenant => {
+                create_tenant_suspension(rule_hash, m, record, false)?;
+            }
+            Action::SuspendCampaign => {
+                create_tenant_suspension(rule_hash, m, record, true)?;
+            }
+            Action::SetConfig(config) => {
+                create_config(rule_hash, m, record, config, domain, source)?;
+            }
+            Action::Backoff(params) => {
+                create_backoff(
+                    rule_hash, m, record, params, shaping, domain, site_name, source, provider,
+                )?;
+            }
+            Action::Bounce => {
+                create_sched_q_bounce(rule_hash, m, record)?;
+            }
+            Action::Reroute(egress_pool) => {
+                create_sched_q_reroute(rule_hash, m, record, egress_pool)?;
+            }
         }
     }
+    Ok(())
 }
 
 async fn publish_log_v1_impl(record: JsonLogRecord) -> anyhow::Result<()> {
@@ -346,54 +731,93 @@ async fn publish_log_v1_impl(record: JsonLogRecord) -> anyhow::Result<()> {
         .await
         .context("in tsa_load_shaping_data event")?;
 
-    let matches = shaping.match_rules(&record, &domain, &site_name);
+    let provider = match shaping.resolve_provider(&domain).await {
+        Ok(provider) => provider,
+        Err(err) => {
+            tracing::debug!("resolving provider for {domain}: {err:#}");
+            None
+        }
+    };
+
+    let matches = shaping.match_rules(&record, &domain, &site_name, provider.as_deref());
     let record_hash = sha256hex(&record)?;
+    let ts = record.timestamp.timestamp();
+    let now = Utc::now().timestamp();
 
     for m in &matches {
         let m_hash = match_hash(m);
 
-        let rule_hash = format!("{store_key}-{m_hash}");
-
-        let triggered = match m.trigger {
-            Trigger::Immediate => true,
-            Trigger::Threshold(spec) => {
-                insert_record(&rule_hash, &record, &record_hash)?;
-                prune_old_records(m, &rule_hash)?;
-
-                let count = count_matching_records(m, &rule_hash)?;
+        let rule_hash = format!("{}-{m_hash}", rule_store_key(m, &store_key, source));
 
-                count >= spec.limit
-            }
+        let mut history = RuleHistory {
+            rule_hash: &rule_hash,
+            record_hash: &record_hash,
         };
+        // Ratio rules are handled via ratio_rules below
+        let triggered = m.record_match(&mut history, ts, now)?;
 
         tracing::trace!("match={m:?} triggered={triggered} for {record:?}");
 
         // To enact the action, we need to generate (or update) a row
         // in the db with its effects and its expiry
         if triggered {
-            for action in &m.action {
-                tracing::info!("{action:?} for {record:?}");
-                match action {
-                    Action::Suspend => {
-                        create_ready_q_suspension(&rule_hash, m, &record, &source)?;
-                    }
-                    Action::SuspendTenant => {
-                        create_tenant_suspension(&rule_hash, m, &record, false)?;
-                    }
-                    Action::SuspendCampaign => {
-                        create_tenant_suspension(&rule_hash, m, &record, true)?;
-                    }
-                    Action::SetConfig(config) => {
-                        create_config(&rule_hash, m, &record, config, &domain, &source)?;
-                    }
-                }
-            }
+            apply_actions(
+                &rule_hash,
+                m,
+                &record,
+                &shaping,
+                &domain,
+                &site_name,
+                &source,
+                provider.as_deref(),
+            )?;
+        }
+    }
+
+    for m in &shaping.ratio_rules(&domain, &site_name, provider.as_deref()) {
+        let matched = match m.ratio_sample(&record) {
+            Some(matched) => matched,
+            None => continue,
+        };
+
+        let m_hash = match_hash(m);
+        let rule_hash = format!("{}-{m_hash}", rule_store_key(m, &store_key, source));
+
+        let mut history = RuleHistory {
+            rule_hash: &rule_hash,
+            record_hash: &record_hash,
+        };
+        let triggered = m.record_attempt(&mut history, matched, ts, now)?;
+
+        tracing::trace!("ratio match={m:?} triggered={triggered} for {record:?}");
+
+        if triggered {
+            apply_actions(
+                &rule_hash,
+                m,
+                &record,
+                &shaping,
+                &domain,
+                &site_name,
+                &source,
+                provider.as_deref(),
+            )?;
         }
     }
 
     Ok(())
 }
 
+/// Returns the key under which the matches of a rule are counted.
+/// The matches of rules defined by a provider entry are counted across
+/// all of the sites of that provider, rather than per site.
+fn rule_store_key(rule: &Rule, store_key: &str, source: &str) -> String {
+    match &rule.provider {
+        Some(provider) => format!("{source}->provider:{provider}"),
+        None => store_key.to_string(),
+    }
+}
+
 /// Serialize T as json, then sha256 hash it, returning the hash as a hex string
 fn sha256hex<T: Serialize>(t: &T) -> anyhow::Result<String> {
     let json = serde_json::to_string(t)?;
@@ -490,8 +914,55 @@ fn json_to_toml_value(item_value: &JsonValue) -> anyhow::Result<TomlValue> {
     })
 }
 
-async fn do_get_config() -> anyhow::Result<String> {
+#[allow(clippy::too_many_arguments)]
+fn insert_config_item(
+    doc: &mut toml_edit::DocumentMut,
+    domain: &str,
+    mx_rollup: bool,
+    source: &str,
+    name: &str,
+    config_value: TomlValue,
+    reason: &str,
+    expires: &str,
+) {
     use toml_edit::Item;
+
+    let domain_entry = doc
+        .entry(domain)
+        .or_insert_with(|| {
+            let mut tbl = toml_edit::Table::new();
+            tbl["mx_rollup"] = value(mx_rollup);
+            Item::Table(tbl)
+        })
+        .as_table_mut()
+        .unwrap();
+    let sources = domain_entry
+        .entry("sources")
+        .or_insert_with(|| {
+            let tbl = toml_edit::Table::new();
+            Item::Table(tbl)
+        })
+        .as_table_mut()
+        .unwrap();
+    let source_entry = sources
+        .entry(source)
+        .or_insert_with(|| {
+            let tbl = toml_edit::Table::new();
+            Item::Table(tbl)
+        })
+        .as_table_mut()
+        .unwrap();
+
+    let item = Item::Value(config_value);
+    source_entry.insert(name, item);
+
+    if let Some(mut key) = source_entry.key_mut(name) {
+        key.leaf_decor_mut()
+            .set_prefix(format!("# reason: {reason}\n# expires: {expires}\n"));
+    }
+}
+
+async fn do_get_config() -> anyhow::Result<String> {
     let mut doc = toml_edit::DocumentMut::new();
 
     let mut stmt = HISTORY.prepare(
@@ -513,39 +984,73 @@ async fn do_get_config() -> anyhow::Result<String> {
         let config_value = serde_json::from_str(&config_value)?;
         let config_value = json_to_toml_value(&config_value)?;
 
-        let domain_entry = doc
-            .entry(&domain)
-            .or_insert_with(|| {
-                let mut tbl = toml_edit::Table::new();
-                tbl["mx_rollup"] = value(mx_rollup != 0);
-                Item::Table(tbl)
-            })
-            .as_table_mut()
-            .unwrap();
-        let sources = domain_entry
-            .entry("sources")
-            .or_insert_with(|| {
-                let tbl = toml_edit::Table::new();
-                Item::Table(tbl)
-            })
-            .as_table_mut()
-            .unwrap();
-        let source_entry = sources
-            .entry(&source)
-            .or_insert_with(|| {
-                let tbl = toml_edit::Table::new();
-                Item::Table(tbl)
-            })
-            .as_table_mut()
-            .unwrap();
+        insert_config_item(
+            &mut doc,
+            &domain,
+            mx_rollup != 0,
+            &source,
+            &name,
+            config_value,
+            &reason,
+            &expires,
+        );
+    }
 
-        let item = Item::Value(config_value);
-        source_entry.insert(&name, item);
+    // Backoffs are applied after the SetConfig entries, so that
+    // the reduced max_message_rate takes precedence over a
+    // max_message_rate set by another rule
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from backoff where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by expires, domain, source",
+    )?;
+    let now = Utc::now();
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let reason: String = stmt.read("reason")?;
+        let domain: String = stmt.read("domain")?;
+        let mx_rollup: i64 = stmt.read("mx_rollup")?;
+        let source: String = stmt.read("source")?;
+        let base_rate: String = stmt.read("base_rate")?;
+        let steps: i64 = stmt.read("steps")?;
+        let recovery: i64 = stmt.read("recovery")?;
+        let last_triggered: String = stmt.read("last_triggered")?;
+        let expires: String = stmt.read("expires")?;
 
-        if let Some(mut key) = source_entry.key_mut(&name) {
-            key.leaf_decor_mut()
-                .set_prefix(format!("# reason: {reason}\n# expires: {expires}\n"));
+        let last_triggered = DateTime::parse_from_rfc3339(&last_triggered)?.to_utc();
+        let steps = effective_backoff_steps(steps, recovery, last_triggered, now);
+        if steps == 0 {
+            continue;
         }
+
+        let mut rate =
+            ThrottleSpec::try_from(base_rate.as_str()).map_err(|err| anyhow!("{err}"))?;
+        let mut halvings = 0;
+        for _ in 0..steps {
+            match rate.halved() {
+                Some(halved) => {
+                    rate = halved;
+                    halvings += 1;
+                }
+                None => break,
+            }
+        }
+        let comment = if halvings < steps {
+            format!("{reason}: halved {halvings} times from {base_rate}, the lowest rate possible")
+        } else {
+            format!("{reason}: halved {steps} times from {base_rate}")
+        };
+
+        num_entries += 1;
+        insert_config_item(
+            &mut doc,
+            &domain,
+            mx_rollup != 0,
+            &source,
+            "max_message_rate",
+            TomlValue::from(rate.as_string()),
+            &comment,
+            &expires,
+        );
     }
 
     Ok(format!(
@@ -632,6 +1137,7 @@ async fn do_get_suspension() -> anyhow::Result<Json<Suspensions>> {
         let tenant: String = stmt.read("tenant")?;
         let domain: String = stmt.read("domain")?;
         let campaign: Option<String> = stmt.read("campaign")?;
+        let campaign = campaign.and_then(non_empty);
         let reason: String = stmt.read("reason")?;
         let expires: String = stmt.read("expires")?;
 
@@ -652,6 +1158,60 @@ async fn do_get_suspension() -> anyhow::Result<Json<Suspensions>> {
 
     suspensions.sched_q = by_rule_hash.into_iter().map(|(_, v)| v).collect();
 
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from sched_q_bounces where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by expires, tenant, domain, campaign",
+    )?;
+
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let rule_hash: String = stmt.read("rule_hash")?;
+        let tenant: String = stmt.read("tenant")?;
+        let domain: String = stmt.read("domain")?;
+        let campaign: String = stmt.read("campaign")?;
+        let reason: String = stmt.read("reason")?;
+        let expires: String = stmt.read("expires")?;
+
+        let expires = DateTime::parse_from_rfc3339(&expires)?.to_utc();
+
+        suspensions.bounce.push(SchedQBounce {
+            rule_hash,
+            domain,
+            tenant: non_empty(tenant),
+            campaign: non_empty(campaign),
+            reason,
+            expires,
+        });
+    }
+
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from sched_q_reroutes where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by expires, tenant, domain, campaign",
+    )?;
+
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let rule_hash: String = stmt.read("rule_hash")?;
+        let tenant: String = stmt.read("tenant")?;
+        let domain: String = stmt.read("domain")?;
+        let campaign: String = stmt.read("campaign")?;
+        let egress_pool: String = stmt.read("egress_pool")?;
+        let reason: String = stmt.read("reason")?;
+        let expires: String = stmt.read("expires")?;
+
+        let expires = DateTime::parse_from_rfc3339(&expires)?.to_utc();
+
+        suspensions.reroute.push(SchedQReroute {
+            rule_hash,
+            domain,
+            tenant: non_empty(tenant),
+            campaign: non_empty(campaign),
+            egress_pool,
+            reason,
+            expires,
+        });
+    }
+
     Ok(Json(suspensions))
 }
 
@@ -660,7 +1220,7 @@ async fn get_suspension_v1(_: TrustedIpRequired) -> Result<Json<Suspensions>, Ap
     Ok(result)
 }
 
-struct SuspensionSubscriberMgr {
+pub(crate) struct SuspensionSubscriberMgr {
     tx: Sender<SuspensionEntry>,
 }
 
@@ -688,6 +1248,14 @@ async fn process_suspension_subscription_inner(mut socket: WebSocket) -> anyhow:
             let json = serde_json::to_string(&SuspensionEntry::ReadyQ(record.clone()))?;
             socket.send(Message::Text(json)).await?;
         }
+        for record in &suspensions.bounce {
+            let json = serde_json::to_string(&SuspensionEntry::Bounce(record.clone()))?;
+            socket.send(Message::Text(json)).await?;
+        }
+        for record in &suspensions.reroute {
+            let json = serde_json::to_string(&SuspensionEntry::Reroute(record.clone()))?;
+            socket.send(Message::Text(json)).await?;
+        }
     }
 
     // then wait for more to show up
@@ -710,3 +1278,272 @@ pub async fn subscribe_suspension_v1(
 ) -> impl IntoResponse {
     ws.on_upgrade(|socket| process_suspension_subscription(socket))
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use kumo_api_types::shaping::Trigger;
+    use rfc5321::Response;
+    use std::time::Duration;
+
+    /// Point HISTORY at an in-memory database. This must be called
+    /// by each test that uses HISTORY, before it is first used.
+    /// Tests share the database, so they should use distinct rule hashes.
+    pub(crate) fn use_memory_db() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            *DB_PATH.lock().unwrap() = ":memory:".to_string();
+            Lazy::force(&HISTORY);
+        });
+    }
+
+    pub(crate) fn make_record(queue: &str, site: &str) -> JsonLogRecord {
+        JsonLogRecord {
+            kind: RecordType::TransientFailure,
+            id: "id".to_string(),
+            sender: "sender@example.com".to_string(),
+            recipient: "recip@example.com".to_string(),
+            queue: queue.to_string(),
+            site: site.to_string(),
+            size: 1024,
+            response: Response {
+                code: 421,
+                enhanced_code: None,
+                content: "try later".to_string(),
+                command: None,
+            },
+            peer_address: None,
+            timestamp: Utc::now(),
+            created: Utc::now(),
+            num_attempts: 1,
+            bounce_classification: Default::default(),
+            egress_pool: None,
+            egress_source: Some("source".to_string()),
+            source_address: None,
+            feedback_report: None,
+            meta: Default::default(),
+            headers: Default::default(),
+            delivery_protocol: Some("ESMTP".to_string()),
+            reception_protocol: None,
+            nodeid: Default::default(),
+            tls_cipher: None,
+            tls_protocol_version: None,
+            tls_peer_subject_name: None,
+            correlated_message: None,
+        }
+    }
+
+    fn make_rule(action: Action) -> Rule {
+        Rule {
+            regex: vec![],
+            action: vec![action],
+            trigger: Trigger::Immediate,
+            duration: Duration::from_secs(3600),
+            was_rollup: false,
+            provider: None,
+        }
+    }
+
+    fn backoff_steps(rule_hash: &str) -> Option<i64> {
+        let mut query = HISTORY
+            .prepare("SELECT steps from backoff where rule_hash = ?")
+            .unwrap();
+        query.bind((1, rule_hash)).unwrap();
+        match query.next().unwrap() {
+            sqlite::State::Row => Some(query.read("steps").unwrap()),
+            sqlite::State::Done => None,
+        }
+    }
+
+    #[test]
+    fn backoff_recovery() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(effective_backoff_steps(3, 3600, now, now), 3);
+        assert_eq!(effective_backoff_steps(3, 3600, now - hour, now), 2);
+        assert_eq!(effective_backoff_steps(3, 3600, now - hour * 5, now), 0);
+        // A trigger time in the future doesn't add steps
+        assert_eq!(effective_backoff_steps(3, 3600, now + hour, now), 3);
+        // A zero recovery period is treated as one second
+        assert_eq!(
+            effective_backoff_steps(3, 0, now - chrono::Duration::seconds(2), now),
+            1
+        );
+    }
+
+    #[test]
+    fn rule_history() {
+        use_memory_db();
+        let mut history = RuleHistory {
+            rule_hash: "rule_history",
+            record_hash: "one",
+        };
+        history.record_event(100).unwrap();
+        // The same record is only counted once
+        history.record_event(100).unwrap();
+        history.record_hash = "two";
+        history.record_event(200).unwrap();
+        assert_eq!(history.count_events(100).unwrap(), 2);
+        assert_eq!(history.count_events(150).unwrap(), 1);
+        history.prune_events(150).unwrap();
+        assert_eq!(history.count_events(0).unwrap(), 1);
+
+        history.record_attempt(100, true).unwrap();
+        history.record_hash = "three";
+        history.record_attempt(200, false).unwrap();
+        assert_eq!(history.count_attempts(0, 200).unwrap(), (1, 2));
+        assert_eq!(history.count_attempts(100, 200).unwrap(), (0, 1));
+        history.prune_attempts(150).unwrap();
+        assert_eq!(history.count_attempts(0, 200).unwrap(), (0, 1));
+
+        // Other rules are unaffected
+        let mut other = RuleHistory {
+            rule_hash: "rule_history_other",
+            record_hash: "one",
+        };
+        assert_eq!(other.count_events(0).unwrap(), 0);
+        assert_eq!(other.count_attempts(0, 200).unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        use_memory_db();
+        let shaping = Shaping::merge_files(&[]).await.unwrap();
+        let params = BackoffParams {
+            max_message_rate: Some(ThrottleSpec::try_from("2/d").unwrap()),
+            max_steps: 3,
+            hold: Duration::from_secs(300),
+        };
+        let rule = make_rule(Action::Backoff(params.clone()));
+        let rule_hash = "test-backoff";
+        let mut record = make_record("backoff.example.com", "mx.backoff.example.com");
+
+        let apply = |record: &JsonLogRecord| {
+            create_backoff(
+                rule_hash,
+                &rule,
+                record,
+                &params,
+                &shaping,
+                "backoff.example.com",
+                &record.site,
+                "source",
+                None,
+            )
+            .unwrap()
+        };
+
+        apply(&record);
+        assert_eq!(backoff_steps(rule_hash), Some(1));
+
+        // Within the hold period, the rate is not halved again
+        record.timestamp += chrono::Duration::seconds(60);
+        apply(&record);
+        assert_eq!(backoff_steps(rule_hash), Some(1));
+
+        // After it, each trigger halves it again, up to max_steps
+        for expected in [2, 3, 3] {
+            record.timestamp += chrono::Duration::seconds(301);
+            apply(&record);
+            assert_eq!(backoff_steps(rule_hash), Some(expected));
+        }
+
+        // 2/d can only be halved once
+        let config = do_get_config().await.unwrap();
+        assert!(
+            config.contains("halved 1 times from 2/d, the lowest rate possible"),
+            "{config}"
+        );
+
+        // Without a rate to back off from, there is nothing to record
+        let params = BackoffParams::default();
+        let record = make_record("backoff.example.com", "mx.backoff.example.com");
+        create_backoff(
+            "test-backoff-no-rate",
+            &rule,
+            &record,
+            &params,
+            &shaping,
+            "backoff.example.com",
+            &record.site,
+            "source",
+            None,
+        )
+        .unwrap();
+        assert_eq!(backoff_steps("test-backoff-no-rate"), None);
+    }
+
+    #[tokio::test]
+    async fn sched_q_bounce() {
+        use_memory_db();
+        let rule = make_rule(Action::Bounce);
+        let record = make_record("camp:tenant@bounce.example.com", "mx.example.com");
+        create_sched_q_bounce("test-bounce", &rule, &record).unwrap();
+        // Triggering again only extends it
+        create_sched_q_bounce("test-bounce", &rule, &record).unwrap();
+
+        let record = make_record("bounce-other.example.com", "mx.example.com");
+        create_sched_q_bounce("test-bounce", &rule, &record).unwrap();
+
+        let suspensions = do_get_suspension().await.unwrap();
+        let mut bounces: Vec<_> = suspensions
+            .bounce
+            .iter()
+            .filter(|b| b.rule_hash == "test-bounce")
+            .map(|b| {
+                (
+                    b.domain.as_str(),
+                    b.tenant.as_deref(),
+                    b.campaign.as_deref(),
+                )
+            })
+            .collect();
+        bounces.sort();
+        assert_eq!(
+            bounces,
+            vec![
+                ("bounce-other.example.com", None, None),
+                ("bounce.example.com", Some("tenant"), Some("camp")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sched_q_reroute() {
+        use_memory_db();
+        let rule = make_rule(Action::Reroute("pool-a".to_string()));
+        let record = make_record("tenant@reroute.example.com", "mx.example.com");
+        create_sched_q_reroute("test-reroute", &rule, &record, "pool-a").unwrap();
+
+        let reroutes = |suspensions: &Suspensions| -> Vec<(String, Option<String>, String)> {
+            suspensions
+                .reroute
+                .iter()
+                .filter(|r| r.rule_hash == "test-reroute")
+                .map(|r| (r.domain.clone(), r.tenant.clone(), r.egress_pool.clone()))
+                .collect()
+        };
+
+        let suspensions = do_get_suspension().await.unwrap();
+        assert_eq!(
+            reroutes(&suspensions),
+            vec![(
+                "reroute.example.com".to_string(),
+                Some("tenant".to_string()),
+                "pool-a".to_string()
+            )]
+        );
+
+        // Triggering again with a different pool replaces the pool
+        create_sched_q_reroute("test-reroute", &rule, &record, "pool-b").unwrap();
+        let suspensions = do_get_suspension().await.unwrap();
+        assert_eq!(
+            reroutes(&suspensions),
+            vec![(
+                "reroute.example.com".to_string(),
+                Some("tenant".to_string()),
+                "pool-b".to_string()
+            )]
+        );
+    }
+}
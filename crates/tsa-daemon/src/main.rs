@@ -10,6 +10,7 @@ use std::pin::Pin;
 
 mod http_server;
 mod mod_auto;
+mod peer;
 
 /// KumoMTA Traffic Shaping Automation Daemon.
 ///
@@ -95,6 +96,8 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
             diag_format: opts.diag_format,
             filter_env_var: "KUMO_TSA_LOG",
             default_filter: "tsa_daemon=info,kumo_server_common=info,kumo_server_runtime=info",
+            otlp_endpoint: None,
+            service_name: "tsa-daemon",
         },
         lua_funcs: &[kumo_server_common::register, mod_auto::register],
         policy: &opts.policy,
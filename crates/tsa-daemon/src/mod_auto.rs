@@ -1,3 +1,4 @@
+use crate::peer::PeerParams;
 use config::{any_err, from_lua_value, get_or_create_module};
 use kumo_server_common::http_server::HttpListenerParams;
 use mlua::{Lua, Value};
@@ -25,5 +26,13 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    tsa_mod.set(
+        "configure_tsa_peers",
+        lua.create_function(|lua, params: Value| {
+            let params: PeerParams = from_lua_value(lua, params)?;
+            params.start().map_err(any_err)
+        })?,
+    )?;
+
     Ok(())
 }
@@ -0,0 +1,761 @@
+//! Replication of the automation state between clustered tsa-daemon
+//! instances.
+//!
+//! Each instance periodically fetches the active state from its peers
+//! via `/get_state_v1` and merges it into its own database. The rows
+//! are keyed identically on every instance, so merging is a matter of
+//! keeping whichever copy of a row is newest, and the instances converge
+//! on the same set of suspensions and config overrides even when one of
+//! them missed some of the published logs.
+//!
+//! The event and attempt history used by the Threshold and Ratio
+//! triggers is replicated too, so that each instance counts the matches
+//! that were published to the others when evaluating those triggers.
+//! The history can be large, so it is fetched incrementally via
+//! `/get_history_v1`, a page at a time, starting after the last row
+//! that was merged from that peer.
+//!
+//! Rows that are new or updated by a merge are also sent to the local
+//! `subscribe_suspension_v1` subscribers, so that a kumod instance
+//! only needs to be connected to one member of the cluster at a time.
+use crate::http_server::{non_empty, SuspensionSubscriberMgr, HISTORY};
+use anyhow::Context;
+use axum::extract::Query;
+use axum::Json;
+use kumo_api_types::tsa::{
+    ReadyQSuspension, SchedQBounce, SchedQReroute, SchedQSuspension, SuspensionEntry,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+use kumo_server_lifecycle::ShutdownSubcription;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use sqlite::Value as SqlValue;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The number of history rows to request from a peer at a time
+const HISTORY_PAGE_SIZE: usize = 1000;
+/// The maximum number of history rows returned by /get_history_v1
+const MAX_HISTORY_PAGE_SIZE: usize = 10_000;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PeerParams {
+    /// The base URLs of the HTTP listeners of the other
+    /// members of the cluster
+    pub peers: Vec<String>,
+
+    /// How often to fetch the state from each peer
+    #[serde(default = "PeerParams::default_interval", with = "duration_serde")]
+    pub interval: Duration,
+
+    /// How long to wait for a peer to respond
+    #[serde(default = "PeerParams::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+}
+
+impl PeerParams {
+    fn default_interval() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn start(self) -> anyhow::Result<()> {
+        if STARTED.swap(true, Ordering::SeqCst) {
+            anyhow::bail!("tsa peers have already been configured");
+        }
+        kumo_server_runtime::spawn("tsa peer sync", async move {
+            if let Err(err) = self.run().await {
+                tracing::error!("tsa peer sync: {err:#}");
+            }
+        })?;
+        Ok(())
+    }
+
+    async fn run(self) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let mut shutdown = ShutdownSubcription::get();
+        let mut marks: HashMap<&str, HistoryMarks> = HashMap::new();
+
+        loop {
+            for peer in &self.peers {
+                let peer_marks = marks.entry(peer.as_str()).or_default();
+                match sync_from_peer(&client, peer, peer_marks).await {
+                    Ok(changes) => {
+                        tracing::debug!("merged {changes} changes from tsa peer {peer}");
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to sync state from tsa peer {peer}: {err:#}");
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = shutdown.shutting_down() => {
+                    tracing::trace!("tsa peer sync shutting down");
+                    return Ok(());
+                },
+                _ = tokio::time::sleep(self.interval) => {}
+            };
+        }
+    }
+}
+
+struct ReplicatedTable {
+    name: &'static str,
+    key: &'static [&'static str],
+    /// The column that determines which copy of a row is newer.
+    /// Rows of the history tables never change once they have been
+    /// recorded, so they have none, and an existing row is kept as-is.
+    /// The tables that have one also have an `expires` column, and
+    /// only rows that have not yet expired are replicated.
+    newer: Option<&'static str>,
+}
+
+const REPLICATED_TABLES: &[ReplicatedTable] = &[
+    ReplicatedTable {
+        name: "config",
+        key: &["rule_hash", "site_name"],
+        newer: Some("expires"),
+    },
+    ReplicatedTable {
+        name: "ready_q_suspensions",
+        key: &["rule_hash", "site_name"],
+        newer: Some("expires"),
+    },
+    ReplicatedTable {
+        name: "sched_q_suspensions",
+        key: &["rule_hash", "campaign", "tenant", "domain"],
+        newer: Some("expires"),
+    },
+    ReplicatedTable {
+        name: "sched_q_bounces",
+        key: &["rule_hash", "campaign", "tenant", "domain"],
+        newer: Some("expires"),
+    },
+    ReplicatedTable {
+        name: "sched_q_reroutes",
+        key: &["rule_hash", "campaign", "tenant", "domain"],
+        newer: Some("expires"),
+    },
+    ReplicatedTable {
+        name: "backoff",
+        key: &["rule_hash", "site_name"],
+        newer: Some("last_triggered"),
+    },
+    // The history is pruned as it ages out of the trigger windows
+    // each time that a record is published for the rule
+    ReplicatedTable {
+        name: "event_history",
+        key: &["rule_hash", "record_hash"],
+        newer: None,
+    },
+    ReplicatedTable {
+        name: "attempt_history",
+        key: &["rule_hash", "record_hash"],
+        newer: None,
+    },
+];
+
+/// The active state of a tsa-daemon, keyed by table name.
+/// The history tables are not included; see TsaHistoryV1.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TsaStateV1 {
+    pub tables: BTreeMap<String, Vec<Map<String, JsonValue>>>,
+}
+
+/// Selects a page of rows from a history table, in
+/// (ts, rule_hash, record_hash) order
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryV1Query {
+    pub table: String,
+    /// Only rows after this position are returned
+    #[serde(default)]
+    pub after_ts: i64,
+    #[serde(default)]
+    pub after_rule_hash: String,
+    #[serde(default)]
+    pub after_record_hash: String,
+    pub limit: usize,
+}
+
+/// The position of a row in a history table
+#[derive(Debug, Default, Clone, PartialEq)]
+struct HistoryMark {
+    ts: i64,
+    rule_hash: String,
+    record_hash: String,
+}
+
+impl HistoryMark {
+    fn from_row(row: &Map<String, JsonValue>) -> Option<Self> {
+        Some(Self {
+            ts: row.get("ts")?.as_i64()?,
+            rule_hash: row.get("rule_hash")?.as_str()?.to_string(),
+            record_hash: row.get("record_hash")?.as_str()?.to_string(),
+        })
+    }
+}
+
+/// The last history row merged from a peer, keyed by table name
+type HistoryMarks = HashMap<&'static str, HistoryMark>;
+
+/// A page of rows from a history table
+#[derive(Serialize, Deserialize, Default)]
+pub struct TsaHistoryV1 {
+    pub rows: Vec<Map<String, JsonValue>>,
+}
+
+fn sql_to_json(value: SqlValue) -> anyhow::Result<JsonValue> {
+    Ok(match value {
+        SqlValue::Null => JsonValue::Null,
+        SqlValue::Integer(i) => i.into(),
+        SqlValue::Float(f) => serde_json::Number::from_f64(f)
+            .map(JsonValue::Number)
+            .ok_or_else(|| anyhow::anyhow!("impossible float value {f}"))?,
+        SqlValue::String(s) => s.into(),
+        SqlValue::Binary(_) => anyhow::bail!("unexpected binary value"),
+    })
+}
+
+fn json_to_sql(value: &JsonValue) -> anyhow::Result<SqlValue> {
+    Ok(match value {
+        JsonValue::Null => SqlValue::Null,
+        JsonValue::Bool(b) => SqlValue::Integer(if *b { 1 } else { 0 }),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Float(
+                n.as_f64()
+                    .ok_or_else(|| anyhow::anyhow!("impossible number value {n:?}"))?,
+            ),
+        },
+        JsonValue::String(s) => SqlValue::String(s.to_string()),
+        JsonValue::Array(_) | JsonValue::Object(_) => {
+            anyhow::bail!("unexpected value {value:?}")
+        }
+    })
+}
+
+fn export_table(table: &ReplicatedTable) -> anyhow::Result<Vec<Map<String, JsonValue>>> {
+    let stmt = HISTORY.prepare(format!(
+        "SELECT * from {} where unixepoch(expires) - unixepoch() > 0",
+        table.name
+    ))?;
+    read_rows(stmt)
+}
+
+fn export_history(query: &HistoryV1Query) -> anyhow::Result<Vec<Map<String, JsonValue>>> {
+    let table = match REPLICATED_TABLES
+        .iter()
+        .find(|table| table.newer.is_none() && table.name == query.table)
+    {
+        Some(table) => table,
+        None => anyhow::bail!("{} is not a history table", query.table),
+    };
+    let mut stmt = HISTORY.prepare(format!(
+        "SELECT * from {} where (ts, rule_hash, record_hash) > (?, ?, ?)
+         ORDER BY ts, rule_hash, record_hash LIMIT ?",
+        table.name
+    ))?;
+    stmt.bind((1, query.after_ts))?;
+    stmt.bind((2, query.after_rule_hash.as_str()))?;
+    stmt.bind((3, query.after_record_hash.as_str()))?;
+    stmt.bind((4, query.limit.min(MAX_HISTORY_PAGE_SIZE) as i64))?;
+    read_rows(stmt)
+}
+
+fn read_rows(mut stmt: sqlite::Statement<'_>) -> anyhow::Result<Vec<Map<String, JsonValue>>> {
+    let columns = stmt.column_names().to_vec();
+
+    let mut rows = vec![];
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let mut row = Map::new();
+        for (idx, name) in columns.iter().enumerate() {
+            let value: SqlValue = stmt.read(idx)?;
+            row.insert(name.to_string(), sql_to_json(value)?);
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Merge a row received from a peer. Returns true if the row was
+/// not present, or was older than the received row.
+fn merge_row(table: &ReplicatedTable, mut row: Map<String, JsonValue>) -> anyhow::Result<bool> {
+    for &key in table.key {
+        // Rows created before empty strings were used in place of
+        // NULL key values would otherwise never conflict
+        let value = row.entry(key).or_insert(JsonValue::Null);
+        if value.is_null() {
+            *value = JsonValue::String(String::new());
+        }
+    }
+
+    let mut columns = vec![];
+    let mut values = vec![];
+    for (name, value) in &row {
+        // The column names are interpolated into the query
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("invalid column name {name:?} in {}", table.name);
+        }
+        columns.push(name.as_str());
+        values.push(json_to_sql(value)?);
+    }
+
+    let placeholders = vec!["?"; columns.len()].join(", ");
+
+    let conflict = match table.newer {
+        Some(newer) => {
+            let updates = columns
+                .iter()
+                .map(|name| format!("{name}=excluded.{name}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "DO UPDATE SET {updates}
+                 WHERE unixepoch(excluded.{newer}) > unixepoch({table}.{newer})",
+                table = table.name
+            )
+        }
+        None => "DO NOTHING".to_string(),
+    };
+
+    let mut stmt = HISTORY.prepare(format!(
+        "INSERT INTO {table} ({columns}) VALUES ({placeholders})
+             ON CONFLICT ({key}) {conflict}
+             RETURNING rule_hash",
+        table = table.name,
+        columns = columns.join(", "),
+        key = table.key.join(", "),
+    ))?;
+    for (idx, value) in values.into_iter().enumerate() {
+        stmt.bind((idx + 1, value))?;
+    }
+
+    let changed = matches!(stmt.next()?, sqlite::State::Row);
+    if changed {
+        if let Some(entry) = suspension_entry(table, row)? {
+            SuspensionSubscriberMgr::submit(entry);
+        }
+    }
+
+    Ok(changed)
+}
+
+fn suspension_entry(
+    table: &ReplicatedTable,
+    row: Map<String, JsonValue>,
+) -> anyhow::Result<Option<SuspensionEntry>> {
+    let row = JsonValue::Object(row);
+    Ok(match table.name {
+        "ready_q_suspensions" => Some(SuspensionEntry::ReadyQ(serde_json::from_value::<
+            ReadyQSuspension,
+        >(row)?)),
+        "sched_q_suspensions" => {
+            let mut item: SchedQSuspension = serde_json::from_value(row)?;
+            item.campaign = item.campaign.and_then(non_empty);
+            Some(SuspensionEntry::SchedQ(item))
+        }
+        "sched_q_bounces" => {
+            let mut item: SchedQBounce = serde_json::from_value(row)?;
+            item.tenant = item.tenant.and_then(non_empty);
+            item.campaign = item.campaign.and_then(non_empty);
+            Some(SuspensionEntry::Bounce(item))
+        }
+        "sched_q_reroutes" => {
+            let mut item: SchedQReroute = serde_json::from_value(row)?;
+            item.tenant = item.tenant.and_then(non_empty);
+            item.campaign = item.campaign.and_then(non_empty);
+            Some(SuspensionEntry::Reroute(item))
+        }
+        _ => None,
+    })
+}
+
+/// Merge the rows received from a peer, returning the number of
+/// rows that were changed. A row that cannot be merged is logged
+/// and skipped, so that it doesn't prevent merging the others.
+fn merge_rows(table: &ReplicatedTable, rows: Vec<Map<String, JsonValue>>, peer: &str) -> usize {
+    let mut changes = 0;
+    for row in rows {
+        match merge_row(table, row) {
+            Ok(true) => changes += 1,
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!("skipping {} row from tsa peer {peer}: {err:#}", table.name);
+            }
+        }
+    }
+    changes
+}
+
+async fn sync_from_peer(
+    client: &reqwest::Client,
+    peer: &str,
+    marks: &mut HistoryMarks,
+) -> anyhow::Result<usize> {
+    let base = peer.trim_end_matches('/');
+    let url = format!("{base}/get_state_v1");
+    let mut state: TsaStateV1 = client
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("GET {url}"))?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| format!("parsing response from {url}"))?;
+
+    let mut changes = 0;
+    for table in REPLICATED_TABLES {
+        match table.newer {
+            Some(_) => {
+                if let Some(rows) = state.tables.remove(table.name) {
+                    changes += merge_rows(table, rows, peer);
+                }
+            }
+            None => {
+                let mark = marks.entry(table.name).or_default();
+                changes += sync_history_from_peer(client, base, table, mark).await?;
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Fetch and merge the rows of a history table that were recorded
+/// after `mark`, advancing `mark` as they are merged
+async fn sync_history_from_peer(
+    client: &reqwest::Client,
+    base: &str,
+    table: &ReplicatedTable,
+    mark: &mut HistoryMark,
+) -> anyhow::Result<usize> {
+    let url = format!("{base}/get_history_v1");
+    let mut changes = 0;
+    loop {
+        let page: TsaHistoryV1 = client
+            .get(&url)
+            .query(&HistoryV1Query {
+                table: table.name.to_string(),
+                after_ts: mark.ts,
+                after_rule_hash: mark.rule_hash.clone(),
+                after_record_hash: mark.record_hash.clone(),
+                limit: HISTORY_PAGE_SIZE,
+            })
+            .send()
+            .await
+            .with_context(|| format!("GET {url}"))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("parsing response from {url}"))?;
+
+        let prior = mark.clone();
+        if let Some(last) = page.rows.iter().rev().find_map(HistoryMark::from_row) {
+            *mark = last;
+        }
+        changes += merge_rows(table, page.rows, base);
+
+        // An empty page means that we are up to date. If none of the
+        // rows had a position, we can't ask for the next page.
+        if *mark == prior {
+            return Ok(changes);
+        }
+    }
+}
+
+fn do_get_state() -> anyhow::Result<TsaStateV1> {
+    let mut state = TsaStateV1::default();
+    for table in REPLICATED_TABLES {
+        if table.newer.is_some() {
+            state
+                .tables
+                .insert(table.name.to_string(), export_table(table)?);
+        }
+    }
+    Ok(state)
+}
+
+pub async fn get_state_v1(_: TrustedIpRequired) -> Result<Json<TsaStateV1>, AppError> {
+    Ok(Json(do_get_state()?))
+}
+
+pub async fn get_history_v1(
+    _: TrustedIpRequired,
+    Query(query): Query<HistoryV1Query>,
+) -> Result<Json<TsaHistoryV1>, AppError> {
+    Ok(Json(TsaHistoryV1 {
+        rows: export_history(&query)?,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http_server::test::use_memory_db;
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn table(name: &str) -> &'static ReplicatedTable {
+        REPLICATED_TABLES.iter().find(|t| t.name == name).unwrap()
+    }
+
+    fn row(value: JsonValue) -> Map<String, JsonValue> {
+        match value {
+            JsonValue::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    fn expires_in(hours: i64) -> String {
+        (Utc::now() + chrono::Duration::hours(hours)).to_rfc3339()
+    }
+
+    fn exported(name: &str, rule_hash: &str) -> Vec<Map<String, JsonValue>> {
+        let table = table(name);
+        let rows = match table.newer {
+            Some(_) => export_table(table),
+            None => export_history(&HistoryV1Query {
+                table: name.to_string(),
+                after_ts: 0,
+                after_rule_hash: String::new(),
+                after_record_hash: String::new(),
+                limit: MAX_HISTORY_PAGE_SIZE,
+            }),
+        };
+        rows.unwrap()
+            .into_iter()
+            .filter(|row| row["rule_hash"] == rule_hash)
+            .collect()
+    }
+
+    fn ready_q_row(expires: &str) -> Map<String, JsonValue> {
+        row(json!({
+            "rule_hash": "peer-merge",
+            "site_name": "mx.example.com",
+            "reason": "testing",
+            "source": "source",
+            "expires": expires,
+        }))
+    }
+
+    #[test]
+    fn merge_keeps_newest() {
+        use_memory_db();
+        let table = table("ready_q_suspensions");
+        let later = expires_in(2);
+
+        assert!(merge_row(table, ready_q_row(&later)).unwrap());
+        // The same row again changes nothing
+        assert!(!merge_row(table, ready_q_row(&later)).unwrap());
+        // Neither does an older copy of it
+        assert!(!merge_row(table, ready_q_row(&expires_in(1))).unwrap());
+
+        let rows = exported("ready_q_suspensions", "peer-merge");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["expires"], JsonValue::String(later));
+
+        // A newer copy replaces it
+        let latest = expires_in(3);
+        assert!(merge_row(table, ready_q_row(&latest)).unwrap());
+        let rows = exported("ready_q_suspensions", "peer-merge");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["expires"], JsonValue::String(latest));
+    }
+
+    #[test]
+    fn merge_expired_not_exported() {
+        use_memory_db();
+        let table = table("ready_q_suspensions");
+        let mut expired = ready_q_row(&expires_in(-1));
+        expired.insert("rule_hash".to_string(), json!("peer-expired"));
+        assert!(merge_row(table, expired).unwrap());
+        assert!(exported("ready_q_suspensions", "peer-expired").is_empty());
+    }
+
+    #[test]
+    fn merge_null_campaign() {
+        use_memory_db();
+        let table = table("sched_q_suspensions");
+        let make = |campaign: JsonValue, expires: &str| {
+            row(json!({
+                "rule_hash": "peer-null-campaign",
+                "campaign": campaign,
+                "tenant": "tenant",
+                "domain": "example.com",
+                "reason": "testing",
+                "expires": expires,
+            }))
+        };
+
+        assert!(merge_row(table, make(json!(""), &expires_in(1))).unwrap());
+        // A row from a peer that still has a NULL campaign
+        // is the same row as the one with an empty campaign
+        let latest = expires_in(2);
+        assert!(merge_row(table, make(JsonValue::Null, &latest)).unwrap());
+
+        let rows = exported("sched_q_suspensions", "peer-null-campaign");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["campaign"], json!(""));
+        assert_eq!(rows[0]["expires"], JsonValue::String(latest));
+    }
+
+    #[test]
+    fn merge_history() {
+        use_memory_db();
+        let table = table("attempt_history");
+        let attempt = |matched: bool| {
+            row(json!({
+                "rule_hash": "peer-history",
+                "record_hash": "abc",
+                "ts": Utc::now().timestamp(),
+                "matched": matched,
+            }))
+        };
+
+        assert!(merge_row(table, attempt(true)).unwrap());
+        // History rows are never replaced
+        assert!(!merge_row(table, attempt(false)).unwrap());
+
+        let rows = exported("attempt_history", "peer-history");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["matched"], json!(1));
+    }
+
+    #[test]
+    fn history_pages() {
+        use_memory_db();
+        let table = table("event_history");
+        for (ts, record_hash) in [(1, "b"), (1, "a"), (2, "a")] {
+            assert!(merge_row(
+                table,
+                row(json!({
+                    "rule_hash": "peer-pages",
+                    "record_hash": record_hash,
+                    "ts": ts,
+                })),
+            )
+            .unwrap());
+        }
+
+        let page = |after_ts, after_record_hash: &str| {
+            export_history(&HistoryV1Query {
+                table: "event_history".to_string(),
+                after_ts,
+                after_rule_hash: "peer-pages".to_string(),
+                after_record_hash: after_record_hash.to_string(),
+                limit: 2,
+            })
+            .unwrap()
+            .into_iter()
+            .filter(|row| row["rule_hash"] == "peer-pages")
+            .map(|row| HistoryMark::from_row(&row).unwrap())
+            .map(|mark| (mark.ts, mark.record_hash))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            page(1, ""),
+            vec![(1, "a".to_string()), (1, "b".to_string())]
+        );
+        assert_eq!(page(1, "b"), vec![(2, "a".to_string())]);
+        assert!(page(2, "a").is_empty());
+
+        assert!(export_history(&HistoryV1Query {
+            table: "config".to_string(),
+            after_ts: 0,
+            after_rule_hash: String::new(),
+            after_record_hash: String::new(),
+            limit: 1,
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn sync() {
+        use_memory_db();
+
+        let state = json!({
+            "tables": {
+                "sched_q_bounces": [{
+                    "rule_hash": "peer-sync",
+                    "campaign": JsonValue::Null,
+                    "tenant": "",
+                    "domain": "example.com",
+                    "reason": "testing",
+                    "expires": expires_in(1),
+                }, {
+                    "rule_hash": "peer-sync-bad",
+                    "not a column": "testing",
+                }],
+                "not_a_table": [{"rule_hash": "peer-sync"}],
+            }
+        });
+        let now = Utc::now().timestamp();
+        let history: Vec<Map<String, JsonValue>> = (0..3)
+            .map(|i| {
+                row(json!({
+                    "rule_hash": "peer-sync",
+                    "record_hash": format!("def{i}"),
+                    "ts": now + i,
+                }))
+            })
+            .collect();
+
+        // Serves the history a row at a time, to exercise paging
+        let app = axum::Router::new()
+            .route(
+                "/get_state_v1",
+                axum::routing::get(move || {
+                    let state = state.clone();
+                    async move { Json(state) }
+                }),
+            )
+            .route(
+                "/get_history_v1",
+                axum::routing::get(move |Query(query): Query<HistoryV1Query>| {
+                    let rows = if query.table == "event_history" {
+                        history
+                            .iter()
+                            .filter(|row| row["ts"].as_i64().unwrap() > query.after_ts)
+                            .take(1)
+                            .cloned()
+                            .collect()
+                    } else {
+                        vec![]
+                    };
+                    async move { Json(TsaHistoryV1 { rows }) }
+                }),
+            );
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = axum_server::Handle::new();
+        let server = axum_server::from_tcp(socket).handle(handle.clone());
+        tokio::spawn(async move { server.serve(app.into_make_service()).await });
+
+        let client = reqwest::Client::new();
+        let peer = format!("http://{addr}/");
+        let mut marks = HistoryMarks::new();
+        // The malformed row is skipped, rather than failing the sync
+        assert_eq!(sync_from_peer(&client, &peer, &mut marks).await.unwrap(), 4);
+        assert_eq!(marks["event_history"].ts, now + 2);
+        assert_eq!(marks["attempt_history"], HistoryMark::default());
+        // Nothing has changed since the last sync
+        assert_eq!(sync_from_peer(&client, &peer, &mut marks).await.unwrap(), 0);
+        handle.shutdown();
+
+        let rows = exported("sched_q_bounces", "peer-sync");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["campaign"], json!(""));
+        assert_eq!(exported("event_history", "peer-sync").len(), 3);
+    }
+}
@@ -0,0 +1,216 @@
+//! Comparing the effective parameters produced by two sets
+//! of shaping files
+use anyhow::Context;
+use kumo_api_types::shaping::Shaping;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EntryKind {
+    Domain,
+    Provider,
+    Site,
+}
+
+/// An entry in the shaping files, and optionally one of the
+/// sources that it configures
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryPath {
+    kind: EntryKind,
+    name: String,
+    source: String,
+}
+
+impl EntryPath {
+    fn describe(&self) -> String {
+        let kind = match self.kind {
+            EntryKind::Domain => "domain",
+            EntryKind::Provider => "provider",
+            EntryKind::Site => "site",
+        };
+        if self.source.is_empty() {
+            format!("{kind} {}", self.name)
+        } else {
+            format!("{kind} {} source {}", self.name, self.source)
+        }
+    }
+
+    /// Returns the (domain, source, site_name, provider) to pass to
+    /// get_effective_config
+    fn as_params(&self) -> (&str, &str, &str, Option<&str>) {
+        match self.kind {
+            EntryKind::Domain => (&self.name, &self.source, "", None),
+            EntryKind::Provider => ("", &self.source, "", Some(&self.name)),
+            EntryKind::Site => ("", &self.source, &self.name, None),
+        }
+    }
+}
+
+fn collect_paths(shaping: &Shaping, paths: &mut BTreeSet<EntryPath>) {
+    for (kind, names) in [
+        (EntryKind::Domain, shaping.get_domain_names()),
+        (EntryKind::Provider, shaping.get_provider_names()),
+        (EntryKind::Site, shaping.get_site_names()),
+    ] {
+        for name in names {
+            paths.insert(EntryPath {
+                kind,
+                name,
+                source: String::new(),
+            });
+        }
+    }
+    for (source, entries) in shaping.get_referenced_sources() {
+        for entry in entries {
+            let (kind, name) = match entry.split_once(':') {
+                Some(("domain", name)) => (EntryKind::Domain, name),
+                Some(("provider", name)) => (EntryKind::Provider, name),
+                Some(("site", name)) => (EntryKind::Site, name),
+                _ => continue,
+            };
+            paths.insert(EntryPath {
+                kind,
+                name: name.to_string(),
+                source: source.clone(),
+            });
+        }
+    }
+}
+
+fn format_value(value: Option<&toml::Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "(unset)".to_string(),
+    }
+}
+
+/// Write the entries whose effective parameters differ between
+/// `prior` and `current`, along with the parameters that changed
+pub fn write_diff(prior: &Shaping, current: &Shaping, out: &mut impl Write) -> anyhow::Result<()> {
+    let mut paths = BTreeSet::new();
+    collect_paths(prior, &mut paths);
+    collect_paths(current, &mut paths);
+
+    let mut num_changed = 0;
+    for path in &paths {
+        let (domain, source, site, provider) = path.as_params();
+        let before = prior
+            .get_effective_config(domain, source, site, provider)
+            .with_context(|| format!("{} in the --diff files", path.describe()))?;
+        let after = current
+            .get_effective_config(domain, source, site, provider)
+            .with_context(|| path.describe())?;
+        if before == after {
+            // Any differences in the way that the parameters are
+            // written have no effect
+            continue;
+        }
+        num_changed += 1;
+
+        writeln!(out, "{}:", path.describe())?;
+        let before = prior.get_egress_path_params(domain, source, site, provider);
+        let after = current.get_egress_path_params(domain, source, site, provider);
+        let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+        for name in names {
+            let old_value = before.get(name);
+            let new_value = after.get(name);
+            if old_value != new_value {
+                writeln!(
+                    out,
+                    "  {name}: {} -> {}",
+                    format_value(old_value),
+                    format_value(new_value)
+                )?;
+            }
+        }
+    }
+
+    if num_changed == 0 {
+        writeln!(out, "No changes to the effective parameters")?;
+    } else {
+        writeln!(
+            out,
+            "{num_changed} entries have changed effective parameters"
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn load(dir: &std::path::Path, name: &str, toml: &str) -> Shaping {
+        let path = dir.join(name);
+        std::fs::write(&path, toml).unwrap();
+        Shaping::merge_files(&[path.to_str().unwrap().to_string()])
+            .await
+            .unwrap()
+    }
+
+    async fn diff(prior: &str, current: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let prior = load(dir.path(), "prior.toml", prior).await;
+        let current = load(dir.path(), "current.toml", current).await;
+        let mut out = vec![];
+        write_diff(&prior, &current, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn changed_params() {
+        let output = diff(
+            r#"
+["example.com"]
+mx_rollup = false
+connection_limit = 3
+
+["unchanged.com"]
+mx_rollup = false
+connection_limit = 4
+"#,
+            r#"
+["example.com"]
+mx_rollup = false
+connection_limit = 5
+
+["example.com".sources."ip-1"]
+connection_limit = 2
+
+["unchanged.com"]
+mx_rollup = false
+connection_limit = 4
+"#,
+        )
+        .await;
+        assert_eq!(
+            output,
+            "domain example.com:
+  connection_limit: 3 -> 5
+domain example.com source ip-1:
+  connection_limit: 3 -> 2
+2 entries have changed effective parameters
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_params() {
+        let output = diff(
+            r#"
+["example.com"]
+mx_rollup = false
+connection_limit = 3
+"#,
+            // Written differently, but with the same effect
+            r#"
+["example.com"]
+connection_limit = 3
+mx_rollup = false
+max_deliveries_per_connection = 1024
+"#,
+        )
+        .await;
+        assert_eq!(output, "No changes to the effective parameters\n");
+    }
+}
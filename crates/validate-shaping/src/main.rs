@@ -1,35 +1,123 @@
+use anyhow::Context;
 use clap::Parser;
 use kumo_api_types::shaping::Shaping;
+use std::path::PathBuf;
+
+mod diff;
+mod replay;
 
 /// KumoMTA shaping configuration validator
 ///
+/// Validates the shaping files and prints any warnings.
+/// The additional options can be used to inspect the effect
+/// of the files before deploying them.
+///
 /// Full docs available at: <https://docs.kumomta.com>
 #[derive(Debug, Parser)]
 #[command(about)]
 struct Opt {
+    /// Print the effective configuration for the path to
+    /// this destination domain
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Print the effective configuration for the path to
+    /// this site name
+    #[arg(long)]
+    site: Option<String>,
+
+    /// Print the effective configuration for the path from
+    /// this egress source
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Compare against this set of shaping files, such as those
+    /// that are currently deployed, and print the domains and sites
+    /// whose effective parameters would change.
+    /// May be specified multiple times.
+    #[arg(long, value_name = "FILE")]
+    diff: Vec<String>,
+
+    /// Replay the log records in this log segment through the
+    /// automation rules and report which rules would have fired.
+    /// May be specified multiple times; the segments are replayed
+    /// in the order given.
+    #[arg(long, value_name = "SEGMENT")]
+    replay: Vec<PathBuf>,
+
+    /// The shaping files to validate
     files: Vec<String>,
 }
 
+impl Opt {
+    async fn print_config(&self, shaping: &Shaping) -> anyhow::Result<()> {
+        let domain = self.domain.as_deref().unwrap_or_default();
+        let site = self.site.as_deref().unwrap_or_default();
+        let source = self.source.as_deref().unwrap_or_default();
+
+        let provider = if domain.is_empty() {
+            None
+        } else {
+            shaping
+                .resolve_provider(domain)
+                .await
+                .with_context(|| format!("resolving the provider for {domain}"))?
+        };
+        if let Some(provider) = &provider {
+            eprintln!("{domain} is handled by provider {provider}");
+        }
+
+        let config = shaping.get_effective_config(domain, source, site, provider.as_deref())?;
+        println!("{config:#?}");
+        Ok(())
+    }
+
+    async fn run(&self) -> anyhow::Result<bool> {
+        let merged = Shaping::merge_files(&self.files).await?;
+
+        let mut ok = true;
+        for warn in merged.get_warnings() {
+            eprintln!("{warn}");
+            ok = false;
+        }
+
+        if self.domain.is_some() || self.site.is_some() || self.source.is_some() {
+            self.print_config(&merged).await?;
+        }
+
+        if !self.diff.is_empty() {
+            let prior = Shaping::merge_files(&self.diff)
+                .await
+                .context("loading the --diff files")?;
+            diff::write_diff(&prior, &merged, &mut std::io::stdout())?;
+        }
+
+        if !self.replay.is_empty() {
+            let mut replay = replay::Replay::new(&merged, std::io::stdout());
+            for path in &self.replay {
+                replay.replay_segment(path).await?;
+            }
+            replay.print_summary()?;
+        }
+
+        if ok {
+            eprintln!("OK");
+        }
+        Ok(ok)
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let opts = Opt::parse();
-    let mut failed = false;
 
-    match Shaping::merge_files(&opts.files).await {
-        Ok(merged) => {
-            for warn in merged.get_warnings() {
-                eprintln!("{warn}");
-                failed = true;
-            }
-            if !failed {
-                eprintln!("OK");
-            }
-        }
+    let failed = match opts.run().await {
+        Ok(ok) => !ok,
         Err(err) => {
             eprintln!("{err:#}");
-            failed = true;
+            true
         }
-    }
+    };
 
     if failed {
         std::process::exit(1);
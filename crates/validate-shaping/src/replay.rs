@@ -0,0 +1,266 @@
+//! Replaying the records in a log segment through the automation
+//! rules, to show which rules would have fired for that traffic.
+//!
+//! The triggers are evaluated in the same way as in tsa-daemon,
+//! except that the history of matches is held in memory, and the
+//! timestamp of each record is used in place of the current time.
+use anyhow::Context;
+use kumo_api_types::shaping::{MemoryTriggerHistory, Rule, Shaping};
+use kumo_log_types::JsonLogRecord;
+use kumo_logq::segment::Segment;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+#[derive(Default)]
+struct RuleStats {
+    matched: u64,
+    fired: u64,
+}
+
+pub struct Replay<'a, W: Write> {
+    shaping: &'a Shaping,
+    /// Where the rules that fired, and the summary, are written
+    out: W,
+    /// The history used to evaluate the triggers,
+    /// keyed by the store_key and the rule
+    history: HashMap<(String, u64), MemoryTriggerHistory>,
+    /// Keyed by the description of the rule
+    stats: BTreeMap<String, RuleStats>,
+    /// The provider of each recipient domain
+    providers: HashMap<String, Option<String>>,
+    num_records: u64,
+    num_skipped: u64,
+}
+
+fn rule_key(rule: &Rule) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    rule.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// As in tsa-daemon, the matches of the rules of a provider are
+/// counted across all of the sites of that provider
+fn store_key(rule: &Rule, record: &JsonLogRecord, source: &str) -> String {
+    match &rule.provider {
+        Some(provider) => format!("{source}->provider:{provider}"),
+        None => record.site.to_string(),
+    }
+}
+
+fn describe_rule(rule: &Rule) -> String {
+    let regex: Vec<&str> = rule.regex.iter().map(|r| r.as_str()).collect();
+    let description = format!(
+        "regex={regex:?} trigger={:?} action={:?}",
+        rule.trigger, rule.action
+    );
+    match &rule.provider {
+        Some(provider) => format!("provider:{provider} {description}"),
+        None => description,
+    }
+}
+
+impl<'a, W: Write> Replay<'a, W> {
+    pub fn new(shaping: &'a Shaping, out: W) -> Self {
+        Self {
+            shaping,
+            out,
+            history: HashMap::new(),
+            stats: BTreeMap::new(),
+            providers: HashMap::new(),
+            num_records: 0,
+            num_skipped: 0,
+        }
+    }
+
+    pub async fn replay_segment(&mut self, path: &Path) -> anyhow::Result<()> {
+        let segment = Segment::open(path)?;
+        for line in segment.reader()?.split(b'\n') {
+            let line = line.with_context(|| format!("reading {}", path.display()))?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<JsonLogRecord>(&line) {
+                Ok(record) => self.replay_record(&record).await?,
+                Err(_) => {
+                    // Not a JSON log record, as can be the case
+                    // when log templates are used
+                    self.num_skipped += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn fired(&mut self, rule: &Rule, record: &JsonLogRecord) -> anyhow::Result<()> {
+        let description = describe_rule(rule);
+        writeln!(
+            self.out,
+            "{} {} {description}: {}",
+            record.timestamp,
+            record.site,
+            record.response.to_single_line()
+        )?;
+        self.stats.entry(description).or_default().fired += 1;
+        Ok(())
+    }
+
+    async fn resolve_provider(&mut self, domain: &str) -> Option<String> {
+        if let Some(provider) = self.providers.get(domain) {
+            return provider.clone();
+        }
+        let provider = match self.shaping.resolve_provider(domain).await {
+            Ok(provider) => provider,
+            Err(err) => {
+                eprintln!("resolving provider for {domain}: {err:#}");
+                None
+            }
+        };
+        self.providers.insert(domain.to_string(), provider.clone());
+        provider
+    }
+
+    async fn replay_record(&mut self, record: &JsonLogRecord) -> anyhow::Result<()> {
+        self.num_records += 1;
+
+        let domain = match record.recipient.rsplit_once('@') {
+            Some((_, domain)) => domain.to_string(),
+            None => {
+                // The local postmaster address
+                return Ok(());
+            }
+        };
+        let source = record.egress_source.as_deref().unwrap_or("unspecified");
+        // NOTE: this is coupled with the logic in tsa-daemon's
+        // publish_log_v1_impl
+        let site_name = record
+            .site
+            .trim_start_matches(&format!("{source}->"))
+            .trim_end_matches("@smtp_client")
+            .to_string();
+        let now = record.timestamp.timestamp();
+        let provider = self.resolve_provider(&domain).await;
+        let provider = provider.as_deref();
+
+        for rule in self
+            .shaping
+            .match_rules(record, &domain, &site_name, provider)
+        {
+            self.stats.entry(describe_rule(&rule)).or_default().matched += 1;
+
+            let history = self
+                .history
+                .entry((store_key(&rule, record, source), rule_key(&rule)))
+                .or_default();
+            if rule.record_match(history, now, now)? {
+                self.fired(&rule, record)?;
+            }
+        }
+
+        for rule in self.shaping.ratio_rules(&domain, &site_name, provider) {
+            let matched = match rule.ratio_sample(record) {
+                Some(matched) => matched,
+                None => continue,
+            };
+            if matched {
+                self.stats.entry(describe_rule(&rule)).or_default().matched += 1;
+            }
+
+            let history = self
+                .history
+                .entry((store_key(&rule, record, source), rule_key(&rule)))
+                .or_default();
+            if rule.record_attempt(history, matched, now, now)? {
+                self.fired(&rule, record)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_summary(&mut self) -> anyhow::Result<()> {
+        writeln!(self.out)?;
+        writeln!(
+            self.out,
+            "Replayed {} records ({} lines were not log records)",
+            self.num_records, self.num_skipped
+        )?;
+        if self.stats.is_empty() {
+            writeln!(self.out, "No automation rules matched")?;
+        }
+        for (description, stats) in &self.stats {
+            writeln!(
+                self.out,
+                "{description}: matched {} times, fired {} times",
+                stats.matched, stats.fired
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record_line(ts: i64, kind: &str, code: u16, content: &str) -> String {
+        format!(
+            r#"{{"type":"{kind}","id":"id","sender":"sender@example.com","recipient":"recip@example.com","queue":"example.com","site":"source->mx.example.com@smtp_client","size":1024,"response":{{"code":{code},"enhanced_code":null,"content":"{content}","command":null}},"peer_address":null,"timestamp":{ts},"created":{ts},"num_attempts":1,"bounce_classification":"Uncategorized","egress_pool":null,"egress_source":"source","source_address":null,"feedback_report":null,"meta":{{}},"headers":{{}},"delivery_protocol":"ESMTP","reception_protocol":null,"nodeid":"00000000-0000-0000-0000-000000000000"}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn replay_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let shaping = dir.path().join("shaping.toml");
+        std::fs::write(
+            &shaping,
+            r#"
+["example.com"]
+mx_rollup = false
+
+[["example.com".automation]]
+regex = "try later"
+action = "Suspend"
+trigger = {Threshold="2/minute"}
+duration = "1 hour"
+"#,
+        )
+        .unwrap();
+        let shaping = Shaping::merge_files(&[shaping.to_str().unwrap().to_string()])
+            .await
+            .unwrap();
+
+        let segment = dir.path().join("segment");
+        let lines = [
+            record_line(1000, "TransientFailure", 421, "try later"),
+            "not a log record".to_string(),
+            record_line(1010, "TransientFailure", 421, "try later"),
+            record_line(1020, "Delivery", 250, "ok"),
+            // Outside of the period of the earlier matches
+            record_line(1100, "TransientFailure", 421, "try later"),
+        ];
+        std::fs::write(&segment, lines.join("\n")).unwrap();
+
+        let mut out = vec![];
+        let mut replay = Replay::new(&shaping, &mut out);
+        replay.replay_segment(&segment).await.unwrap();
+        replay.print_summary().unwrap();
+
+        let description = "regex=[\"try later\"] \
+            trigger=Threshold(ThrottleSpec { limit: 2, period: 60, max_burst: None, pacing: false }) \
+            action=[Suspend]";
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "1970-01-01 00:16:50 UTC source->mx.example.com@smtp_client \
+                 {description}: 421 try later
+
+Replayed 4 records (1 lines were not log records)
+{description}: matched 3 times, fired 1 times
+"
+            )
+        );
+    }
+}
@@ -302,6 +302,10 @@ TOC = [
                         "Ongage",
                         "userguide/integrations/ongage.md",
                     ),
+                    Page(
+                        "OpenTelemetry",
+                        "userguide/integrations/opentelemetry.md",
+                    ),
                     Page(
                         "Prometheus",
                         "userguide/integrations/prometheus.md",